@@ -0,0 +1,112 @@
+//! Packs `initramfs/` into a newc cpio archive, which the kernel embeds and
+//! unpacks into its root filesystem at boot. Setting `INITRAMFS` to a cpio or
+//! ustar archive embeds that instead.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const SOURCE_DIR: &str = "initramfs";
+
+/// Placeholder files that only exist so git keeps empty directories.
+const PLACEHOLDER: &str = ".keep";
+
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=INITRAMFS");
+    println!("cargo:rerun-if-changed={}", SOURCE_DIR);
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("initramfs.cpio");
+
+    let archive = match env::var("INITRAMFS") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            fs::read(&path).unwrap_or_else(|e| panic!("cannot read INITRAMFS {}: {}", path, e))
+        }
+        Err(_) => {
+            let mut archive = Cpio::default();
+            if Path::new(SOURCE_DIR).is_dir() {
+                archive.add_tree(Path::new(SOURCE_DIR), "").expect("cannot pack initramfs/");
+            }
+            archive.finish()
+        }
+    };
+    fs::write(out, archive).unwrap();
+}
+
+#[cfg(unix)]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+    if metadata.is_dir() { 0o755 } else { 0o644 }
+}
+
+#[derive(Default)]
+struct Cpio {
+    data: Vec<u8>,
+    next_ino: u32,
+}
+
+impl Cpio {
+    /// Adds the contents of `dir` under `prefix`, each directory before what
+    /// is in it, in name order so the archive is reproducible.
+    fn add_tree(&mut self, dir: &Path, prefix: &str) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name == PLACEHOLDER {
+                continue;
+            }
+            let path = format!("{}{}", prefix, name);
+            let metadata = fs::symlink_metadata(entry.path())?;
+            let mode = permissions(&metadata);
+            if metadata.file_type().is_symlink() {
+                let target = fs::read_link(entry.path())?;
+                self.add(&path, S_IFLNK | 0o777, target.to_string_lossy().as_bytes());
+            } else if metadata.is_dir() {
+                self.add(&path, S_IFDIR | mode, &[]);
+                self.add_tree(&entry.path(), &format!("{}/", path))?;
+            } else {
+                self.add(&path, S_IFREG | mode, &fs::read(entry.path())?);
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, name: &str, mode: u32, contents: &[u8]) {
+        self.next_ino += 1;
+        let nlink = if mode & 0o170000 == S_IFDIR { 2 } else { 1 };
+        let fields = [
+            self.next_ino, mode, 0, 0, nlink, 0, contents.len() as u32,
+            0, 0, 0, 0, name.len() as u32 + 1, 0,
+        ];
+        self.data.extend_from_slice(b"070701");
+        for field in fields {
+            self.data.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        self.pad();
+        self.data.extend_from_slice(contents);
+        self.pad();
+    }
+
+    fn pad(&mut self) {
+        while self.data.len() % 4 != 0 {
+            self.data.push(0);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.add("TRAILER!!!", 0, &[]);
+        self.data
+    }
+}
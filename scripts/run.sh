@@ -18,4 +18,6 @@ qemu-system-x86_64 \
     -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
     -serial stdio \
     -display gtk \
-    -machine type=q35 
\ No newline at end of file
+    -smp 4 \
+    -nic user,model=rtl8139 \
+    -machine type=q35
\ No newline at end of file
@@ -0,0 +1,156 @@
+use alloc::vec::Vec;
+use core::ptr;
+use spin::Once;
+use x86_64::PhysAddr;
+use crate::memory::phys_to_virt;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const SDT_HEADER_SIZE: usize = 36;
+
+static MADT: Once<Madt> = Once::new();
+
+/// A processor listed in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessorApic {
+    pub acpi_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that is wired to a different global system interrupt.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+/// Interrupt controller layout described by the MADT ("APIC" table).
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub processors: Vec<ProcessorApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(phys_to_virt(addr).as_ptr::<T>())
+}
+
+unsafe fn checksum_ok(addr: PhysAddr, len: usize) -> bool {
+    let bytes = core::slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), len);
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Scans the EBDA and the BIOS ROM area for the root system description pointer.
+unsafe fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = (read::<u16>(PhysAddr::new(0x40E)) as u64) << 4;
+    let areas = [(ebda, ebda + 1024), (0xE0000, 0x100000)];
+
+    for (start, end) in areas {
+        if start == 0 {
+            continue;
+        }
+        for addr in (start..end).step_by(16) {
+            let addr = PhysAddr::new(addr);
+            if &read::<[u8; 8]>(addr) == RSDP_SIGNATURE && checksum_ok(addr, 20) {
+                return Some(addr);
+            }
+        }
+    }
+    None
+}
+
+/// Walks the RSDT (or XSDT on ACPI 2.0+) for a table with `signature`.
+unsafe fn find_table(rsdp: PhysAddr, signature: &[u8; 4]) -> Option<PhysAddr> {
+    let revision = read::<u8>(rsdp + 15u64);
+    let (root, entry_size) = if revision >= 2 {
+        (PhysAddr::new(read::<u64>(rsdp + 24u64)), 8)
+    } else {
+        (PhysAddr::new(read::<u32>(rsdp + 16u64) as u64), 4)
+    };
+
+    let length = read::<u32>(root + 4u64) as usize;
+    let entries = (length - SDT_HEADER_SIZE) / entry_size;
+    (0..entries)
+        .map(|i| {
+            let entry = root + (SDT_HEADER_SIZE + i * entry_size) as u64;
+            if entry_size == 8 {
+                PhysAddr::new(read::<u64>(entry))
+            } else {
+                PhysAddr::new(read::<u32>(entry) as u64)
+            }
+        })
+        .find(|&table| {
+            &read::<[u8; 4]>(table) == signature
+                && checksum_ok(table, read::<u32>(table + 4u64) as usize)
+        })
+}
+
+unsafe fn parse_madt(table: PhysAddr) -> Madt {
+    let length = read::<u32>(table + 4u64) as u64;
+    let mut madt = Madt {
+        local_apic_address: read::<u32>(table + SDT_HEADER_SIZE as u64) as u64,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    // Entries start after the header, the local APIC address and the flags
+    let mut offset = SDT_HEADER_SIZE as u64 + 8;
+    while offset + 2 <= length {
+        let entry = table + offset;
+        let kind = read::<u8>(entry);
+        let len = read::<u8>(entry + 1u64) as u64;
+        if len < 2 {
+            break;
+        }
+
+        match kind {
+            0 => madt.processors.push(ProcessorApic {
+                acpi_id: read(entry + 2u64),
+                apic_id: read(entry + 3u64),
+                enabled: read::<u32>(entry + 4u64) & 1 != 0,
+            }),
+            1 => madt.io_apics.push(IoApic {
+                id: read(entry + 2u64),
+                address: read(entry + 4u64),
+                gsi_base: read(entry + 8u64),
+            }),
+            2 => madt.overrides.push(InterruptOverride {
+                source: read(entry + 3u64),
+                gsi: read(entry + 4u64),
+                flags: read(entry + 8u64),
+            }),
+            5 => madt.local_apic_address = read(entry + 4u64),
+            _ => {}
+        }
+        offset += len;
+    }
+
+    madt
+}
+
+/// Locates and parses the MADT. Must run after memory management is set up.
+pub fn init() -> Result<(), &'static str> {
+    let madt = unsafe {
+        let rsdp = find_rsdp().ok_or("RSDP not found")?;
+        let table = find_table(rsdp, MADT_SIGNATURE).ok_or("MADT not found")?;
+        parse_madt(table)
+    };
+    MADT.call_once(|| madt);
+    Ok(())
+}
+
+pub fn madt() -> Option<&'static Madt> {
+    MADT.r#try()
+}
@@ -0,0 +1,321 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::fs;
+use super::{check_range, BlockDevice, SECTOR_SIZE};
+
+/// Legacy ports of the primary IDE channel, which QEMU's first two `-drive`s
+/// sit on.
+const PRIMARY_IO_BASE: u16 = 0x1F0;
+const PRIMARY_CONTROL: u16 = 0x3F6;
+
+const REG_DATA: u16 = 0;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+/// Set in the device control register: the drive raises no interrupts, as
+/// transfers are polled.
+const CONTROL_NIEN: u8 = 1 << 1;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+/// Highest sector LBA28 commands can reach.
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// Sectors moved per command. Each command runs with interrupts off, so this
+/// bounds how long they stay off.
+const MAX_SECTORS_PER_COMMAND: u64 = 16;
+
+/// Status polls before a drive is given up on.
+const TIMEOUT: usize = 1_000_000;
+
+/// Major number Linux gives the first IDE channel.
+const IDE0_MAJOR: u32 = 3;
+
+/// The task file of one channel. Master and slave share it, so a command runs
+/// to completion before the other drive is selected.
+struct Channel {
+    io_base: u16,
+    control: u16,
+}
+
+impl Channel {
+    fn read(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io_base + reg).read() }
+    }
+
+    fn write(&self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io_base + reg).write(value) }
+    }
+
+    /// Status without acknowledging anything, from the control block.
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control).read() }
+    }
+
+    /// Gives the drive the 400ns it needs to post its status after a command
+    /// or drive select.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, &'static str> {
+        for _ in 0..TIMEOUT {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err("ATA drive timed out")
+    }
+
+    /// Waits until the drive is ready to move a sector of data.
+    fn wait_data(&self) -> Result<(), &'static str> {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err("ATA drive reported an error");
+        }
+        if status & STATUS_DRQ == 0 {
+            return Err("ATA drive has no data ready");
+        }
+        Ok(())
+    }
+
+    fn select(&self, slave: bool, lba_bits: u8) -> Result<(), &'static str> {
+        self.write(REG_DRIVE, 0xE0 | (slave as u8) << 4 | (lba_bits & 0x0F));
+        self.delay();
+        self.wait_not_busy().map(|_| ())
+    }
+
+    /// Loads the registers for a transfer of `count` sectors at `lba` and
+    /// issues the LBA28 or LBA48 form of the command.
+    fn issue(&self, slave: bool, lba: u64, count: u64, lba48: bool, commands: (u8, u8)) -> Result<(), &'static str> {
+        if lba48 {
+            self.select(slave, 0)?;
+            // High bytes go first; the registers are two-deep FIFOs
+            self.write(REG_SECTOR_COUNT, (count >> 8) as u8);
+            self.write(REG_LBA_LOW, (lba >> 24) as u8);
+            self.write(REG_LBA_MID, (lba >> 32) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 40) as u8);
+            self.write(REG_SECTOR_COUNT, count as u8);
+            self.write(REG_LBA_LOW, lba as u8);
+            self.write(REG_LBA_MID, (lba >> 8) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 16) as u8);
+            self.write(REG_COMMAND, commands.1);
+        } else {
+            self.select(slave, (lba >> 24) as u8)?;
+            self.write(REG_SECTOR_COUNT, count as u8);
+            self.write(REG_LBA_LOW, lba as u8);
+            self.write(REG_LBA_MID, (lba >> 8) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 16) as u8);
+            self.write(REG_COMMAND, commands.0);
+        }
+        self.delay();
+        Ok(())
+    }
+
+    fn read_sector(&self, buf: &mut [u8]) {
+        let mut data = Port::<u16>::new(self.io_base + REG_DATA);
+        for word in buf.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_sector(&self, buf: &[u8]) {
+        let mut data = Port::<u16>::new(self.io_base + REG_DATA);
+        for word in buf.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    /// Runs IDENTIFY DEVICE. `None` when nothing, or an ATAPI device, answers.
+    fn identify(&self, slave: bool) -> Option<[u16; 256]> {
+        self.write(REG_DRIVE, 0xA0 | (slave as u8) << 4);
+        self.delay();
+        self.write(REG_SECTOR_COUNT, 0);
+        self.write(REG_LBA_LOW, 0);
+        self.write(REG_LBA_MID, 0);
+        self.write(REG_LBA_HIGH, 0);
+        self.write(REG_COMMAND, CMD_IDENTIFY);
+        self.delay();
+
+        // A floating bus reads all ones, an absent drive zero
+        let status = self.read(REG_STATUS);
+        if status == 0 || status == 0xFF {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        // Packet devices put their signature here and abort the command
+        if self.read(REG_LBA_MID) != 0 || self.read(REG_LBA_HIGH) != 0 {
+            return None;
+        }
+        self.wait_data().ok()?;
+
+        let mut raw = [0u8; SECTOR_SIZE];
+        self.read_sector(&mut raw);
+        let mut words = [0u16; 256];
+        for (word, bytes) in words.iter_mut().zip(raw.chunks_exact(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Some(words)
+    }
+}
+
+/// A drive on an IDE channel, driven by polled PIO.
+pub struct AtaDrive {
+    channel: Arc<Mutex<Channel>>,
+    slave: bool,
+    name: String,
+    model: String,
+    sectors: u64,
+    lba48: bool,
+}
+
+impl AtaDrive {
+    fn from_identify(channel: Arc<Mutex<Channel>>, slave: bool, words: &[u16; 256]) -> Option<Self> {
+        let lba48 = words[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (0..4).fold(0u64, |sectors, i| sectors | (words[100 + i] as u64) << (16 * i))
+        } else {
+            words[60] as u64 | (words[61] as u64) << 16
+        };
+        // Drives without LBA (word 49 bit 9) predate anything QEMU emulates
+        if words[49] & (1 << 9) == 0 || sectors == 0 {
+            return None;
+        }
+
+        // ASCII with the two bytes of each word swapped, padded with spaces
+        let mut model = String::new();
+        for word in &words[27..47] {
+            model.push((word >> 8) as u8 as char);
+            model.push(*word as u8 as char);
+        }
+
+        Some(Self {
+            channel,
+            slave,
+            name: String::from(if slave { "hdb" } else { "hda" }),
+            model: String::from(model.trim()),
+            sectors,
+            lba48,
+        })
+    }
+
+    /// Whether commands must use the 48-bit form to reach `end`.
+    fn needs_lba48(&self, end: u64) -> bool {
+        self.lba48 && end > LBA28_LIMIT
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dev(&self) -> u64 {
+        fs::makedev(IDE0_MAJOR, if self.slave { 64 } else { 0 })
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(lba, buf.len(), self.sectors)?;
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS_PER_COMMAND as usize * SECTOR_SIZE).enumerate() {
+            let start = lba + i as u64 * MAX_SECTORS_PER_COMMAND;
+            let count = (chunk.len() / SECTOR_SIZE) as u64;
+            let lba48 = self.needs_lba48(start + count);
+            interrupts::without_interrupts(|| {
+                let channel = self.channel.lock();
+                channel.issue(self.slave, start, count, lba48, (CMD_READ_SECTORS, CMD_READ_SECTORS_EXT))?;
+                for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                    channel.wait_data()?;
+                    channel.read_sector(sector);
+                }
+                Ok::<(), &'static str>(())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, data: &[u8]) -> Result<(), &'static str> {
+        check_range(lba, data.len(), self.sectors)?;
+        for (i, chunk) in data.chunks(MAX_SECTORS_PER_COMMAND as usize * SECTOR_SIZE).enumerate() {
+            let start = lba + i as u64 * MAX_SECTORS_PER_COMMAND;
+            let count = (chunk.len() / SECTOR_SIZE) as u64;
+            let lba48 = self.needs_lba48(start + count);
+            interrupts::without_interrupts(|| {
+                let channel = self.channel.lock();
+                channel.issue(self.slave, start, count, lba48, (CMD_WRITE_SECTORS, CMD_WRITE_SECTORS_EXT))?;
+                for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                    channel.wait_data()?;
+                    channel.write_sector(sector);
+                }
+                let status = channel.wait_not_busy()?;
+                if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    return Err("ATA write failed");
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        interrupts::without_interrupts(|| {
+            let channel = self.channel.lock();
+            channel.select(self.slave, 0)?;
+            channel.write(REG_COMMAND, if self.lba48 { CMD_CACHE_FLUSH_EXT } else { CMD_CACHE_FLUSH });
+            channel.delay();
+            let status = channel.wait_not_busy()?;
+            if status & STATUS_ERR != 0 {
+                return Err("ATA cache flush failed");
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Looks for the master and slave drives of the primary channel and registers
+/// them as `hda` and `hdb`.
+pub fn probe() {
+    let channel = Channel {
+        io_base: PRIMARY_IO_BASE,
+        control: PRIMARY_CONTROL,
+    };
+    unsafe { Port::<u8>::new(channel.control).write(CONTROL_NIEN) };
+    let channel = Arc::new(Mutex::new(channel));
+
+    for slave in [false, true] {
+        let words = interrupts::without_interrupts(|| channel.lock().identify(slave));
+        let drive = words.and_then(|words| AtaDrive::from_identify(Arc::clone(&channel), slave, &words));
+        if let Some(drive) = drive {
+            super::register(Arc::new(drive));
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::{Mutex, MutexGuard};
+use crate::interrupts::apic::TIMER_HZ;
+use crate::println;
+use crate::task::{self, timer};
+use super::{BlockDevice, SECTOR_SIZE};
+
+/// Unit of caching. Filesystem blocks of any size map onto these.
+pub const BLOCK_SIZE: usize = 4096;
+const SECTORS_PER_BLOCK: u64 = (BLOCK_SIZE / SECTOR_SIZE) as u64;
+
+/// Blocks kept in memory, 1 MiB in all, taken from the kernel heap.
+const CAPACITY: usize = 256;
+
+/// Blocks fetched ahead once reads are seen to be sequential.
+const READ_AHEAD: u64 = 8;
+
+/// Seconds between background write-backs of dirty blocks.
+const FLUSH_INTERVAL: u64 = 5;
+
+/// Device number and block index.
+type Key = (u64, u64);
+
+struct Buffer {
+    device: Arc<dyn BlockDevice>,
+    data: Box<[u8; BLOCK_SIZE]>,
+    dirty: bool,
+    /// Set while the block is being read in or written back, which happens
+    /// without the cache lock; nobody may use or evict the buffer meanwhile.
+    busy: bool,
+    /// Position in the LRU order; higher is more recent.
+    stamp: u64,
+}
+
+/// Counters since boot, and the current occupancy.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks brought in by read-ahead rather than on demand.
+    pub read_ahead: u64,
+    pub write_backs: u64,
+    pub evictions: u64,
+    pub cached: usize,
+    pub dirty: usize,
+    pub capacity: usize,
+}
+
+struct Cache {
+    buffers: BTreeMap<Key, Buffer>,
+    /// Keys by stamp, least recently used first.
+    lru: BTreeMap<u64, Key>,
+    next_stamp: u64,
+    /// Last block read from each device, to detect sequential access.
+    last_read: BTreeMap<u64, u64>,
+    stats: CacheStats,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    buffers: BTreeMap::new(),
+    lru: BTreeMap::new(),
+    next_stamp: 0,
+    last_read: BTreeMap::new(),
+    stats: CacheStats {
+        hits: 0,
+        misses: 0,
+        read_ahead: 0,
+        write_backs: 0,
+        evictions: 0,
+        cached: 0,
+        dirty: 0,
+        capacity: CAPACITY,
+    },
+});
+
+/// Sectors of `block` that exist on `device`; the last block of a disk whose
+/// size is not a multiple of `BLOCK_SIZE` is short.
+fn block_sectors(device: &dyn BlockDevice, block: u64) -> u64 {
+    device.sector_count().saturating_sub(block * SECTORS_PER_BLOCK).min(SECTORS_PER_BLOCK)
+}
+
+fn write_back(device: &dyn BlockDevice, block: u64, data: &[u8; BLOCK_SIZE]) -> Result<(), &'static str> {
+    let len = block_sectors(device, block) as usize * SECTOR_SIZE;
+    device.write_sectors(block * SECTORS_PER_BLOCK, &data[..len])
+}
+
+/// I/O a request needs done before it can go on. It is found with the cache
+/// locked and done with the lock dropped, so other tasks can use the cache
+/// while the device works.
+enum Blocked {
+    /// Another task has I/O in flight on a block needed.
+    Busy,
+    /// A dirty block has to reach the disk before its buffer can be reused.
+    WriteBack(WriteBack),
+    /// Blocks have to be read in; busy buffers for them are in place.
+    Fill(Fill),
+}
+
+/// A dirty block being written back. Its buffer stays busy, so nobody
+/// changes it, until the write is done.
+struct WriteBack {
+    key: Key,
+    device: Arc<dyn BlockDevice>,
+    data: Box<[u8; BLOCK_SIZE]>,
+}
+
+impl WriteBack {
+    fn run(self) -> Result<(), &'static str> {
+        let result = write_back(&*self.device, self.key.1, &self.data);
+        let mut cache = CACHE.lock();
+        if let Some(buffer) = cache.buffers.get_mut(&self.key) {
+            buffer.busy = false;
+            if result.is_ok() {
+                buffer.dirty = false;
+            }
+        }
+        if result.is_ok() {
+            cache.stats.write_backs += 1;
+        }
+        result
+    }
+}
+
+/// `count` consecutive blocks from `first` to read in one go.
+struct Fill {
+    device: Arc<dyn BlockDevice>,
+    first: u64,
+    count: u64,
+}
+
+impl Fill {
+    /// Reads the blocks in and returns with the cache locked again, so the
+    /// caller finds the first one there.
+    fn run(self) -> Result<MutexGuard<'static, Cache>, &'static str> {
+        let dev = self.device.dev();
+        let sectors = (self.first..self.first + self.count)
+            .map(|block| block_sectors(&*self.device, block))
+            .sum::<u64>();
+        let mut data = alloc::vec![0u8; sectors as usize * SECTOR_SIZE];
+        let result = self.device.read_sectors(self.first * SECTORS_PER_BLOCK, &mut data);
+
+        let mut cache = CACHE.lock();
+        for (i, block) in (self.first..self.first + self.count).enumerate() {
+            let key = (dev, block);
+            if result.is_err() {
+                cache.remove(key);
+                continue;
+            }
+            if let Some(buffer) = cache.buffers.get_mut(&key) {
+                let chunk = &data[i * BLOCK_SIZE..((i + 1) * BLOCK_SIZE).min(data.len())];
+                buffer.data[..chunk.len()].copy_from_slice(chunk);
+                buffer.busy = false;
+            }
+        }
+        result?;
+        // The requested block is the most recently used, not the read-ahead
+        cache.touch((dev, self.first));
+        Ok(cache)
+    }
+}
+
+impl Blocked {
+    /// Does the I/O, or for `Busy` lets the task doing it run.
+    fn run(self) -> Result<(), &'static str> {
+        match self {
+            Blocked::Busy => task::yield_now(),
+            Blocked::WriteBack(write) => write.run()?,
+            Blocked::Fill(fill) => drop(fill.run()?),
+        }
+        Ok(())
+    }
+}
+
+impl Cache {
+    fn touch(&mut self, key: Key) {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        if let Some(buffer) = self.buffers.get_mut(&key) {
+            self.lru.remove(&buffer.stamp);
+            buffer.stamp = stamp;
+            self.lru.insert(stamp, key);
+        }
+    }
+
+    fn remove(&mut self, key: Key) {
+        if let Some(buffer) = self.buffers.remove(&key) {
+            self.lru.remove(&buffer.stamp);
+        }
+    }
+
+    /// Makes room for `count` more buffers by dropping the least recently
+    /// used idle ones. A dirty one has to be written back first.
+    fn make_room(&mut self, count: usize) -> Result<(), Blocked> {
+        while self.buffers.len() + count > CAPACITY {
+            let victim = self.lru.values().copied().find(|key| !self.buffers[key].busy);
+            let key = victim.ok_or(Blocked::Busy)?;
+            let buffer = self.buffers.get_mut(&key).ok_or(Blocked::Busy)?;
+            if buffer.dirty {
+                buffer.busy = true;
+                return Err(Blocked::WriteBack(WriteBack {
+                    key,
+                    device: Arc::clone(&buffer.device),
+                    data: buffer.data.clone(),
+                }));
+            }
+            self.remove(key);
+            self.stats.evictions += 1;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, key: Key, device: &Arc<dyn BlockDevice>, dirty: bool, busy: bool) {
+        self.buffers.insert(key, Buffer {
+            device: Arc::clone(device),
+            data: Box::new([0u8; BLOCK_SIZE]),
+            dirty,
+            busy,
+            stamp: 0,
+        });
+        self.touch(key);
+    }
+
+    /// Makes sure `block` is cached and idle. Unless `whole` says the caller
+    /// overwrites all of it, a missing block is read in, along with the
+    /// blocks after it for sequential access.
+    fn load(&mut self, device: &Arc<dyn BlockDevice>, block: u64, whole: bool) -> Result<(), Blocked> {
+        let dev = device.dev();
+        let key = (dev, block);
+        if let Some(buffer) = self.buffers.get(&key) {
+            if buffer.busy {
+                return Err(Blocked::Busy);
+            }
+            self.stats.hits += 1;
+            self.touch(key);
+            if !whole {
+                self.last_read.insert(dev, block);
+            }
+            return Ok(());
+        }
+        if whole {
+            // Overwritten whole, so there is nothing to read first
+            self.make_room(1)?;
+            self.stats.misses += 1;
+            self.insert(key, device, true, false);
+            return Ok(());
+        }
+
+        let sequential = self.last_read.get(&dev) == Some(&block.wrapping_sub(1));
+        let total_blocks = (device.sector_count() + SECTORS_PER_BLOCK - 1) / SECTORS_PER_BLOCK;
+        let mut count = 1;
+        if sequential {
+            // Stop at the first block that is already here
+            while count <= READ_AHEAD
+                && block + count < total_blocks
+                && !self.buffers.contains_key(&(dev, block + count))
+            {
+                count += 1;
+            }
+        }
+        self.make_room(count as usize)?;
+        self.stats.misses += 1;
+        self.stats.read_ahead += count - 1;
+        self.last_read.insert(dev, block);
+        for block in block..block + count {
+            self.insert((dev, block), device, false, true);
+        }
+        Err(Blocked::Fill(Fill { device: Arc::clone(device), first: block, count }))
+    }
+
+    /// The first dirty block at or after `from`, of `dev` or of any device.
+    /// It is marked busy and handed out to be written back, unless someone
+    /// else already has I/O in flight on it.
+    fn next_dirty(&mut self, dev: Option<u64>, from: Key) -> Option<Result<WriteBack, Key>> {
+        let (&key, buffer) = self.buffers.range_mut(from..)
+            .find(|(key, buffer)| buffer.dirty && dev.map_or(true, |dev| dev == key.0))?;
+        if buffer.busy {
+            return Some(Err(key));
+        }
+        buffer.busy = true;
+        Some(Ok(WriteBack {
+            key,
+            device: Arc::clone(&buffer.device),
+            data: buffer.data.clone(),
+        }))
+    }
+}
+
+/// Locks the cache with `block` of `device` in it and idle, doing whatever
+/// I/O that takes with the lock dropped.
+fn lock_block(device: &Arc<dyn BlockDevice>, block: u64, whole: bool) -> Result<MutexGuard<'static, Cache>, &'static str> {
+    if block_sectors(&**device, block) == 0 {
+        return Err("read past the end of the device");
+    }
+    loop {
+        let mut cache = CACHE.lock();
+        match cache.load(device, block, whole) {
+            Ok(()) => return Ok(cache),
+            Err(Blocked::Fill(fill)) => {
+                drop(cache);
+                return fill.run();
+            }
+            Err(blocked) => {
+                drop(cache);
+                blocked.run()?;
+            }
+        }
+    }
+}
+
+/// Reads `buf.len()` bytes at byte `offset` of `device` through the cache.
+pub fn read(device: &Arc<dyn BlockDevice>, offset: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+    let dev = device.dev();
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let (block, start) = (pos / BLOCK_SIZE as u64, (pos % BLOCK_SIZE as u64) as usize);
+        let chunk = (BLOCK_SIZE - start).min(buf.len() - done);
+        let cache = lock_block(device, block, false)?;
+        let data = &cache.buffers[&(dev, block)].data;
+        buf[done..done + chunk].copy_from_slice(&data[start..start + chunk]);
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Writes `data` at byte `offset` of `device`. The blocks are only marked
+/// dirty; they reach the disk on eviction, on the periodic flush or on
+/// `sync`.
+pub fn write(device: &Arc<dyn BlockDevice>, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+    if device.read_only() {
+        return Err("device is read-only");
+    }
+    if offset + data.len() as u64 > device.size() {
+        return Err("write past the end of the device");
+    }
+    let dev = device.dev();
+    let mut done = 0;
+    while done < data.len() {
+        let pos = offset + done as u64;
+        let (block, start) = (pos / BLOCK_SIZE as u64, (pos % BLOCK_SIZE as u64) as usize);
+        let chunk = (BLOCK_SIZE - start).min(data.len() - done);
+        let mut cache = lock_block(device, block, chunk == BLOCK_SIZE)?;
+        let buffer = cache.buffers.get_mut(&(dev, block)).ok_or("block vanished from the cache")?;
+        buffer.data[start..start + chunk].copy_from_slice(&data[done..done + chunk]);
+        buffer.dirty = true;
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Writes back the dirty blocks of `dev`, or of every device, in block
+/// order, then flushes the devices written to.
+fn sync_dirty(dev: Option<u64>) -> Result<(), &'static str> {
+    let mut devices: Vec<Arc<dyn BlockDevice>> = Vec::new();
+    let mut result = Ok(());
+    let mut from = (0, 0);
+    loop {
+        let next = CACHE.lock().next_dirty(dev, from);
+        match next {
+            None => break,
+            // Being written back or evicted already; it has to be on the
+            // disk before this returns, so wait for it
+            Some(Err(_)) => task::yield_now(),
+            Some(Ok(write)) => {
+                from = (write.key.0, write.key.1 + 1);
+                if !devices.iter().any(|device| device.dev() == write.key.0) {
+                    devices.push(Arc::clone(&write.device));
+                }
+                if let Err(e) = write.run() {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+    for device in devices {
+        result = result.and(device.flush());
+    }
+    result
+}
+
+/// Writes every dirty block back and flushes the disks' write caches.
+pub fn sync() -> Result<(), &'static str> {
+    sync_dirty(None)
+}
+
+/// Writes back the dirty blocks of one device, e.g. before unmounting it.
+pub fn sync_device(device: &dyn BlockDevice) -> Result<(), &'static str> {
+    sync_dirty(Some(device.dev()))
+}
+
+pub fn stats() -> CacheStats {
+    let cache = CACHE.lock();
+    let mut stats = cache.stats;
+    stats.cached = cache.buffers.len();
+    stats.dirty = cache.buffers.values().filter(|buffer| buffer.dirty).count();
+    stats
+}
+
+/// Kernel task that writes dirty blocks back every `FLUSH_INTERVAL` seconds,
+/// so little is lost if the machine goes down without a `sync`. It runs on
+/// its own stack rather than in the executor, which would stall the shell
+/// for as long as the disk takes.
+pub fn flush_task() {
+    let mut next = timer::ticks() + FLUSH_INTERVAL * TIMER_HZ;
+    loop {
+        while timer::ticks() < next {
+            task::yield_now();
+        }
+        next += FLUSH_INTERVAL * TIMER_HZ;
+        if let Err(e) = sync() {
+            println!("buffer cache: write-back failed: {}", e);
+        }
+    }
+}
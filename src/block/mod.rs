@@ -0,0 +1,103 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+use crate::println;
+
+pub mod ata;
+pub mod cache;
+pub mod virtio;
+
+/// Sector size every device reports; all offsets are in these units.
+pub const SECTOR_SIZE: usize = 512;
+
+/// A disk addressed in fixed-size sectors, which is what disk filesystems are
+/// mounted from.
+pub trait BlockDevice: Send + Sync {
+    /// Short name such as `hda`, used as the mount source.
+    fn name(&self) -> &str;
+    /// Device number, as reported in `FileStats::rdev`.
+    fn dev(&self) -> u64;
+    fn model(&self) -> &str;
+    fn sector_count(&self) -> u64;
+    /// Reads `buf.len() / SECTOR_SIZE` sectors starting at `lba`.
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+    /// Writes `data.len() / SECTOR_SIZE` sectors starting at `lba`.
+    fn write_sectors(&self, lba: u64, data: &[u8]) -> Result<(), &'static str>;
+    /// Returns once everything written so far is on stable storage.
+    fn flush(&self) -> Result<(), &'static str>;
+
+    /// Whether writes are refused.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Size in bytes.
+    fn size(&self) -> u64 {
+        self.sector_count() * SECTOR_SIZE as u64
+    }
+}
+
+/// Checks that a transfer of `len` bytes at `lba` is whole sectors inside a
+/// device of `sectors` sectors.
+pub fn check_range(lba: u64, len: usize, sectors: u64) -> Result<u64, &'static str> {
+    if len % SECTOR_SIZE != 0 {
+        return Err("transfer is not a whole number of sectors");
+    }
+    let count = (len / SECTOR_SIZE) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= sectors => Ok(count),
+        _ => Err("transfer past the end of the device"),
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref DEVICES: RwLock<Vec<Arc<dyn BlockDevice>>> = RwLock::new(Vec::new());
+}
+
+/// Makes `device` available by name.
+pub fn register(device: Arc<dyn BlockDevice>) {
+    interrupts::without_interrupts(|| {
+        DEVICES.write().push(device);
+    });
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    interrupts::without_interrupts(|| DEVICES.read().clone())
+}
+
+/// Device called `name`; a `/dev/` prefix is accepted.
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    let name = name.strip_prefix("/dev/").unwrap_or(name);
+    interrupts::without_interrupts(|| {
+        DEVICES.read().iter().find(|device| device.name() == name).cloned()
+    })
+}
+
+/// Size in the short form `lsblk` uses, e.g. `64M` or `1.5G`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    // In tenths, for one decimal place
+    let mut size = bytes as u128 * 10;
+    let mut unit = 0;
+    while size >= 1024 * 10 && unit < UNITS.len() - 1 {
+        size /= 1024;
+        unit += 1;
+    }
+    if size % 10 == 0 {
+        alloc::format!("{}{}", size / 10, UNITS[unit])
+    } else {
+        alloc::format!("{}.{}{}", size / 10, size % 10, UNITS[unit])
+    }
+}
+
+/// Probes the disk controllers and registers what is found.
+pub fn init() {
+    ata::probe();
+    virtio::probe();
+    for device in devices() {
+        println!("{}: {} sectors ({}), {}",
+            device.name(), device.sector_count(), format_size(device.size()), device.model());
+    }
+}
@@ -0,0 +1,516 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::interrupts::{register_irq, IrqReturn};
+use crate::pci::{self, Bar};
+use crate::{fs, memory, println, task};
+use super::{check_range, BlockDevice, SECTOR_SIZE};
+
+const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+/// Transitional virtio-blk, which QEMU offers by default and which keeps the
+/// legacy I/O port interface.
+const VIRTIO_BLK_DEVICE_ID: u16 = 0x1001;
+
+// Legacy register block in BAR0, followed by the device configuration
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0C;
+const REG_QUEUE_SELECT: u16 = 0x0E;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+const REG_ISR_STATUS: u16 = 0x13;
+const REG_CONFIG: u16 = 0x14;
+
+const CONFIG_CAPACITY: u16 = REG_CONFIG;
+const CONFIG_SEG_MAX: u16 = REG_CONFIG + 12;
+const CONFIG_BLK_SIZE: u16 = REG_CONFIG + 20;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 128;
+
+const ISR_QUEUE: u8 = 1;
+
+const F_SEG_MAX: u32 = 1 << 2;
+const F_RO: u32 = 1 << 5;
+const F_BLK_SIZE: u32 = 1 << 6;
+const F_FLUSH: u32 = 1 << 9;
+
+/// Features the driver understands, with the names they are reported under.
+const KNOWN_FEATURES: [(u32, &str); 4] = [
+    (F_SEG_MAX, "seg_max"),
+    (F_RO, "ro"),
+    (F_BLK_SIZE, "blk_size"),
+    (F_FLUSH, "flush"),
+];
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+const S_OK: u8 = 0;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Largest transfer put in one request; bigger ones are split into several
+/// requests that are all queued before waiting on any.
+const MAX_REQUEST_SECTORS: usize = 128;
+
+/// Major number of virtio disks, as Linux usually ends up assigning.
+const VIRTBLK_MAJOR: u32 = 254;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// `struct virtio_blk_req` up to the data.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// A split virtqueue in the legacy layout: descriptor table, available ring,
+/// then the used ring on the next page boundary. Next to it sit a request
+/// header and a status byte for every possible chain head.
+struct Virtqueue {
+    size: u16,
+    descriptors: *mut Descriptor,
+    avail: *mut u16,
+    used: *mut u16,
+    headers: *mut RequestHeader,
+    headers_phys: PhysAddr,
+    statuses: *mut u8,
+    statuses_phys: PhysAddr,
+    free: Vec<u16>,
+    /// Chain heads the device has given back, until their submitter collects
+    /// them.
+    completed: Vec<bool>,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+// The raw pointers refer to memory owned by the queue for good
+unsafe impl Send for Virtqueue {}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+impl Virtqueue {
+    fn new(size: u16) -> Result<(Self, PhysAddr), &'static str> {
+        let n = size as usize;
+        let avail_offset = 16 * n;
+        let used_offset = align_up(avail_offset + 6 + 2 * n, PAGE_SIZE);
+        let ring_bytes = used_offset + align_up(6 + 8 * n, PAGE_SIZE);
+        let ring = memory::alloc_dma(ring_bytes / PAGE_SIZE)?;
+        let extra = memory::alloc_dma(align_up(n * 17, PAGE_SIZE) / PAGE_SIZE)?;
+
+        let base = memory::phys_to_virt(ring).as_mut_ptr::<u8>();
+        let extra_base = memory::phys_to_virt(extra).as_mut_ptr::<u8>();
+        let queue = Self {
+            size,
+            descriptors: base as *mut Descriptor,
+            avail: unsafe { base.add(avail_offset) } as *mut u16,
+            used: unsafe { base.add(used_offset) } as *mut u16,
+            headers: extra_base as *mut RequestHeader,
+            headers_phys: extra,
+            statuses: unsafe { extra_base.add(16 * n) },
+            statuses_phys: extra + 16 * n as u64,
+            free: (0..size).rev().collect(),
+            completed: alloc::vec![false; n],
+            avail_idx: 0,
+            last_used: 0,
+        };
+        Ok((queue, ring))
+    }
+
+    /// Builds a chain of the request header, `segments` of data and the
+    /// status byte, and makes it available. `None` if the table is too full.
+    fn push(&mut self, header: RequestHeader, segments: &[(PhysAddr, u32)], device_writes: bool) -> Option<u16> {
+        let count = segments.len() + 2;
+        if self.free.len() < count {
+            return None;
+        }
+        let chain: Vec<u16> = (0..count).map(|_| self.free.pop().unwrap()).collect();
+        let head = chain[0];
+
+        let data_flags = if device_writes { DESC_F_WRITE } else { 0 };
+        let mut parts = Vec::with_capacity(count);
+        parts.push((self.headers_phys + 16 * head as u64, 16, 0));
+        parts.extend(segments.iter().map(|&(addr, len)| (addr, len, data_flags)));
+        parts.push((self.statuses_phys + head as u64, 1, DESC_F_WRITE));
+
+        unsafe {
+            ptr::write_volatile(self.headers.add(head as usize), header);
+            ptr::write_volatile(self.statuses.add(head as usize), 0xFF);
+            for (i, &(addr, len, flags)) in parts.iter().enumerate() {
+                let last = i + 1 == count;
+                ptr::write_volatile(self.descriptors.add(chain[i] as usize), Descriptor {
+                    addr: addr.as_u64(),
+                    len,
+                    flags: if last { flags } else { flags | DESC_F_NEXT },
+                    next: if last { 0 } else { chain[i + 1] },
+                });
+            }
+
+            // ring[avail_idx % size] follows the flags and idx fields
+            let slot = 2 + (self.avail_idx % self.size) as usize;
+            ptr::write_volatile(self.avail.add(slot), head);
+            // The device must see the entry before the index that covers it
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            ptr::write_volatile(self.avail.add(1), self.avail_idx);
+            fence(Ordering::SeqCst);
+        }
+        Some(head)
+    }
+
+    /// Marks every chain the device has finished with since the last call.
+    fn reap(&mut self) {
+        loop {
+            let used_idx = unsafe { ptr::read_volatile(self.used.add(1)) };
+            if used_idx == self.last_used {
+                break;
+            }
+            fence(Ordering::SeqCst);
+            // Elements of 8 bytes start after the flags and idx fields
+            let slot = (self.last_used % self.size) as usize;
+            let id = unsafe { ptr::read_volatile((self.used.add(2) as *const u32).add(slot * 2)) };
+            self.completed[id as usize] = true;
+            self.last_used = self.last_used.wrapping_add(1);
+        }
+    }
+
+    /// Status of the finished chain at `head`, whose descriptors are freed.
+    /// `None` while the device still has it.
+    fn collect(&mut self, head: u16) -> Option<u8> {
+        if !self.completed[head as usize] {
+            return None;
+        }
+        self.completed[head as usize] = false;
+        let status = unsafe { ptr::read_volatile(self.statuses.add(head as usize)) };
+        let mut index = head;
+        loop {
+            self.free.push(index);
+            let descriptor = unsafe { ptr::read_volatile(self.descriptors.add(index as usize)) };
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = descriptor.next;
+        }
+        Some(status)
+    }
+}
+
+/// A virtio block device on the PCI bus, using one request queue.
+pub struct VirtioBlk {
+    io_base: u16,
+    name: String,
+    index: usize,
+    model: String,
+    sectors: u64,
+    features: u32,
+    /// Data segments one request may have.
+    max_segments: usize,
+    queue: Mutex<Virtqueue>,
+}
+
+lazy_static::lazy_static! {
+    /// Devices the shared interrupt handler looks at.
+    static ref DEVICES: RwLock<Vec<Arc<VirtioBlk>>> = RwLock::new(Vec::new());
+}
+
+impl VirtioBlk {
+    fn read8(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io_base + reg).read() }
+    }
+
+    fn write8(&self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io_base + reg).write(value) }
+    }
+
+    fn read32(io_base: u16, reg: u16) -> u32 {
+        unsafe { Port::<u32>::new(io_base + reg).read() }
+    }
+
+    /// Resets the device, negotiates features and sets up queue 0.
+    fn new(io_base: u16, index: usize) -> Result<Self, &'static str> {
+        let status = |value: u8| unsafe { Port::<u8>::new(io_base + REG_DEVICE_STATUS).write(value) };
+        status(0);
+        status(STATUS_ACKNOWLEDGE);
+        status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let offered = Self::read32(io_base, REG_DEVICE_FEATURES);
+        let features = offered & KNOWN_FEATURES.iter().fold(0, |mask, &(bit, _)| mask | bit);
+        unsafe { Port::<u32>::new(io_base + REG_GUEST_FEATURES).write(features) };
+
+        let size = unsafe {
+            Port::<u16>::new(io_base + REG_QUEUE_SELECT).write(0);
+            Port::<u16>::new(io_base + REG_QUEUE_SIZE).read()
+        };
+        if size == 0 {
+            status(STATUS_FAILED);
+            return Err("virtio-blk has no request queue");
+        }
+        let (queue, ring) = Virtqueue::new(size)?;
+        unsafe {
+            Port::<u32>::new(io_base + REG_QUEUE_ADDRESS).write((ring.as_u64() / PAGE_SIZE as u64) as u32);
+        }
+
+        let capacity = Self::read32(io_base, CONFIG_CAPACITY) as u64
+            | (Self::read32(io_base, CONFIG_CAPACITY + 4) as u64) << 32;
+        let seg_max = if features & F_SEG_MAX != 0 {
+            Self::read32(io_base, CONFIG_SEG_MAX) as usize
+        } else {
+            usize::MAX
+        };
+        let block_size = if features & F_BLK_SIZE != 0 {
+            Self::read32(io_base, CONFIG_BLK_SIZE)
+        } else {
+            SECTOR_SIZE as u32
+        };
+
+        let names: Vec<&str> = KNOWN_FEATURES.iter()
+            .filter(|&&(bit, _)| features & bit != 0)
+            .map(|&(_, name)| name)
+            .collect();
+        Ok(Self {
+            io_base,
+            name: format!("vd{}", (b'a' + index as u8) as char),
+            index,
+            model: format!("virtio-blk, {}-byte blocks [{}]", block_size, names.join(" ")),
+            sectors: capacity,
+            features,
+            // The header and status take two descriptors of every chain
+            max_segments: seg_max.min(size as usize - 2).max(1),
+            queue: Mutex::new(queue),
+        })
+    }
+
+    fn start(&self) {
+        self.write8(REG_DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK);
+    }
+
+    fn notify(&self) {
+        unsafe { Port::<u16>::new(self.io_base + REG_QUEUE_NOTIFY).write(0) };
+    }
+
+    /// Queues a request; `None` if it has to wait for descriptors.
+    fn submit(&self, kind: u32, sector: u64, segments: &[(PhysAddr, u32)]) -> Option<u16> {
+        let header = RequestHeader { kind, reserved: 0, sector };
+        let head = interrupts::without_interrupts(|| {
+            self.queue.lock().push(header, segments, kind == T_IN)
+        })?;
+        self.notify();
+        Some(head)
+    }
+
+    /// Waits for the request at `head`. Completions are normally picked up by
+    /// the interrupt handler; checking the ring here as well keeps things
+    /// moving when the interrupt line is not routed.
+    fn wait(&self, head: u16) -> Result<(), &'static str> {
+        loop {
+            let status = interrupts::without_interrupts(|| {
+                let mut queue = self.queue.lock();
+                queue.reap();
+                queue.collect(head)
+            });
+            match status {
+                Some(S_OK) => return Ok(()),
+                Some(_) => return Err("virtio-blk request failed"),
+                None => {
+                    task::yield_now();
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
+
+    /// Physical segments of the buffer at `addr`, split at page boundaries
+    /// since the heap is only virtually contiguous.
+    fn segments(addr: VirtAddr, len: usize) -> Result<Vec<(PhysAddr, u32)>, &'static str> {
+        let mut segments: Vec<(PhysAddr, u32)> = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let virt = addr + done as u64;
+            let chunk = (PAGE_SIZE - virt.as_u64() as usize % PAGE_SIZE).min(len - done);
+            let phys = memory::virt_to_phys(virt).ok_or("buffer is not mapped")?;
+            match segments.last_mut() {
+                Some((start, seg_len)) if *start + *seg_len as u64 == phys => *seg_len += chunk as u32,
+                _ => segments.push((phys, chunk as u32)),
+            }
+            done += chunk;
+        }
+        Ok(segments)
+    }
+
+    /// Moves `len` bytes at `addr` to or from the disk at `lba`, as several
+    /// requests in flight at once if needed.
+    fn transfer(&self, kind: u32, lba: u64, addr: VirtAddr, len: usize) -> Result<(), &'static str> {
+        check_range(lba, len, self.sectors)?;
+        // Each request must fit in `max_segments` even if no two pages of the
+        // buffer are physically adjacent
+        let request_sectors = MAX_REQUEST_SECTORS
+            .min((self.max_segments.saturating_sub(1) * PAGE_SIZE / SECTOR_SIZE).max(1));
+
+        let mut in_flight: Vec<u16> = Vec::new();
+        let mut result = Ok(());
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(request_sectors * SECTOR_SIZE);
+            // Requests already queued still point into the buffer, so they
+            // must complete before the error goes back to the caller
+            let segments = match Self::segments(addr + done as u64, chunk) {
+                Ok(segments) => segments,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            let sector = lba + (done / SECTOR_SIZE) as u64;
+            match self.submit(kind, sector, &segments) {
+                Some(head) => {
+                    in_flight.push(head);
+                    done += chunk;
+                }
+                // Out of descriptors: wait for our oldest request, or for
+                // someone else's if we have none
+                None if !in_flight.is_empty() => {
+                    result = result.and(self.wait(in_flight.remove(0)));
+                }
+                None => task::yield_now(),
+            }
+        }
+        for head in in_flight {
+            result = result.and(self.wait(head));
+        }
+        result
+    }
+
+    /// Acknowledges the interrupt and collects finished requests.
+    fn handle_interrupt(&self) -> bool {
+        if self.read8(REG_ISR_STATUS) & ISR_QUEUE == 0 {
+            return false;
+        }
+        // A submitter holding the lock reaps for itself
+        if let Some(mut queue) = self.queue.try_lock() {
+            queue.reap();
+        }
+        true
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dev(&self) -> u64 {
+        fs::makedev(VIRTBLK_MAJOR, self.index as u32 * 16)
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.features & F_RO != 0
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.transfer(T_IN, lba, VirtAddr::from_ptr(buf.as_mut_ptr()), buf.len())
+    }
+
+    fn write_sectors(&self, lba: u64, data: &[u8]) -> Result<(), &'static str> {
+        if self.read_only() {
+            return Err("device is read-only");
+        }
+        self.transfer(T_OUT, lba, VirtAddr::from_ptr(data.as_ptr()), data.len())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        // Without the feature the device writes through
+        if self.features & F_FLUSH == 0 {
+            return Ok(());
+        }
+        loop {
+            match self.submit(T_FLUSH, 0, &[]) {
+                Some(head) => return self.wait(head),
+                None => task::yield_now(),
+            }
+        }
+    }
+}
+
+fn handle_interrupt() -> IrqReturn {
+    let mut handled = false;
+    for device in DEVICES.read().iter() {
+        handled |= device.handle_interrupt();
+    }
+    if handled { IrqReturn::Handled } else { IrqReturn::None }
+}
+
+/// Sets up every virtio-blk function on the PCI bus and registers them as
+/// `vda`, `vdb`, ...
+pub fn probe() {
+    let mut found = Vec::new();
+    pci::for_each_device(|device| {
+        if device.vendor_id == VIRTIO_VENDOR_ID && device.device_id == VIRTIO_BLK_DEVICE_ID {
+            found.push(*device);
+        }
+    });
+
+    let mut lines = Vec::new();
+    for (index, device) in found.into_iter().enumerate() {
+        let io_base = match device.bar(0) {
+            Some(Bar::Io(io_base)) => io_base,
+            _ => {
+                println!("virtio-blk: no legacy I/O BAR");
+                continue;
+            }
+        };
+        device.enable();
+        let blk = match VirtioBlk::new(io_base, index) {
+            Ok(blk) => Arc::new(blk),
+            Err(e) => {
+                println!("virtio-blk: {}", e);
+                continue;
+            }
+        };
+        interrupts::without_interrupts(|| DEVICES.write().push(Arc::clone(&blk)));
+        if let Some(line) = device.interrupt_line() {
+            if !lines.contains(&line) {
+                lines.push(line);
+                if let Err(e) = register_irq(line, "virtio-blk", handle_interrupt) {
+                    println!("virtio-blk: IRQ {} unavailable: {}", line, e);
+                }
+            }
+        }
+        blk.start();
+        super::register(blk);
+    }
+}
@@ -0,0 +1,334 @@
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::random::RdRand;
+use crate::block::{self, cache, BlockDevice};
+use crate::fs::{self, path, Directory, File, FileStats, FileType, FsError, Filesystem, Result};
+use crate::keyboard::{self, KeyEvent};
+use crate::{print, serial};
+
+/// Most a `File::read` of a character device returns, as the stream behind
+/// it may never end.
+const READ_CHUNK: usize = 4096;
+
+/// What a device file stands for.
+#[derive(Clone)]
+enum Device {
+    Null,
+    Zero,
+    /// Random bytes from the CPU's `RdRand`, or from a TSC-seeded generator
+    /// where it is missing.
+    Random,
+    /// The VGA screen for output, the keyboard for input.
+    Console,
+    Serial,
+    /// Key presses as UTF-8, without the shell's line editing.
+    Keyboard,
+    Block(Arc<dyn BlockDevice>),
+}
+
+/// Character devices, with the device numbers Linux gives them.
+const CHAR_DEVICES: [(&str, u32, u32, Device); 7] = [
+    ("null", 1, 3, Device::Null),
+    ("zero", 1, 5, Device::Zero),
+    ("random", 1, 8, Device::Random),
+    ("urandom", 1, 9, Device::Random),
+    ("ttyS0", 4, 64, Device::Serial),
+    ("console", 5, 1, Device::Console),
+    // Linux's input major; there is no evdev protocol, only characters
+    ("keyboard", 13, 64, Device::Keyboard),
+];
+
+/// Device file called `name`: one of the fixed character devices, or a
+/// registered block device.
+fn lookup(name: &str) -> Option<DevFile> {
+    // Inode 1 is the root directory
+    if let Some(index) = CHAR_DEVICES.iter().position(|(n, ..)| *n == name) {
+        let (_, major, minor, device) = &CHAR_DEVICES[index];
+        return Some(DevFile {
+            device: device.clone(),
+            rdev: fs::makedev(*major, *minor),
+            inode: index as u64 + 2,
+        });
+    }
+    let devices = block::devices();
+    let index = devices.iter().position(|device| device.name() == name)?;
+    let device = Arc::clone(&devices[index]);
+    Some(DevFile {
+        rdev: device.dev(),
+        device: Device::Block(device),
+        inode: (CHAR_DEVICES.len() + index) as u64 + 2,
+    })
+}
+
+/// State of the generator used without `RdRand`.
+static FALLBACK_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Next value of a splitmix64 generator with the time stamp counter mixed
+/// into each step. Fine for `/dev/urandom` users that want variety; nothing
+/// cryptographic should rely on it.
+fn fallback_u64() -> u64 {
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    let step = FALLBACK_STATE.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed);
+    let mut z = step.wrapping_add(tsc);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn fill_random(buf: &mut [u8]) {
+    let rdrand = RdRand::new();
+    for chunk in buf.chunks_mut(8) {
+        let value = rdrand.and_then(|rdrand| rdrand.get_u64()).unwrap_or_else(fallback_u64);
+        chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+/// Bytes of a key press that a short read had no room for, handed out
+/// first on the next one.
+static PENDING_KEY: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// Fills `buf` with the key presses already waiting, without blocking. A
+/// character longer than the room left is split across reads.
+fn read_keys(buf: &mut [u8]) -> usize {
+    let mut pending = PENDING_KEY.lock();
+    let mut count = 0;
+    while count < buf.len() {
+        if pending.is_empty() {
+            match keyboard::try_read_key() {
+                Some(KeyEvent::Char(c)) => pending.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                // Arrows and the like have no character
+                Some(KeyEvent::SpecialKey(_)) => continue,
+                None => break,
+            }
+        }
+        let chunk = pending.len().min(buf.len() - count);
+        buf[count..count + chunk].copy_from_slice(&pending[..chunk]);
+        pending.drain(..chunk);
+        count += chunk;
+    }
+    count
+}
+
+/// A device, opened as a file.
+struct DevFile {
+    device: Device,
+    rdev: u64,
+    inode: u64,
+}
+
+impl DevFile {
+    fn file_type(&self) -> FileType {
+        match self.device {
+            Device::Block(_) => FileType::BlockDevice,
+            _ => FileType::CharDevice,
+        }
+    }
+}
+
+impl File for DevFile {
+    fn read(&self) -> Result<Vec<u8>> {
+        let mut data = alloc::vec![0; READ_CHUNK];
+        let count = self.read_at(0, &mut data)?;
+        data.truncate(count);
+        Ok(data)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        self.write_at(0, data).map(|_| ())
+    }
+
+    fn append(&self, data: &[u8]) -> Result<()> {
+        self.write(data)
+    }
+
+    /// Devices have no length to cut, so opening one for writing with
+    /// truncation is allowed and does nothing.
+    fn truncate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        match &self.device {
+            Device::Null => Ok(0),
+            Device::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            Device::Random => {
+                fill_random(buf);
+                Ok(buf.len())
+            }
+            Device::Console | Device::Keyboard => Ok(read_keys(buf)),
+            Device::Serial => {
+                let mut count = 0;
+                while count < buf.len() {
+                    match serial::try_read() {
+                        Some(byte) => buf[count] = byte,
+                        None => break,
+                    }
+                    count += 1;
+                }
+                Ok(count)
+            }
+            Device::Block(device) => {
+                let size = device.size();
+                if offset as u64 >= size {
+                    return Ok(0);
+                }
+                let count = buf.len().min((size - offset as u64) as usize);
+                cache::read(device, offset as u64, &mut buf[..count]).map_err(|_| FsError::Io)?;
+                Ok(count)
+            }
+        }
+    }
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize> {
+        match &self.device {
+            // Writes to the random device would add entropy; there is no
+            // pool to add it to
+            Device::Null | Device::Zero | Device::Random => Ok(data.len()),
+            Device::Console => {
+                print!("{}", String::from_utf8_lossy(data));
+                Ok(data.len())
+            }
+            Device::Serial => {
+                serial::write(data);
+                Ok(data.len())
+            }
+            Device::Keyboard => Err(FsError::PermissionDenied),
+            Device::Block(device) => {
+                if offset as u64 + data.len() as u64 > device.size() {
+                    return Err(FsError::NoSpace);
+                }
+                cache::write(device, offset as u64, data).map_err(|_| FsError::Io)?;
+                Ok(data.len())
+            }
+        }
+    }
+
+    fn set_len(&self, _len: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<FileStats> {
+        let (size, permissions) = match &self.device {
+            Device::Block(device) => (device.size() as usize, 0o660),
+            Device::Keyboard => (0, 0o440),
+            _ => (0, 0o666),
+        };
+        let mut stats = FileStats::new(self.file_type(), size, permissions);
+        stats.inode = self.inode;
+        stats.rdev = self.rdev;
+        stats.blocks = 0;
+        Ok(stats)
+    }
+}
+
+/// The one directory of a devfs.
+struct DevDir;
+
+impl Directory for DevDir {
+    fn list(&self) -> Result<Vec<(String, FileType)>> {
+        let mut entries: Vec<(String, FileType)> = CHAR_DEVICES.iter()
+            .map(|(name, ..)| (name.to_string(), FileType::CharDevice))
+            .collect();
+        entries.extend(block::devices().iter().map(|device| (device.name().to_string(), FileType::BlockDevice)));
+        Ok(entries)
+    }
+
+    fn get_file(&self, name: &str) -> Result<Arc<dyn File>> {
+        match lookup(name) {
+            Some(file) => Ok(Arc::new(file)),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn get_dir(&self, name: &str) -> Result<Arc<dyn Directory>> {
+        match lookup(name) {
+            Some(_) => Err(FsError::NotADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create_file(&self, name: &str, _data: Vec<u8>) -> Result<()> {
+        match lookup(name) {
+            Some(_) => Err(FsError::AlreadyExists),
+            None => Err(FsError::PermissionDenied),
+        }
+    }
+
+    fn create_dir(&self, name: &str) -> Result<()> {
+        self.create_file(name, Vec::new())
+    }
+
+    fn remove(&self, _name: &str) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn rename(&self, _old_name: &str, _new_name: &str) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn stats(&self) -> Result<FileStats> {
+        let mut stats = FileStats::new(FileType::Directory, 0, 0o755);
+        stats.inode = 1;
+        stats.links = 2;
+        stats.blocks = 0;
+        Ok(stats)
+    }
+}
+
+/// Device files for the kernel's devices, mounted on `/dev`. The set is
+/// fixed apart from block devices, which appear as they are registered;
+/// nothing can be created or removed.
+pub struct DevFs;
+
+/// `FsConstructor` for `devfs`; the source is ignored.
+pub fn mount(_source: &str) -> Result<Arc<dyn Filesystem>> {
+    Ok(Arc::new(DevFs))
+}
+
+impl DevFs {
+    /// Name of the device at `path`, which has to be directly in the root.
+    fn name(path: &str) -> Result<&str> {
+        let mut components = path::components(path);
+        match (components.next(), components.next()) {
+            (Some(name), None) => Ok(name),
+            (None, _) => Err(FsError::IsADirectory),
+            (Some(_), Some(_)) => Err(FsError::NotADirectory),
+        }
+    }
+}
+
+impl Filesystem for DevFs {
+    fn root_dir(&self) -> Arc<dyn Directory> {
+        Arc::new(DevDir)
+    }
+
+    fn create_file(&self, path: &str, data: Vec<u8>) -> Result<()> {
+        DevDir.create_file(Self::name(path)?, data)
+    }
+
+    fn create_dir(&self, path: &str) -> Result<()> {
+        DevDir.create_dir(Self::name(path)?)
+    }
+
+    fn remove(&self, _path: &str) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn get_file(&self, path: &str) -> Result<Arc<dyn File>> {
+        DevDir.get_file(Self::name(path)?)
+    }
+
+    fn get_dir(&self, path: &str) -> Result<Arc<dyn Directory>> {
+        match Self::name(path) {
+            Err(FsError::IsADirectory) => Ok(self.root_dir()),
+            Ok(name) => DevDir.get_dir(name),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,1358 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::block::{self, cache, BlockDevice};
+use crate::fs::{self, path, Directory, File, FileStats, FileType, FsError, Filesystem, Result};
+use crate::rtc;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+
+/// Inode size and first free inode of revision 0, which has no fields for
+/// them.
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const RO_COMPAT_BTREE_DIR: u32 = 0x0004;
+const RO_COMPAT_KNOWN: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR;
+
+const S_IFMT: u16 = 0xF000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xA000;
+const S_IFCHR: u16 = 0x2000;
+const S_IFBLK: u16 = 0x6000;
+
+/// Hashed directory; cleared when such a directory is changed, as only the
+/// linear format is maintained.
+const INDEX_FL: u32 = 0x1000;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_SYMLINK: u8 = 7;
+
+/// Block pointers in the inode: twelve direct, then single, double and triple
+/// indirect.
+const DIRECT_BLOCKS: usize = 12;
+const BLOCK_POINTERS: usize = 15;
+/// Symbolic link targets shorter than this live in the block pointers.
+const FAST_SYMLINK_MAX: usize = BLOCK_POINTERS * 4;
+
+const DIRENT_HEADER: usize = 8;
+const MAX_NAME: usize = 255;
+
+fn io<T>(result: core::result::Result<T, &'static str>) -> Result<T> {
+    result.map_err(|_| FsError::Io)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Space a directory entry with a name of `name_len` bytes needs.
+fn dirent_size(name_len: usize) -> usize {
+    (DIRENT_HEADER + name_len + 3) & !3
+}
+
+/// File type code of directory entries for `file_type`.
+fn dirent_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::File => FT_REG_FILE,
+        FileType::Directory => FT_DIR,
+        FileType::Symlink => FT_SYMLINK,
+        FileType::CharDevice => FT_CHRDEV,
+        FileType::BlockDevice => FT_BLKDEV,
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.len() > MAX_NAME || name.contains('/') {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+/// An on-disk inode, kept as raw bytes so fields this driver does not know
+/// about survive being written back.
+#[derive(Clone)]
+struct Inode {
+    raw: Vec<u8>,
+}
+
+impl Inode {
+    fn mode(&self) -> u16 {
+        u16_at(&self.raw, 0)
+    }
+
+    fn file_type(&self) -> FileType {
+        match self.mode() & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            _ => FileType::File,
+        }
+    }
+
+    /// Device number of a device file, in the old 8:8 encoding in the first
+    /// block pointer or the new 12:20 one in the second.
+    fn rdev(&self) -> u64 {
+        match (self.block(0), self.block(1)) {
+            (0, new) => fs::makedev((new & 0xFFF00) >> 8, (new & 0xFF) | (new >> 12) & 0xFFF00),
+            (old, _) => fs::makedev((old >> 8) & 0xFF, old & 0xFF),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode() & S_IFMT == S_IFDIR
+    }
+
+    fn size(&self) -> u64 {
+        let high = if self.mode() & S_IFMT == S_IFREG { u32_at(&self.raw, 108) as u64 } else { 0 };
+        u32_at(&self.raw, 4) as u64 | high << 32
+    }
+
+    fn set_size(&mut self, size: u64) {
+        put_u32(&mut self.raw, 4, size as u32);
+        if self.mode() & S_IFMT == S_IFREG {
+            put_u32(&mut self.raw, 108, (size >> 32) as u32);
+        }
+    }
+
+    fn links(&self) -> u16 {
+        u16_at(&self.raw, 26)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        put_u16(&mut self.raw, 26, links);
+    }
+
+    /// Allocated storage in 512-byte units, indirect blocks included.
+    fn sectors(&self) -> u32 {
+        u32_at(&self.raw, 28)
+    }
+
+    fn set_sectors(&mut self, sectors: u32) {
+        put_u32(&mut self.raw, 28, sectors);
+    }
+
+    fn flags(&self) -> u32 {
+        u32_at(&self.raw, 32)
+    }
+
+    fn block(&self, index: usize) -> u32 {
+        u32_at(&self.raw, 40 + index * 4)
+    }
+
+    fn set_block(&mut self, index: usize, block: u32) {
+        put_u32(&mut self.raw, 40 + index * 4, block);
+    }
+
+    /// Extended attribute block, counted in `sectors` too.
+    fn file_acl(&self) -> u32 {
+        u32_at(&self.raw, 104)
+    }
+
+    fn uid(&self) -> u32 {
+        u16_at(&self.raw, 2) as u32 | (u16_at(&self.raw, 120) as u32) << 16
+    }
+
+    fn gid(&self) -> u32 {
+        u16_at(&self.raw, 24) as u32 | (u16_at(&self.raw, 122) as u32) << 16
+    }
+
+    fn touch(&mut self, atime: bool, mtime: bool, ctime: bool) {
+        let now = rtc::now() as u32;
+        for (set, offset) in [(atime, 8), (ctime, 12), (mtime, 16)] {
+            if set {
+                put_u32(&mut self.raw, offset, now);
+            }
+        }
+    }
+
+    fn new(size: usize, mode: u16) -> Self {
+        let mut inode = Self { raw: alloc::vec![0; size] };
+        put_u16(&mut inode.raw, 0, mode);
+        inode.touch(true, true, true);
+        inode
+    }
+}
+
+/// One block group's descriptor.
+#[derive(Clone, Copy)]
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16,
+}
+
+/// Free counts, which every allocation updates; the lock on them serialises
+/// all operations on the volume.
+struct Meta {
+    free_blocks: u32,
+    free_inodes: u32,
+    groups: Vec<Group>,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    blocks_count: u32,
+    inodes_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    first_inode: u32,
+    /// Directory entries carry the file type.
+    filetype: bool,
+    /// The volume has features this driver cannot keep consistent, or sits
+    /// on a read-only device.
+    read_only: bool,
+    meta: Mutex<Meta>,
+}
+
+impl Volume {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        io(cache::read(&self.device, offset, buf))
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        if self.read_only {
+            return Err(FsError::PermissionDenied);
+        }
+        io(cache::write(&self.device, offset, data))
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size as u64
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>> {
+        let mut data = alloc::vec![0; self.block_size];
+        self.read(self.block_offset(block), &mut data)?;
+        Ok(data)
+    }
+
+    fn write_block(&self, block: u32, data: &[u8]) -> Result<()> {
+        self.write(self.block_offset(block), data)
+    }
+
+    /// Block pointers per indirect block.
+    fn pointers(&self) -> usize {
+        self.block_size / 4
+    }
+
+    fn sectors_per_block(&self) -> u32 {
+        (self.block_size / 512) as u32
+    }
+
+    fn group_descriptors(&self) -> u64 {
+        self.block_offset(self.first_data_block + 1)
+    }
+
+    fn write_group(&self, meta: &Meta, group: usize) -> Result<()> {
+        let g = &meta.groups[group];
+        let mut raw = [0u8; 32];
+        self.read(self.group_descriptors() + group as u64 * 32, &mut raw)?;
+        put_u32(&mut raw, 0, g.block_bitmap);
+        put_u32(&mut raw, 4, g.inode_bitmap);
+        put_u32(&mut raw, 8, g.inode_table);
+        put_u16(&mut raw, 12, g.free_blocks);
+        put_u16(&mut raw, 14, g.free_inodes);
+        put_u16(&mut raw, 16, g.used_dirs);
+        self.write(self.group_descriptors() + group as u64 * 32, &raw)?;
+        self.write(SUPERBLOCK_OFFSET + 12, &meta.free_blocks.to_le_bytes())?;
+        self.write(SUPERBLOCK_OFFSET + 16, &meta.free_inodes.to_le_bytes())
+    }
+
+    fn inode_offset(&self, meta: &Meta, ino: u32) -> Result<u64> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(FsError::Io);
+        }
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        let table = meta.groups.get(group).ok_or(FsError::Io)?.inode_table;
+        Ok(self.block_offset(table) + index * self.inode_size as u64)
+    }
+
+    fn read_inode(&self, meta: &Meta, ino: u32) -> Result<Inode> {
+        let mut raw = alloc::vec![0; self.inode_size];
+        self.read(self.inode_offset(meta, ino)?, &mut raw)?;
+        let inode = Inode { raw };
+        // Freed since it was looked up
+        if inode.mode() == 0 || inode.links() == 0 {
+            return Err(FsError::NotFound);
+        }
+        Ok(inode)
+    }
+
+    fn write_inode(&self, meta: &Meta, ino: u32, inode: &Inode) -> Result<()> {
+        self.write(self.inode_offset(meta, ino)?, &inode.raw)
+    }
+
+    /// Finds a clear bit in a group's bitmap, sets it and returns its index.
+    fn take_bit(&self, bitmap: u32, limit: u32) -> Result<Option<u32>> {
+        let mut data = self.read_block(bitmap)?;
+        for (i, byte) in data.iter_mut().enumerate() {
+            if *byte == 0xFF {
+                continue;
+            }
+            let bit = byte.trailing_ones();
+            let index = i as u32 * 8 + bit;
+            if index >= limit {
+                break;
+            }
+            *byte |= 1 << bit;
+            self.write_block(bitmap, &data)?;
+            return Ok(Some(index));
+        }
+        Ok(None)
+    }
+
+    fn clear_bit(&self, bitmap: u32, index: u32) -> Result<()> {
+        let offset = self.block_offset(bitmap) + index as u64 / 8;
+        let mut byte = [0u8];
+        self.read(offset, &mut byte)?;
+        byte[0] &= !(1 << (index % 8));
+        self.write(offset, &byte)
+    }
+
+    /// Blocks in `group`; the last group may be short.
+    fn group_blocks(&self, group: usize) -> u32 {
+        let start = self.first_data_block + group as u32 * self.blocks_per_group;
+        (self.blocks_count - start).min(self.blocks_per_group)
+    }
+
+    /// Allocates a zeroed block, preferably in group `goal`.
+    fn alloc_block(&self, meta: &mut Meta, goal: usize) -> Result<u32> {
+        let count = meta.groups.len();
+        for i in 0..count {
+            let group = (goal + i) % count;
+            if meta.groups[group].free_blocks == 0 {
+                continue;
+            }
+            let bitmap = meta.groups[group].block_bitmap;
+            if let Some(index) = self.take_bit(bitmap, self.group_blocks(group))? {
+                meta.groups[group].free_blocks -= 1;
+                meta.free_blocks = meta.free_blocks.saturating_sub(1);
+                self.write_group(meta, group)?;
+                let block = self.first_data_block + group as u32 * self.blocks_per_group + index;
+                self.write_block(block, &alloc::vec![0; self.block_size])?;
+                return Ok(block);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, meta: &mut Meta, block: u32) -> Result<()> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(FsError::Io);
+        }
+        let group = ((block - self.first_data_block) / self.blocks_per_group) as usize;
+        self.clear_bit(meta.groups[group].block_bitmap, (block - self.first_data_block) % self.blocks_per_group)?;
+        meta.groups[group].free_blocks += 1;
+        meta.free_blocks += 1;
+        self.write_group(meta, group)
+    }
+
+    /// Allocates an inode number. Directories go to the group with the most
+    /// free inodes to spread them out; files stay near their directory.
+    fn alloc_inode(&self, meta: &mut Meta, parent: u32, directory: bool) -> Result<u32> {
+        let count = meta.groups.len();
+        let goal = if directory {
+            (0..count).max_by_key(|&g| meta.groups[g].free_inodes).unwrap_or(0)
+        } else {
+            ((parent - 1) / self.inodes_per_group) as usize
+        };
+        for i in 0..count {
+            let group = (goal + i) % count;
+            if meta.groups[group].free_inodes == 0 {
+                continue;
+            }
+            let bitmap = meta.groups[group].inode_bitmap;
+            while let Some(index) = self.take_bit(bitmap, self.inodes_per_group)? {
+                let ino = group as u32 * self.inodes_per_group + index + 1;
+                // Reserved inodes are normally marked used already
+                if ino < self.first_inode {
+                    continue;
+                }
+                meta.groups[group].free_inodes -= 1;
+                if directory {
+                    meta.groups[group].used_dirs += 1;
+                }
+                meta.free_inodes = meta.free_inodes.saturating_sub(1);
+                self.write_group(meta, group)?;
+                return Ok(ino);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Frees the inode number of `inode`, whose blocks are already gone, and
+    /// stamps its deletion time.
+    fn free_inode(&self, meta: &mut Meta, ino: u32, inode: &mut Inode) -> Result<()> {
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        self.clear_bit(meta.groups[group].inode_bitmap, (ino - 1) % self.inodes_per_group)?;
+        meta.groups[group].free_inodes += 1;
+        if inode.is_dir() {
+            meta.groups[group].used_dirs = meta.groups[group].used_dirs.saturating_sub(1);
+        }
+        meta.free_inodes += 1;
+        self.write_group(meta, group)?;
+        inode.set_links(0);
+        put_u32(&mut inode.raw, 20, rtc::now() as u32);
+        self.write_inode(meta, ino, inode)
+    }
+
+    fn inode_group(&self, ino: u32) -> usize {
+        ((ino - 1) / self.inodes_per_group) as usize
+    }
+
+    /// Block holding logical block `index` of `inode`, or 0 for a hole. With
+    /// `alloc`, holes are filled, as are missing indirect blocks on the way.
+    fn map_block(&self, meta: &mut Meta, ino: u32, inode: &mut Inode, index: u64, alloc: bool) -> Result<u32> {
+        let per = self.pointers() as u64;
+        // Pointer slot in the inode and the path through indirect blocks
+        let (slot, path): (usize, Vec<u64>) = if index < DIRECT_BLOCKS as u64 {
+            (index as usize, Vec::new())
+        } else if index - (DIRECT_BLOCKS as u64) < per {
+            (12, alloc::vec![index - DIRECT_BLOCKS as u64])
+        } else if index - DIRECT_BLOCKS as u64 - per < per * per {
+            let i = index - DIRECT_BLOCKS as u64 - per;
+            (13, alloc::vec![i / per, i % per])
+        } else {
+            let i = index - DIRECT_BLOCKS as u64 - per - per * per;
+            if i >= per * per * per {
+                return Err(FsError::NoSpace);
+            }
+            (14, alloc::vec![i / (per * per), i / per % per, i % per])
+        };
+
+        let goal = self.inode_group(ino);
+        let mut block = inode.block(slot);
+        if block == 0 {
+            if !alloc {
+                return Ok(0);
+            }
+            block = self.alloc_block(meta, goal)?;
+            inode.set_block(slot, block);
+            inode.set_sectors(inode.sectors() + self.sectors_per_block());
+        }
+        for entry in path {
+            let offset = self.block_offset(block) + entry * 4;
+            let mut raw = [0u8; 4];
+            self.read(offset, &mut raw)?;
+            let mut next = u32::from_le_bytes(raw);
+            if next == 0 {
+                if !alloc {
+                    return Ok(0);
+                }
+                next = self.alloc_block(meta, goal)?;
+                self.write(offset, &next.to_le_bytes())?;
+                inode.set_sectors(inode.sectors() + self.sectors_per_block());
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Frees the blocks under `block`, a tree `depth` levels deep, past the
+    /// first `keep` leaves. Returns whether `block` itself was freed.
+    fn truncate_tree(&self, meta: &mut Meta, inode: &mut Inode, block: u32, depth: u32, keep: u64) -> Result<bool> {
+        if depth > 0 {
+            let per = self.pointers() as u64;
+            let span = per.pow(depth - 1);
+            let mut pointers = self.read_block(block)?;
+            let mut changed = false;
+            for i in 0..per {
+                let child = u32_at(&pointers, i as usize * 4);
+                let child_keep = keep.saturating_sub(i * span).min(span);
+                if child != 0 && child_keep < span
+                    && self.truncate_tree(meta, inode, child, depth - 1, child_keep)?
+                {
+                    put_u32(&mut pointers, i as usize * 4, 0);
+                    changed = true;
+                }
+            }
+            if keep > 0 {
+                if changed {
+                    self.write_block(block, &pointers)?;
+                }
+                return Ok(false);
+            }
+        } else if keep > 0 {
+            return Ok(false);
+        }
+        self.free_block(meta, block)?;
+        inode.set_sectors(inode.sectors().saturating_sub(self.sectors_per_block()));
+        Ok(true)
+    }
+
+    /// Frees every block of `inode` past the first `keep`.
+    fn truncate_blocks(&self, meta: &mut Meta, inode: &mut Inode, keep: u64) -> Result<()> {
+        let per = self.pointers() as u64;
+        let mut first = 0;
+        for slot in 0..BLOCK_POINTERS {
+            let (depth, span) = match slot {
+                0..=11 => (0, 1),
+                12 => (1, per),
+                13 => (2, per * per),
+                _ => (3, per * per * per),
+            };
+            let block = inode.block(slot);
+            let slot_keep = keep.saturating_sub(first).min(span);
+            if block != 0 && slot_keep < span && self.truncate_tree(meta, inode, block, depth, slot_keep)? {
+                inode.set_block(slot, 0);
+            }
+            first += span;
+        }
+        Ok(())
+    }
+
+    /// Reads up to `buf.len()` bytes at `offset`; holes read as zeroes.
+    fn read_data(&self, meta: &mut Meta, ino: u32, inode: &mut Inode, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let size = inode.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = buf.len().min((size - offset) as usize);
+        let mut done = 0;
+        while done < count {
+            let pos = offset + done as u64;
+            let start = (pos % self.block_size as u64) as usize;
+            let chunk = (self.block_size - start).min(count - done);
+            match self.map_block(meta, ino, inode, pos / self.block_size as u64, false)? {
+                0 => buf[done..done + chunk].fill(0),
+                block => self.read(self.block_offset(block) + start as u64, &mut buf[done..done + chunk])?,
+            }
+            done += chunk;
+        }
+        Ok(count)
+    }
+
+    /// Writes `data` at `offset`, growing the size if it ends past it. The
+    /// caller writes the inode back.
+    fn write_data(&self, meta: &mut Meta, ino: u32, inode: &mut Inode, offset: u64, data: &[u8]) -> Result<()> {
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let start = (pos % self.block_size as u64) as usize;
+            let chunk = (self.block_size - start).min(data.len() - done);
+            let block = self.map_block(meta, ino, inode, pos / self.block_size as u64, true)?;
+            self.write(self.block_offset(block) + start as u64, &data[done..done + chunk])?;
+            done += chunk;
+        }
+        let end = offset + data.len() as u64;
+        if end > inode.size() {
+            self.set_size(inode, end)?;
+        }
+        inode.touch(false, true, true);
+        Ok(())
+    }
+
+    fn set_size(&self, inode: &mut Inode, size: u64) -> Result<()> {
+        // Sizes past 2 GiB need the high word, which old kernels ignore
+        if size > i32::MAX as u64 {
+            let mut raw = [0u8; 4];
+            self.read(SUPERBLOCK_OFFSET + 100, &mut raw)?;
+            let ro_compat = u32::from_le_bytes(raw);
+            if ro_compat & RO_COMPAT_LARGE_FILE == 0 {
+                self.write(SUPERBLOCK_OFFSET + 100, &(ro_compat | RO_COMPAT_LARGE_FILE).to_le_bytes())?;
+            }
+        }
+        inode.set_size(size);
+        Ok(())
+    }
+
+    /// Truncates or extends to `len`. Growing leaves a hole; shrinking zeroes
+    /// the rest of the last block so a later extension reads zeroes.
+    fn resize(&self, meta: &mut Meta, ino: u32, inode: &mut Inode, len: u64) -> Result<()> {
+        let bs = self.block_size as u64;
+        if len < inode.size() {
+            self.truncate_blocks(meta, inode, (len + bs - 1) / bs)?;
+            if len % bs != 0 {
+                let block = self.map_block(meta, ino, inode, len / bs, false)?;
+                if block != 0 {
+                    let tail = (bs - len % bs) as usize;
+                    self.write(self.block_offset(block) + len % bs, &alloc::vec![0; tail])?;
+                }
+            }
+        }
+        self.set_size(inode, len)?;
+        inode.touch(false, true, true);
+        Ok(())
+    }
+
+    fn symlink_target(&self, meta: &mut Meta, ino: u32, inode: &mut Inode) -> Result<String> {
+        let size = inode.size() as usize;
+        let xattr_sectors = if inode.file_acl() != 0 { self.sectors_per_block() } else { 0 };
+        // Short targets are stored in place of the block pointers
+        if inode.sectors() == xattr_sectors && size <= FAST_SYMLINK_MAX {
+            return Ok(String::from_utf8_lossy(&inode.raw[40..40 + size]).into_owned());
+        }
+        let mut data = alloc::vec![0; size];
+        self.read_data(meta, ino, inode, 0, &mut data)?;
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    /// Calls `f` on each entry of a directory with the block it is in, the
+    /// entry's offset there and the block's data, until `f` returns
+    /// something.
+    fn scan_dir<R>(
+        &self,
+        meta: &mut Meta,
+        ino: u32,
+        dir: &mut Inode,
+        mut f: impl FnMut(u32, usize, &[u8]) -> Option<R>,
+    ) -> Result<Option<R>> {
+        let blocks = (dir.size() + self.block_size as u64 - 1) / self.block_size as u64;
+        for index in 0..blocks {
+            let block = self.map_block(meta, ino, dir, index, false)?;
+            if block == 0 {
+                continue;
+            }
+            let data = self.read_block(block)?;
+            let mut offset = 0;
+            while offset + DIRENT_HEADER <= data.len() {
+                let rec_len = u16_at(&data, offset + 4) as usize;
+                if rec_len < DIRENT_HEADER || offset + rec_len > data.len() {
+                    return Err(FsError::Io);
+                }
+                if let Some(result) = f(block, offset, &data) {
+                    return Ok(Some(result));
+                }
+                offset += rec_len;
+            }
+        }
+        Ok(None)
+    }
+
+    fn name_len(&self, data: &[u8], offset: usize) -> usize {
+        if self.filetype { data[offset + 6] as usize } else { u16_at(data, offset + 6) as usize }
+    }
+
+    /// Entries of a directory other than `.` and `..`, as name, inode and the
+    /// type recorded in the entry (0 if unknown).
+    fn entries(&self, meta: &mut Meta, ino: u32, dir: &mut Inode) -> Result<Vec<(String, u32, u8)>> {
+        let mut entries = Vec::new();
+        self.scan_dir(meta, ino, dir, |_, offset, data| {
+            let entry_ino = u32_at(data, offset);
+            let len = self.name_len(data, offset).min(data.len() - offset - DIRENT_HEADER);
+            let name = &data[offset + DIRENT_HEADER..offset + DIRENT_HEADER + len];
+            if entry_ino != 0 && name != b"." && name != b".." {
+                let file_type = if self.filetype { data[offset + 7] } else { 0 };
+                entries.push((String::from_utf8_lossy(name).into_owned(), entry_ino, file_type));
+            }
+            None::<()>
+        })?;
+        Ok(entries)
+    }
+
+    /// Block and offset of the entry for `name`, and the inode it names.
+    fn find(&self, meta: &mut Meta, ino: u32, dir: &mut Inode, name: &str) -> Result<Option<(u32, usize, u32)>> {
+        self.scan_dir(meta, ino, dir, |block, offset, data| {
+            let entry_ino = u32_at(data, offset);
+            let len = self.name_len(data, offset);
+            let start = offset + DIRENT_HEADER;
+            if entry_ino != 0 && start + len <= data.len() && &data[start..start + len] == name.as_bytes() {
+                Some((block, offset, entry_ino))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn lookup(&self, meta: &mut Meta, dir_ino: u32, name: &str) -> Result<u32> {
+        let mut dir = self.read_inode(meta, dir_ino)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        match self.find(meta, dir_ino, &mut dir, name)? {
+            Some((_, _, ino)) => Ok(ino),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn write_dirent(&self, data: &mut [u8], offset: usize, ino: u32, rec_len: usize, name: &str, file_type: u8) {
+        put_u32(data, offset, ino);
+        put_u16(data, offset + 4, rec_len as u16);
+        if self.filetype {
+            data[offset + 6] = name.len() as u8;
+            data[offset + 7] = file_type;
+        } else {
+            put_u16(data, offset + 6, name.len() as u16);
+        }
+        data[offset + DIRENT_HEADER..offset + DIRENT_HEADER + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    /// Hashed directories are rewritten in the linear format only, so their
+    /// index must not be trusted afterwards.
+    fn unindex(dir: &mut Inode) {
+        let flags = dir.flags() & !INDEX_FL;
+        put_u32(&mut dir.raw, 32, flags);
+    }
+
+    /// Adds `name` for inode `ino` to a directory, splitting an entry with
+    /// enough slack or adding a block.
+    fn add_entry(&self, meta: &mut Meta, dir_ino: u32, dir: &mut Inode, name: &str, ino: u32, file_type: u8) -> Result<()> {
+        let needed = dirent_size(name.len());
+        let slot = self.scan_dir(meta, dir_ino, dir, |block, offset, data| {
+            let rec_len = u16_at(data, offset + 4) as usize;
+            let used = if u32_at(data, offset) == 0 { 0 } else { dirent_size(self.name_len(data, offset)) };
+            if rec_len.checked_sub(used)? >= needed { Some((block, offset, used)) } else { None }
+        })?;
+
+        match slot {
+            Some((block, offset, used)) => {
+                let mut data = self.read_block(block)?;
+                let rec_len = u16_at(&data, offset + 4) as usize;
+                if used == 0 {
+                    self.write_dirent(&mut data, offset, ino, rec_len, name, file_type);
+                } else {
+                    let slack = rec_len.checked_sub(used).ok_or(FsError::Io)?;
+                    put_u16(&mut data, offset + 4, used as u16);
+                    self.write_dirent(&mut data, offset + used, ino, slack, name, file_type);
+                }
+                self.write_block(block, &data)?;
+            }
+            None => {
+                let index = dir.size() / self.block_size as u64;
+                let block = self.map_block(meta, dir_ino, dir, index, true)?;
+                let mut data = alloc::vec![0; self.block_size];
+                self.write_dirent(&mut data, 0, ino, self.block_size, name, file_type);
+                self.write_block(block, &data)?;
+                dir.set_size((index + 1) * self.block_size as u64);
+            }
+        }
+        Self::unindex(dir);
+        dir.touch(false, true, true);
+        self.write_inode(meta, dir_ino, dir)
+    }
+
+    /// Removes the entry for `name` and returns the inode it named. The
+    /// space goes to the entry before it in the block.
+    fn remove_entry(&self, meta: &mut Meta, dir_ino: u32, dir: &mut Inode, name: &str) -> Result<u32> {
+        let (block, offset, ino) = self.find(meta, dir_ino, dir, name)?.ok_or(FsError::NotFound)?;
+        let mut data = self.read_block(block)?;
+        let mut prev = None;
+        let mut at = 0;
+        while at < offset {
+            prev = Some(at);
+            at += u16_at(&data, at + 4) as usize;
+        }
+        match prev {
+            Some(prev) => {
+                let merged = u16_at(&data, prev + 4) + u16_at(&data, offset + 4);
+                put_u16(&mut data, prev + 4, merged);
+            }
+            None => put_u32(&mut data, offset, 0),
+        }
+        self.write_block(block, &data)?;
+        Self::unindex(dir);
+        dir.touch(false, true, true);
+        self.write_inode(meta, dir_ino, dir)?;
+        Ok(ino)
+    }
+
+    /// Points the entry for `name` at inode `ino` in place and returns the
+    /// inode it named before.
+    fn retarget_entry(&self, meta: &mut Meta, dir_ino: u32, dir: &mut Inode, name: &str, ino: u32, file_type: u8) -> Result<u32> {
+        let (block, offset, old) = self.find(meta, dir_ino, dir, name)?.ok_or(FsError::NotFound)?;
+        let mut data = self.read_block(block)?;
+        put_u32(&mut data, offset, ino);
+        if self.filetype {
+            data[offset + 7] = file_type;
+        }
+        self.write_block(block, &data)?;
+        dir.touch(false, true, true);
+        self.write_inode(meta, dir_ino, dir)?;
+        Ok(old)
+    }
+
+    /// Points the `..` entry of directory `ino` at `parent`.
+    fn set_dotdot(&self, meta: &mut Meta, ino: u32, parent: u32) -> Result<()> {
+        let mut dir = self.read_inode(meta, ino)?;
+        let block = self.map_block(meta, ino, &mut dir, 0, false)?;
+        let mut data = self.read_block(block)?;
+        let offset = u16_at(&data, 4) as usize;
+        put_u32(&mut data, offset, parent);
+        self.write_block(block, &data)
+    }
+
+    /// Inode of the parent of directory `ino`, from its `..` entry.
+    fn parent_of(&self, meta: &mut Meta, ino: u32) -> Result<u32> {
+        let mut dir = self.read_inode(meta, ino)?;
+        self.find(meta, ino, &mut dir, "..")?
+            .map(|(_, _, parent)| parent)
+            .ok_or(FsError::Io)
+    }
+
+    /// Drops a link to `ino`, freeing it with the last one. Directories lose
+    /// their `.` link too and give back the parent's `..` link.
+    fn unlink(&self, meta: &mut Meta, parent: u32, ino: u32) -> Result<()> {
+        let mut inode = self.read_inode(meta, ino)?;
+        if inode.is_dir() {
+            let mut dir = self.read_inode(meta, parent)?;
+            dir.set_links(dir.links().saturating_sub(1));
+            self.write_inode(meta, parent, &dir)?;
+            inode.set_links(0);
+        } else {
+            inode.set_links(inode.links().saturating_sub(1));
+        }
+        inode.touch(false, false, true);
+        if inode.links() > 0 {
+            return self.write_inode(meta, ino, &inode);
+        }
+        self.truncate_blocks(meta, &mut inode, 0)?;
+        if inode.file_acl() != 0 {
+            self.free_block(meta, inode.file_acl())?;
+            put_u32(&mut inode.raw, 104, 0);
+        }
+        inode.set_sectors(0);
+        self.free_inode(meta, ino, &mut inode)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only { Err(FsError::PermissionDenied) } else { Ok(()) }
+    }
+
+    /// Creates `name` in directory `parent` as a file holding `data`, a
+    /// directory, or a symbolic link to `target`.
+    fn create(&self, parent: u32, name: &str, kind: FileType, data: &[u8]) -> Result<()> {
+        check_name(name)?;
+        self.check_writable()?;
+        let mut meta = self.meta.lock();
+        let meta = &mut *meta;
+        let mut dir = self.read_inode(meta, parent)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        if self.find(meta, parent, &mut dir, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let mode = match kind {
+            FileType::File => S_IFREG | 0o644,
+            FileType::Directory => S_IFDIR | 0o755,
+            FileType::Symlink => S_IFLNK | 0o777,
+            // Device files have nothing to make them from
+            FileType::CharDevice | FileType::BlockDevice => return Err(FsError::InvalidArgument),
+        };
+        let file_type = dirent_type(kind);
+        let directory = kind == FileType::Directory;
+        let ino = self.alloc_inode(meta, parent, directory)?;
+        let mut inode = Inode::new(self.inode_size, mode);
+        inode.set_links(if directory { 2 } else { 1 });
+        // Room for the extended fields, as mke2fs reserves it
+        if self.inode_size > GOOD_OLD_INODE_SIZE {
+            put_u16(&mut inode.raw, 128, 32.min(self.inode_size - GOOD_OLD_INODE_SIZE) as u16);
+        }
+
+        match kind {
+            FileType::Directory => {
+                let block = self.map_block(meta, ino, &mut inode, 0, true)?;
+                let mut contents = alloc::vec![0; self.block_size];
+                self.write_dirent(&mut contents, 0, ino, 12, ".", FT_DIR);
+                self.write_dirent(&mut contents, 12, parent, self.block_size - 12, "..", FT_DIR);
+                self.write_block(block, &contents)?;
+                inode.set_size(self.block_size as u64);
+                dir.set_links(dir.links() + 1);
+            }
+            FileType::Symlink if data.len() < FAST_SYMLINK_MAX => {
+                inode.raw[40..40 + data.len()].copy_from_slice(data);
+                inode.set_size(data.len() as u64);
+            }
+            _ => self.write_data(meta, ino, &mut inode, 0, data)?,
+        }
+        self.write_inode(meta, ino, &inode)?;
+        self.add_entry(meta, parent, &mut dir, name, ino, file_type)
+    }
+
+    fn remove(&self, parent: u32, name: &str) -> Result<()> {
+        check_name(name)?;
+        self.check_writable()?;
+        let mut meta = self.meta.lock();
+        let meta = &mut *meta;
+        let ino = self.lookup(meta, parent, name)?;
+        let mut inode = self.read_inode(meta, ino)?;
+        if inode.is_dir() && !self.entries(meta, ino, &mut inode)?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        let mut dir = self.read_inode(meta, parent)?;
+        self.remove_entry(meta, parent, &mut dir, name)?;
+        self.unlink(meta, parent, ino)
+    }
+
+    fn link(&self, ino: u32, parent: u32, name: &str) -> Result<()> {
+        check_name(name)?;
+        self.check_writable()?;
+        let mut meta = self.meta.lock();
+        let meta = &mut *meta;
+        let mut inode = self.read_inode(meta, ino)?;
+        // Hard links to directories would make the tree a graph
+        if inode.is_dir() {
+            return Err(FsError::PermissionDenied);
+        }
+        let mut dir = self.read_inode(meta, parent)?;
+        if self.find(meta, parent, &mut dir, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        self.add_entry(meta, parent, &mut dir, name, ino, dirent_type(inode.file_type()))?;
+        inode.set_links(inode.links() + 1);
+        inode.touch(false, false, true);
+        self.write_inode(meta, ino, &inode)
+    }
+
+    /// Moves entry `old_name` of directory `src` to `new_name` in `dst`,
+    /// replacing an existing target by the same rules as the other
+    /// filesystems.
+    fn rename(&self, src: u32, old_name: &str, dst: u32, new_name: &str) -> Result<()> {
+        check_name(old_name)?;
+        check_name(new_name)?;
+        self.check_writable()?;
+        let mut meta = self.meta.lock();
+        let meta = &mut *meta;
+        let ino = self.lookup(meta, src, old_name)?;
+        let inode = self.read_inode(meta, ino)?;
+        let directory = inode.is_dir();
+
+        // A directory cannot move into itself
+        if directory && src != dst {
+            let mut at = dst;
+            let mut steps = 0;
+            while at != ROOT_INODE {
+                if at == ino {
+                    return Err(FsError::InvalidPath);
+                }
+                // A corrupt `..` chain could go round forever
+                steps += 1;
+                if steps > self.inodes_count {
+                    return Err(FsError::Io);
+                }
+                at = self.parent_of(meta, at)?;
+            }
+        }
+
+        // The new name is in place before the old one goes, and a replaced
+        // target only loses its link once nothing can fail any more that
+        // would leave neither name
+        let file_type = dirent_type(inode.file_type());
+        let replaced = match self.lookup(meta, dst, new_name) {
+            Ok(existing) if existing == ino => return Ok(()),
+            Ok(existing) => {
+                let mut target = self.read_inode(meta, existing)?;
+                match (directory, target.is_dir()) {
+                    (true, false) => return Err(FsError::NotADirectory),
+                    (false, true) => return Err(FsError::IsADirectory),
+                    (true, true) if !self.entries(meta, existing, &mut target)?.is_empty() => {
+                        return Err(FsError::NotEmpty);
+                    }
+                    _ => {}
+                }
+                let mut dir = self.read_inode(meta, dst)?;
+                Some(self.retarget_entry(meta, dst, &mut dir, new_name, ino, file_type)?)
+            }
+            Err(FsError::NotFound) => {
+                let mut dir = self.read_inode(meta, dst)?;
+                self.add_entry(meta, dst, &mut dir, new_name, ino, file_type)?;
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        let mut dir = self.read_inode(meta, src)?;
+        self.remove_entry(meta, src, &mut dir, old_name)?;
+        if let Some(existing) = replaced {
+            self.unlink(meta, dst, existing)?;
+        }
+
+        if directory && src != dst {
+            self.set_dotdot(meta, ino, dst)?;
+            dir.set_links(dir.links().saturating_sub(1));
+            self.write_inode(meta, src, &dir)?;
+            let mut dir = self.read_inode(meta, dst)?;
+            dir.set_links(dir.links() + 1);
+            self.write_inode(meta, dst, &dir)?;
+        }
+        Ok(())
+    }
+
+    /// Walks `path` from the root to an inode, which must be a directory for
+    /// any component but the last.
+    fn walk(&self, path: &str) -> Result<u32> {
+        let mut meta = self.meta.lock();
+        let mut ino = ROOT_INODE;
+        for component in path::components(path) {
+            ino = self.lookup(&mut meta, ino, component)?;
+        }
+        Ok(ino)
+    }
+
+    /// Directory inode holding the last component of `path`, and that
+    /// component.
+    fn walk_parent<'a>(&self, path: &'a str) -> Result<(u32, &'a str)> {
+        let (parent, name) = path::split_last(path);
+        Ok((self.walk(&parent)?, name))
+    }
+
+    fn stats(&self, ino: u32) -> Result<FileStats> {
+        let meta = self.meta.lock();
+        let inode = self.read_inode(&meta, ino)?;
+        let mut stats = FileStats::new(inode.file_type(), inode.size() as usize, inode.mode() & 0o7777);
+        stats.inode = ino as u64;
+        stats.links = inode.links() as usize;
+        stats.uid = inode.uid();
+        stats.gid = inode.gid();
+        stats.atime = u32_at(&inode.raw, 8) as u64;
+        stats.ctime = u32_at(&inode.raw, 12) as u64;
+        stats.mtime = u32_at(&inode.raw, 16) as u64;
+        stats.blocks = inode.sectors() as u64;
+        if matches!(stats.file_type, FileType::CharDevice | FileType::BlockDevice) {
+            stats.rdev = inode.rdev();
+        }
+        Ok(stats)
+    }
+}
+
+/// An ext2 volume on a block device, mounted with `mount -t ext2 <device>`.
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+}
+
+impl Ext2Fs {
+    /// Reads the superblock and group descriptors of `device`. Volumes with
+    /// features that change the on-disk format are refused; ones with
+    /// features that only need maintaining on write are mounted read-only.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self> {
+        let mut sb = [0u8; 1024];
+        io(cache::read(&device, SUPERBLOCK_OFFSET, &mut sb))?;
+        if u16_at(&sb, 56) != MAGIC {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let log_block_size = u32_at(&sb, 24);
+        let rev_level = u32_at(&sb, 76);
+        let (inode_size, first_inode) = if rev_level == 0 {
+            (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INODE)
+        } else {
+            (u16_at(&sb, 88) as usize, u32_at(&sb, 84))
+        };
+        let (incompat, ro_compat) = if rev_level == 0 { (0, 0) } else { (u32_at(&sb, 96), u32_at(&sb, 100)) };
+        if log_block_size > 2 || incompat & !INCOMPAT_FILETYPE != 0
+            || inode_size < GOOD_OLD_INODE_SIZE || !inode_size.is_power_of_two()
+        {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let block_size = 1024usize << log_block_size;
+        let blocks_count = u32_at(&sb, 4);
+        let inodes_count = u32_at(&sb, 0);
+        let first_data_block = u32_at(&sb, 20);
+        let blocks_per_group = u32_at(&sb, 32);
+        let inodes_per_group = u32_at(&sb, 40);
+        if blocks_per_group == 0 || inodes_per_group == 0 || blocks_count <= first_data_block
+            || blocks_count as u64 * block_size as u64 > device.size()
+        {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let group_count = ((blocks_count - first_data_block + blocks_per_group - 1) / blocks_per_group) as usize;
+        if inodes_count as u64 > group_count as u64 * inodes_per_group as u64 {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut descriptors = alloc::vec![0u8; group_count * 32];
+        io(cache::read(&device, (first_data_block as u64 + 1) * block_size as u64, &mut descriptors))?;
+        let groups = descriptors.chunks_exact(32)
+            .map(|raw| Group {
+                block_bitmap: u32_at(raw, 0),
+                inode_bitmap: u32_at(raw, 4),
+                inode_table: u32_at(raw, 8),
+                free_blocks: u16_at(raw, 12),
+                free_inodes: u16_at(raw, 14),
+                used_dirs: u16_at(raw, 16),
+            })
+            .collect();
+
+        let read_only = device.read_only() || ro_compat & !RO_COMPAT_KNOWN != 0;
+        let volume = Volume {
+            device,
+            block_size,
+            blocks_count,
+            inodes_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            first_inode,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            read_only,
+            meta: Mutex::new(Meta {
+                free_blocks: u32_at(&sb, 12),
+                free_inodes: u32_at(&sb, 16),
+                groups,
+            }),
+        };
+        if !volume.read_inode(&volume.meta.lock(), ROOT_INODE)?.is_dir() {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(Self { volume: Arc::new(volume) })
+    }
+
+    fn dir(&self, ino: u32) -> Arc<dyn Directory> {
+        Arc::new(Ext2Dir { volume: Arc::clone(&self.volume), ino })
+    }
+}
+
+/// `FsConstructor` for `ext2`: `source` names a block device.
+pub fn mount(source: &str) -> Result<Arc<dyn Filesystem>> {
+    let device = block::get(source).ok_or(FsError::NotFound)?;
+    Ok(Arc::new(Ext2Fs::new(device)?))
+}
+
+impl Filesystem for Ext2Fs {
+    fn root_dir(&self) -> Arc<dyn Directory> {
+        self.dir(ROOT_INODE)
+    }
+
+    fn create_file(&self, path: &str, data: Vec<u8>) -> Result<()> {
+        let (dir, name) = self.volume.walk_parent(path)?;
+        self.volume.create(dir, name, FileType::File, &data)
+    }
+
+    fn create_dir(&self, path: &str) -> Result<()> {
+        let (dir, name) = self.volume.walk_parent(path)?;
+        self.volume.create(dir, name, FileType::Directory, &[])
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        let (dir, name) = self.volume.walk_parent(path)?;
+        self.volume.remove(dir, name)
+    }
+
+    fn get_file(&self, path: &str) -> Result<Arc<dyn File>> {
+        let (dir, name) = self.volume.walk_parent(path)?;
+        Ext2Dir { volume: Arc::clone(&self.volume), ino: dir }.get_file(name)
+    }
+
+    fn get_dir(&self, path: &str) -> Result<Arc<dyn Directory>> {
+        let ino = self.volume.walk(path)?;
+        if self.volume.stats(ino)?.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(self.dir(ino))
+    }
+
+    fn symlink(&self, target: &str, path: &str) -> Result<()> {
+        let (dir, name) = self.volume.walk_parent(path)?;
+        self.volume.create(dir, name, FileType::Symlink, target.as_bytes())
+    }
+
+    fn link(&self, existing: &str, path: &str) -> Result<()> {
+        let ino = self.volume.walk(existing)?;
+        let (dir, name) = self.volume.walk_parent(path)?;
+        self.volume.link(ino, dir, name)
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<()> {
+        let (src, old_name) = self.volume.walk_parent(old)?;
+        let (dst, new_name) = self.volume.walk_parent(new)?;
+        if old_name.is_empty() || new_name.is_empty() {
+            return Err(FsError::Busy);
+        }
+        self.volume.rename(src, old_name, dst, new_name)
+    }
+
+    fn readlink(&self, path: &str) -> Result<String> {
+        let ino = self.volume.walk(path)?;
+        let mut meta = self.volume.meta.lock();
+        let mut inode = self.volume.read_inode(&meta, ino)?;
+        if inode.file_type() != FileType::Symlink {
+            return Err(FsError::NotASymlink);
+        }
+        self.volume.symlink_target(&mut meta, ino, &mut inode)
+    }
+
+    fn set_permissions(&self, path: &str, permissions: u16) -> Result<()> {
+        let ino = self.volume.walk(path)?;
+        self.volume.check_writable()?;
+        let meta = self.volume.meta.lock();
+        let mut inode = self.volume.read_inode(&meta, ino)?;
+        let mode = inode.mode() & S_IFMT | permissions & 0o7777;
+        put_u16(&mut inode.raw, 0, mode);
+        inode.touch(false, false, true);
+        self.volume.write_inode(&meta, ino, &inode)
+    }
+
+    fn sync(&self) -> Result<()> {
+        io(cache::sync_device(&*self.volume.device))
+    }
+
+    fn lstat(&self, path: &str) -> Result<FileStats> {
+        self.volume.stats(self.volume.walk(path)?)
+    }
+}
+
+struct Ext2Dir {
+    volume: Arc<Volume>,
+    ino: u32,
+}
+
+impl Ext2Dir {
+    /// Inode of entry `name` and its type.
+    fn entry(&self, name: &str) -> Result<(u32, FileType)> {
+        let mut meta = self.volume.meta.lock();
+        let ino = self.volume.lookup(&mut meta, self.ino, name)?;
+        Ok((ino, self.volume.read_inode(&meta, ino)?.file_type()))
+    }
+}
+
+impl Directory for Ext2Dir {
+    fn list(&self) -> Result<Vec<(String, FileType)>> {
+        let mut meta = self.volume.meta.lock();
+        let mut dir = self.volume.read_inode(&meta, self.ino)?;
+        let entries = self.volume.entries(&mut meta, self.ino, &mut dir)?;
+        entries.into_iter()
+            .map(|(name, ino, file_type)| {
+                let file_type = match file_type {
+                    FT_REG_FILE => FileType::File,
+                    FT_DIR => FileType::Directory,
+                    FT_SYMLINK => FileType::Symlink,
+                    FT_CHRDEV => FileType::CharDevice,
+                    FT_BLKDEV => FileType::BlockDevice,
+                    _ => self.volume.read_inode(&meta, ino)?.file_type(),
+                };
+                Ok((name, file_type))
+            })
+            .collect()
+    }
+
+    fn get_file(&self, name: &str) -> Result<Arc<dyn File>> {
+        match self.entry(name)? {
+            (_, FileType::Directory) => Err(FsError::NotAFile),
+            (ino, _) => Ok(Arc::new(Ext2File { volume: Arc::clone(&self.volume), ino })),
+        }
+    }
+
+    fn get_dir(&self, name: &str) -> Result<Arc<dyn Directory>> {
+        match self.entry(name)? {
+            (ino, FileType::Directory) => Ok(Arc::new(Ext2Dir { volume: Arc::clone(&self.volume), ino })),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn create_file(&self, name: &str, data: Vec<u8>) -> Result<()> {
+        self.volume.create(self.ino, name, FileType::File, &data)
+    }
+
+    fn create_dir(&self, name: &str) -> Result<()> {
+        self.volume.create(self.ino, name, FileType::Directory, &[])
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.volume.remove(self.ino, name)
+    }
+
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        self.volume.rename(self.ino, old_name, self.ino, new_name)
+    }
+
+    fn stats(&self) -> Result<FileStats> {
+        self.volume.stats(self.ino)
+    }
+}
+
+struct Ext2File {
+    volume: Arc<Volume>,
+    ino: u32,
+}
+
+impl Ext2File {
+    /// Runs `f` on the inode with the volume locked and writes the inode
+    /// back if `f` succeeds and `write` is set.
+    fn with_inode<R>(&self, write: bool, f: impl FnOnce(&Volume, &mut Meta, &mut Inode) -> Result<R>) -> Result<R> {
+        if write {
+            self.volume.check_writable()?;
+        }
+        let mut meta = self.volume.meta.lock();
+        let mut inode = self.volume.read_inode(&meta, self.ino)?;
+        let result = f(&self.volume, &mut meta, &mut inode)?;
+        if write {
+            self.volume.write_inode(&meta, self.ino, &inode)?;
+        }
+        Ok(result)
+    }
+}
+
+impl File for Ext2File {
+    fn read(&self) -> Result<Vec<u8>> {
+        self.with_inode(false, |volume, meta, inode| {
+            // A sparse file can be far larger than the heap
+            let mut data = Vec::new();
+            data.try_reserve_exact(inode.size() as usize).map_err(|_| FsError::OutOfMemory)?;
+            data.resize(inode.size() as usize, 0);
+            volume.read_data(meta, self.ino, inode, 0, &mut data)?;
+            Ok(data)
+        })
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        self.with_inode(true, |volume, meta, inode| {
+            volume.resize(meta, self.ino, inode, 0)?;
+            volume.write_data(meta, self.ino, inode, 0, data)
+        })
+    }
+
+    fn append(&self, data: &[u8]) -> Result<()> {
+        self.with_inode(true, |volume, meta, inode| {
+            let end = inode.size();
+            volume.write_data(meta, self.ino, inode, end, data)
+        })
+    }
+
+    fn truncate(&self) -> Result<()> {
+        self.set_len(0)
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.with_inode(false, |volume, meta, inode| {
+            volume.read_data(meta, self.ino, inode, offset as u64, buf)
+        })
+    }
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize> {
+        self.with_inode(true, |volume, meta, inode| {
+            volume.write_data(meta, self.ino, inode, offset as u64, data)?;
+            Ok(data.len())
+        })
+    }
+
+    fn set_len(&self, len: usize) -> Result<()> {
+        self.with_inode(true, |volume, meta, inode| volume.resize(meta, self.ino, inode, len as u64))
+    }
+
+    fn stats(&self) -> Result<FileStats> {
+        self.volume.stats(self.ino)
+    }
+}
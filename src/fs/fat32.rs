@@ -0,0 +1,1297 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::block::{self, cache, BlockDevice};
+use crate::rtc;
+use crate::fs::{path, Directory, File, FileStats, FileType, FsError, Filesystem, Result, STAT_BLOCK_SIZE};
+
+const ENTRY_SIZE: usize = 32;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+const ENTRY_FREE: u8 = 0xE5;
+const ENTRY_END: u8 = 0x00;
+const LFN_LAST: u8 = 0x40;
+/// UCS-2 characters in one long name entry.
+const LFN_CHARS: usize = 13;
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME: usize = 255;
+
+/// Low 28 bits of a FAT entry; the top four are reserved.
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+const FAT_FREE: u32 = 0;
+const FAT_BAD: u32 = 0x0FFF_FFF7;
+const FAT_EOC: u32 = 0x0FFF_FFF8;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// 1980-01-01, the FAT epoch and the earliest date an entry can hold.
+const EPOCH_DATE: u16 = (1 << 5) | 1;
+
+/// Where the short entry of a file or directory lives: the first cluster of
+/// the directory holding it and the byte offset within that directory.
+type Location = (u32, u32);
+
+fn io<T>(result: core::result::Result<T, &'static str>) -> Result<T> {
+    result.map_err(|_| FsError::Io)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// Seconds since 1970 for a FAT date and time.
+fn unix_time(date: u16, time: u16) -> u64 {
+    let (year, month, day) = (1980 + (date >> 9) as i64, ((date >> 5) & 0xF) as i64, (date & 0x1F) as i64);
+    if month == 0 || day == 0 {
+        return 0;
+    }
+    let days = rtc::days_from_civil(year, month, day);
+    let seconds = (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3F) as i64 * 60 + (time & 0x1F) as i64 * 2;
+    (days * 86_400 + seconds) as u64
+}
+
+/// FAT date and time for seconds since 1970. Times before the FAT epoch
+/// become the epoch; the time keeps two-second resolution.
+fn fat_time(unix: u64) -> (u16, u16) {
+    let (year, month, day) = rtc::civil_from_days((unix / 86_400) as i64);
+    if year < 1980 {
+        return (EPOCH_DATE, 0);
+    }
+    let date = (((year - 1980).min(127) << 9) | (month << 5) | day) as u16;
+    let seconds = unix % 86_400;
+    let time = ((seconds / 3600) << 11) | ((seconds / 60 % 60) << 5) | (seconds % 60 / 2);
+    (date, time as u16)
+}
+
+/// Checksum of an 8.3 name that its long name entries carry.
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// Display form of an 8.3 name, e.g. `README.TXT`.
+fn short_to_string(short_name: &[u8; 11]) -> String {
+    let base = core::str::from_utf8(&short_name[..8]).unwrap_or("").trim_end();
+    let ext = core::str::from_utf8(&short_name[8..]).unwrap_or("").trim_end();
+    let mut name = base.to_string();
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(ext);
+    }
+    // 0x05 stands for a leading 0xE5
+    name.replace('\u{5}', "\u{E5}")
+}
+
+fn valid_short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+/// `name` as an 8.3 name if it already is one exactly, case included, so it
+/// needs no long name entries.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3
+        || !base.chars().chain(ext.chars()).all(valid_short_char)
+    {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+/// A unique `BASIS~N.EXT` alias for a long name, as Windows generates them.
+fn generate_short_name(name: &str, taken: &[[u8; 11]]) -> Result<[u8; 11]> {
+    let clean = |s: &str| -> Vec<u8> {
+        s.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if valid_short_char(c) { c as u8 } else { b'_' }
+            })
+            .collect()
+    };
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (clean(&name[..dot]), clean(&name[dot + 1..])),
+        _ => (clean(name), Vec::new()),
+    };
+
+    for n in 1..1_000_000u32 {
+        let tail = alloc::format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        let ext_len = ext.len().min(3);
+        short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(FsError::NoSpace)
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.encode_utf16().count() > MAX_NAME
+        || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+    {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+/// A parsed directory entry, with its long name if it has one.
+#[derive(Clone)]
+struct RawEntry {
+    name: String,
+    short_name: [u8; 11],
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+    /// Offset of the short entry in the directory.
+    offset: u32,
+    /// Offset of the first long name entry, or of the short entry if none.
+    first_slot: u32,
+    /// The whole 32-byte short entry, carried over on rename.
+    raw: [u8; ENTRY_SIZE],
+}
+
+impl RawEntry {
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+/// Entries of a directory's contents, without `.`, `..` and the volume label.
+fn parse_entries(data: &[u8]) -> Vec<RawEntry> {
+    let mut entries = Vec::new();
+    let mut lfn: Vec<u16> = Vec::new();
+    let mut lfn_checksum = 0;
+    let mut lfn_start = None;
+
+    for (index, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        let offset = (index * ENTRY_SIZE) as u32;
+        match raw[0] {
+            ENTRY_END => break,
+            ENTRY_FREE => {
+                lfn_start = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        let attr = raw[11];
+        if attr & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            let order = raw[0];
+            if order & LFN_LAST != 0 {
+                lfn = alloc::vec![0xFFFF; (order & 0x1F) as usize * LFN_CHARS];
+                lfn_checksum = raw[13];
+                lfn_start = Some(offset);
+            }
+            let part = (order & 0x1F) as usize;
+            if lfn_start.is_some() && part >= 1 && part * LFN_CHARS <= lfn.len() {
+                for (i, &at) in LFN_OFFSETS.iter().enumerate() {
+                    lfn[(part - 1) * LFN_CHARS + i] = u16_at(raw, at);
+                }
+            }
+            continue;
+        }
+
+        let mut short_name = [0u8; 11];
+        short_name.copy_from_slice(&raw[..11]);
+        let long = match lfn_start.take() {
+            Some(start) if checksum(&short_name) == lfn_checksum => {
+                let end = lfn.iter().position(|&c| c == 0 || c == 0xFFFF).unwrap_or(lfn.len());
+                Some((String::from_utf16_lossy(&lfn[..end]), start))
+            }
+            _ => None,
+        };
+        if attr & ATTR_VOLUME_ID != 0 || short_name[0] == b'.' {
+            continue;
+        }
+
+        let (name, first_slot) = match long {
+            Some((name, start)) => (name, start),
+            None => (short_to_string(&short_name), offset),
+        };
+        let mut copy = [0u8; ENTRY_SIZE];
+        copy.copy_from_slice(raw);
+        entries.push(RawEntry {
+            name,
+            short_name,
+            attr,
+            first_cluster: ((u16_at(raw, 20) as u32) << 16) | u16_at(raw, 26) as u32,
+            size: u32_at(raw, 28),
+            offset,
+            first_slot,
+            raw: copy,
+        });
+    }
+    entries
+}
+
+/// What is known about a file or directory while it is in use. Shared by all
+/// handles to it so they agree on its size and clusters.
+struct NodeState {
+    first_cluster: u32,
+    size: u32,
+    attr: u8,
+    /// Where its short entry is; `None` for the root directory.
+    location: Option<Location>,
+    /// Set once the entry is deleted; further access fails.
+    removed: bool,
+    modified: u64,
+    /// Cluster chain, read on first use.
+    clusters: Option<Vec<u32>>,
+}
+
+struct Node {
+    state: Mutex<NodeState>,
+}
+
+/// Allocation state and open nodes. One lock covers every operation on the
+/// volume, which keeps the FAT, FSInfo and directories consistent.
+struct Meta {
+    free_count: u32,
+    next_free: u32,
+    nodes: BTreeMap<Location, Weak<Node>>,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    cluster_size: usize,
+    /// Byte offsets of the first FAT, the FSInfo sector and cluster 2.
+    fat_start: u64,
+    fat_size: u64,
+    num_fats: u64,
+    fsinfo: Option<u64>,
+    data_start: u64,
+    root_cluster: u32,
+    /// Highest valid cluster number plus one.
+    cluster_limit: u32,
+    root: Arc<Node>,
+    meta: Mutex<Meta>,
+}
+
+impl Volume {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        io(cache::read(&self.device, offset, buf))
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        io(cache::write(&self.device, offset, data))
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.cluster_size as u64
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32> {
+        let mut raw = [0u8; 4];
+        self.read(self.fat_start + cluster as u64 * 4, &mut raw)?;
+        Ok(u32::from_le_bytes(raw) & FAT_MASK)
+    }
+
+    /// Sets the entry for `cluster` in every copy of the FAT.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<()> {
+        let mut raw = [0u8; 4];
+        self.read(self.fat_start + cluster as u64 * 4, &mut raw)?;
+        let value = (u32::from_le_bytes(raw) & !FAT_MASK) | (value & FAT_MASK);
+        for fat in 0..self.num_fats {
+            self.write(self.fat_start + fat * self.fat_size + cluster as u64 * 4, &value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_limit
+    }
+
+    /// Clusters of the chain starting at `first`; empty for cluster 0.
+    fn chain(&self, first: u32) -> Result<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while self.valid_cluster(cluster) {
+            clusters.push(cluster);
+            // A loop in the FAT would otherwise never end
+            if clusters.len() > self.cluster_limit as usize {
+                return Err(FsError::Io);
+            }
+            cluster = self.fat_entry(cluster)?;
+        }
+        if cluster == FAT_BAD {
+            return Err(FsError::Io);
+        }
+        Ok(clusters)
+    }
+
+    /// Takes a free cluster, zeroes it and appends it to the chain ending in
+    /// `last`, if any.
+    fn alloc_cluster(&self, meta: &mut Meta, last: Option<u32>) -> Result<u32> {
+        let count = self.cluster_limit - 2;
+        let start = if self.valid_cluster(meta.next_free) { meta.next_free } else { 2 };
+        let mut found = None;
+        for i in 0..count {
+            let cluster = 2 + (start - 2 + i) % count;
+            if self.fat_entry(cluster)? == FAT_FREE {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+
+        self.set_fat_entry(cluster, FAT_EOC | 0xF)?;
+        if let Some(last) = last {
+            self.set_fat_entry(last, cluster)?;
+        }
+        self.write(self.cluster_offset(cluster), &alloc::vec![0; self.cluster_size])?;
+        meta.free_count = meta.free_count.saturating_sub(1);
+        meta.next_free = cluster + 1;
+        self.write_fsinfo(meta)?;
+        Ok(cluster)
+    }
+
+    /// Frees `clusters`, which end a chain.
+    fn free_clusters(&self, meta: &mut Meta, clusters: &[u32]) -> Result<()> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, FAT_FREE)?;
+            meta.free_count += 1;
+        }
+        self.write_fsinfo(meta)
+    }
+
+    fn write_fsinfo(&self, meta: &Meta) -> Result<()> {
+        if let Some(offset) = self.fsinfo {
+            self.write(offset + 488, &meta.free_count.to_le_bytes())?;
+            self.write(offset + 492, &meta.next_free.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Counts free clusters by scanning the FAT, for volumes whose FSInfo does
+    /// not know.
+    fn count_free(&self) -> Result<u32> {
+        let mut free = 0;
+        let mut buf = alloc::vec![0u8; cache::BLOCK_SIZE];
+        let mut cluster = 2u32;
+        while cluster < self.cluster_limit {
+            let count = ((self.cluster_limit - cluster) as usize).min(buf.len() / 4);
+            self.read(self.fat_start + cluster as u64 * 4, &mut buf[..count * 4])?;
+            free += buf[..count * 4].chunks_exact(4)
+                .filter(|raw| u32_at(raw, 0) & FAT_MASK == FAT_FREE)
+                .count() as u32;
+            cluster += count as u32;
+        }
+        Ok(free)
+    }
+
+    fn state_clusters<'a>(&self, state: &'a mut NodeState) -> Result<&'a mut Vec<u32>> {
+        if state.clusters.is_none() {
+            state.clusters = Some(self.chain(state.first_cluster)?);
+        }
+        Ok(state.clusters.as_mut().unwrap())
+    }
+
+    /// Reads from a node's data, up to `limit` bytes into it.
+    fn read_data(&self, state: &mut NodeState, offset: usize, buf: &mut [u8], limit: usize) -> Result<usize> {
+        if offset >= limit {
+            return Ok(0);
+        }
+        let count = buf.len().min(limit - offset);
+        let cluster_size = self.cluster_size;
+        let clusters = self.state_clusters(state)?.clone();
+        let mut done = 0;
+        while done < count {
+            let pos = offset + done;
+            let cluster = *clusters.get(pos / cluster_size).ok_or(FsError::Io)?;
+            let start = pos % cluster_size;
+            let chunk = (cluster_size - start).min(count - done);
+            self.read(self.cluster_offset(cluster) + start as u64, &mut buf[done..done + chunk])?;
+            done += chunk;
+        }
+        Ok(count)
+    }
+
+    /// Grows a node's chain to at least `len` bytes' worth of clusters.
+    fn reserve(&self, meta: &mut Meta, state: &mut NodeState, len: usize) -> Result<()> {
+        let needed = (len + self.cluster_size - 1) / self.cluster_size;
+        let mut clusters = self.state_clusters(state)?.clone();
+        let had = clusters.len();
+        while clusters.len() < needed {
+            match self.alloc_cluster(meta, clusters.last().copied()) {
+                Ok(cluster) => clusters.push(cluster),
+                Err(e) => {
+                    // Running out part way must not strand what was taken
+                    if let Some(&last) = clusters[..had].last() {
+                        self.set_fat_entry(last, FAT_EOC | 0xF)?;
+                    }
+                    self.free_clusters(meta, &clusters[had..])?;
+                    return Err(e);
+                }
+            }
+        }
+        if state.first_cluster == 0 {
+            state.first_cluster = clusters.first().copied().unwrap_or(0);
+        }
+        state.clusters = Some(clusters);
+        Ok(())
+    }
+
+    fn write_data(&self, meta: &mut Meta, state: &mut NodeState, offset: usize, data: &[u8]) -> Result<()> {
+        self.reserve(meta, state, offset + data.len())?;
+        let clusters = state.clusters.as_ref().unwrap();
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done;
+            let cluster = clusters[pos / self.cluster_size];
+            let start = pos % self.cluster_size;
+            let chunk = (self.cluster_size - start).min(data.len() - done);
+            self.write(self.cluster_offset(cluster) + start as u64, &data[done..done + chunk])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Byte offset on the device of `offset` within the directory starting
+    /// at `dir_cluster`.
+    fn dir_offset(&self, dir_cluster: u32, offset: u32) -> Result<u64> {
+        let clusters = self.chain(dir_cluster)?;
+        let cluster = *clusters.get(offset as usize / self.cluster_size).ok_or(FsError::Io)?;
+        Ok(self.cluster_offset(cluster) + (offset as usize % self.cluster_size) as u64)
+    }
+
+    /// Writes a node's first cluster and size into its short entry, and
+    /// stamps it as modified now.
+    fn update_entry(&self, state: &mut NodeState) -> Result<()> {
+        state.modified = rtc::now();
+        let (dir, offset) = match state.location {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+        let base = self.dir_offset(dir, offset)?;
+        let (date, time) = fat_time(state.modified);
+        self.write(base + 18, &date.to_le_bytes())?;
+        self.write(base + 22, &time.to_le_bytes())?;
+        self.write(base + 24, &date.to_le_bytes())?;
+        self.write(base + 20, &((state.first_cluster >> 16) as u16).to_le_bytes())?;
+        self.write(base + 26, &(state.first_cluster as u16).to_le_bytes())?;
+        if state.attr & ATTR_DIRECTORY == 0 {
+            self.write(base + 28, &state.size.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn dir_contents(&self, dir_cluster: u32) -> Result<Vec<u8>> {
+        let clusters = self.chain(dir_cluster)?;
+        let mut data = alloc::vec![0u8; clusters.len() * self.cluster_size];
+        for (i, &cluster) in clusters.iter().enumerate() {
+            let chunk = &mut data[i * self.cluster_size..(i + 1) * self.cluster_size];
+            self.read(self.cluster_offset(cluster), chunk)?;
+        }
+        Ok(data)
+    }
+
+    fn dir_entries(&self, dir: &NodeState) -> Result<Vec<RawEntry>> {
+        if dir.removed {
+            return Err(FsError::NotFound);
+        }
+        Ok(parse_entries(&self.dir_contents(dir.first_cluster)?))
+    }
+
+    fn find(&self, dir: &NodeState, name: &str) -> Result<Option<RawEntry>> {
+        let entries = self.dir_entries(dir)?;
+        Ok(entries.into_iter().find(|entry| {
+            entry.name.eq_ignore_ascii_case(name) || short_to_string(&entry.short_name).eq_ignore_ascii_case(name)
+        }))
+    }
+
+    /// The shared node for an entry of the directory starting at `dir`.
+    fn node(&self, meta: &mut Meta, dir: u32, entry: &RawEntry) -> Arc<Node> {
+        let location = (dir, entry.offset);
+        if let Some(node) = meta.nodes.get(&location).and_then(Weak::upgrade) {
+            return node;
+        }
+        let node = Arc::new(Node {
+            state: Mutex::new(NodeState {
+                first_cluster: entry.first_cluster,
+                size: entry.size,
+                attr: entry.attr,
+                location: Some(location),
+                removed: false,
+                modified: unix_time(u16_at(&entry.raw, 24), u16_at(&entry.raw, 22)),
+                clusters: None,
+            }),
+        });
+        meta.nodes.retain(|_, node| node.strong_count() > 0);
+        meta.nodes.insert(location, Arc::downgrade(&node));
+        node
+    }
+
+    /// Writes the long name entries and `short` entry for `name` into free
+    /// slots of `dir`, growing it if needed. Returns the short entry's offset.
+    /// Adds `name` to `dir` with the fields of `short`; the entry at
+    /// `replacing`, about to be removed, does not count as a clash.
+    fn add_entry(
+        &self,
+        meta: &mut Meta,
+        dir: &mut NodeState,
+        name: &str,
+        mut short: [u8; ENTRY_SIZE],
+        replacing: Option<u32>,
+    ) -> Result<u32> {
+        check_name(name)?;
+        let data = self.dir_contents(dir.first_cluster)?;
+        let mut entries = parse_entries(&data);
+        entries.retain(|entry| Some(entry.offset) != replacing);
+        if entries.iter().any(|entry| entry.name.eq_ignore_ascii_case(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short_name, lfn) = match exact_short_name(name) {
+            Some(short_name) => (short_name, Vec::new()),
+            None => {
+                let taken: Vec<[u8; 11]> = entries.iter().map(|entry| entry.short_name).collect();
+                (generate_short_name(name, &taken)?, name.encode_utf16().collect::<Vec<u16>>())
+            }
+        };
+        short[..11].copy_from_slice(&short_name);
+        let lfn_entries = (lfn.len() + LFN_CHARS - 1) / LFN_CHARS;
+        let slots = lfn_entries + 1;
+
+        // First run of enough free slots; everything after the end marker is
+        // free
+        let mut run_start = 0;
+        let mut run = 0;
+        let mut found = None;
+        for (index, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+            if raw[0] == ENTRY_FREE || raw[0] == ENTRY_END {
+                if run == 0 {
+                    run_start = index;
+                }
+                run += 1;
+                if run == slots {
+                    found = Some(run_start);
+                    break;
+                }
+            } else {
+                run = 0;
+            }
+        }
+        let first = match found {
+            Some(first) => first,
+            None => {
+                let start = if run > 0 { run_start } else { data.len() / ENTRY_SIZE };
+                let needed = (start + slots) * ENTRY_SIZE;
+                self.reserve(meta, dir, needed)?;
+                start
+            }
+        };
+
+        let sum = checksum(&short_name);
+        for i in 0..lfn_entries {
+            // Stored last part first
+            let part = lfn_entries - i;
+            let mut raw = [0u8; ENTRY_SIZE];
+            raw[0] = part as u8 | if i == 0 { LFN_LAST } else { 0 };
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = sum;
+            for (j, &at) in LFN_OFFSETS.iter().enumerate() {
+                let index = (part - 1) * LFN_CHARS + j;
+                let c = match index.cmp(&lfn.len()) {
+                    core::cmp::Ordering::Less => lfn[index],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xFFFF,
+                };
+                raw[at..at + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            let offset = ((first + i) * ENTRY_SIZE) as u32;
+            self.write(self.dir_offset(dir.first_cluster, offset)?, &raw)?;
+        }
+        let offset = ((first + lfn_entries) * ENTRY_SIZE) as u32;
+        self.write(self.dir_offset(dir.first_cluster, offset)?, &short)?;
+        Ok(offset)
+    }
+
+    /// Marks the slots of `entry` free.
+    fn remove_slots(&self, dir: u32, entry: &RawEntry) -> Result<()> {
+        let mut offset = entry.first_slot;
+        while offset <= entry.offset {
+            self.write(self.dir_offset(dir, offset)?, &[ENTRY_FREE])?;
+            offset += ENTRY_SIZE as u32;
+        }
+        Ok(())
+    }
+
+    /// A fresh short entry for a new file or directory.
+    fn new_short_entry(attr: u8, first_cluster: u32) -> [u8; ENTRY_SIZE] {
+        let mut raw = [0u8; ENTRY_SIZE];
+        raw[11] = attr;
+        let (date, time) = fat_time(rtc::now());
+        for at in [14, 22] {
+            raw[at..at + 2].copy_from_slice(&time.to_le_bytes());
+        }
+        for at in [16, 18, 24] {
+            raw[at..at + 2].copy_from_slice(&date.to_le_bytes());
+        }
+        raw[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        raw
+    }
+
+    /// Points the `..` entry of the directory at `dir_cluster` to `parent`.
+    fn set_dotdot(&self, dir_cluster: u32, parent: u32) -> Result<()> {
+        // The root is cluster 0 in `..` entries
+        let parent = if parent == self.root_cluster { 0 } else { parent };
+        let base = self.dir_offset(dir_cluster, ENTRY_SIZE as u32)?;
+        self.write(base + 20, &((parent >> 16) as u16).to_le_bytes())?;
+        self.write(base + 26, &(parent as u16).to_le_bytes())
+    }
+
+    fn create(&self, dir_node: &Node, name: &str, directory: bool, data: &[u8]) -> Result<()> {
+        let mut meta = self.meta.lock();
+        let mut dir = dir_node.state.lock();
+        if dir.removed {
+            return Err(FsError::NotFound);
+        }
+        check_name(name)?;
+        if self.find(&dir, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        if directory {
+            let cluster = self.alloc_cluster(&mut meta, None)?;
+            let mut dot = Self::new_short_entry(ATTR_DIRECTORY, cluster);
+            dot[..11].copy_from_slice(b".          ");
+            let mut dotdot = Self::new_short_entry(ATTR_DIRECTORY, 0);
+            dotdot[..11].copy_from_slice(b"..         ");
+            self.write(self.cluster_offset(cluster), &dot)?;
+            self.write(self.cluster_offset(cluster) + ENTRY_SIZE as u64, &dotdot)?;
+            self.set_dotdot(cluster, dir.first_cluster)?;
+            let short = Self::new_short_entry(ATTR_DIRECTORY, cluster);
+            if let Err(e) = self.add_entry(&mut meta, &mut dir, name, short, None) {
+                self.free_clusters(&mut meta, &[cluster])?;
+                return Err(e);
+            }
+            return Ok(());
+        }
+
+        let short = Self::new_short_entry(ATTR_ARCHIVE, 0);
+        let offset = self.add_entry(&mut meta, &mut dir, name, short, None)?;
+        if !data.is_empty() {
+            let mut state = NodeState {
+                first_cluster: 0,
+                size: data.len() as u32,
+                attr: ATTR_ARCHIVE,
+                location: Some((dir.first_cluster, offset)),
+                removed: false,
+                modified: 0,
+                clusters: None,
+            };
+            if let Err(e) = self.write_data(&mut meta, &mut state, 0, data) {
+                // The entry still says empty; give back what was reserved
+                let clusters = self.chain(state.first_cluster)?;
+                self.free_clusters(&mut meta, &clusters)?;
+                return Err(e);
+            }
+            self.update_entry(&mut state)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, dir_node: &Node, name: &str) -> Result<()> {
+        let mut meta = self.meta.lock();
+        let dir = dir_node.state.lock();
+        let entry = self.find(&dir, name)?.ok_or(FsError::NotFound)?;
+        self.remove_entry(&mut meta, dir.first_cluster, &entry)
+    }
+
+    fn remove_entry(&self, meta: &mut Meta, dir: u32, entry: &RawEntry) -> Result<()> {
+        if entry.is_dir() {
+            let contents = self.dir_contents(entry.first_cluster)?;
+            if !parse_entries(&contents).is_empty() {
+                return Err(FsError::NotEmpty);
+            }
+        }
+        self.remove_slots(dir, entry)?;
+        let clusters = self.chain(entry.first_cluster)?;
+        self.free_clusters(meta, &clusters)?;
+        if let Some(node) = meta.nodes.remove(&(dir, entry.offset)).and_then(|n| n.upgrade()) {
+            node.state.lock().removed = true;
+        }
+        Ok(())
+    }
+
+    /// Cluster of the parent of the directory at `dir_cluster`, from its
+    /// `..` entry.
+    fn parent_of(&self, dir_cluster: u32) -> Result<u32> {
+        let mut raw = [0u8; ENTRY_SIZE];
+        self.read(self.dir_offset(dir_cluster, ENTRY_SIZE as u32)?, &mut raw)?;
+        match ((u16_at(&raw, 20) as u32) << 16) | u16_at(&raw, 26) as u32 {
+            0 => Ok(self.root_cluster),
+            cluster => Ok(cluster),
+        }
+    }
+
+    /// Moves entry `old_name` of `src` to `new_name` in `dst`, replacing an
+    /// existing target by the same rules as the other filesystems.
+    fn rename(&self, src: &Node, old_name: &str, dst: &Node, new_name: &str) -> Result<()> {
+        check_name(new_name)?;
+        let mut meta = self.meta.lock();
+        let (src_cluster, entry) = {
+            let state = src.state.lock();
+            (state.first_cluster, self.find(&state, old_name)?.ok_or(FsError::NotFound)?)
+        };
+        let (dst_cluster, existing) = {
+            let state = dst.state.lock();
+            (state.first_cluster, self.find(&state, new_name)?)
+        };
+
+        // A directory cannot move into itself
+        if entry.is_dir() && dst_cluster != src_cluster {
+            let mut cluster = dst_cluster;
+            let mut steps = 0;
+            while cluster != self.root_cluster {
+                if cluster == entry.first_cluster {
+                    return Err(FsError::InvalidPath);
+                }
+                // A `..` loop on a corrupt volume would never reach the root
+                steps += 1;
+                if steps > self.cluster_limit {
+                    return Err(FsError::Io);
+                }
+                cluster = self.parent_of(cluster)?;
+            }
+        }
+
+        // The new name is in place before the old one goes, so a failure
+        // part way leaves the entry reachable under one name or both, never
+        // neither
+        let offset = match existing {
+            Some(existing) => {
+                if dst_cluster == src_cluster && existing.offset == entry.offset {
+                    if entry.name == new_name {
+                        return Ok(());
+                    }
+                    // Only the case differs: write the new long name before
+                    // dropping the old entry
+                    self.add_entry(&mut meta, &mut dst.state.lock(), new_name, entry.raw, Some(entry.offset))?
+                } else {
+                    match (entry.is_dir(), existing.is_dir()) {
+                        (true, false) => return Err(FsError::NotADirectory),
+                        (false, true) => return Err(FsError::IsADirectory),
+                        _ => {}
+                    }
+                    if existing.is_dir() && !parse_entries(&self.dir_contents(existing.first_cluster)?).is_empty() {
+                        return Err(FsError::NotEmpty);
+                    }
+                    // Take over the target's short entry, keeping its name, in
+                    // one write; its long name entries still match
+                    let mut short = entry.raw;
+                    short[..11].copy_from_slice(&existing.short_name);
+                    self.write(self.dir_offset(dst_cluster, existing.offset)?, &short)?;
+                    if let Some(node) = meta.nodes.remove(&(dst_cluster, existing.offset)).and_then(|n| n.upgrade()) {
+                        node.state.lock().removed = true;
+                    }
+                    let clusters = self.chain(existing.first_cluster)?;
+                    self.free_clusters(&mut meta, &clusters)?;
+                    existing.offset
+                }
+            }
+            None => self.add_entry(&mut meta, &mut dst.state.lock(), new_name, entry.raw, None)?,
+        };
+        self.remove_slots(src_cluster, &entry)?;
+        if entry.is_dir() && dst_cluster != src_cluster {
+            self.set_dotdot(entry.first_cluster, dst_cluster)?;
+        }
+        self.moved(&mut meta, (src_cluster, entry.offset), (dst_cluster, offset));
+        Ok(())
+    }
+
+    /// Re-keys an open node after its entry moved.
+    fn moved(&self, meta: &mut Meta, old: Location, new: Location) {
+        if let Some(node) = meta.nodes.remove(&old) {
+            if let Some(live) = node.upgrade() {
+                live.state.lock().location = Some(new);
+            }
+            meta.nodes.insert(new, node);
+        }
+    }
+
+    /// Walks `path` from the root to a directory node.
+    fn walk(&self, path: &str) -> Result<Arc<Node>> {
+        let mut node = Arc::clone(&self.root);
+        for component in path::components(path) {
+            let mut meta = self.meta.lock();
+            let dir_cluster = node.state.lock().first_cluster;
+            let entry = {
+                let state = node.state.lock();
+                self.find(&state, component)?.ok_or(FsError::NotFound)?
+            };
+            if !entry.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            node = self.node(&mut meta, dir_cluster, &entry);
+        }
+        Ok(node)
+    }
+
+    /// Directory holding the last component of `path`, and that component.
+    fn walk_parent<'a>(&self, path: &'a str) -> Result<(Arc<Node>, &'a str)> {
+        let (parent, name) = path::split_last(path);
+        Ok((self.walk(&parent)?, name))
+    }
+
+    fn lookup(&self, dir_node: &Node, name: &str) -> Result<(Arc<Node>, bool)> {
+        let mut meta = self.meta.lock();
+        let (dir_cluster, entry) = {
+            let state = dir_node.state.lock();
+            (state.first_cluster, self.find(&state, name)?.ok_or(FsError::NotFound)?)
+        };
+        Ok((self.node(&mut meta, dir_cluster, &entry), entry.is_dir()))
+    }
+
+    fn stats(&self, state: &NodeState) -> FileStats {
+        let directory = state.attr & ATTR_DIRECTORY != 0;
+        let clusters = state.clusters.as_ref().map(|c| c.len()).unwrap_or_else(|| {
+            (state.size as usize + self.cluster_size - 1) / self.cluster_size
+        });
+        let read_only = state.attr & ATTR_READ_ONLY != 0;
+        let mut stats = FileStats::new(
+            if directory { FileType::Directory } else { FileType::File },
+            if directory { clusters * self.cluster_size } else { state.size as usize },
+            match (directory, read_only) {
+                (true, false) => 0o755,
+                (true, true) => 0o555,
+                (false, false) => 0o644,
+                (false, true) => 0o444,
+            },
+        );
+        // FAT has no inode numbers; the position of the entry is used instead
+        stats.inode = match state.location {
+            Some((dir, offset)) => (dir as u64) << 32 | (offset as u64 / ENTRY_SIZE as u64),
+            None => 1,
+        };
+        stats.links = if directory { 2 } else { 1 };
+        stats.blocks = (clusters * self.cluster_size / STAT_BLOCK_SIZE) as u64;
+        stats.atime = state.modified;
+        stats.mtime = state.modified;
+        stats.ctime = state.modified;
+        stats
+    }
+}
+
+/// A FAT32 volume on a block device, mounted with `mount -t vfat <device>`.
+pub struct Fat32Fs {
+    volume: Arc<Volume>,
+}
+
+impl Fat32Fs {
+    /// Reads the boot sector and FSInfo of `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self> {
+        let mut boot = [0u8; 512];
+        io(cache::read(&device, 0, &mut boot))?;
+        if boot[510] != 0x55 || boot[511] != 0xAA {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let bytes_per_sector = u16_at(&boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = u16_at(&boot, 14) as u64;
+        let num_fats = boot[16] as u64;
+        let root_entries = u16_at(&boot, 17);
+        let fat_size16 = u16_at(&boot, 22);
+        let total_sectors = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            count => count as u64,
+        };
+        let fat_size = u32_at(&boot, 36) as u64;
+        let root_cluster = u32_at(&boot, 44);
+        let fsinfo_sector = u16_at(&boot, 48) as u64;
+
+        // FAT12/16 have a fixed root directory and a 16-bit FAT size
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || num_fats == 0 || root_entries != 0 || fat_size16 != 0 || fat_size == 0
+        {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let data_sector = reserved + num_fats * fat_size;
+        let clusters = total_sectors.saturating_sub(data_sector) / sectors_per_cluster;
+        // Also limited by how many entries the FAT can hold
+        let cluster_limit = (clusters + 2).min(fat_size * bytes_per_sector / 4) as u32;
+        if total_sectors * bytes_per_sector > device.size() || root_cluster < 2 || root_cluster >= cluster_limit {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let mut fsinfo = None;
+        let mut free_count = FSINFO_UNKNOWN;
+        let mut next_free = FSINFO_UNKNOWN;
+        if fsinfo_sector != 0 && fsinfo_sector != 0xFFFF {
+            let offset = fsinfo_sector * bytes_per_sector;
+            let mut raw = [0u8; 512];
+            io(cache::read(&device, offset, &mut raw))?;
+            if u32_at(&raw, 0) == FSINFO_LEAD_SIG && u32_at(&raw, 484) == FSINFO_STRUCT_SIG {
+                fsinfo = Some(offset);
+                free_count = u32_at(&raw, 488);
+                next_free = u32_at(&raw, 492);
+            }
+        }
+
+        let root = Arc::new(Node {
+            state: Mutex::new(NodeState {
+                first_cluster: root_cluster,
+                size: 0,
+                attr: ATTR_DIRECTORY,
+                location: None,
+                removed: false,
+                modified: 0,
+                clusters: None,
+            }),
+        });
+        let volume = Volume {
+            device,
+            cluster_size: (sectors_per_cluster * bytes_per_sector) as usize,
+            fat_start: reserved * bytes_per_sector,
+            fat_size: fat_size * bytes_per_sector,
+            num_fats,
+            fsinfo,
+            data_start: data_sector * bytes_per_sector,
+            root_cluster,
+            cluster_limit,
+            root,
+            meta: Mutex::new(Meta {
+                free_count,
+                next_free,
+                nodes: BTreeMap::new(),
+            }),
+        };
+        if free_count == FSINFO_UNKNOWN || free_count > cluster_limit {
+            let free = volume.count_free()?;
+            let mut meta = volume.meta.lock();
+            meta.free_count = free;
+            volume.write_fsinfo(&meta)?;
+        }
+        Ok(Self { volume: Arc::new(volume) })
+    }
+
+    fn dir(&self, node: Arc<Node>) -> Arc<dyn Directory> {
+        Arc::new(FatDir { volume: Arc::clone(&self.volume), node })
+    }
+}
+
+/// `FsConstructor` for `vfat`: `source` names a block device.
+pub fn mount(source: &str) -> Result<Arc<dyn Filesystem>> {
+    let device = block::get(source).ok_or(FsError::NotFound)?;
+    Ok(Arc::new(Fat32Fs::new(device)?))
+}
+
+impl Filesystem for Fat32Fs {
+    fn root_dir(&self) -> Arc<dyn Directory> {
+        self.dir(Arc::clone(&self.volume.root))
+    }
+
+    fn create_file(&self, path: &str, data: Vec<u8>) -> Result<()> {
+        let (dir, name) = self.volume.walk_parent(path)?;
+        self.volume.create(&dir, name, false, &data)
+    }
+
+    fn create_dir(&self, path: &str) -> Result<()> {
+        let (dir, name) = self.volume.walk_parent(path)?;
+        self.volume.create(&dir, name, true, &[])
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        let (dir, name) = self.volume.walk_parent(path)?;
+        self.volume.remove(&dir, name)
+    }
+
+    fn get_file(&self, path: &str) -> Result<Arc<dyn File>> {
+        let (dir, name) = self.volume.walk_parent(path)?;
+        FatDir { volume: Arc::clone(&self.volume), node: dir }.get_file(name)
+    }
+
+    fn get_dir(&self, path: &str) -> Result<Arc<dyn Directory>> {
+        Ok(self.dir(self.volume.walk(path)?))
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<()> {
+        let (src, old_name) = self.volume.walk_parent(old)?;
+        let (dst, new_name) = self.volume.walk_parent(new)?;
+        if old_name.is_empty() || new_name.is_empty() {
+            return Err(FsError::Busy);
+        }
+        // Both lookups hand out the shared node, so the same directory is
+        // the same `Node`
+        self.volume.rename(&src, old_name, &dst, new_name)
+    }
+
+    fn sync(&self) -> Result<()> {
+        io(cache::sync_device(&*self.volume.device))
+    }
+}
+
+struct FatDir {
+    volume: Arc<Volume>,
+    node: Arc<Node>,
+}
+
+impl Directory for FatDir {
+    fn list(&self) -> Result<Vec<(String, FileType)>> {
+        let _meta = self.volume.meta.lock();
+        let state = self.node.state.lock();
+        Ok(self.volume.dir_entries(&state)?
+            .into_iter()
+            .map(|entry| {
+                let file_type = if entry.is_dir() { FileType::Directory } else { FileType::File };
+                (entry.name, file_type)
+            })
+            .collect())
+    }
+
+    fn get_file(&self, name: &str) -> Result<Arc<dyn File>> {
+        match self.volume.lookup(&self.node, name)? {
+            (_, true) => Err(FsError::NotAFile),
+            (node, false) => Ok(Arc::new(FatFile { volume: Arc::clone(&self.volume), node })),
+        }
+    }
+
+    fn get_dir(&self, name: &str) -> Result<Arc<dyn Directory>> {
+        match self.volume.lookup(&self.node, name)? {
+            (node, true) => Ok(Arc::new(FatDir { volume: Arc::clone(&self.volume), node })),
+            (_, false) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn create_file(&self, name: &str, data: Vec<u8>) -> Result<()> {
+        self.volume.create(&self.node, name, false, &data)
+    }
+
+    fn create_dir(&self, name: &str) -> Result<()> {
+        self.volume.create(&self.node, name, true, &[])
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.volume.remove(&self.node, name)
+    }
+
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        self.volume.rename(&self.node, old_name, &self.node, new_name)
+    }
+
+    fn stats(&self) -> Result<FileStats> {
+        let _meta = self.volume.meta.lock();
+        let mut state = self.node.state.lock();
+        self.volume.state_clusters(&mut state)?;
+        Ok(self.volume.stats(&state))
+    }
+}
+
+struct FatFile {
+    volume: Arc<Volume>,
+    node: Arc<Node>,
+}
+
+impl FatFile {
+    /// Runs `f` with the volume and the file locked, failing once the file
+    /// has been deleted.
+    fn with_state<R>(&self, f: impl FnOnce(&mut Meta, &mut NodeState) -> Result<R>) -> Result<R> {
+        let mut meta = self.volume.meta.lock();
+        let mut state = self.node.state.lock();
+        if state.removed {
+            return Err(FsError::NotFound);
+        }
+        f(&mut meta, &mut state)
+    }
+
+    /// Truncates or extends to `len`, zero-filling any extension.
+    fn resize(&self, meta: &mut Meta, state: &mut NodeState, len: usize) -> Result<()> {
+        let volume = &self.volume;
+        let old = state.size as usize;
+        if len > u32::MAX as usize {
+            return Err(FsError::NoSpace);
+        }
+        match len.cmp(&old) {
+            core::cmp::Ordering::Less => {
+                let keep = (len + volume.cluster_size - 1) / volume.cluster_size;
+                let clusters = volume.state_clusters(state)?.clone();
+                if keep < clusters.len() {
+                    match keep {
+                        0 => state.first_cluster = 0,
+                        _ => volume.set_fat_entry(clusters[keep - 1], FAT_EOC | 0xF)?,
+                    }
+                    volume.free_clusters(meta, &clusters[keep..])?;
+                    state.clusters = Some(clusters[..keep].to_vec());
+                }
+            }
+            core::cmp::Ordering::Greater => {
+                // New clusters come zeroed; only the tail of the last one may
+                // hold old data
+                let tail_end = ((old + volume.cluster_size - 1) / volume.cluster_size * volume.cluster_size).min(len);
+                volume.reserve(meta, state, len)?;
+                if tail_end > old {
+                    volume.write_data(meta, state, old, &alloc::vec![0; tail_end - old])?;
+                }
+            }
+            core::cmp::Ordering::Equal => {}
+        }
+        state.size = len as u32;
+        volume.update_entry(state)
+    }
+}
+
+impl File for FatFile {
+    fn read(&self) -> Result<Vec<u8>> {
+        self.with_state(|_, state| {
+            let size = state.size as usize;
+            // A file of up to 4 GiB is far larger than the heap
+            let mut data = Vec::new();
+            data.try_reserve_exact(size).map_err(|_| FsError::OutOfMemory)?;
+            data.resize(size, 0);
+            self.volume.read_data(state, 0, &mut data, size)?;
+            Ok(data)
+        })
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        self.with_state(|meta, state| {
+            self.resize(meta, state, 0)?;
+            self.volume.write_data(meta, state, 0, data)?;
+            state.size = data.len() as u32;
+            self.volume.update_entry(state)
+        })
+    }
+
+    fn append(&self, data: &[u8]) -> Result<()> {
+        self.with_state(|meta, state| {
+            let end = state.size as usize;
+            if end + data.len() > u32::MAX as usize {
+                return Err(FsError::NoSpace);
+            }
+            self.volume.write_data(meta, state, end, data)?;
+            state.size += data.len() as u32;
+            self.volume.update_entry(state)
+        })
+    }
+
+    fn truncate(&self) -> Result<()> {
+        self.set_len(0)
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.with_state(|_, state| {
+            let size = state.size as usize;
+            self.volume.read_data(state, offset, buf, size)
+        })
+    }
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize> {
+        self.with_state(|meta, state| {
+            let end = offset + data.len();
+            if end > u32::MAX as usize {
+                return Err(FsError::NoSpace);
+            }
+            if offset > state.size as usize {
+                self.resize(meta, state, offset)?;
+            }
+            self.volume.write_data(meta, state, offset, data)?;
+            if end > state.size as usize {
+                state.size = end as u32;
+            }
+            self.volume.update_entry(state)?;
+            Ok(data.len())
+        })
+    }
+
+    fn set_len(&self, len: usize) -> Result<()> {
+        self.with_state(|meta, state| self.resize(meta, state, len))
+    }
+
+    fn stats(&self) -> Result<FileStats> {
+        self.with_state(|_, state| {
+            self.volume.state_clusters(state)?;
+            Ok(self.volume.stats(state))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single long name entry holding `name`, of up to 13 characters.
+    fn lfn_entry(name: &str, sum: u8) -> [u8; ENTRY_SIZE] {
+        let chars: Vec<u16> = name.encode_utf16().collect();
+        let mut raw = [0u8; ENTRY_SIZE];
+        raw[0] = 1 | LFN_LAST;
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = sum;
+        for (i, &at) in LFN_OFFSETS.iter().enumerate() {
+            let c = chars.get(i).copied().unwrap_or(if i == chars.len() { 0 } else { 0xFFFF });
+            raw[at..at + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        raw
+    }
+
+    fn short_entry(short_name: &[u8; 11]) -> [u8; ENTRY_SIZE] {
+        let mut raw = Volume::new_short_entry(ATTR_ARCHIVE, 0);
+        raw[..11].copy_from_slice(short_name);
+        raw
+    }
+
+    #[test_case]
+    fn short_name_aliases_count_up_past_taken_ones() {
+        let first = generate_short_name("long file name.txt", &[]).unwrap();
+        assert_eq!(&first, b"LONGFI~1TXT");
+        let second = generate_short_name("Long File Names.txt", &[first]).unwrap();
+        assert_eq!(&second, b"LONGFI~2TXT");
+        let taken: Vec<[u8; 11]> = (1..10)
+            .map(|n| {
+                let mut short = *b"LONGFI~0TXT";
+                short[7] = b'0' + n;
+                short
+            })
+            .collect();
+        // Two digits leave room for one less character of the basis
+        assert_eq!(&generate_short_name("long file name.txt", &taken).unwrap(), b"LONGF~10TXT");
+    }
+
+    #[test_case]
+    fn long_names_are_used_only_when_the_checksum_matches() {
+        let short_name = *b"HELLOW~1MD ";
+        let mut data = Vec::new();
+        data.extend_from_slice(&lfn_entry("HelloWorld.md", checksum(&short_name)));
+        data.extend_from_slice(&short_entry(&short_name));
+        // Left over from a system that renamed the short entry but knew
+        // nothing of long names
+        data.extend_from_slice(&lfn_entry("stale", checksum(&short_name)));
+        data.extend_from_slice(&short_entry(b"OTHER   TXT"));
+        data.extend_from_slice(&[0u8; ENTRY_SIZE]);
+
+        let entries = parse_entries(&data);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "HelloWorld.md");
+        assert_eq!(entries[0].first_slot, 0);
+        assert_eq!(entries[0].offset, ENTRY_SIZE as u32);
+        assert_eq!(entries[1].name, "OTHER.TXT");
+        assert_eq!(entries[1].first_slot, entries[1].offset);
+    }
+}
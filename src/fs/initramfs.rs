@@ -0,0 +1,332 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::fs::{path, Filesystem, FsError, Result};
+use crate::println;
+
+/// Built by `build.rs` from `initramfs/`, or the archive `INITRAMFS` named at
+/// build time.
+static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+const CPIO_HEADER: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const TAR_BLOCK: usize = 512;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+enum Kind<'a> {
+    File(&'a [u8]),
+    Directory,
+    Symlink(String),
+    /// Another name for the file at the given path, from earlier in the
+    /// archive.
+    HardLink(String),
+}
+
+struct Entry<'a> {
+    path: String,
+    permissions: u16,
+    kind: Kind<'a>,
+}
+
+/// Absolute form of a name as archivers store it, e.g. `./bin/ls` or
+/// `bin/ls`.
+fn absolute(name: &str) -> Result<String> {
+    path::normalize("/", name.trim_start_matches("./"))
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn bytes(archive: &[u8], start: usize, len: usize) -> Result<&[u8]> {
+    archive.get(start..start.checked_add(len).ok_or(FsError::InvalidArgument)?)
+        .ok_or(FsError::InvalidArgument)
+}
+
+fn text(raw: &[u8]) -> Result<&str> {
+    let end = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+    core::str::from_utf8(&raw[..end]).map_err(|_| FsError::InvalidArgument)
+}
+
+/// Entries of a newc cpio archive (`cpio -H newc`).
+fn parse_cpio(archive: &[u8]) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    // Files with several links, by inode: the path of the first
+    let mut linked: BTreeMap<(u32, u32, u32), String> = BTreeMap::new();
+    let mut offset = 0;
+    loop {
+        let header = bytes(archive, offset, CPIO_HEADER)?;
+        if &header[..6] != b"070701" && &header[..6] != b"070702" {
+            return Err(FsError::InvalidArgument);
+        }
+        let field = |i: usize| -> Result<u32> {
+            let raw = text(&header[6 + i * 8..14 + i * 8])?;
+            u32::from_str_radix(raw, 16).map_err(|_| FsError::InvalidArgument)
+        };
+        let (ino, mode, nlink, size) = (field(0)?, field(1)?, field(4)?, field(6)? as usize);
+        let (dev_major, dev_minor) = (field(7)?, field(8)?);
+        let name_size = field(11)? as usize;
+
+        let name = text(bytes(archive, offset + CPIO_HEADER, name_size)?)?;
+        let data_start = align4(offset + CPIO_HEADER + name_size);
+        let data = bytes(archive, data_start, size)?;
+        offset = align4(data_start + size);
+        if name == CPIO_TRAILER {
+            return Ok(entries);
+        }
+
+        let path = absolute(name)?;
+        let kind = match mode & S_IFMT {
+            S_IFDIR => Kind::Directory,
+            S_IFLNK => Kind::Symlink(String::from(text(data)?)),
+            S_IFREG if nlink > 1 => match linked.get(&(dev_major, dev_minor, ino)) {
+                Some(first) => Kind::HardLink(first.clone()),
+                None => {
+                    linked.insert((dev_major, dev_minor, ino), path.clone());
+                    Kind::File(data)
+                }
+            },
+            S_IFREG => Kind::File(data),
+            // Device nodes, FIFOs and sockets have nothing to be made into
+            _ => continue,
+        };
+        // GNU cpio stores the data of a linked file with its last name
+        if let (Kind::HardLink(_), false) = (&kind, data.is_empty()) {
+            entries.push(Entry { path: path.clone(), permissions: (mode & 0o7777) as u16, kind });
+            entries.push(Entry { path, permissions: (mode & 0o7777) as u16, kind: Kind::File(data) });
+            continue;
+        }
+        entries.push(Entry { path, permissions: (mode & 0o7777) as u16, kind });
+    }
+}
+
+fn octal(raw: &[u8]) -> Result<usize> {
+    let raw = text(raw)?.trim_matches(|c| c == ' ' || c == '\0');
+    if raw.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(raw, 8).map_err(|_| FsError::InvalidArgument)
+}
+
+/// Entries of a ustar archive, with the GNU long name extension.
+fn parse_tar(archive: &[u8]) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut long_name = None;
+    let mut long_link = None;
+    let mut offset = 0;
+    while offset < archive.len() {
+        let header = bytes(archive, offset, TAR_BLOCK)?;
+        // Two zero blocks end the archive; one is enough to stop at
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let size = octal(&header[124..136])?;
+        let data = bytes(archive, offset + TAR_BLOCK, size)?;
+        offset += TAR_BLOCK + (size + TAR_BLOCK - 1) / TAR_BLOCK * TAR_BLOCK;
+
+        let typeflag = header[156];
+        match typeflag {
+            b'L' => {
+                long_name = Some(String::from(text(data)?));
+                continue;
+            }
+            b'K' => {
+                long_link = Some(String::from(text(data)?));
+                continue;
+            }
+            _ => {}
+        }
+        let name = match long_name.take() {
+            Some(name) => name,
+            None if &header[257..262] == b"ustar" && header[345] != 0 => {
+                alloc::format!("{}/{}", text(&header[345..500])?, text(&header[..100])?)
+            }
+            None => String::from(text(&header[..100])?),
+        };
+        let link = match long_link.take() {
+            Some(link) => link,
+            None => String::from(text(&header[157..257])?),
+        };
+
+        let kind = match typeflag {
+            b'0' | 0 | b'7' => Kind::File(data),
+            b'5' => Kind::Directory,
+            b'2' => Kind::Symlink(link),
+            b'1' => Kind::HardLink(absolute(&link)?),
+            // Devices, FIFOs and pax metadata
+            _ => continue,
+        };
+        entries.push(Entry {
+            path: absolute(&name)?,
+            permissions: (octal(&header[100..108])? & 0o7777) as u16,
+            kind,
+        });
+    }
+    Ok(entries)
+}
+
+/// Creates each directory above `path` that is missing, for archives that
+/// do not list them.
+fn create_parents(fs: &dyn Filesystem, path: &str) -> Result<()> {
+    let (parent, _) = path::split_last(path);
+    let mut current = String::new();
+    for component in path::components(&parent) {
+        current.push('/');
+        current.push_str(component);
+        match fs.create_dir(&current) {
+            Ok(()) | Err(FsError::AlreadyExists) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn extract(fs: &dyn Filesystem, entry: &Entry) -> Result<()> {
+    create_parents(fs, &entry.path)?;
+    match &entry.kind {
+        Kind::Directory => match fs.create_dir(&entry.path) {
+            Ok(()) | Err(FsError::AlreadyExists) => {}
+            Err(e) => return Err(e),
+        },
+        Kind::File(data) => match fs.create_file(&entry.path, data.to_vec()) {
+            Err(FsError::AlreadyExists) => fs.get_file(&entry.path)?.write(data)?,
+            result => result?,
+        },
+        Kind::Symlink(target) => return fs.symlink(target, &entry.path),
+        Kind::HardLink(existing) => fs.link(existing, &entry.path)?,
+    }
+    fs.set_permissions(&entry.path, entry.permissions)
+}
+
+/// Unpacks a newc cpio or ustar archive into `fs`, returning how many
+/// entries were created. Existing directories are kept and existing files
+/// overwritten. An entry that fails is reported and skipped.
+pub fn unpack(fs: &dyn Filesystem, archive: &[u8]) -> Result<usize> {
+    let entries = if archive.is_empty() {
+        Vec::new()
+    } else if archive.starts_with(b"0707") {
+        parse_cpio(archive)?
+    } else if archive.len() >= TAR_BLOCK && &archive[257..262] == b"ustar" {
+        parse_tar(archive)?
+    } else {
+        return Err(FsError::InvalidArgument);
+    };
+
+    let mut count = 0;
+    // `.` stands for the root, which is there already
+    for entry in entries.iter().filter(|entry| entry.path != "/") {
+        match extract(fs, entry) {
+            Ok(()) => count += 1,
+            Err(e) => println!("initramfs: {}: {}", entry.path, e),
+        }
+    }
+    Ok(count)
+}
+
+/// Unpacks the archive built into the kernel into the root filesystem.
+pub fn load(fs: &dyn Filesystem) {
+    match unpack(fs, ARCHIVE) {
+        Ok(count) => println!("initramfs: unpacked {} entries ({} bytes)", count, ARCHIVE.len()),
+        Err(e) => println!("initramfs: bad archive: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::memfs::MemFs;
+
+    fn cpio_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [1, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        archive.extend_from_slice(b"070701");
+        for field in fields {
+            archive.extend_from_slice(alloc::format!("{:08X}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align4(archive.len()), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align4(archive.len()), 0);
+    }
+
+    fn tar_entry(archive: &mut Vec<u8>, name: &str, typeflag: u8, link: &str, data: &[u8]) {
+        let mut header = [0u8; TAR_BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(alloc::format!("{:011o}\0", data.len()).as_bytes());
+        header[156] = typeflag;
+        header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(TAR_BLOCK), 0);
+    }
+
+    fn cpio_archive() -> Vec<u8> {
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, ".", S_IFDIR | 0o755, b"");
+        cpio_entry(&mut archive, "bin", S_IFDIR | 0o755, b"");
+        cpio_entry(&mut archive, "bin/hello", S_IFREG | 0o644, b"hi\n");
+        cpio_entry(&mut archive, "./bin/hi", S_IFLNK | 0o777, b"hello");
+        cpio_entry(&mut archive, CPIO_TRAILER, 0, b"");
+        archive
+    }
+
+    #[test_case]
+    fn cpio_entries_are_read_up_to_the_trailer() {
+        let archive = cpio_archive();
+        let entries = parse_cpio(&archive).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["/", "/bin", "/bin/hello", "/bin/hi"]);
+        assert!(matches!(entries[2].kind, Kind::File(b"hi\n")));
+        assert_eq!(entries[2].permissions, 0o644);
+        assert!(matches!(&entries[3].kind, Kind::Symlink(target) if target == "hello"));
+    }
+
+    #[test_case]
+    fn truncated_cpio_is_rejected() {
+        let archive = cpio_archive();
+        // Cut inside the second header, then inside the first file's data
+        assert!(matches!(parse_cpio(&archive[..CPIO_HEADER + 20]), Err(FsError::InvalidArgument)));
+        let data_end = archive.windows(3).position(|w| w == b"hi\n").unwrap() + 1;
+        assert!(matches!(parse_cpio(&archive[..data_end]), Err(FsError::InvalidArgument)));
+    }
+
+    #[test_case]
+    fn tar_entries_stop_at_a_zero_block() {
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "./", b'5', "", b"");
+        tar_entry(&mut archive, "etc/motd", b'0', "", b"welcome\n");
+        tar_entry(&mut archive, "etc/issue", b'1', "etc/motd", b"");
+        archive.resize(archive.len() + 2 * TAR_BLOCK, 0);
+        tar_entry(&mut archive, "after/end", b'0', "", b"");
+
+        let entries = parse_tar(&archive).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["/", "/etc/motd", "/etc/issue"]);
+        assert!(matches!(entries[1].kind, Kind::File(b"welcome\n")));
+        assert!(matches!(&entries[2].kind, Kind::HardLink(existing) if existing == "/etc/motd"));
+    }
+
+    #[test_case]
+    fn truncated_tar_is_rejected() {
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "big", b'0', "", &[7; 600]);
+        assert!(matches!(parse_tar(&archive[..TAR_BLOCK + 100]), Err(FsError::InvalidArgument)));
+        tar_entry(&mut archive, "next", b'0', "", b"");
+        let cut = archive.len() - TAR_BLOCK / 2;
+        assert!(matches!(parse_tar(&archive[..cut]), Err(FsError::InvalidArgument)));
+    }
+
+    #[test_case]
+    fn unpacking_leaves_the_root_alone() {
+        let fs = MemFs::new();
+        assert_eq!(unpack(&fs, &cpio_archive()).unwrap(), 3);
+        assert_eq!(fs.get_file("/bin/hello").unwrap().read().unwrap(), b"hi\n");
+        assert_eq!(fs.readlink("/bin/hi").unwrap(), "hello");
+    }
+}
@@ -0,0 +1,359 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, RwLock};
+use crate::fs::{FileStats, FsError, Result};
+use crate::task::{self, Task};
+
+/// End of a range that runs to the end of the file, however long it grows.
+pub const EOF: u64 = u64::MAX;
+
+/// A file as locks see it: the device and inode number the VFS reports, so
+/// every name and descriptor of a file shares its locks.
+pub type Key = (u64, u64);
+
+pub fn key(stats: &FileStats) -> Key {
+    (stats.dev, stats.inode)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockType {
+    Shared,
+    Exclusive,
+}
+
+/// Who holds a lock. The two kinds never conflict with each other, as on
+/// Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    /// `flock`: one descriptor of a process, so two descriptors of the same
+    /// file conflict even within a process.
+    File { pid: usize, fd: usize },
+    /// `fcntl`: a process, whichever descriptor it locks through.
+    Process(usize),
+}
+
+impl Owner {
+    pub fn pid(&self) -> usize {
+        match *self {
+            Owner::File { pid, .. } | Owner::Process(pid) => pid,
+        }
+    }
+
+    fn same_kind(&self, other: &Owner) -> bool {
+        matches!((self, other), (Owner::File { .. }, Owner::File { .. }) | (Owner::Process(_), Owner::Process(_)))
+    }
+}
+
+/// An advisory lock on the bytes `start..end` of a file; `flock` locks cover
+/// `0..EOF`.
+#[derive(Debug, Clone, Copy)]
+pub struct Lock {
+    pub owner: Owner,
+    pub lock_type: LockType,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+
+    /// Whether holding `self` keeps `request` from being granted.
+    fn conflicts(&self, request: &Lock) -> bool {
+        self.owner != request.owner
+            && self.owner.same_kind(&request.owner)
+            && self.overlaps(request.start, request.end)
+            && (self.lock_type == LockType::Exclusive || request.lock_type == LockType::Exclusive)
+    }
+}
+
+/// A task parked until a lock it waits for may have become free.
+#[derive(Clone)]
+struct Waiter {
+    woken: Arc<AtomicBool>,
+    task: Option<Arc<RwLock<Task>>>,
+}
+
+impl Waiter {
+    fn wake(&self) {
+        self.woken.store(true, Ordering::SeqCst);
+        if let Some(ref task) = self.task {
+            task::unblock_task(Arc::clone(task));
+        }
+    }
+}
+
+#[derive(Default)]
+struct FileLocks {
+    locks: Vec<Lock>,
+    /// Tasks waiting for a lock, all woken when one is released.
+    waiters: Vec<Waiter>,
+}
+
+impl FileLocks {
+    fn wake(&mut self) {
+        for waiter in self.waiters.drain(..) {
+            waiter.wake();
+        }
+    }
+
+    /// Drops what `owner` holds of `start..end`, keeping the parts of its
+    /// locks outside that range.
+    fn remove(&mut self, owner: Owner, start: u64, end: u64) -> bool {
+        let mut removed = false;
+        let mut kept = Vec::with_capacity(self.locks.len());
+        for lock in self.locks.drain(..) {
+            if lock.owner != owner || !lock.overlaps(start, end) {
+                kept.push(lock);
+                continue;
+            }
+            removed = true;
+            if lock.start < start {
+                kept.push(Lock { end: start, ..lock });
+            }
+            if lock.end > end {
+                kept.push(Lock { start: end, ..lock });
+            }
+        }
+        self.locks = kept;
+        removed
+    }
+
+    /// Merges the locks of `owner` that touch and are of the same type.
+    fn coalesce(&mut self, owner: Owner) {
+        let (mut mine, others): (Vec<Lock>, Vec<Lock>) =
+            self.locks.drain(..).partition(|lock| lock.owner == owner);
+        mine.sort_by_key(|lock| lock.start);
+        self.locks = others;
+        for lock in mine {
+            match self.locks.last_mut() {
+                Some(last) if last.owner == owner && last.lock_type == lock.lock_type && lock.start <= last.end => {
+                    last.end = last.end.max(lock.end);
+                }
+                _ => self.locks.push(lock),
+            }
+        }
+    }
+}
+
+struct Table {
+    files: BTreeMap<Key, FileLocks>,
+    /// What each blocked process waits for, to find cycles, and how to wake
+    /// it.
+    waiting: BTreeMap<usize, (Key, Lock, Waiter)>,
+}
+
+static TABLE: Mutex<Table> = Mutex::new(Table {
+    files: BTreeMap::new(),
+    waiting: BTreeMap::new(),
+});
+
+impl Table {
+    fn conflict(&self, key: Key, request: &Lock) -> Option<Lock> {
+        self.files.get(&key)?.locks.iter().find(|lock| lock.conflicts(request)).copied()
+    }
+
+    /// Whether waiting for `request` would close a cycle of processes, each
+    /// waiting for a lock the next one holds, back to the requester.
+    fn would_deadlock(&self, key: Key, request: &Lock) -> bool {
+        let pid = request.owner.pid();
+        let mut pending = alloc::vec![(key, *request)];
+        let mut visited = BTreeSet::new();
+        while let Some((key, request)) = pending.pop() {
+            let file = match self.files.get(&key) {
+                Some(file) => file,
+                None => continue,
+            };
+            for holder in file.locks.iter().filter(|lock| lock.conflicts(&request)) {
+                let holder = holder.owner.pid();
+                if holder == pid {
+                    return true;
+                }
+                if visited.insert(holder) {
+                    pending.extend(self.waiting.get(&holder).map(|&(key, request, _)| (key, request)));
+                }
+            }
+        }
+        false
+    }
+
+    /// Removes the locks `release` picks, waking the waiters of each file
+    /// that lost one.
+    fn release(&mut self, mut release: impl FnMut(Key, &Lock) -> bool) {
+        for (&key, file) in self.files.iter_mut() {
+            let before = file.locks.len();
+            file.locks.retain(|lock| !release(key, lock));
+            if file.locks.len() != before {
+                file.wake();
+            }
+        }
+        self.files.retain(|_, file| !file.locks.is_empty() || !file.waiters.is_empty());
+    }
+}
+
+/// Takes a `lock_type` lock on `start..end` of the file for `owner`,
+/// replacing whatever the owner already holds of that range. A conflicting
+/// lock fails the request with `WouldBlock` unless `wait` is set, in which
+/// case the caller sleeps until it is released, or gets `Deadlock` if the
+/// holder is itself waiting on the caller. A caller whose process goes away
+/// meanwhile gets `BadDescriptor`.
+pub fn lock(key: Key, owner: Owner, lock_type: LockType, start: u64, end: u64, wait: bool) -> Result<()> {
+    if start >= end {
+        return Err(FsError::InvalidArgument);
+    }
+    let request = Lock { owner, lock_type, start, end };
+    let pid = owner.pid();
+    let current = task::current();
+    let mut waited = false;
+    loop {
+        let waiter = {
+            let mut table = TABLE.lock();
+            // `release_process` drops the entry of a process that went away
+            // while waiting, and it must not be granted a lock after that
+            if waited && !table.waiting.contains_key(&pid) {
+                return Err(FsError::BadDescriptor);
+            }
+            if table.conflict(key, &request).is_none() {
+                table.waiting.remove(&pid);
+                let file = table.files.entry(key).or_default();
+                // Giving up part of a lock, e.g. turning exclusive into shared,
+                // can let others in
+                if file.remove(owner, start, end) {
+                    file.wake();
+                }
+                file.locks.push(request);
+                file.coalesce(owner);
+                return Ok(());
+            }
+            if !wait {
+                return Err(FsError::WouldBlock);
+            }
+            if table.would_deadlock(key, &request) {
+                table.waiting.remove(&pid);
+                return Err(FsError::Deadlock);
+            }
+            let waiter = Waiter { woken: Arc::new(AtomicBool::new(false)), task: current.clone() };
+            table.waiting.insert(pid, (key, request, waiter.clone()));
+            table.files.entry(key).or_default().waiters.push(waiter.clone());
+            waiter
+        };
+
+        task::park(&waiter.woken);
+        waited = true;
+    }
+}
+
+/// Drops what `owner` holds of `start..end` of the file.
+pub fn unlock(key: Key, owner: Owner, start: u64, end: u64) {
+    let mut table = TABLE.lock();
+    if let Some(file) = table.files.get_mut(&key) {
+        if file.remove(owner, start, end) {
+            file.wake();
+        }
+        if file.locks.is_empty() && file.waiters.is_empty() {
+            table.files.remove(&key);
+        }
+    }
+}
+
+/// A lock that would keep `owner` from taking `lock_type` on `start..end`,
+/// as `F_GETLK` reports.
+pub fn test(key: Key, owner: Owner, lock_type: LockType, start: u64, end: u64) -> Option<Lock> {
+    TABLE.lock().conflict(key, &Lock { owner, lock_type, start, end })
+}
+
+/// Releases what goes away with the descriptor `fd` of process `pid`: its
+/// `flock` lock, and, as POSIX has it, every `fcntl` lock the process holds
+/// on the file, whichever descriptor took it.
+pub fn release_fd(key: Option<Key>, pid: usize, fd: usize) {
+    TABLE.lock().release(|lock_key, lock| match lock.owner {
+        Owner::File { pid: p, fd: f } => p == pid && f == fd,
+        Owner::Process(p) => p == pid && Some(lock_key) == key,
+    });
+}
+
+/// Releases every lock of a process that went away.
+pub fn release_process(pid: usize) {
+    let mut table = TABLE.lock();
+    if let Some((_, _, waiter)) = table.waiting.remove(&pid) {
+        waiter.wake();
+    }
+    table.release(|_, lock| lock.owner.pid() == pid);
+}
+
+/// Every lock held, by file.
+pub fn locks() -> Vec<(Key, Lock)> {
+    TABLE.lock().files.iter()
+        .flat_map(|(&key, file)| file.locks.iter().map(move |&lock| (key, lock)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: Key = (1, 2);
+
+    fn lock(pid: usize, lock_type: LockType, start: u64, end: u64) -> Lock {
+        Lock { owner: Owner::Process(pid), lock_type, start, end }
+    }
+
+    fn ranges(file: &FileLocks) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = file.locks.iter().map(|lock| (lock.start, lock.end)).collect();
+        ranges.sort();
+        ranges
+    }
+
+    #[test_case]
+    fn unlocking_the_middle_splits_a_lock() {
+        let mut file = FileLocks::default();
+        file.locks.push(lock(1, LockType::Exclusive, 0, 100));
+        assert!(file.remove(Owner::Process(1), 40, 60));
+        assert_eq!(ranges(&file), [(0, 40), (60, 100)]);
+        assert!(!file.remove(Owner::Process(2), 0, EOF));
+    }
+
+    #[test_case]
+    fn adjacent_locks_of_one_type_merge() {
+        let mut file = FileLocks::default();
+        file.locks.push(lock(1, LockType::Shared, 0, 10));
+        file.locks.push(lock(1, LockType::Shared, 10, 20));
+        file.locks.push(lock(1, LockType::Exclusive, 20, 30));
+        file.locks.push(lock(2, LockType::Shared, 30, 40));
+        file.coalesce(Owner::Process(1));
+        assert_eq!(ranges(&file), [(0, 20), (20, 30), (30, 40)]);
+    }
+
+    #[test_case]
+    fn upgrading_to_exclusive_conflicts_only_with_other_readers() {
+        let mine = lock(1, LockType::Shared, 0, 10);
+        let theirs = lock(2, LockType::Shared, 5, 15);
+        let upgrade = lock(1, LockType::Exclusive, 0, 10);
+        assert!(!mine.conflicts(&upgrade));
+        assert!(theirs.conflicts(&upgrade));
+        assert!(!theirs.conflicts(&lock(1, LockType::Shared, 0, 10)));
+        // `flock` and `fcntl` locks never get in each other's way
+        let flock = Lock { owner: Owner::File { pid: 2, fd: 3 }, ..upgrade };
+        assert!(!flock.conflicts(&upgrade));
+    }
+
+    #[test_case]
+    fn two_processes_waiting_on_each_other_deadlock() {
+        let other: Key = (1, 3);
+        let mut table = Table { files: BTreeMap::new(), waiting: BTreeMap::new() };
+        table.files.entry(FILE).or_default().locks.push(lock(1, LockType::Exclusive, 0, EOF));
+        table.files.entry(other).or_default().locks.push(lock(2, LockType::Exclusive, 0, EOF));
+
+        let wanted = lock(1, LockType::Exclusive, 0, EOF);
+        assert!(!table.would_deadlock(other, &wanted));
+        let waiter = Waiter { woken: Arc::new(AtomicBool::new(false)), task: None };
+        table.waiting.insert(1, (other, wanted, waiter));
+        assert!(table.would_deadlock(FILE, &lock(2, LockType::Shared, 0, 1)));
+        // A range process 1 does not hold is free of the cycle
+        table.files.get_mut(&FILE).unwrap().remove(Owner::Process(1), 0, 10);
+        assert!(!table.would_deadlock(FILE, &lock(2, LockType::Shared, 0, 10)));
+    }
+}
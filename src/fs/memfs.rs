@@ -1,9 +1,11 @@
 use alloc::string::{String, ToString};
 use alloc::collections::BTreeMap;
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
 use spin::RwLock;
-use crate::fs::{Directory, File, FileStats, FileType, FsError, Filesystem, Result};
+use crate::fs::{self, path, Directory, File, FileStats, FileType, FsError, Filesystem, Result, STAT_BLOCK_SIZE};
 
 pub struct MemFs {
     root: Arc<MemDir>,
@@ -16,29 +18,21 @@ impl MemFs {
         }
     }
 
-    fn resolve_path<'a>(&self, path: &'a str) -> Result<(Arc<MemDir>, &'a str)> {
+    /// Directory holding the last component of `path`, and that component.
+    /// The root itself comes back with an empty name.
+    fn resolve_path(&self, path: &str) -> Result<(Arc<MemDir>, String)> {
         if !path.starts_with('/') {
             return Err(FsError::InvalidPath);
         }
 
+        let path = path::normalize("/", path)?;
+        let (parent, name) = path::split_last(&path);
         let mut current_dir = Arc::clone(&self.root);
-        let path = path.trim_start_matches('/');
-        
-        if path.is_empty() {
-            return Ok((current_dir, ""));
-        }
-
-        let components: Vec<&str> = path.split('/').collect();
-        let (file_name, parents) = components.split_last().unwrap();
-        
-        for component in parents {
-            if component.is_empty() {
-                continue;
-            }
+        for component in path::components(&parent) {
             current_dir = current_dir.get_dir_as_memdir(component)?;
         }
 
-        Ok((current_dir, file_name))
+        Ok((current_dir, name.to_string()))
     }
 }
 
@@ -49,85 +43,412 @@ impl Filesystem for MemFs {
 
     fn create_file(&self, path: &str, data: Vec<u8>) -> Result<()> {
         let (dir, name) = self.resolve_path(path)?;
-        dir.create_file(name, data)
+        dir.create_file(&name, data)
     }
 
     fn create_dir(&self, path: &str) -> Result<()> {
         let (dir, name) = self.resolve_path(path)?;
-        dir.create_dir(name)
+        dir.create_dir(&name)
     }
 
     fn remove(&self, path: &str) -> Result<()> {
         let (dir, name) = self.resolve_path(path)?;
-        dir.remove(name)
+        dir.remove(&name)
     }
 
     fn get_file(&self, path: &str) -> Result<Arc<dyn File>> {
         let (dir, name) = self.resolve_path(path)?;
-        dir.get_file(name)
+        dir.get_file(&name)
     }
 
     fn get_dir(&self, path: &str) -> Result<Arc<dyn Directory>> {
         let (dir, name) = self.resolve_path(path)?;
-        dir.get_dir(name)
+        if name.is_empty() {
+            return Ok(dir as Arc<dyn Directory>);
+        }
+        dir.get_dir(&name)
+    }
+
+    fn symlink(&self, target: &str, path: &str) -> Result<()> {
+        let (dir, name) = self.resolve_path(path)?;
+        dir.insert(&name, Entry::Symlink(Arc::new(MemSymlink::new(target))))
+    }
+
+    fn link(&self, existing: &str, path: &str) -> Result<()> {
+        let (dir, name) = self.resolve_path(existing)?;
+        let (new_dir, new_name) = self.resolve_path(path)?;
+        if new_name.is_empty() {
+            return Err(FsError::InvalidPath);
+        }
+
+        // Both directories stay locked so the entry cannot go away between
+        // looking it up and adding the new name
+        if Arc::ptr_eq(&dir, &new_dir) {
+            link_entry(None, &name, &mut new_dir.entries.write(), &new_name)?;
+        } else if Arc::as_ptr(&dir) < Arc::as_ptr(&new_dir) {
+            let src = dir.entries.read();
+            link_entry(Some(&src), &name, &mut new_dir.entries.write(), &new_name)?;
+        } else {
+            let mut dst = new_dir.entries.write();
+            link_entry(Some(&dir.entries.read()), &name, &mut dst, &new_name)?;
+        }
+        new_dir.inode.modified();
+        Ok(())
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<()> {
+        if !old.starts_with('/') || !new.starts_with('/') {
+            return Err(FsError::InvalidPath);
+        }
+        let old = path::normalize("/", old)?;
+        let new = path::normalize("/", new)?;
+        let (old_dir, old_name) = self.resolve_path(&old)?;
+        let (new_dir, new_name) = self.resolve_path(&new)?;
+        if old_name.is_empty() || new_name.is_empty() {
+            return Err(FsError::Busy);
+        }
+
+        // A directory cannot become its own descendant
+        let old_parts: Vec<&str> = path::components(&old).collect();
+        let new_parts: Vec<&str> = path::components(&new).collect();
+        if new_parts.len() > old_parts.len() && new_parts.starts_with(&old_parts) {
+            return Err(FsError::InvalidPath);
+        }
+
+        if Arc::ptr_eq(&old_dir, &new_dir) {
+            let mut entries = old_dir.entries.write();
+            move_entry(&old_dir, &mut entries, &old_name, None, &new_name)?;
+            old_dir.inode.modified();
+            return Ok(());
+        }
+
+        // Lock in address order so two opposite renames cannot deadlock
+        let old_first = Arc::as_ptr(&old_dir) < Arc::as_ptr(&new_dir);
+        let (mut src, mut dst);
+        if old_first {
+            src = old_dir.entries.write();
+            dst = new_dir.entries.write();
+        } else {
+            dst = new_dir.entries.write();
+            src = old_dir.entries.write();
+        }
+        move_entry(&old_dir, &mut src, &old_name, Some(&mut dst), &new_name)?;
+        old_dir.inode.modified();
+        new_dir.inode.modified();
+        Ok(())
+    }
+
+    fn readlink(&self, path: &str) -> Result<String> {
+        let (dir, name) = self.resolve_path(path)?;
+        let entries = dir.entries.read();
+        match entries.get(&name) {
+            Some(Entry::Symlink(link)) => {
+                link.inode.accessed();
+                Ok(link.target.clone())
+            }
+            Some(_) => Err(FsError::NotASymlink),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn lstat(&self, path: &str) -> Result<FileStats> {
+        let (dir, name) = self.resolve_path(path)?;
+        if name.is_empty() {
+            return dir.stats();
+        }
+        let entry = dir.entries.read().get(&name).cloned();
+        match entry {
+            Some(Entry::File(file)) => file.stats(),
+            Some(Entry::Directory(dir)) => dir.stats(),
+            Some(Entry::Symlink(link)) => Ok(link.stats()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn set_permissions(&self, path: &str, permissions: u16) -> Result<()> {
+        let (dir, name) = self.resolve_path(path)?;
+        if name.is_empty() {
+            dir.inode.set_permissions(permissions);
+            return Ok(());
+        }
+        let entries = dir.entries.read();
+        let entry = entries.get(&name).ok_or(FsError::NotFound)?;
+        entry.inode().set_permissions(permissions);
+        Ok(())
+    }
+}
+
+/// Inode numbers are handed out from one counter for every `MemFs`; the root
+/// of the first one gets 1.
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+/// Identity, permissions and timestamps shared by every kind of entry.
+struct Inode {
+    number: u64,
+    permissions: AtomicU16,
+    atime: AtomicU64,
+    mtime: AtomicU64,
+    ctime: AtomicU64,
+}
+
+impl Inode {
+    fn new(permissions: u16) -> Self {
+        let now = fs::now();
+        Self {
+            number: NEXT_INODE.fetch_add(1, Ordering::SeqCst),
+            permissions: AtomicU16::new(permissions),
+            atime: AtomicU64::new(now),
+            mtime: AtomicU64::new(now),
+            ctime: AtomicU64::new(now),
+        }
+    }
+
+    fn accessed(&self) {
+        self.atime.store(fs::now(), Ordering::Relaxed);
+    }
+
+    /// Contents changed, which changes the metadata too.
+    fn modified(&self) {
+        let now = fs::now();
+        self.mtime.store(now, Ordering::Relaxed);
+        self.ctime.store(now, Ordering::Relaxed);
+    }
+
+    /// Only metadata such as the link count changed.
+    fn changed(&self) {
+        self.ctime.store(fs::now(), Ordering::Relaxed);
+    }
+
+    fn set_permissions(&self, permissions: u16) {
+        self.permissions.store(permissions, Ordering::Relaxed);
+        self.changed();
+    }
+
+    fn stats(&self, file_type: FileType, size: usize) -> FileStats {
+        let mut stats = FileStats::new(file_type, size, self.permissions.load(Ordering::Relaxed));
+        stats.inode = self.number;
+        stats.atime = self.atime.load(Ordering::Relaxed);
+        stats.mtime = self.mtime.load(Ordering::Relaxed);
+        stats.ctime = self.ctime.load(Ordering::Relaxed);
+        stats
+    }
+}
+
+/// Granularity of file storage. Pages that were never written are holes and
+/// read back as zeros without taking memory.
+const PAGE_SIZE: usize = 4096;
+
+type Page = Box<[u8; PAGE_SIZE]>;
+
+/// Contents of a file as a sparse set of pages.
+struct FileData {
+    pages: BTreeMap<usize, Page>,
+    len: usize,
+}
+
+impl FileData {
+    fn new() -> Self {
+        Self {
+            pages: BTreeMap::new(),
+            len: 0,
+        }
+    }
+
+    fn from_vec(data: Vec<u8>) -> Self {
+        let mut file = Self::new();
+        file.write_at(0, &data);
+        file
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        if offset >= self.len {
+            return 0;
+        }
+        let count = buf.len().min(self.len - offset);
+        let mut done = 0;
+        while done < count {
+            let pos = offset + done;
+            let (index, start) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+            let chunk = (PAGE_SIZE - start).min(count - done);
+            let dest = &mut buf[done..done + chunk];
+            match self.pages.get(&index) {
+                Some(page) => dest.copy_from_slice(&page[start..start + chunk]),
+                None => dest.fill(0),
+            }
+            done += chunk;
+        }
+        count
+    }
+
+    fn write_at(&mut self, offset: usize, data: &[u8]) {
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done;
+            let (index, start) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+            let chunk = (PAGE_SIZE - start).min(data.len() - done);
+            let page = self.pages.entry(index).or_insert_with(|| Box::new([0; PAGE_SIZE]));
+            page[start..start + chunk].copy_from_slice(&data[done..done + chunk]);
+            done += chunk;
+        }
+        self.len = self.len.max(offset + data.len());
+    }
+
+    /// Shrinking frees the pages past the end; growing only moves the end,
+    /// leaving a hole.
+    fn set_len(&mut self, len: usize) {
+        if len < self.len {
+            let first_unused = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+            self.pages.split_off(&first_unused);
+            // Bytes past the end of the last page must read as zeros again
+            // if the file grows later
+            if len % PAGE_SIZE != 0 {
+                if let Some(page) = self.pages.get_mut(&(len / PAGE_SIZE)) {
+                    page[len % PAGE_SIZE..].fill(0);
+                }
+            }
+        }
+        self.len = len;
+    }
+
+    /// The whole file, holes included. A sparse file can be far larger than
+    /// the heap, so this fails rather than aborting when it does not fit.
+    fn to_vec(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        data.try_reserve_exact(self.len).map_err(|_| FsError::OutOfMemory)?;
+        data.resize(self.len, 0);
+        self.read_at(0, &mut data);
+        Ok(data)
     }
 }
 
 pub struct MemFile {
-    data: RwLock<Vec<u8>>,
+    data: RwLock<FileData>,
+    /// Directory entries referring to this file.
+    links: AtomicUsize,
+    inode: Inode,
 }
 
 impl MemFile {
     fn new(data: Vec<u8>) -> Self {
         Self {
-            data: RwLock::new(data),
+            data: RwLock::new(FileData::from_vec(data)),
+            links: AtomicUsize::new(1),
+            inode: Inode::new(0o644),
         }
     }
+
+    pub fn links(&self) -> usize {
+        self.links.load(Ordering::SeqCst)
+    }
 }
 
 impl File for MemFile {
     fn read(&self) -> Result<Vec<u8>> {
-        Ok(self.data.read().clone())
+        self.inode.accessed();
+        self.data.read().to_vec()
     }
 
     fn write(&self, data: &[u8]) -> Result<()> {
-        *self.data.write() = data.to_vec();
+        *self.data.write() = FileData::from_vec(data.to_vec());
+        self.inode.modified();
         Ok(())
     }
 
     fn append(&self, data: &[u8]) -> Result<()> {
-        self.data.write().extend_from_slice(data);
+        let mut file = self.data.write();
+        let end = file.len;
+        file.write_at(end, data);
+        self.inode.modified();
         Ok(())
     }
 
     fn truncate(&self) -> Result<()> {
-        self.data.write().clear();
+        self.data.write().set_len(0);
+        self.inode.modified();
+        Ok(())
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inode.accessed();
+        Ok(self.data.read().read_at(offset, buf))
+    }
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize> {
+        self.data.write().write_at(offset, data);
+        self.inode.modified();
+        Ok(data.len())
+    }
+
+    fn set_len(&self, len: usize) -> Result<()> {
+        self.data.write().set_len(len);
+        self.inode.modified();
         Ok(())
     }
 
     fn stats(&self) -> Result<FileStats> {
-        Ok(FileStats {
-            file_type: FileType::File,
-            size: self.data.read().len(),
-            permissions: 0o644,
-        })
+        let data = self.data.read();
+        let mut stats = self.inode.stats(FileType::File, data.len);
+        stats.links = self.links();
+        // Holes take no pages
+        stats.blocks = (data.pages.len() * PAGE_SIZE / STAT_BLOCK_SIZE) as u64;
+        Ok(stats)
     }
 }
 
 pub struct MemDir {
     entries: RwLock<BTreeMap<String, Entry>>,
+    inode: Inode,
+}
+
+/// A symbolic link. Hard links to it share the inode.
+struct MemSymlink {
+    target: String,
+    inode: Inode,
 }
 
+impl MemSymlink {
+    fn new(target: &str) -> Self {
+        Self {
+            target: target.to_string(),
+            inode: Inode::new(0o777),
+        }
+    }
+
+    fn stats(&self) -> FileStats {
+        let mut stats = self.inode.stats(FileType::Symlink, self.target.len());
+        stats.blocks = 0;
+        stats
+    }
+}
+
+#[derive(Clone)]
 enum Entry {
+    /// One of possibly several hard links to the file.
     File(Arc<MemFile>),
     Directory(Arc<MemDir>),
+    /// A symbolic link, resolved by the VFS.
+    Symlink(Arc<MemSymlink>),
 }
 
 impl MemDir {
     pub fn new() -> Self {
         Self {
             entries: RwLock::new(BTreeMap::new()),
+            inode: Inode::new(0o755),
+        }
+    }
+
+    fn insert(&self, name: &str, entry: Entry) -> Result<()> {
+        if name.is_empty() {
+            return Err(FsError::InvalidPath);
+        }
+        let mut entries = self.entries.write();
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
         }
+        entries.insert(name.to_string(), entry);
+        self.inode.modified();
+        Ok(())
     }
 
     fn get_dir_as_memdir(&self, name: &str) -> Result<Arc<MemDir>> {
@@ -140,15 +461,102 @@ impl MemDir {
     }
 }
 
+impl Entry {
+    fn inode(&self) -> &Inode {
+        match self {
+            Entry::File(file) => &file.inode,
+            Entry::Directory(dir) => &dir.inode,
+            Entry::Symlink(link) => &link.inode,
+        }
+    }
+}
+
+/// Adds `new_name` to `dst` for entry `name` of `src`, which is `None` when
+/// both names are in `dst`. Both directories are locked by the caller.
+fn link_entry(
+    src: Option<&BTreeMap<String, Entry>>,
+    name: &str,
+    dst: &mut BTreeMap<String, Entry>,
+    new_name: &str,
+) -> Result<()> {
+    let entry = match src {
+        Some(src) => src.get(name),
+        None => dst.get(name),
+    };
+    let entry = entry.ok_or(FsError::NotFound)?;
+    if let Entry::Directory(_) = entry {
+        // Hard links to directories would make the tree a graph
+        return Err(FsError::PermissionDenied);
+    }
+    if dst.contains_key(new_name) {
+        return Err(FsError::AlreadyExists);
+    }
+    if let Entry::File(file) = entry {
+        file.links.fetch_add(1, Ordering::SeqCst);
+        file.inode.changed();
+    }
+    let entry = entry.clone();
+    dst.insert(new_name.to_string(), entry);
+    Ok(())
+}
+
+/// Moves entry `old_name` of `src` to `new_name` in `dst`, replacing what is
+/// there by the rules of POSIX `rename`. `dst` is `None` when both names are
+/// in the same directory. Both directories are locked by the caller, so no
+/// one sees the entry missing or present twice.
+fn move_entry(
+    owner: &MemDir,
+    src: &mut BTreeMap<String, Entry>,
+    old_name: &str,
+    dst: Option<&mut BTreeMap<String, Entry>>,
+    new_name: &str,
+) -> Result<()> {
+    let entry = src.get(old_name).ok_or(FsError::NotFound)?;
+    let existing = match &dst {
+        Some(dst) => dst.get(new_name),
+        None => src.get(new_name),
+    };
+
+    match (entry, existing) {
+        (_, None) => {}
+        // Two names of the same file: nothing to do
+        (Entry::File(a), Some(Entry::File(b))) if Arc::ptr_eq(a, b) => return Ok(()),
+        (Entry::Directory(a), Some(Entry::Directory(b))) if Arc::ptr_eq(a, b) => return Ok(()),
+        (Entry::Directory(_), Some(Entry::Directory(target))) => {
+            // The target may be the source's own parent, which is locked
+            if core::ptr::eq(&**target, owner) || !target.entries.read().is_empty() {
+                return Err(FsError::NotEmpty);
+            }
+        }
+        (Entry::Directory(_), Some(_)) => return Err(FsError::NotADirectory),
+        (_, Some(Entry::Directory(_))) => return Err(FsError::IsADirectory),
+        (_, Some(_)) => {}
+    }
+
+    let entry = src.remove(old_name).ok_or(FsError::NotFound)?;
+    entry.inode().changed();
+    let replaced = match dst {
+        Some(dst) => dst.insert(new_name.to_string(), entry),
+        None => src.insert(new_name.to_string(), entry),
+    };
+    if let Some(Entry::File(file)) = replaced {
+        file.links.fetch_sub(1, Ordering::SeqCst);
+        file.inode.changed();
+    }
+    Ok(())
+}
+
 impl Directory for MemDir {
     fn list(&self) -> Result<Vec<(String, FileType)>> {
         let entries = self.entries.read();
         let mut result = Vec::new();
-        
+        self.inode.accessed();
+
         for (name, entry) in entries.iter() {
             let file_type = match entry {
                 Entry::File(_) => FileType::File,
                 Entry::Directory(_) => FileType::Directory,
+                Entry::Symlink(_) => FileType::Symlink,
             };
             result.push((name.clone(), file_type));
         }
@@ -171,36 +579,91 @@ impl Directory for MemDir {
     }
 
     fn create_file(&self, name: &str, data: Vec<u8>) -> Result<()> {
-        let mut entries = self.entries.write();
-        if entries.contains_key(name) {
-            return Err(FsError::AlreadyExists);
-        }
-        entries.insert(name.to_string(), Entry::File(Arc::new(MemFile::new(data))));
-        Ok(())
+        self.insert(name, Entry::File(Arc::new(MemFile::new(data))))
     }
 
     fn create_dir(&self, name: &str) -> Result<()> {
+        self.insert(name, Entry::Directory(Arc::new(MemDir::new())))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
         let mut entries = self.entries.write();
-        if entries.contains_key(name) {
-            return Err(FsError::AlreadyExists);
+        match entries.remove(name) {
+            Some(Entry::File(file)) => {
+                file.links.fetch_sub(1, Ordering::SeqCst);
+                file.inode.changed();
+            }
+            Some(_) => {}
+            None => return Err(FsError::NotFound),
         }
-        entries.insert(name.to_string(), Entry::Directory(Arc::new(MemDir::new())));
+        self.inode.modified();
         Ok(())
     }
 
-    fn remove(&self, name: &str) -> Result<()> {
-        let mut entries = self.entries.write();
-        if entries.remove(name).is_none() {
-            return Err(FsError::NotFound);
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        if old_name.is_empty() || new_name.is_empty() || old_name.contains('/') || new_name.contains('/') {
+            return Err(FsError::InvalidPath);
         }
+        let mut entries = self.entries.write();
+        move_entry(self, &mut entries, old_name, None, new_name)?;
+        self.inode.modified();
         Ok(())
     }
 
     fn stats(&self) -> Result<FileStats> {
-        Ok(FileStats {
-            file_type: FileType::Directory,
-            size: self.entries.read().len(),
-            permissions: 0o755,
-        })
+        let entries = self.entries.read();
+        let mut stats = self.inode.stats(FileType::Directory, entries.len());
+        // `.`, the entry in the parent, and `..` in every subdirectory
+        let subdirs = entries.values().filter(|e| matches!(e, Entry::Directory(_))).count();
+        stats.links = 2 + subdirs;
+        stats.blocks = 0;
+        Ok(stats)
     }
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn sparse_writes_leave_unallocated_holes() {
+        let mut file = FileData::new();
+        file.write_at(3 * PAGE_SIZE + 10, b"tail");
+        assert_eq!(file.len, 3 * PAGE_SIZE + 14);
+        assert_eq!(file.pages.len(), 1);
+
+        let mut buf = [0xAA; 8];
+        assert_eq!(file.read_at(PAGE_SIZE, &mut buf), 8);
+        assert_eq!(buf, [0; 8]);
+        // A write across a page boundary fills in both pages
+        file.write_at(PAGE_SIZE - 2, b"abcd");
+        assert_eq!(file.pages.len(), 3);
+        let mut buf = [0; 4];
+        file.read_at(PAGE_SIZE - 2, &mut buf);
+        assert_eq!(&buf, b"abcd");
+        assert_eq!(file.read_at(file.len, &mut buf), 0);
+    }
+
+    #[test_case]
+    fn shrinking_frees_pages_and_zeroes_the_cut_tail() {
+        let mut file = FileData::from_vec(alloc::vec![1; 2 * PAGE_SIZE + 100]);
+        file.set_len(PAGE_SIZE + 10);
+        assert_eq!(file.pages.len(), 2);
+        // Growing again must not bring the old bytes back
+        file.set_len(2 * PAGE_SIZE);
+        let data = file.to_vec().unwrap();
+        assert_eq!(data.len(), 2 * PAGE_SIZE);
+        assert!(data[..PAGE_SIZE + 10].iter().all(|&b| b == 1));
+        assert!(data[PAGE_SIZE + 10..].iter().all(|&b| b == 0));
+    }
+
+    #[test_case]
+    fn growing_only_moves_the_end() {
+        let mut file = FileData::from_vec(b"data".to_vec());
+        file.set_len(10 * PAGE_SIZE);
+        assert_eq!(file.len, 10 * PAGE_SIZE);
+        assert_eq!(file.pages.len(), 1);
+        file.set_len(0);
+        assert!(file.pages.is_empty());
+        assert!(file.to_vec().unwrap().is_empty());
+    }
+}
@@ -4,12 +4,27 @@ use alloc::sync::Arc;
 use spin::RwLock;
 use crate::println;
 
+pub mod devfs;
+pub mod ext2;
+pub mod fat32;
+pub mod initramfs;
+pub mod lock;
 pub mod memfs;
+pub mod notify;
+pub mod path;
+pub mod procfs;
+pub mod vfs;
+
+use vfs::{MountInfo, Vfs};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FileType {
     File,
     Directory,
+    Symlink,
+    /// Device files, which read and write a device rather than stored data.
+    CharDevice,
+    BlockDevice,
 }
 
 #[derive(Debug)]
@@ -20,15 +35,106 @@ pub enum FsError {
     NotAFile,
     InvalidPath,
     PermissionDenied,
+    /// A mount point still in use, or an attempt to mount over one.
+    Busy,
+    NotMounted,
+    UnknownFilesystem,
+    NotASymlink,
+    /// Too many symbolic links while resolving a path (ELOOP).
+    SymlinkLoop,
+    /// The operation would span two mounted filesystems (EXDEV).
+    CrossDevice,
+    /// A directory where a file was needed (EISDIR).
+    IsADirectory,
+    /// A directory that had to be empty was not (ENOTEMPTY).
+    NotEmpty,
+    /// No open file behind a descriptor (EBADF).
+    BadDescriptor,
+    InvalidArgument,
+    /// The device under a disk filesystem failed (EIO).
+    Io,
+    /// No free blocks or directory slots left (ENOSPC).
+    NoSpace,
+    /// The kernel heap cannot hold what was asked for (ENOMEM).
+    OutOfMemory,
+    /// A non-blocking request found a conflicting lock (EWOULDBLOCK).
+    WouldBlock,
+    /// Waiting for a lock would never end, as its holder waits on the caller
+    /// (EDEADLK).
+    Deadlock,
+    /// The filesystem has no such operation, e.g. links on FAT (EOPNOTSUPP).
+    NotSupported,
 }
 
 pub type Result<T> = core::result::Result<T, FsError>;
 
+/// Unit `FileStats::blocks` is counted in, as for `st_blocks`.
+pub const STAT_BLOCK_SIZE: usize = 512;
+
 #[derive(Debug, Clone)]
 pub struct FileStats {
     pub file_type: FileType,
     pub size: usize,
     pub permissions: u16,
+    /// Inode number, unique within the filesystem.
+    pub inode: u64,
+    /// Directory entries referring to the inode.
+    pub links: usize,
+    pub uid: u32,
+    pub gid: u32,
+    /// Last access, content change and metadata change. In-memory
+    /// filesystems count seconds since boot as there is no wall clock; disk
+    /// filesystems report what is stored, in Unix time.
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    /// Storage actually allocated, in `STAT_BLOCK_SIZE` units.
+    pub blocks: u64,
+    /// Device holding the filesystem, filled in by the VFS for the mount.
+    pub dev: u64,
+    /// Device a device file stands for.
+    pub rdev: u64,
+}
+
+impl FileStats {
+    /// Stats with just the basics known; the rest is zero, one link and the
+    /// blocks needed for `size`.
+    pub fn new(file_type: FileType, size: usize, permissions: u16) -> Self {
+        Self {
+            file_type,
+            size,
+            permissions,
+            inode: 0,
+            links: 1,
+            uid: 0,
+            gid: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            blocks: ((size + STAT_BLOCK_SIZE - 1) / STAT_BLOCK_SIZE) as u64,
+            dev: 0,
+            rdev: 0,
+        }
+    }
+}
+
+/// Device number from its major and minor parts, encoded as Linux does.
+pub fn makedev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    (major & 0xFFF) << 8 | (major & !0xFFF) << 32 | (minor & 0xFF) | (minor & !0xFF) << 12
+}
+
+pub fn major(dev: u64) -> u32 {
+    ((dev >> 8) & 0xFFF | (dev >> 32) & 0xFFFF_F000) as u32
+}
+
+pub fn minor(dev: u64) -> u32 {
+    (dev & 0xFF | (dev >> 12) & 0xFFFF_FF00) as u32
+}
+
+/// Timestamp for file metadata: seconds since boot.
+pub fn now() -> u64 {
+    crate::task::timer::ticks() / crate::interrupts::apic::TIMER_HZ
 }
 
 pub trait Filesystem: Send + Sync {
@@ -38,6 +144,54 @@ pub trait Filesystem: Send + Sync {
     fn remove(&self, path: &str) -> Result<()>;
     fn get_file(&self, path: &str) -> Result<Arc<dyn File>>;
     fn get_dir(&self, path: &str) -> Result<Arc<dyn Directory>>;
+
+    /// Creates a symbolic link at `path` pointing to `target`, which is stored
+    /// as given and need not exist.
+    fn symlink(&self, _target: &str, _path: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Adds `path` as another name for the file at `existing`.
+    fn link(&self, _existing: &str, _path: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Atomically moves the file, link or directory subtree at `old` to `new`.
+    /// An existing `new` is replaced if it is of the same kind, and only if
+    /// empty when it is a directory.
+    fn rename(&self, _old: &str, _new: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Target of the symbolic link at `path`. Filesystems without symbolic
+    /// links keep the default.
+    fn readlink(&self, _path: &str) -> Result<String> {
+        Err(FsError::NotASymlink)
+    }
+
+    /// Sets the permission bits of what `path` names, a final symbolic link
+    /// itself included.
+    fn set_permissions(&self, _path: &str, _permissions: u16) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Writes anything the filesystem holds back in memory to its device.
+    /// Called on `sync` and before unmounting.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Metadata of the entry at `path`, describing a final symbolic link
+    /// itself rather than its target.
+    fn lstat(&self, path: &str) -> Result<FileStats> {
+        if let Ok(target) = self.readlink(path) {
+            return Ok(FileStats::new(FileType::Symlink, target.len(), 0o777));
+        }
+        match self.get_dir(path) {
+            Ok(dir) => dir.stats(),
+            Err(_) => self.get_file(path)?.stats(),
+        }
+    }
 }
 
 pub trait File: Send + Sync {
@@ -45,6 +199,14 @@ pub trait File: Send + Sync {
     fn write(&self, data: &[u8]) -> Result<()>;
     fn append(&self, data: &[u8]) -> Result<()>;
     fn truncate(&self) -> Result<()>;
+    /// Reads from `offset` into `buf`, returning the bytes read; 0 at or past
+    /// the end of the file.
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize>;
+    /// Writes `data` at `offset`, extending the file if needed. A gap between
+    /// the old end and `offset` reads as zeros.
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize>;
+    /// Truncates or extends the file to `len` bytes; extension adds zeros.
+    fn set_len(&self, len: usize) -> Result<()>;
     fn stats(&self) -> Result<FileStats>;
 }
 
@@ -55,25 +217,86 @@ pub trait Directory: Send + Sync {
     fn create_file(&self, name: &str, data: Vec<u8>) -> Result<()>;
     fn create_dir(&self, name: &str) -> Result<()>;
     fn remove(&self, name: &str) -> Result<()>;
+    /// Renames an entry within this directory, with the same replacement
+    /// rules as `Filesystem::rename`.
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<()>;
     fn stats(&self) -> Result<FileStats>;
 }
 
 lazy_static::lazy_static! {
+    /// Mount table, with a `MemFs` at `/`.
+    pub static ref VFS: Arc<Vfs> = Arc::new(Vfs::new(Arc::new(memfs::MemFs::new()), "memfs"));
     pub static ref ROOT_FS: Arc<RwLock<Arc<dyn Filesystem>>> = {
-        Arc::new(RwLock::new(Arc::new(memfs::MemFs::new())))
+        Arc::new(RwLock::new(Arc::clone(&*VFS) as Arc<dyn Filesystem>))
     };
 }
 
+fn new_tmpfs(_source: &str) -> Result<Arc<dyn Filesystem>> {
+    Ok(Arc::new(memfs::MemFs::new()))
+}
+
+/// Mounts a filesystem of type `fs_type` (e.g. `tmpfs`) built from `source`
+/// on the directory `target`.
+pub fn mount(source: &str, target: &str, fs_type: &str) -> Result<()> {
+    VFS.mount(source, target, fs_type)
+}
+
+pub fn umount(target: &str) -> Result<()> {
+    VFS.umount(target)
+}
+
+pub fn mounts() -> Vec<MountInfo> {
+    VFS.mounts()
+}
+
+/// Syncs every mounted filesystem, then writes the buffer cache back.
+pub fn sync() -> Result<()> {
+    let result = VFS.sync();
+    crate::block::cache::sync().map_err(|_| FsError::Io)?;
+    result
+}
+
+/// Absolute, symlink-free form of `path` taken relative to `cwd`.
+pub fn resolve_path(cwd: &str, path: &str) -> Result<String> {
+    VFS.resolve(cwd, path, true)
+}
+
+/// Metadata of what `path` refers to, following symbolic links.
+pub fn stat(cwd: &str, path: &str) -> Result<FileStats> {
+    VFS.lstat(&VFS.resolve(cwd, path, true)?)
+}
+
+/// Metadata of `path` itself, even if it is a symbolic link.
+pub fn lstat(cwd: &str, path: &str) -> Result<FileStats> {
+    VFS.lstat(&path::absolute(cwd, path))
+}
+
 pub fn init() {
     // Initialize the root filesystem
     println!("Initializing filesystem...");
     let fs = ROOT_FS.read();
-    let root = fs.root_dir();
-    
-    // Create some initial directories
-    let _ = fs.create_dir("/bin");
-    let _ = fs.create_dir("/home");
+    VFS.register_fs_type("tmpfs", new_tmpfs);
+    VFS.register_fs_type("vfat", fat32::mount);
+    VFS.register_fs_type("ext2", ext2::mount);
+    VFS.register_fs_type("devfs", devfs::mount);
+    VFS.register_fs_type("procfs", procfs::mount);
+
+    // Everything else in the tree comes from the initramfs; the kernel only
+    // needs its mount points
+    initramfs::load(&**fs);
+    let _ = fs.create_dir("/dev");
+    let _ = fs.create_dir("/proc");
+    let _ = fs.create_dir("/mnt");
     let _ = fs.create_dir("/tmp");
-    
+    if let Err(e) = mount("tmpfs", "/tmp", "tmpfs") {
+        println!("Failed to mount /tmp: {:?}", e);
+    }
+    if let Err(e) = mount("devfs", "/dev", "devfs") {
+        println!("Failed to mount /dev: {:?}", e);
+    }
+    if let Err(e) = mount("proc", "/proc", "procfs") {
+        println!("Failed to mount /proc: {:?}", e);
+    }
+
     println!("Filesystem initialized successfully!");
 } 
\ No newline at end of file
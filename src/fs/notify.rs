@@ -0,0 +1,371 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::fs::{self, path, File, FileStats, FileType, Filesystem, FsError, Result};
+
+// Event bits, as Linux numbers them
+pub const IN_MODIFY: u32 = 0x0000_0002;
+pub const IN_ATTRIB: u32 = 0x0000_0004;
+pub const IN_MOVED_FROM: u32 = 0x0000_0040;
+pub const IN_MOVED_TO: u32 = 0x0000_0080;
+pub const IN_CREATE: u32 = 0x0000_0100;
+pub const IN_DELETE: u32 = 0x0000_0200;
+pub const IN_DELETE_SELF: u32 = 0x0000_0400;
+pub const IN_MOVE_SELF: u32 = 0x0000_0800;
+pub const IN_ALL_EVENTS: u32 = IN_MODIFY | IN_ATTRIB | IN_MOVED_FROM | IN_MOVED_TO
+    | IN_CREATE | IN_DELETE | IN_DELETE_SELF | IN_MOVE_SELF;
+
+// Bits only ever reported
+pub const IN_Q_OVERFLOW: u32 = 0x0000_4000;
+/// The watch is gone, removed or because what it watched was deleted.
+pub const IN_IGNORED: u32 = 0x0000_8000;
+/// The event is about a directory.
+pub const IN_ISDIR: u32 = 0x4000_0000;
+
+// Flags for `add_watch`
+pub const IN_ONLYDIR: u32 = 0x0100_0000;
+pub const IN_DONT_FOLLOW: u32 = 0x0200_0000;
+/// Adds to the mask of an existing watch rather than replacing it.
+pub const IN_MASK_ADD: u32 = 0x2000_0000;
+/// Removes the watch after its first event.
+pub const IN_ONESHOT: u32 = 0x8000_0000;
+
+/// Events reported on a directory for what happens to its entries, but not
+/// on the entries themselves.
+const CHILD_EVENTS: u32 = IN_CREATE | IN_DELETE | IN_MOVED_FROM | IN_MOVED_TO;
+
+/// Events an instance queues before reporting an overflow and dropping the
+/// rest until it is read.
+const MAX_QUEUED: usize = 256;
+
+/// Size of `struct inotify_event` without its name.
+const EVENT_HEADER: usize = 16;
+
+/// Watches across all instances, so the VFS can skip notifying when there
+/// are none.
+static WATCHES: AtomicUsize = AtomicUsize::new(0);
+/// Pairs the two halves of a rename.
+static NEXT_COOKIE: AtomicU32 = AtomicU32::new(1);
+
+static INSTANCES: Mutex<Vec<Weak<Inotify>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Watch the event is for; -1 for an overflow.
+    pub wd: i32,
+    pub mask: u32,
+    /// Same for the `IN_MOVED_FROM` and `IN_MOVED_TO` of one rename.
+    pub cookie: u32,
+    /// Entry the event is about, for events on a watched directory.
+    pub name: String,
+}
+
+impl Event {
+    /// Bytes the event takes as a `struct inotify_event`, its name padded
+    /// with NULs to a multiple of the header size.
+    fn len(&self) -> usize {
+        EVENT_HEADER + self.name_len()
+    }
+
+    fn name_len(&self) -> usize {
+        if self.name.is_empty() {
+            0
+        } else {
+            (self.name.len() + EVENT_HEADER) / EVENT_HEADER * EVENT_HEADER
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.wd.to_ne_bytes());
+        buf.extend_from_slice(&self.mask.to_ne_bytes());
+        buf.extend_from_slice(&self.cookie.to_ne_bytes());
+        buf.extend_from_slice(&(self.name_len() as u32).to_ne_bytes());
+        let name_start = buf.len();
+        buf.extend_from_slice(self.name.as_bytes());
+        buf.resize(name_start + self.name_len(), 0);
+    }
+}
+
+struct Watch {
+    /// Absolute, resolved path being watched. Kept up to date across renames
+    /// of it or a directory above it.
+    path: String,
+    mask: u32,
+}
+
+#[derive(Default)]
+struct State {
+    watches: BTreeMap<i32, Watch>,
+    queue: VecDeque<Event>,
+    next_wd: i32,
+}
+
+impl State {
+    /// Queues an event, unless it repeats the last one still unread or the
+    /// queue is full.
+    fn push(&mut self, event: Event) {
+        if self.queue.back() == Some(&event) {
+            return;
+        }
+        if self.queue.len() >= MAX_QUEUED {
+            if self.queue.back().map_or(true, |last| last.mask != IN_Q_OVERFLOW) {
+                self.queue.push_back(Event { wd: -1, mask: IN_Q_OVERFLOW, cookie: 0, name: String::new() });
+            }
+            return;
+        }
+        self.queue.push_back(event);
+    }
+
+    /// Drops a watch, telling the reader with `IN_IGNORED`.
+    fn ignore(&mut self, wd: i32) {
+        if self.watches.remove(&wd).is_some() {
+            WATCHES.fetch_sub(1, Ordering::SeqCst);
+            self.push(Event { wd, mask: IN_IGNORED, cookie: 0, name: String::new() });
+        }
+    }
+
+    /// Reports `mask` to the watch `wd` if it asked for it.
+    fn report(&mut self, wd: i32, mask: u32, cookie: u32, name: &str) {
+        let watch_mask = match self.watches.get(&wd) {
+            Some(watch) => watch.mask,
+            None => return,
+        };
+        if watch_mask & mask & IN_ALL_EVENTS == 0 {
+            return;
+        }
+        self.push(Event { wd, mask, cookie, name: name.to_string() });
+        if watch_mask & IN_ONESHOT != 0 {
+            self.ignore(wd);
+        }
+    }
+
+    /// Watches on `path` itself, and on its directory; the root has none.
+    fn watchers(&self, path: &str) -> (Vec<i32>, Vec<i32>) {
+        let on = |target: &str| -> Vec<i32> {
+            self.watches.iter().filter(|(_, watch)| watch.path == target).map(|(&wd, _)| wd).collect()
+        };
+        match path::split_last(path) {
+            (_, "") => (on(path), Vec::new()),
+            (parent, _) => (on(path), on(&parent)),
+        }
+    }
+
+    /// Watches on `path` and on everything below it.
+    fn under(&self, path: &str) -> Vec<i32> {
+        self.watches.iter()
+            .filter(|(_, watch)| {
+                watch.path == path || watch.path.strip_prefix(path).map_or(false, |rest| rest.starts_with('/'))
+            })
+            .map(|(&wd, _)| wd)
+            .collect()
+    }
+}
+
+/// An inotify instance: watches on paths of the VFS, and the events they
+/// have seen, read as `struct inotify_event` records. Reads take whole
+/// events and never block; an empty queue reads as 0 bytes.
+pub struct Inotify {
+    state: Mutex<State>,
+}
+
+impl Inotify {
+    pub fn new() -> Arc<Self> {
+        let instance = Arc::new(Self { state: Mutex::new(State { next_wd: 1, ..State::default() }) });
+        let mut instances = INSTANCES.lock();
+        instances.retain(|instance| instance.strong_count() > 0);
+        instances.push(Arc::downgrade(&instance));
+        instance
+    }
+
+    /// Watches what `path` names for the events in `mask`, returning the
+    /// watch descriptor. Watching a path already watched returns its
+    /// descriptor and replaces its mask, or adds to it with `IN_MASK_ADD`.
+    pub fn add_watch(&self, path: &str, mask: u32) -> Result<i32> {
+        if mask & IN_ALL_EVENTS == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let path = fs::VFS.resolve("/", path, mask & IN_DONT_FOLLOW == 0)?;
+        let stats = fs::VFS.lstat(&path)?;
+        if mask & IN_ONLYDIR != 0 && stats.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut state = self.state.lock();
+        let existing = state.watches.iter_mut().find(|(_, watch)| watch.path == path);
+        if let Some((&wd, watch)) = existing {
+            watch.mask = if mask & IN_MASK_ADD != 0 { watch.mask | mask } else { mask } & !IN_MASK_ADD;
+            return Ok(wd);
+        }
+        let wd = state.next_wd;
+        state.next_wd += 1;
+        state.watches.insert(wd, Watch { path, mask: mask & !IN_MASK_ADD });
+        WATCHES.fetch_add(1, Ordering::SeqCst);
+        Ok(wd)
+    }
+
+    pub fn rm_watch(&self, wd: i32) -> Result<()> {
+        let mut state = self.state.lock();
+        if !state.watches.contains_key(&wd) {
+            return Err(FsError::InvalidArgument);
+        }
+        state.ignore(wd);
+        Ok(())
+    }
+
+    /// Takes the queued events, oldest first.
+    pub fn read_events(&self) -> Vec<Event> {
+        self.state.lock().queue.drain(..).collect()
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        WATCHES.fetch_sub(self.state.lock().watches.len(), Ordering::SeqCst);
+    }
+}
+
+impl File for Inotify {
+    fn read(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for event in self.read_events() {
+            event.encode(&mut data);
+        }
+        Ok(data)
+    }
+
+    fn write(&self, _data: &[u8]) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn append(&self, _data: &[u8]) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn truncate(&self) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    /// Takes as many whole events as fit in `buf`. A buffer too small for
+    /// the next event is an error, as on Linux.
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut state = self.state.lock();
+        let mut data = Vec::new();
+        while let Some(event) = state.queue.front() {
+            if data.len() + event.len() > buf.len() {
+                break;
+            }
+            event.encode(&mut data);
+            state.queue.pop_front();
+        }
+        if data.is_empty() && !state.queue.is_empty() {
+            return Err(FsError::InvalidArgument);
+        }
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    fn write_at(&self, _offset: usize, _data: &[u8]) -> Result<usize> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn set_len(&self, _len: usize) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn stats(&self) -> Result<FileStats> {
+        let mut stats = FileStats::new(FileType::File, 0, 0o600);
+        stats.blocks = 0;
+        Ok(stats)
+    }
+}
+
+/// Runs `f` on the state of every live instance.
+fn each(mut f: impl FnMut(&mut State)) {
+    if WATCHES.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    for instance in INSTANCES.lock().iter().filter_map(Weak::upgrade) {
+        f(&mut instance.state.lock());
+    }
+}
+
+fn isdir(is_dir: bool) -> u32 {
+    if is_dir { IN_ISDIR } else { 0 }
+}
+
+/// Reports `mask` to watches on `path` and on its directory; events only
+/// meant for the directory skip `path`'s own watches.
+fn notify(path: &str, mask: u32, cookie: u32) {
+    let (_, name) = path::split_last(path);
+    each(|state| {
+        let (own, parent) = state.watchers(path);
+        if mask & CHILD_EVENTS == 0 {
+            for wd in own {
+                state.report(wd, mask, cookie, "");
+            }
+        }
+        for wd in parent {
+            state.report(wd, mask, cookie, name);
+        }
+    });
+}
+
+/// Whether any watch exists, so callers can skip work only notifying needs.
+pub fn active() -> bool {
+    WATCHES.load(Ordering::SeqCst) > 0
+}
+
+pub fn created(path: &str, is_dir: bool) {
+    notify(path, IN_CREATE | isdir(is_dir), 0);
+}
+
+pub fn modified(path: &str) {
+    notify(path, IN_MODIFY, 0);
+}
+
+pub fn attrib(path: &str, is_dir: bool) {
+    notify(path, IN_ATTRIB | isdir(is_dir), 0);
+}
+
+/// `path` was removed: its directory sees `IN_DELETE`, its own watches
+/// `IN_DELETE_SELF` and then go away.
+pub fn removed(path: &str, is_dir: bool) {
+    notify(path, IN_DELETE | isdir(is_dir), 0);
+    each(|state| {
+        for wd in state.watchers(path).0 {
+            state.report(wd, IN_DELETE_SELF, 0, "");
+            state.ignore(wd);
+        }
+    });
+}
+
+/// `old` was renamed to `new`, replacing whatever `new` was. Watches on the
+/// moved entry and below it follow it to its new path.
+pub fn renamed(old: &str, new: &str, is_dir: bool) {
+    if !active() {
+        return;
+    }
+    let cookie = NEXT_COOKIE.fetch_add(1, Ordering::SeqCst);
+    notify(old, IN_MOVED_FROM | isdir(is_dir), cookie);
+    notify(new, IN_MOVED_TO | isdir(is_dir), cookie);
+    each(|state| {
+        // Whatever was at `new` is gone, with anything below it
+        for wd in state.under(new) {
+            state.report(wd, IN_DELETE_SELF, 0, "");
+            state.ignore(wd);
+        }
+        for wd in state.watchers(old).0 {
+            state.report(wd, IN_MOVE_SELF, 0, "");
+        }
+        for watch in state.watches.values_mut() {
+            if watch.path == old {
+                watch.path = new.to_string();
+            } else if let Some(rest) = watch.path.strip_prefix(old).filter(|rest| rest.starts_with('/')) {
+                watch.path = alloc::format!("{}{}", new, rest);
+            }
+        }
+    });
+}
@@ -0,0 +1,168 @@
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use crate::fs::{FsError, Result};
+
+/// Symbolic links followed while resolving one path before giving up with
+/// `SymlinkLoop` (ELOOP), as on Linux.
+pub const MAX_SYMLINKS: usize = 40;
+
+/// Components of `path`, without empty ones from repeated or trailing slashes.
+pub fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty())
+}
+
+/// Builds an absolute path from already resolved components.
+pub fn join<S: AsRef<str>>(components: &[S]) -> String {
+    let mut path = String::new();
+    for component in components {
+        path.push('/');
+        path.push_str(component.as_ref());
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+/// Splits a resolved path into its parent directory and final name. The root
+/// has no name.
+pub fn split_last(path: &str) -> (String, &str) {
+    let parts: Vec<&str> = components(path).collect();
+    match parts.split_last() {
+        Some((name, parents)) => (join(parents), name),
+        None => ("/".to_string(), ""),
+    }
+}
+
+/// `path` made absolute by prefixing `cwd` if needed. Nothing is folded, so
+/// `..` after a symbolic link is still resolved against the link's target
+/// when the result is walked.
+pub fn absolute(cwd: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        let mut absolute = cwd.trim_end_matches('/').to_string();
+        absolute.push('/');
+        absolute.push_str(path);
+        absolute
+    }
+}
+
+/// Makes `path` absolute relative to `cwd` and folds `.`, `..` and repeated
+/// slashes, without looking at the filesystem. `..` at the root stays there.
+/// Fails with `InvalidPath` if `cwd` is needed and is not absolute.
+pub fn normalize(cwd: &str, path: &str) -> Result<String> {
+    let path = if path.is_empty() { "." } else { path };
+    walk(cwd, path, false, |_| Err(FsError::NotASymlink))
+}
+
+/// Resolves `path` against `cwd` one component at a time. `readlink` is asked
+/// about every component that is reached; when it returns a target the walk
+/// continues from there, relative targets being taken relative to the
+/// directory containing the link. The final component is only followed if
+/// `follow_last` is set, so operations on links themselves can see them.
+/// `readlink` answers `NotASymlink` for anything else that exists; any other
+/// error, such as a missing directory, ends the walk.
+pub fn walk(
+    cwd: &str,
+    path: &str,
+    follow_last: bool,
+    mut readlink: impl FnMut(&str) -> Result<String>,
+) -> Result<String> {
+    if path.is_empty() {
+        return Err(FsError::NotFound);
+    }
+
+    let mut resolved: Vec<String> = Vec::new();
+    let mut pending: VecDeque<String> = VecDeque::new();
+    if !path.starts_with('/') {
+        if !cwd.starts_with('/') {
+            return Err(FsError::InvalidPath);
+        }
+        pending.extend(components(cwd).map(|c| c.to_string()));
+    }
+    pending.extend(components(path).map(|c| c.to_string()));
+
+    let mut links = 0;
+    while let Some(component) = pending.pop_front() {
+        match component.as_str() {
+            "." => continue,
+            ".." => {
+                resolved.pop();
+                continue;
+            }
+            _ => {}
+        }
+
+        resolved.push(component);
+        if pending.is_empty() && !follow_last {
+            break;
+        }
+        let target = match readlink(&join(&resolved)) {
+            Ok(target) => target,
+            Err(FsError::NotASymlink) => continue,
+            Err(e) => return Err(e),
+        };
+
+        links += 1;
+        if links > MAX_SYMLINKS {
+            return Err(FsError::SymlinkLoop);
+        }
+        resolved.pop();
+        if target.starts_with('/') {
+            resolved.clear();
+        }
+        for component in components(&target).collect::<Vec<_>>().into_iter().rev() {
+            pending.push_front(component.to_string());
+        }
+    }
+
+    Ok(join(&resolved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `readlink` for a tree with `/lib -> usr/lib`, `/loop -> loop` and
+    /// nothing else that is a link.
+    fn links(path: &str) -> Result<String> {
+        match path {
+            "/lib" => Ok("usr/lib".to_string()),
+            "/loop" => Ok("loop".to_string()),
+            _ => Err(FsError::NotASymlink),
+        }
+    }
+
+    #[test_case]
+    fn normalize_stops_dot_dot_at_the_root() {
+        assert_eq!(normalize("/", "..").unwrap(), "/");
+        assert_eq!(normalize("/home", "../../../etc").unwrap(), "/etc");
+        assert_eq!(normalize("/a/b", "../c/./d").unwrap(), "/a/c/d");
+    }
+
+    #[test_case]
+    fn normalize_folds_repeated_and_trailing_slashes() {
+        assert_eq!(normalize("/", "//usr///bin//").unwrap(), "/usr/bin");
+        assert_eq!(normalize("/home/", "user/").unwrap(), "/home/user");
+        assert_eq!(normalize("/tmp", "").unwrap(), "/tmp");
+        assert!(matches!(normalize("tmp", "x"), Err(FsError::InvalidPath)));
+    }
+
+    #[test_case]
+    fn walk_follows_links_relative_to_their_directory() {
+        assert_eq!(walk("/", "/lib/libc.so", false, links).unwrap(), "/usr/lib/libc.so");
+        // `..` after a link leaves the link's target, not the link
+        assert_eq!(walk("/", "/lib/../bin", false, links).unwrap(), "/usr/bin");
+        assert_eq!(walk("/", "/../../lib/", true, links).unwrap(), "/usr/lib");
+    }
+
+    #[test_case]
+    fn walk_leaves_a_final_link_unless_asked() {
+        assert_eq!(walk("/", "/lib", false, links).unwrap(), "/lib");
+        assert_eq!(walk("/", "lib", true, links).unwrap(), "/usr/lib");
+        assert!(matches!(walk("/", "/loop", true, links), Err(FsError::SymlinkLoop)));
+        assert!(matches!(walk("/", "", true, links), Err(FsError::NotFound)));
+    }
+}
@@ -0,0 +1,489 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use spin::RwLock;
+use crate::block::cache;
+use crate::fs::{self, lock, path, Directory, File, FileStats, FileType, FsError, Filesystem, Result};
+use crate::interrupts::{self, apic::TIMER_HZ, irq::{self, IrqCounter}};
+use crate::network::{arp, socket::{SocketType, SOCKETS}, udp};
+use crate::process::{Process, PROCESS_MANAGER};
+use crate::{memory, smp, task};
+
+/// A file whose contents are generated each time it is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Info {
+    Meminfo,
+    Interrupts,
+    Uptime,
+    Sched,
+    Locks,
+    Arp,
+    Tcp,
+    Udp,
+    Status(usize),
+    Maps(usize),
+}
+
+/// Files directly in the root.
+const ROOT_FILES: [(&str, Info); 5] = [
+    ("meminfo", Info::Meminfo),
+    ("interrupts", Info::Interrupts),
+    ("uptime", Info::Uptime),
+    ("sched", Info::Sched),
+    ("locks", Info::Locks),
+];
+
+/// Files in `net`.
+const NET_FILES: [(&str, Info); 3] = [
+    ("arp", Info::Arp),
+    ("tcp", Info::Tcp),
+    ("udp", Info::Udp),
+];
+
+/// A process file's contents, given the pid.
+type ProcessInfo = fn(usize) -> Info;
+
+/// Files in each process directory, besides `fd`.
+const PROCESS_FILES: [(&str, ProcessInfo); 2] = [
+    ("status", Info::Status),
+    ("maps", Info::Maps),
+];
+
+/// What a path in a procfs names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Node {
+    Root,
+    Net,
+    Process(usize),
+    /// The `fd` directory of a process.
+    Fds(usize),
+    File(Info),
+    /// A link to the file a process has open as the given descriptor.
+    Fd(usize, usize),
+    /// A link to the directory of the running process.
+    SelfLink,
+}
+
+fn process(pid: usize) -> Result<Arc<RwLock<Process>>> {
+    PROCESS_MANAGER.read().get_process(pid).ok_or(FsError::NotFound)
+}
+
+fn named<T: Copy>(table: &[(&str, T)], name: &str) -> Result<T> {
+    table.iter().find(|(n, _)| *n == name).map(|(_, value)| *value).ok_or(FsError::NotFound)
+}
+
+/// Looks up `path`, relative to the root of the procfs.
+fn node(path: &str) -> Result<Node> {
+    let components: Vec<&str> = path::components(path).collect();
+    match components.as_slice() {
+        [] => Ok(Node::Root),
+        ["self"] => match PROCESS_MANAGER.read().current_process() {
+            Some(_) => Ok(Node::SelfLink),
+            None => Err(FsError::NotFound),
+        },
+        ["net"] => Ok(Node::Net),
+        ["net", name] => named(&NET_FILES, name).map(Node::File),
+        [name] => match name.parse::<usize>() {
+            Ok(pid) => process(pid).map(|_| Node::Process(pid)),
+            Err(_) => named(&ROOT_FILES, name).map(Node::File),
+        },
+        [pid, rest @ ..] => {
+            let pid = pid.parse::<usize>().map_err(|_| FsError::NotFound)?;
+            let process = process(pid)?;
+            match rest {
+                ["fd"] => Ok(Node::Fds(pid)),
+                ["fd", fd] => {
+                    let fd = fd.parse::<usize>().map_err(|_| FsError::NotFound)?;
+                    process.read().files().get(fd).map_err(|_| FsError::NotFound)?;
+                    Ok(Node::Fd(pid, fd))
+                }
+                [name] => named(&PROCESS_FILES, name).map(|info| Node::File(info(pid))),
+                _ => Err(FsError::NotFound),
+            }
+        }
+    }
+}
+
+/// Inode numbers are made up from what a node names, so they stay the same
+/// between lookups.
+fn inode(node: Node) -> u64 {
+    let index = |table: &[(&str, Info)], info| {
+        table.iter().position(|(_, i)| *i == info).unwrap_or(0) as u64
+    };
+    match node {
+        Node::Root => 1,
+        Node::SelfLink => 2,
+        Node::Net => 3,
+        Node::File(info @ (Info::Arp | Info::Tcp | Info::Udp)) => 0x10 + index(&NET_FILES, info),
+        Node::File(Info::Status(pid)) => ((pid as u64) << 16) | 1,
+        Node::File(Info::Maps(pid)) => ((pid as u64) << 16) | 2,
+        Node::File(info) => 0x20 + index(&ROOT_FILES, info),
+        Node::Process(pid) => (pid as u64) << 16,
+        Node::Fds(pid) => ((pid as u64) << 16) | 3,
+        Node::Fd(pid, fd) => ((pid as u64) << 16) | (0x100 + fd as u64),
+    }
+}
+
+fn meminfo() -> String {
+    let mut text = String::new();
+    let kb = |bytes: usize| bytes / 1024;
+    if let Some((total, used)) = memory::frame_stats() {
+        let _ = writeln!(text, "MemTotal:     {:>10} kB", kb(total * 4096));
+        let _ = writeln!(text, "MemFree:      {:>10} kB", kb((total - used) * 4096));
+    }
+    let (heap_used, heap_size) = memory::heap::usage();
+    let _ = writeln!(text, "HeapTotal:    {:>10} kB", kb(heap_size));
+    let _ = writeln!(text, "HeapFree:     {:>10} kB", kb(heap_size - heap_used));
+    let buffers = cache::stats();
+    let _ = writeln!(text, "Buffers:      {:>10} kB", kb(buffers.cached * cache::BLOCK_SIZE));
+    let _ = writeln!(text, "Dirty:        {:>10} kB", kb(buffers.dirty * cache::BLOCK_SIZE));
+    text
+}
+
+/// Per-CPU counts for each line, laid out as the `interrupts` command does.
+fn interrupts() -> String {
+    let cpus = smp::online_cpus();
+    let counts = |counter: &IrqCounter| {
+        let mut line = String::new();
+        for cpu in 0..cpus {
+            let _ = write!(line, " {:>10}", counter.get(cpu));
+        }
+        line
+    };
+
+    let mut text = String::from("    ");
+    for cpu in 0..cpus {
+        let _ = write!(text, " {:>10}", format!("CPU{}", cpu));
+    }
+    text.push('\n');
+    let controller = if interrupts::apic_enabled() { "IO-APIC" } else { "XT-PIC" };
+    for stats in irq::irq_stats() {
+        let _ = writeln!(text, "{:>3}:{}  {:<8} {}", stats.line, counts(stats.counts),
+            controller, stats.handlers.join(", "));
+    }
+    let _ = writeln!(text, "LOC:{}  Timer interrupts", counts(&irq::TIMER));
+    let _ = writeln!(text, "RES:{}  Rescheduling interrupts", counts(&irq::RESCHEDULE));
+    let _ = writeln!(text, "SPU:{}  Spurious interrupts", counts(&irq::SPURIOUS));
+    let _ = writeln!(text, "ERR:{}  Unhandled interrupts", counts(&irq::UNHANDLED));
+    text
+}
+
+/// Seconds since boot, to the hundredth.
+fn uptime() -> String {
+    let ticks = task::timer::ticks();
+    format!("{}.{:02}\n", ticks / TIMER_HZ, ticks % TIMER_HZ * 100 / TIMER_HZ)
+}
+
+fn sched() -> String {
+    let mut text = format!("policy: {}\n\n", task::policy_name());
+    let _ = writeln!(text, "{:>4} {:>8} {:>6}", "CPU", "TASK", "READY");
+    for stats in task::cpu_stats() {
+        let current = stats.current.map_or("idle".to_string(), |id| id.to_string());
+        let _ = writeln!(text, "{:>4} {:>8} {:>6}", stats.cpu, current, stats.ready);
+    }
+    let _ = writeln!(text, "\n{:>4} {:>8} {:>8} {:>14} {:>14} {:>8}",
+        "ID", "PRIO", "BASE", "TOTAL WAIT", "MAX WAIT", "SWITCHES");
+    for stats in task::wait_stats() {
+        let _ = writeln!(text, "{:>4} {:>8} {:>8} {:>14} {:>14} {:>8}",
+            stats.id, stats.priority, stats.base_priority,
+            stats.total_wait, stats.max_wait, stats.context_switches);
+    }
+    text
+}
+
+/// Advisory locks in the layout of Linux's `/proc/locks`.
+fn locks() -> String {
+    let mut text = String::new();
+    for (i, ((dev, inode), held)) in lock::locks().into_iter().enumerate() {
+        let kind = match held.owner {
+            lock::Owner::File { .. } => "FLOCK",
+            lock::Owner::Process(_) => "POSIX",
+        };
+        let mode = match held.lock_type {
+            lock::LockType::Shared => "READ",
+            lock::LockType::Exclusive => "WRITE",
+        };
+        let end = match held.end {
+            lock::EOF => "EOF".to_string(),
+            end => (end - 1).to_string(),
+        };
+        let _ = writeln!(text, "{}: {:<6} ADVISORY  {:<5} {} {:02x}:{:02x}:{} {} {}",
+            i + 1, kind, mode, held.owner.pid(), fs::major(dev), fs::minor(dev), inode,
+            held.start, end);
+    }
+    text
+}
+
+fn arp() -> String {
+    let mut text = format!("{:<16} {:<18} {}\n", "IP address", "HW address", "Device");
+    for (ip, mac) in arp::entries() {
+        let _ = writeln!(text, "{:<16} {:<18} eth0", ip.to_string(), mac.to_string());
+    }
+    text
+}
+
+/// Sockets of one type, with the peer and state.
+fn sockets(socket_type: SocketType) -> String {
+    let mut text = format!("{:>4} {:<22} {:<22} {}\n", "ID", "Local Address", "Foreign Address", "State");
+    for (id, socket) in SOCKETS.lock().iter() {
+        let socket = socket.lock();
+        if socket.socket_type() != socket_type {
+            continue;
+        }
+        let local = format!("{}:{}", socket.local_addr(), socket.local_port());
+        let remote = socket.remote()
+            .map_or("*:*".to_string(), |(addr, port)| format!("{}:{}", addr, port));
+        let state = match socket.tcp_state() {
+            Some(state) => format!("{:?}", state),
+            None => format!("{:?}", socket.state()),
+        };
+        let _ = writeln!(text, "{:>4} {:<22} {:<22} {}", id, local, remote, state);
+    }
+    text
+}
+
+fn udp() -> String {
+    let mut text = sockets(SocketType::Dgram);
+    // Ports the kernel's own services (DHCP, DNS) listen on
+    for port in udp::bound_ports() {
+        let _ = writeln!(text, "   - {:<22} *:*                    Bound", format!("*:{}", port));
+    }
+    text
+}
+
+fn status(pid: usize) -> Result<String> {
+    let process = process(pid)?;
+    let process = process.read();
+    let mut text = String::new();
+    let _ = writeln!(text, "Name:\t{}", process.name());
+    let _ = writeln!(text, "Pid:\t{}", process.id());
+    let _ = writeln!(text, "State:\t{:?}", process.state());
+    let _ = writeln!(text, "Cwd:\t{}", process.cwd());
+    let _ = writeln!(text, "FDSize:\t{}", process.files().iter().count());
+    let task = process.task().read();
+    let _ = writeln!(text, "Task:\t{}", task.id());
+    let _ = writeln!(text, "Priority:\t{:?} (base {:?})", task.priority(), task.base_priority());
+    let _ = writeln!(text, "Switches:\t{}", task.get_stats().context_switches());
+    let _ = writeln!(text, "Runtime:\t{}", task.get_stats().total_runtime());
+    Ok(text)
+}
+
+/// Address ranges in the format of Linux's `maps`, without offsets or
+/// device numbers as nothing is file-backed.
+fn maps(pid: usize) -> Result<String> {
+    let process = process(pid)?;
+    let mut text = String::new();
+    for region in process.read().memory_space().regions() {
+        let start = region.start.as_u64();
+        let _ = writeln!(text, "{:012x}-{:012x} r{}{}p {}",
+            start, start + region.size as u64,
+            if region.writable { 'w' } else { '-' },
+            if region.executable { 'x' } else { '-' },
+            region.name);
+    }
+    Ok(text)
+}
+
+fn generate(info: Info) -> Result<String> {
+    Ok(match info {
+        Info::Meminfo => meminfo(),
+        Info::Interrupts => interrupts(),
+        Info::Uptime => uptime(),
+        Info::Sched => sched(),
+        Info::Locks => locks(),
+        Info::Arp => arp(),
+        Info::Tcp => sockets(SocketType::Stream),
+        Info::Udp => udp(),
+        Info::Status(pid) => return status(pid),
+        Info::Maps(pid) => return maps(pid),
+    })
+}
+
+/// A generated file. Each read produces the contents afresh, so reads at
+/// successive offsets may see different snapshots.
+struct ProcFile {
+    info: Info,
+}
+
+impl File for ProcFile {
+    fn read(&self) -> Result<Vec<u8>> {
+        Ok(generate(self.info)?.into_bytes())
+    }
+
+    fn write(&self, _data: &[u8]) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn append(&self, _data: &[u8]) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn truncate(&self) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let data = generate(self.info)?;
+        let data = data.as_bytes().get(offset..).unwrap_or(&[]);
+        let count = buf.len().min(data.len());
+        buf[..count].copy_from_slice(&data[..count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, _offset: usize, _data: &[u8]) -> Result<usize> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn set_len(&self, _len: usize) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    /// Sizes are 0 as on Linux; the contents are only known once read.
+    fn stats(&self) -> Result<FileStats> {
+        let mut stats = FileStats::new(FileType::File, 0, 0o444);
+        stats.inode = inode(Node::File(self.info));
+        stats.blocks = 0;
+        Ok(stats)
+    }
+}
+
+/// A directory of a procfs, by its path from the root.
+struct ProcDir {
+    path: String,
+    node: Node,
+}
+
+impl ProcDir {
+    fn child(&self, name: &str) -> String {
+        format!("{}/{}", self.path, name)
+    }
+}
+
+impl Directory for ProcDir {
+    fn list(&self) -> Result<Vec<(String, FileType)>> {
+        let files = |table: &[(&str, Info)]| -> Vec<(String, FileType)> {
+            table.iter().map(|(name, _)| (name.to_string(), FileType::File)).collect()
+        };
+        Ok(match self.node {
+            Node::Root => {
+                let mut entries = files(&ROOT_FILES);
+                entries.push(("net".to_string(), FileType::Directory));
+                if PROCESS_MANAGER.read().current_process().is_some() {
+                    entries.push(("self".to_string(), FileType::Symlink));
+                }
+                for process in PROCESS_MANAGER.read().processes() {
+                    entries.push((process.read().id().to_string(), FileType::Directory));
+                }
+                entries
+            }
+            Node::Net => files(&NET_FILES),
+            Node::Process(_) => {
+                let mut entries: Vec<(String, FileType)> = PROCESS_FILES.iter()
+                    .map(|(name, _)| (name.to_string(), FileType::File))
+                    .collect();
+                entries.push(("fd".to_string(), FileType::Directory));
+                entries
+            }
+            Node::Fds(pid) => process(pid)?.read().files().iter()
+                .map(|(fd, _)| (fd.to_string(), FileType::Symlink))
+                .collect(),
+            _ => return Err(FsError::NotADirectory),
+        })
+    }
+
+    fn get_file(&self, name: &str) -> Result<Arc<dyn File>> {
+        ProcFs.get_file(&self.child(name))
+    }
+
+    fn get_dir(&self, name: &str) -> Result<Arc<dyn Directory>> {
+        ProcFs.get_dir(&self.child(name))
+    }
+
+    fn create_file(&self, name: &str, _data: Vec<u8>) -> Result<()> {
+        match node(&self.child(name)) {
+            Ok(_) => Err(FsError::AlreadyExists),
+            Err(_) => Err(FsError::PermissionDenied),
+        }
+    }
+
+    fn create_dir(&self, name: &str) -> Result<()> {
+        self.create_file(name, Vec::new())
+    }
+
+    fn remove(&self, _name: &str) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn rename(&self, _old_name: &str, _new_name: &str) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn stats(&self) -> Result<FileStats> {
+        let mut stats = FileStats::new(FileType::Directory, 0, 0o555);
+        stats.inode = inode(self.node);
+        stats.links = 2;
+        stats.blocks = 0;
+        Ok(stats)
+    }
+}
+
+/// Kernel state as files, generated when read, mounted on `/proc`: one
+/// directory per process with its `status`, `maps` and open descriptors
+/// under `fd`, and system-wide `meminfo`, `interrupts`, `uptime`, `sched`,
+/// `locks` and `net/{arp,tcp,udp}`. Nothing can be written.
+pub struct ProcFs;
+
+/// `FsConstructor` for `procfs`; the source is ignored.
+pub fn mount(_source: &str) -> Result<Arc<dyn Filesystem>> {
+    Ok(Arc::new(ProcFs))
+}
+
+impl Filesystem for ProcFs {
+    fn root_dir(&self) -> Arc<dyn Directory> {
+        Arc::new(ProcDir { path: String::new(), node: Node::Root })
+    }
+
+    fn create_file(&self, path: &str, data: Vec<u8>) -> Result<()> {
+        let (parent, name) = path::split_last(path);
+        self.get_dir(&parent)?.create_file(name, data)
+    }
+
+    fn create_dir(&self, path: &str) -> Result<()> {
+        self.create_file(path, Vec::new())
+    }
+
+    fn remove(&self, _path: &str) -> Result<()> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn get_file(&self, path: &str) -> Result<Arc<dyn File>> {
+        match node(path)? {
+            Node::File(info) => Ok(Arc::new(ProcFile { info })),
+            // Links are followed by the VFS before it gets here
+            Node::Fd(..) | Node::SelfLink => Err(FsError::NotFound),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn get_dir(&self, path: &str) -> Result<Arc<dyn Directory>> {
+        match node(path)? {
+            node @ (Node::Root | Node::Net | Node::Process(_) | Node::Fds(_)) => {
+                let path = path::components(path).fold(String::new(), |path, c| path + "/" + c);
+                Ok(Arc::new(ProcDir { path, node }))
+            }
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn readlink(&self, path: &str) -> Result<String> {
+        match node(path) {
+            Ok(Node::SelfLink) => PROCESS_MANAGER.read().current_process()
+                .map(|process| process.read().id().to_string())
+                .ok_or(FsError::NotFound),
+            Ok(Node::Fd(pid, fd)) => Ok(process(pid)?.read().files().get(fd)?.path().to_string()),
+            _ => Err(FsError::NotASymlink),
+        }
+    }
+}
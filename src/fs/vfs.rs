@@ -0,0 +1,510 @@
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::RwLock;
+use crate::fs::{self, notify, path, Directory, File, FileStats, FileType, FsError, Filesystem, Result};
+
+/// Builds a filesystem instance for `mount -t <type> <source>`.
+pub type FsConstructor = fn(source: &str) -> Result<Arc<dyn Filesystem>>;
+
+struct FsType {
+    name: &'static str,
+    construct: FsConstructor,
+}
+
+/// A filesystem attached to the tree. Handles handed out from it keep a
+/// reference to the mount, which is what makes it busy.
+struct Mount {
+    path: String,
+    source: String,
+    fs_type: &'static str,
+    fs: Arc<dyn Filesystem>,
+    /// Device number reported for everything on the filesystem.
+    dev: u64,
+}
+
+impl Mount {
+    /// `stats` as seen through this mount.
+    fn stats(&self, mut stats: FileStats) -> FileStats {
+        stats.dev = self.dev;
+        stats
+    }
+}
+
+/// A row of the mount table, as shown by `mount`.
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    pub path: String,
+    pub source: String,
+    pub fs_type: &'static str,
+}
+
+/// Root of the file tree: dispatches every path to the filesystem mounted at
+/// its longest matching mount point.
+pub struct Vfs {
+    mounts: RwLock<Vec<Arc<Mount>>>,
+    fs_types: RwLock<Vec<FsType>>,
+    /// Minor number of the next mount's anonymous device (major 0), as Linux
+    /// gives filesystems without a backing device.
+    next_minor: AtomicU32,
+}
+
+/// Components of an absolute path that has already been resolved.
+fn components(path: &str) -> Result<Vec<&str>> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(path::components(path).collect())
+}
+
+impl Vfs {
+    pub fn new(root: Arc<dyn Filesystem>, fs_type: &'static str) -> Self {
+        Self {
+            mounts: RwLock::new(alloc::vec![Arc::new(Mount {
+                path: "/".to_string(),
+                source: "none".to_string(),
+                fs_type,
+                fs: root,
+                dev: fs::makedev(0, 1),
+            })]),
+            fs_types: RwLock::new(Vec::new()),
+            next_minor: AtomicU32::new(2),
+        }
+    }
+
+    /// Makes `name` available to `mount -t`.
+    pub fn register_fs_type(&self, name: &'static str, construct: FsConstructor) {
+        let mut fs_types = self.fs_types.write();
+        fs_types.retain(|fs_type| fs_type.name != name);
+        fs_types.push(FsType { name, construct });
+    }
+
+    pub fn fs_types(&self) -> Vec<&'static str> {
+        self.fs_types.read().iter().map(|fs_type| fs_type.name).collect()
+    }
+
+    /// Resolves `path` relative to `cwd` into an absolute path free of `.`,
+    /// `..` and symbolic links, crossing mount points on the way. The final
+    /// component is only dereferenced if `follow_last` is set.
+    pub fn resolve(&self, cwd: &str, path: &str, follow_last: bool) -> Result<String> {
+        path::walk(cwd, path, follow_last, |resolved| {
+            let (mount, rest) = self.lookup(resolved)?;
+            // The root of a mounted filesystem is never a link
+            if rest == "/" {
+                return Err(FsError::NotASymlink);
+            }
+            mount.fs.readlink(&rest)
+        })
+    }
+
+    /// Mount whose mount point is the longest prefix of the resolved `path`,
+    /// and the rest of the path relative to that filesystem's root.
+    fn lookup(&self, path: &str) -> Result<(Arc<Mount>, String)> {
+        let parts = components(path)?;
+        let mounts = self.mounts.read();
+        let mut best: Option<(&Arc<Mount>, usize)> = None;
+        for mount in mounts.iter() {
+            let mount_parts = components(&mount.path)?;
+            if parts.starts_with(&mount_parts)
+                && best.map_or(true, |(_, len)| mount_parts.len() > len)
+            {
+                best = Some((mount, mount_parts.len()));
+            }
+        }
+        let (mount, len) = best.ok_or(FsError::NotFound)?;
+        Ok((Arc::clone(mount), path::join(&parts[len..])))
+    }
+
+    fn is_mount_point(&self, path: &str) -> Result<bool> {
+        let path = path::join(&components(path)?);
+        Ok(self.mounts.read().iter().any(|mount| mount.path == path))
+    }
+
+    /// Whether something is mounted at the resolved `path` or below it.
+    fn mounted_below(&self, path: &str) -> Result<bool> {
+        let path_parts = components(path)?;
+        Ok(self.mounts.read().iter().any(|mount| {
+            components(&mount.path).map_or(false, |parts| parts.starts_with(&path_parts))
+        }))
+    }
+
+    /// Fails with `Busy` if moving the resolved `old` to `new` would move or
+    /// replace a mount point. Mount points are pinned to their path, including
+    /// ones further down a directory being moved.
+    fn check_rename(&self, old: &str, new: &str) -> Result<()> {
+        if self.mounted_below(old)? || self.is_mount_point(new)? {
+            return Err(FsError::Busy);
+        }
+        Ok(())
+    }
+
+    /// Fails with `Busy` if removing the resolved `path` would take a mount
+    /// point, or a directory above one, away.
+    fn check_remove(&self, path: &str) -> Result<()> {
+        if self.mounted_below(path)? {
+            return Err(FsError::Busy);
+        }
+        Ok(())
+    }
+
+    /// Filesystem mounted right at the resolved `path`, if any.
+    fn mounted_at(&self, path: &str) -> Option<Arc<Mount>> {
+        let path = path::join(&components(path).ok()?);
+        self.mounts.read().iter().find(|mount| mount.path == path).cloned()
+    }
+
+    /// Attaches `fs` at `target`, which must be an existing directory that is
+    /// not already a mount point.
+    pub fn mount_fs(&self, source: &str, target: &str, fs_type: &'static str, fs: Arc<dyn Filesystem>) -> Result<()> {
+        let target = self.resolve("/", target, true)?;
+        self.get_dir(&target)?;
+
+        let mut mounts = self.mounts.write();
+        if mounts.iter().any(|mount| mount.path == target) {
+            return Err(FsError::Busy);
+        }
+        mounts.push(Arc::new(Mount {
+            path: target,
+            source: source.to_string(),
+            fs_type,
+            fs,
+            dev: fs::makedev(0, self.next_minor.fetch_add(1, Ordering::SeqCst)),
+        }));
+        Ok(())
+    }
+
+    /// Creates a filesystem of the registered type `fs_type` from `source` and
+    /// mounts it at `target`.
+    pub fn mount(&self, source: &str, target: &str, fs_type: &str) -> Result<()> {
+        let (name, construct) = self.fs_types.read().iter()
+            .find(|t| t.name == fs_type)
+            .map(|t| (t.name, t.construct))
+            .ok_or(FsError::UnknownFilesystem)?;
+        let fs = construct(source)?;
+        self.mount_fs(source, target, name, fs)
+    }
+
+    /// Detaches the filesystem mounted at `target`. Fails with `Busy` while
+    /// something is mounted below it or files and directories from it are
+    /// still held open.
+    pub fn umount(&self, target: &str) -> Result<()> {
+        let target = self.resolve("/", target, true)?;
+        let parts = components(&target)?;
+        if target == "/" {
+            return Err(FsError::Busy);
+        }
+
+        let mut mounts = self.mounts.write();
+        let index = mounts.iter()
+            .position(|mount| mount.path == target)
+            .ok_or(FsError::NotMounted)?;
+        let nested = mounts.iter().any(|mount| {
+            let mount_parts = components(&mount.path).unwrap_or_default();
+            mount_parts.len() > parts.len() && mount_parts.starts_with(&parts)
+        });
+        if nested || Arc::strong_count(&mounts[index]) > 1 {
+            return Err(FsError::Busy);
+        }
+        mounts[index].fs.sync()?;
+        mounts.remove(index);
+        Ok(())
+    }
+
+    /// Syncs every mounted filesystem, carrying on past failures and
+    /// reporting the first.
+    pub fn sync(&self) -> Result<()> {
+        let mounts = self.mounts.read().clone();
+        let mut result = Ok(());
+        for mount in mounts {
+            if let Err(e) = mount.fs.sync() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    pub fn mounts(&self) -> Vec<MountInfo> {
+        self.mounts.read().iter()
+            .map(|mount| MountInfo {
+                path: mount.path.clone(),
+                source: mount.source.clone(),
+                fs_type: mount.fs_type,
+            })
+            .collect()
+    }
+}
+
+impl Filesystem for Vfs {
+    fn root_dir(&self) -> Arc<dyn Directory> {
+        let mount = self.lookup("/").expect("root filesystem not mounted").0;
+        let dir = mount.fs.root_dir();
+        Arc::new(VfsDir { inner: dir, mount, path: "/".to_string() })
+    }
+
+    fn lstat(&self, path: &str) -> Result<FileStats> {
+        let path = self.resolve("/", path, false)?;
+        let (mount, path) = self.lookup(&path)?;
+        Ok(mount.stats(mount.fs.lstat(&path)?))
+    }
+
+    fn create_file(&self, path: &str, data: Vec<u8>) -> Result<()> {
+        let full_path = self.resolve("/", path, false)?;
+        if self.is_mount_point(&full_path)? {
+            return Err(FsError::AlreadyExists);
+        }
+        let (mount, path) = self.lookup(&full_path)?;
+        mount.fs.create_file(&path, data)?;
+        notify::created(&full_path, false);
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &str) -> Result<()> {
+        let full_path = self.resolve("/", path, false)?;
+        if self.is_mount_point(&full_path)? {
+            return Err(FsError::AlreadyExists);
+        }
+        let (mount, path) = self.lookup(&full_path)?;
+        mount.fs.create_dir(&path)?;
+        notify::created(&full_path, true);
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        let full_path = self.resolve("/", path, false)?;
+        self.check_remove(&full_path)?;
+        let (mount, path) = self.lookup(&full_path)?;
+        let is_dir = notify::active() && is_dir(&*mount.fs, &path);
+        mount.fs.remove(&path)?;
+        notify::removed(&full_path, is_dir);
+        Ok(())
+    }
+
+    fn get_file(&self, path: &str) -> Result<Arc<dyn File>> {
+        let full_path = self.resolve("/", path, true)?;
+        let (mount, path) = self.lookup(&full_path)?;
+        let file = mount.fs.get_file(&path)?;
+        Ok(Arc::new(VfsFile { inner: file, mount, path: full_path }))
+    }
+
+    fn get_dir(&self, path: &str) -> Result<Arc<dyn Directory>> {
+        let full_path = self.resolve("/", path, true)?;
+        let (mount, path) = self.lookup(&full_path)?;
+        let dir = if path == "/" { mount.fs.root_dir() } else { mount.fs.get_dir(&path)? };
+        Ok(Arc::new(VfsDir { inner: dir, mount, path: full_path }))
+    }
+
+    fn symlink(&self, target: &str, path: &str) -> Result<()> {
+        let full_path = self.resolve("/", path, false)?;
+        if self.is_mount_point(&full_path)? {
+            return Err(FsError::AlreadyExists);
+        }
+        let (mount, path) = self.lookup(&full_path)?;
+        mount.fs.symlink(target, &path)?;
+        notify::created(&full_path, false);
+        Ok(())
+    }
+
+    fn link(&self, existing: &str, path: &str) -> Result<()> {
+        let existing = self.resolve("/", existing, false)?;
+        let full_path = self.resolve("/", path, false)?;
+        if self.is_mount_point(&full_path)? {
+            return Err(FsError::AlreadyExists);
+        }
+        let (mount, existing) = self.lookup(&existing)?;
+        let (new_mount, path) = self.lookup(&full_path)?;
+        if !Arc::ptr_eq(&mount, &new_mount) {
+            return Err(FsError::CrossDevice);
+        }
+        mount.fs.link(&existing, &path)?;
+        notify::created(&full_path, false);
+        Ok(())
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<()> {
+        let old = self.resolve("/", old, false)?;
+        let new = self.resolve("/", new, false)?;
+
+        self.check_rename(&old, &new)?;
+        let (mount, old_path) = self.lookup(&old)?;
+        let (new_mount, new_path) = self.lookup(&new)?;
+        if !Arc::ptr_eq(&mount, &new_mount) {
+            return Err(FsError::CrossDevice);
+        }
+        if same_file(&*mount.fs, &old_path, &new_path)? {
+            return Ok(());
+        }
+        let is_dir = notify::active() && is_dir(&*mount.fs, &old_path);
+        mount.fs.rename(&old_path, &new_path)?;
+        notify::renamed(&old, &new, is_dir);
+        Ok(())
+    }
+
+    fn readlink(&self, path: &str) -> Result<String> {
+        let path = self.resolve("/", path, false)?;
+        let (mount, path) = self.lookup(&path)?;
+        mount.fs.readlink(&path)
+    }
+
+    fn set_permissions(&self, path: &str, permissions: u16) -> Result<()> {
+        let full_path = self.resolve("/", path, false)?;
+        let (mount, path) = self.lookup(&full_path)?;
+        mount.fs.set_permissions(&path, permissions)?;
+        notify::attrib(&full_path, notify::active() && is_dir(&*mount.fs, &path));
+        Ok(())
+    }
+}
+
+/// Whether `path` names a directory itself rather than a link to one, for
+/// the `IN_ISDIR` bit of change notifications.
+fn is_dir(fs: &dyn Filesystem, path: &str) -> bool {
+    fs.lstat(path).map_or(false, |stats| stats.file_type == FileType::Directory)
+}
+
+/// Whether `old` and `new` are the same entry, or links to one file. Renaming
+/// between them changes nothing, so nothing is done or reported. A single
+/// entry found under two spellings on a case-insensitive filesystem still
+/// gets renamed.
+fn same_file(fs: &dyn Filesystem, old: &str, new: &str) -> Result<bool> {
+    let old_stats = fs.lstat(old)?;
+    if old == new {
+        return Ok(true);
+    }
+    let new_stats = match fs.lstat(new) {
+        Ok(stats) => stats,
+        Err(FsError::NotFound) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    Ok(old_stats.inode == new_stats.inode
+        && old_stats.file_type != FileType::Directory
+        && old_stats.links > 1)
+}
+
+/// A file handed out by the VFS; pins its mount while alive.
+struct VfsFile {
+    inner: Arc<dyn File>,
+    mount: Arc<Mount>,
+    /// Path the file was looked up by, which changes are reported under.
+    path: String,
+}
+
+impl VfsFile {
+    fn modified<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_ok() {
+            notify::modified(&self.path);
+        }
+        result
+    }
+}
+
+impl File for VfsFile {
+    fn read(&self) -> Result<Vec<u8>> {
+        self.inner.read()
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        self.modified(self.inner.write(data))
+    }
+
+    fn append(&self, data: &[u8]) -> Result<()> {
+        self.modified(self.inner.append(data))
+    }
+
+    fn truncate(&self) -> Result<()> {
+        self.modified(self.inner.truncate())
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, data: &[u8]) -> Result<usize> {
+        self.modified(self.inner.write_at(offset, data))
+    }
+
+    fn set_len(&self, len: usize) -> Result<()> {
+        self.modified(self.inner.set_len(len))
+    }
+
+    fn stats(&self) -> Result<FileStats> {
+        Ok(self.mount.stats(self.inner.stats()?))
+    }
+}
+
+/// A directory handed out by the VFS; pins its mount while alive, and so do
+/// the entries looked up through it.
+struct VfsDir {
+    inner: Arc<dyn Directory>,
+    mount: Arc<Mount>,
+    path: String,
+}
+
+impl VfsDir {
+    fn child(&self, name: &str) -> String {
+        path::absolute(&self.path, name)
+    }
+}
+
+impl Directory for VfsDir {
+    fn list(&self) -> Result<Vec<(String, FileType)>> {
+        self.inner.list()
+    }
+
+    fn get_file(&self, name: &str) -> Result<Arc<dyn File>> {
+        if fs::VFS.mounted_at(&self.child(name)).is_some() {
+            return Err(FsError::IsADirectory);
+        }
+        let file = self.inner.get_file(name)?;
+        Ok(Arc::new(VfsFile { inner: file, mount: Arc::clone(&self.mount), path: self.child(name) }))
+    }
+
+    fn get_dir(&self, name: &str) -> Result<Arc<dyn Directory>> {
+        let path = self.child(name);
+        // A directory something is mounted on stands for that filesystem's root
+        if let Some(mount) = fs::VFS.mounted_at(&path) {
+            return Ok(Arc::new(VfsDir { inner: mount.fs.root_dir(), mount, path }));
+        }
+        let dir = self.inner.get_dir(name)?;
+        Ok(Arc::new(VfsDir { inner: dir, mount: Arc::clone(&self.mount), path }))
+    }
+
+    fn create_file(&self, name: &str, data: Vec<u8>) -> Result<()> {
+        self.inner.create_file(name, data)?;
+        notify::created(&self.child(name), false);
+        Ok(())
+    }
+
+    fn create_dir(&self, name: &str) -> Result<()> {
+        self.inner.create_dir(name)?;
+        notify::created(&self.child(name), true);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        fs::VFS.check_remove(&self.child(name))?;
+        let is_dir = notify::active() && self.inner.get_dir(name).is_ok();
+        self.inner.remove(name)?;
+        notify::removed(&self.child(name), is_dir);
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
+        let (old, new) = (self.child(old_name), self.child(new_name));
+        fs::VFS.check_rename(&old, &new)?;
+        let (_, old_path) = fs::VFS.lookup(&old)?;
+        let (_, new_path) = fs::VFS.lookup(&new)?;
+        if same_file(&*self.mount.fs, &old_path, &new_path)? {
+            return Ok(());
+        }
+        let is_dir = notify::active() && self.inner.get_dir(old_name).is_ok();
+        self.inner.rename(old_name, new_name)?;
+        notify::renamed(&old, &new, is_dir);
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<FileStats> {
+        Ok(self.mount.stats(self.inner.stats()?))
+    }
+}
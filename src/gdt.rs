@@ -5,6 +5,8 @@ use x86_64::registers::segmentation::{CS, DS, SS, Segment};
 use x86_64::PrivilegeLevel;
 use lazy_static::lazy_static;
 use static_assertions::const_assert;
+use alloc::boxed::Box;
+use crate::smp::MAX_CPUS;
 
 // Constants for stack sizes and IST indices
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
@@ -44,63 +46,91 @@ static mut PRIVILEGE_LEVEL_STACKS: [PrivilegeStack; 3] = [
     PrivilegeStack([0; PRIVILEGE_STACK_SIZE]),
 ];
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        
-        // Set up interrupt stack table entries
-        unsafe {
-            tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-                let stack_start = VirtAddr::from_ptr(&DOUBLE_FAULT_STACK);
-                stack_start + INTERRUPT_STACK_SIZE
-            };
-            
-            tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
-                let stack_start = VirtAddr::from_ptr(&PAGE_FAULT_STACK);
-                stack_start + INTERRUPT_STACK_SIZE
-            };
-            
-            tss.interrupt_stack_table[GENERAL_PROTECTION_IST_INDEX as usize] = {
-                let stack_start = VirtAddr::from_ptr(&GP_FAULT_STACK);
-                stack_start + INTERRUPT_STACK_SIZE
-            };
-
-            // Initialize privilege stack table
-            for (i, stack) in PRIVILEGE_LEVEL_STACKS.iter().enumerate() {
-                tss.privilege_stack_table[i] = VirtAddr::from_ptr(stack) + PRIVILEGE_STACK_SIZE;
-            }
-        }
-        
-        tss
+/// Interrupt and privilege stacks of one application processor; the BSP uses
+/// the statics above.
+#[repr(align(16))]
+struct CpuStacks {
+    interrupt: [InterruptStack; 3],
+    privilege: [PrivilegeStack; 3],
+}
+
+impl CpuStacks {
+    const EMPTY: CpuStacks = CpuStacks {
+        interrupt: [InterruptStack::EMPTY; 3],
+        privilege: [PrivilegeStack::EMPTY; 3],
     };
 }
 
+impl InterruptStack {
+    const EMPTY: InterruptStack = InterruptStack([0; INTERRUPT_STACK_SIZE]);
+}
+
+impl PrivilegeStack {
+    const EMPTY: PrivilegeStack = PrivilegeStack([0; PRIVILEGE_STACK_SIZE]);
+}
+
+static mut AP_STACKS: [CpuStacks; MAX_CPUS - 1] = [CpuStacks::EMPTY; MAX_CPUS - 1];
+
+fn build_tss(
+    double_fault: &'static InterruptStack,
+    page_fault: &'static InterruptStack,
+    gp_fault: &'static InterruptStack,
+    privilege: &'static [PrivilegeStack; 3],
+) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+
+    // Set up interrupt stack table entries
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(double_fault) + INTERRUPT_STACK_SIZE;
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(page_fault) + INTERRUPT_STACK_SIZE;
+    tss.interrupt_stack_table[GENERAL_PROTECTION_IST_INDEX as usize] =
+        VirtAddr::from_ptr(gp_fault) + INTERRUPT_STACK_SIZE;
+
+    // Initialize privilege stack table
+    for (i, stack) in privilege.iter().enumerate() {
+        tss.privilege_stack_table[i] = VirtAddr::from_ptr(stack) + PRIVILEGE_STACK_SIZE;
+    }
+
+    tss
+}
+
+/// Builds a GDT referencing `tss`. Every CPU uses the same layout, so the
+/// selectors are interchangeable between CPUs.
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+
+    // Add segments in correct order with proper access rights
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+
+    // Add system call segment (ring 3 to ring 0 fast transitions)
+    let syscall_code = gdt.add_entry(Descriptor::UserSegment(0xc0_9a_00_00_00_00_00_00));
+
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    (gdt, Selectors {
+        kernel_code,
+        kernel_data,
+        user_code,
+        user_data,
+        syscall_code,
+        tss,
+    })
+}
+
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        
-        // Add segments in correct order with proper access rights
-        let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
-        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
-        let user_data = gdt.add_entry(Descriptor::user_data_segment());
-        let user_code = gdt.add_entry(Descriptor::user_code_segment());
-        
-        // Add system call segment (ring 3 to ring 0 fast transitions)
-        let syscall_code = gdt.add_entry(Descriptor::UserSegment(0xc0_9a_00_00_00_00_00_00));
-        
-        let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
-
-        (gdt, Selectors { 
-            kernel_code,
-            kernel_data,
-            user_code,
-            user_data,
-            syscall_code,
-            tss,
-        })
+    static ref TSS: TaskStateSegment = unsafe {
+        build_tss(&DOUBLE_FAULT_STACK, &PAGE_FAULT_STACK, &GP_FAULT_STACK, &PRIVILEGE_LEVEL_STACKS)
     };
 }
 
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = build_gdt(&TSS);
+}
+
 #[derive(Debug)]
 pub struct Selectors {
     kernel_code: SegmentSelector,
@@ -125,22 +155,36 @@ impl Selectors {
     }
 }
 
-pub fn init() {
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::tables::load_tss;
 
     // Load GDT and segment registers
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
         // Set code and data segments for kernel mode
-        CS::set_reg(GDT.1.kernel_code);
-        DS::set_reg(GDT.1.kernel_data);
-        SS::set_reg(GDT.1.kernel_data);
-        
+        CS::set_reg(gdt.1.kernel_code);
+        DS::set_reg(gdt.1.kernel_data);
+        SS::set_reg(gdt.1.kernel_data);
+
         // Load TSS
-        load_tss(GDT.1.tss);
+        load_tss(gdt.1.tss);
     }
 }
 
+pub fn init() {
+    load(&GDT);
+}
+
+/// Loads a GDT and TSS of its own on application processor `cpu` (1-based;
+/// CPU 0 is the BSP), giving it private interrupt stacks.
+pub fn init_ap(cpu: usize) {
+    assert!(cpu > 0 && cpu < MAX_CPUS, "invalid AP index");
+    let stacks = unsafe { &AP_STACKS[cpu - 1] };
+    let [double_fault, page_fault, gp_fault] = &stacks.interrupt;
+    let tss = Box::leak(Box::new(build_tss(double_fault, page_fault, gp_fault, &stacks.privilege)));
+    load(Box::leak(Box::new(build_gdt(tss))));
+}
+
 pub fn get_current_privilege_level() -> PrivilegeLevel {
     let selector = CS::get_reg();
     selector.rpl()
@@ -0,0 +1,146 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+use crate::memory;
+use crate::task::timer;
+
+/// Vector the local APIC raises for spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Scheduling ticks per second generated by the local APIC timer, or by the
+/// PIT where there is no APIC.
+pub const TIMER_HZ: u64 = 100;
+
+/// Input frequency of the legacy PIT, which still drives the timer interrupt
+/// during calibration, and the BIOS default divisor it runs with.
+pub(super) const PIT_HZ: u64 = 1_193_182;
+const PIT_DIVISOR: u64 = 65536;
+
+/// PIT ticks the APIC timer is measured against.
+const CALIBRATION_TICKS: u64 = 2;
+
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xB0;
+const REG_SVR: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// Virtual address the local APIC registers are mapped at; every CPU sees its
+/// own APIC at the same address.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Initial count giving `TIMER_HZ` interrupts per second; 0 until calibrated.
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
+
+fn read(reg: usize) -> u32 {
+    let base = BASE.load(Ordering::SeqCst);
+    unsafe { core::ptr::read_volatile((base as usize + reg) as *const u32) }
+}
+
+fn write(reg: usize, value: u32) {
+    let base = BASE.load(Ordering::SeqCst);
+    unsafe { core::ptr::write_volatile((base as usize + reg) as *mut u32, value) }
+}
+
+/// Maps the local APIC registers. Called once, on the BSP.
+pub fn init(phys_base: u64) -> Result<(), &'static str> {
+    let base = memory::map_mmio(PhysAddr::new(phys_base), 4096)?;
+    BASE.store(base.as_u64(), Ordering::SeqCst);
+    enable();
+    Ok(())
+}
+
+/// Software-enables the calling CPU's local APIC.
+pub fn enable() {
+    write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+/// APIC ID of the calling CPU.
+pub fn id() -> u32 {
+    read(REG_ID) >> 24
+}
+
+pub fn end_of_interrupt() {
+    write(REG_EOI, 0);
+}
+
+/// Measures the APIC timer against the PIT-driven tick counter. Needs the
+/// 8259 timer interrupt running, i.e. must happen before switching to the
+/// IOAPIC.
+pub fn calibrate_timer() {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, LVT_MASKED);
+
+    // Start on a tick boundary so the measured window is whole ticks
+    let start = timer::ticks();
+    while timer::ticks() == start {
+        core::hint::spin_loop();
+    }
+    write(REG_TIMER_INITIAL, u32::MAX);
+    let start = timer::ticks();
+    while timer::ticks() < start + CALIBRATION_TICKS {
+        core::hint::spin_loop();
+    }
+    let elapsed = (u32::MAX - read(REG_TIMER_CURRENT)) as u64;
+    write(REG_TIMER_INITIAL, 0);
+
+    let per_second = elapsed * PIT_HZ / (CALIBRATION_TICKS * PIT_DIVISOR);
+    TIMER_COUNT.store((per_second / TIMER_HZ).max(1) as u32, Ordering::SeqCst);
+}
+
+/// Starts the calling CPU's periodic timer on `vector`, if calibrated.
+pub fn start_timer(vector: u8) {
+    let count = TIMER_COUNT.load(Ordering::SeqCst);
+    if count == 0 {
+        return;
+    }
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, TIMER_PERIODIC | vector as u32);
+    write(REG_TIMER_INITIAL, count);
+}
+
+fn send_ipi(apic_id: u32, command: u32) {
+    // The two ICR writes must not be split by an interrupt that sends an IPI
+    interrupts::without_interrupts(|| {
+        write(REG_ICR_HIGH, apic_id << 24);
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Raises `vector` on the CPU with `apic_id`.
+pub fn send_fixed(apic_id: u32, vector: u8) {
+    send_ipi(apic_id, vector as u32);
+}
+
+/// Raises `vector` on every CPU but the caller.
+pub fn broadcast(vector: u8) {
+    send_ipi(0, ICR_ALL_EXCLUDING_SELF | vector as u32);
+}
+
+/// Resets the target CPU into its wait-for-SIPI state.
+pub fn send_init(apic_id: u32) {
+    send_ipi(apic_id, ICR_INIT | ICR_ASSERT);
+}
+
+/// Starts the target CPU in real mode at physical address `page << 12`.
+pub fn send_startup(apic_id: u32, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32);
+}
@@ -0,0 +1,165 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+use crate::acpi::Madt;
+use crate::memory;
+
+const REG_SELECT: usize = 0x00;
+const REG_WINDOW: usize = 0x10;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const ENTRY_MASKED: u64 = 1 << 16;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+
+/// MADT override flags: bits 0-1 polarity, bits 2-3 trigger mode.
+const POLARITY_LOW: u16 = 0b11;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+/// GSIs from here on are PCI interrupt pins, which are level triggered and
+/// active low; below are ISA IRQs, edge triggered and active high.
+const FIRST_PCI_GSI: u32 = 16;
+
+struct IoApic {
+    base: usize,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile((self.base + REG_SELECT) as *mut u32, reg);
+            core::ptr::read_volatile((self.base + REG_WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.base + REG_SELECT) as *mut u32, reg);
+            core::ptr::write_volatile((self.base + REG_WINDOW) as *mut u32, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    fn read_entry(&self, gsi: u32) -> u64 {
+        let reg = REG_REDIRECTION + (gsi - self.gsi_base) * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    fn write_entry(&self, gsi: u32, entry: u64) {
+        let reg = REG_REDIRECTION + (gsi - self.gsi_base) * 2;
+        // Mask first so the entry never fires half written
+        self.write(reg, ENTRY_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+/// Where an interrupt line ends up: its GSI and the redirection flags.
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    gsi: u32,
+    flags: u64,
+}
+
+static IOAPICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+static ROUTES: Mutex<Vec<Option<Route>>> = Mutex::new(Vec::new());
+
+/// Maps every IOAPIC in the MADT and routes interrupt line `n` to vector
+/// `vector_base + n` on the CPU with `apic_id`, masked. ISA lines honour the
+/// MADT's interrupt source overrides.
+pub fn init(madt: &Madt, vector_base: u8, lines: usize, apic_id: u32) -> Result<(), &'static str> {
+    if madt.io_apics.is_empty() {
+        return Err("no IOAPIC");
+    }
+
+    let mut ioapics = Vec::new();
+    for ioapic in &madt.io_apics {
+        let base = memory::map_mmio(PhysAddr::new(ioapic.address as u64), 4096)?;
+        let mut ioapic = IoApic {
+            base: base.as_u64() as usize,
+            gsi_base: ioapic.gsi_base,
+            entries: 0,
+        };
+        ioapic.entries = ((ioapic.read(REG_VERSION) >> 16) & 0xFF) + 1;
+        for gsi in ioapic.gsi_base..ioapic.gsi_base + ioapic.entries {
+            ioapic.write_entry(gsi, ENTRY_MASKED);
+        }
+        ioapics.push(ioapic);
+    }
+
+    let mut routes = Vec::with_capacity(lines);
+    for line in 0..lines as u32 {
+        // A GSI another ISA IRQ was redirected to (e.g. the PIT on GSI 2)
+        // is not available under its own number
+        let taken = madt.overrides.iter().any(|o| o.gsi == line && o.source as u32 != line);
+        let route = match madt.overrides.iter().find(|o| o.source as u32 == line) {
+            Some(o) => Route {
+                gsi: o.gsi,
+                flags: override_flags(o.flags),
+            },
+            None if taken => {
+                routes.push(None);
+                continue;
+            }
+            None if line < FIRST_PCI_GSI => Route { gsi: line, flags: 0 },
+            None => Route { gsi: line, flags: ENTRY_LEVEL | ENTRY_ACTIVE_LOW },
+        };
+        let entry = ENTRY_MASKED
+            | route.flags
+            | (vector_base as u64 + line as u64)
+            | (apic_id as u64) << 56;
+        if let Some(ioapic) = ioapics.iter().find(|ioapic| ioapic.handles(route.gsi)) {
+            ioapic.write_entry(route.gsi, entry);
+        }
+        routes.push(Some(route));
+    }
+
+    interrupts::without_interrupts(|| {
+        *IOAPICS.lock() = ioapics;
+        *ROUTES.lock() = routes;
+    });
+    Ok(())
+}
+
+fn override_flags(flags: u16) -> u64 {
+    let mut entry = 0;
+    if flags & POLARITY_LOW == POLARITY_LOW {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+    if flags & TRIGGER_LEVEL == TRIGGER_LEVEL {
+        entry |= ENTRY_LEVEL;
+    }
+    entry
+}
+
+fn set_masked(line: u8, masked: bool) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let route = ROUTES.lock().get(line as usize)
+            .copied()
+            .flatten()
+            .ok_or("interrupt line not routed")?;
+        let ioapics = IOAPICS.lock();
+        let ioapic = ioapics.iter()
+            .find(|ioapic| ioapic.handles(route.gsi))
+            .ok_or("interrupt line not wired to an IOAPIC")?;
+        let entry = ioapic.read_entry(route.gsi);
+        let entry = if masked { entry | ENTRY_MASKED } else { entry & !ENTRY_MASKED };
+        ioapic.write_entry(route.gsi, entry);
+        Ok(())
+    })
+}
+
+pub fn unmask(line: u8) -> Result<(), &'static str> {
+    set_masked(line, false)
+}
+
+pub fn mask(line: u8) -> Result<(), &'static str> {
+    set_masked(line, true)
+}
@@ -0,0 +1,180 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+use crate::smp::{self, MAX_CPUS};
+use super::{apic, ioapic, pic::PICS, IRQ_BASE, IRQ_LINES};
+
+/// Lines 0 and 2 belong to the timer and the PIC cascade.
+const RESERVED_LINES: [u8; 2] = [0, 2];
+
+/// What a handler on a shared line reports back to the dispatcher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The device behind this handler raised the interrupt and was serviced.
+    Handled,
+    /// Not this handler's device.
+    None,
+}
+
+pub type IrqHandler = fn() -> IrqReturn;
+
+/// A handler attached to an interrupt line, named for the listing.
+#[derive(Clone, Copy)]
+struct IrqAction {
+    name: &'static str,
+    handler: IrqHandler,
+}
+
+/// Per-CPU interrupt counts.
+pub struct IrqCounter([AtomicU64; MAX_CPUS]);
+
+impl IrqCounter {
+    const fn new() -> Self {
+        IrqCounter([const { AtomicU64::new(0) }; MAX_CPUS])
+    }
+
+    pub(super) fn increment(&self) {
+        self.0[smp::cpu_id()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, cpu: usize) -> u64 {
+        self.0.get(cpu).map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
+}
+
+static ACTIONS: RwLock<[Vec<IrqAction>; IRQ_LINES]> = {
+    const EMPTY: Vec<IrqAction> = Vec::new();
+    RwLock::new([EMPTY; IRQ_LINES])
+};
+
+static COUNTS: [IrqCounter; IRQ_LINES] = [const { IrqCounter::new() }; IRQ_LINES];
+
+/// Interrupts no handler on their line claimed.
+pub static UNHANDLED: IrqCounter = IrqCounter::new();
+
+/// Local APIC timer (or PIT) interrupts.
+pub static TIMER: IrqCounter = IrqCounter::new();
+
+/// Reschedule IPIs received.
+pub static RESCHEDULE: IrqCounter = IrqCounter::new();
+
+/// Spurious interrupts reported by the local APIC.
+pub static SPURIOUS: IrqCounter = IrqCounter::new();
+
+/// Adds `handler` to the chain of interrupt line `line` and unmasks the line.
+/// Several devices may share a line; every handler on it is called, in
+/// registration order, each time the line fires. Handlers run with interrupts
+/// disabled and must not take locks that are held with interrupts enabled.
+/// End of interrupt is signalled for them.
+pub fn register_irq(line: u8, name: &'static str, handler: IrqHandler) -> Result<(), &'static str> {
+    if line as usize >= IRQ_LINES || RESERVED_LINES.contains(&line) {
+        return Err("invalid interrupt line");
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut actions = ACTIONS.write();
+        let chain = &mut actions[line as usize];
+        if chain.iter().any(|action| action.handler == handler) {
+            return Err("handler already registered on this line");
+        }
+        chain.push(IrqAction { name, handler });
+        let first = chain.len() == 1;
+        drop(actions);
+
+        if first {
+            if let Err(e) = set_line_masked(line, false) {
+                ACTIONS.write()[line as usize].clear();
+                return Err(e);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Lines that have at least one handler attached.
+pub(super) fn registered_lines() -> Vec<u8> {
+    let actions = ACTIONS.read();
+    (0..IRQ_LINES as u8)
+        .filter(|&line| !actions[line as usize].is_empty())
+        .collect()
+}
+
+fn set_line_masked(line: u8, masked: bool) -> Result<(), &'static str> {
+    if super::apic_enabled() {
+        return if masked { ioapic::mask(line) } else { ioapic::unmask(line) };
+    }
+    if line >= 16 {
+        return Err("interrupt line not available on the 8259");
+    }
+
+    unsafe {
+        let mut pics = PICS.lock();
+        let [mut master, mut slave] = pics.read_masks();
+        let (mask, bit) = if line < 8 { (&mut master, line) } else { (&mut slave, line - 8) };
+        if masked {
+            *mask |= 1 << bit;
+        } else {
+            *mask &= !(1 << bit);
+            // Lines on the slave PIC arrive through the cascade
+            if line >= 8 {
+                master &= !(1 << 2);
+            }
+        }
+        pics.write_masks(master, slave);
+    }
+    Ok(())
+}
+
+/// Runs the handler chain of `line`, counts the interrupt and signals end of
+/// interrupt. Called from the line's IDT stub.
+pub(super) fn dispatch_irq(line: u8) {
+    COUNTS[line as usize].increment();
+
+    let mut handled = false;
+    for action in ACTIONS.read()[line as usize].iter() {
+        if (action.handler)() == IrqReturn::Handled {
+            handled = true;
+        }
+    }
+    if !handled {
+        UNHANDLED.increment();
+    }
+
+    end_of_interrupt(IRQ_BASE + line);
+}
+
+pub(super) fn end_of_interrupt(vector: u8) {
+    if super::apic_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        }
+    }
+}
+
+/// One row of the interrupt listing.
+pub struct IrqLineStats {
+    pub line: u8,
+    pub counts: &'static IrqCounter,
+    pub handlers: Vec<&'static str>,
+}
+
+/// Lines that have a handler or have fired, in line order.
+pub fn irq_stats() -> Vec<IrqLineStats> {
+    let actions = ACTIONS.read();
+    (0..IRQ_LINES)
+        .filter(|&line| !actions[line].is_empty() || COUNTS[line].total() > 0)
+        .map(|line| IrqLineStats {
+            line: line as u8,
+            counts: &COUNTS[line],
+            handlers: actions[line].iter().map(|action| action.name).collect(),
+        })
+        .collect()
+}
+
@@ -1,12 +1,57 @@
-use crate::{println, gdt};
+use crate::{acpi, memory, println, gdt};
 use x86_64::structures::idt::{
     InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode
 };
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
 use lazy_static::lazy_static;
 
+pub mod apic;
+pub mod ioapic;
+pub mod irq;
 pub mod pic;
 use pic::PICS;
 
+pub use irq::{register_irq, IrqReturn};
+
+/// Device interrupt line `n` (an ISA IRQ, or a GSI above 15) arrives on vector
+/// `IRQ_BASE + n`, whether the 8259s or the IOAPIC deliver it.
+pub const IRQ_BASE: u8 = pic::PIC_1_OFFSET;
+pub const IRQ_LINES: usize = 24;
+
+/// Sent to a CPU to make it re-run the scheduler, e.g. when work was queued
+/// on it while it was halted.
+pub const RESCHEDULE_VECTOR: u8 = 0xF0;
+
+/// Stops the receiving CPU for good; broadcast when the kernel panics.
+pub const HALT_VECTOR: u8 = 0xF1;
+
+/// Set once interrupts are delivered by the local APIC and IOAPIC instead of
+/// the 8259s.
+static APIC_MODE: AtomicBool = AtomicBool::new(false);
+
+macro_rules! irq_stubs {
+    ($($line:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                irq::dispatch_irq($line);
+            }
+        )*
+
+        /// IDT entry points of the lines drivers can register handlers for.
+        const IRQ_STUBS: &[(u8, extern "x86-interrupt" fn(InterruptStackFrame))] = &[
+            $(($line, $name)),*
+        ];
+    };
+}
+
+irq_stubs! {
+    1 => irq1, 3 => irq3, 4 => irq4, 5 => irq5, 6 => irq6, 7 => irq7,
+    8 => irq8, 9 => irq9, 10 => irq10, 11 => irq11, 12 => irq12, 13 => irq13,
+    14 => irq14, 15 => irq15, 16 => irq16, 17 => irq17, 18 => irq18, 19 => irq19,
+    20 => irq20, 21 => irq21, 22 => irq22, 23 => irq23,
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -15,15 +60,26 @@ lazy_static! {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-            idt.page_fault.set_handler_fn(page_fault_handler);
+            // A task that ran off its stack has no stack left to take the
+            // fault on
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
             idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
             idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
 
             // Hardware interrupt handlers
             idt[pic::InterruptIndex::Timer.as_usize()]
                 .set_handler_fn(timer_interrupt_handler);
-            idt[pic::InterruptIndex::Keyboard.as_usize()]
-                .set_handler_fn(keyboard_interrupt_handler);
+            for &(line, stub) in IRQ_STUBS {
+                idt[(IRQ_BASE + line) as usize].set_handler_fn(stub);
+            }
+            idt[RESCHEDULE_VECTOR as usize]
+                .set_handler_fn(reschedule_interrupt_handler);
+            idt[HALT_VECTOR as usize]
+                .set_handler_fn(halt_interrupt_handler);
+            idt[apic::SPURIOUS_VECTOR as usize]
+                .set_handler_fn(spurious_interrupt_handler);
         }
         idt
     };
@@ -43,6 +99,59 @@ pub fn init() {
     x86_64::instructions::interrupts::enable();
 }
 
+/// Switches interrupt delivery from the 8259s to the local APIC and the
+/// IOAPICs described by the MADT, and drives scheduling from the APIC timer.
+/// On error the 8259s stay in charge.
+pub fn init_apic() -> Result<(), &'static str> {
+    let madt = acpi::madt().ok_or("MADT not found")?;
+    apic::init(madt.local_apic_address)?;
+    apic::calibrate_timer();
+    ioapic::init(madt, IRQ_BASE, IRQ_LINES, apic::id())?;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe {
+            PICS.lock().disable();
+        }
+        APIC_MODE.store(true, Ordering::SeqCst);
+
+        // Lines claimed while the 8259s were in charge move over as well
+        for line in irq::registered_lines() {
+            let _ = ioapic::unmask(line);
+        }
+        apic::start_timer(pic::InterruptIndex::Timer.as_u8());
+    });
+    Ok(())
+}
+
+/// Runs PIT channel 0 at `apic::TIMER_HZ` for when the 8259s stay in charge;
+/// left at the BIOS default, ticks would be 5.5 times too long.
+pub fn start_pit_timer() {
+    const PIT_COMMAND: u16 = 0x43;
+    const PIT_CHANNEL_0: u16 = 0x40;
+    // Channel 0, low then high divisor byte, square wave
+    const PIT_MODE: u8 = 0b0011_0110;
+
+    let divisor = (apic::PIT_HZ / apic::TIMER_HZ) as u16;
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(PIT_MODE);
+        let mut data = Port::<u8>::new(PIT_CHANNEL_0);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    });
+}
+
+pub fn apic_enabled() -> bool {
+    APIC_MODE.load(Ordering::SeqCst)
+}
+
+/// Interrupt setup for an application processor. The IDT is shared; each CPU
+/// only needs its local APIC and timer.
+pub fn init_ap() {
+    init_idt();
+    apic::enable();
+    apic::start_timer(pic::InterruptIndex::Timer.as_u8());
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
@@ -58,8 +167,17 @@ extern "x86-interrupt" fn page_fault_handler(
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
+
+    let address = Cr2::read();
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if let Some(task) = memory::stack::guard_page_owner(address) {
+            panic!("KERNEL STACK OVERFLOW in task {} (guard page hit at {:?})\n{:#?}",
+                task, address, stack_frame);
+        }
+    }
+
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    println!("Accessed Address: {:?}", address);
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
     hlt_loop();
@@ -86,37 +204,50 @@ extern "x86-interrupt" fn invalid_opcode_handler(
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
-    use crate::task;
-    
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(pic::InterruptIndex::Timer.as_u8());
-    }
-    
-    // Perform task switching if time slice is expired
-    let mut scheduler = task::SCHEDULER.lock();
-    if let Some(current) = scheduler.schedule() {
-        drop(scheduler); // Release the lock before yielding
-        task::yield_now();
+    use crate::{smp, task};
+
+    // Every CPU gets its own APIC timer interrupt, but only one keeps time
+    if smp::cpu_id() == 0 {
+        task::timer::tick();
     }
+
+    irq::TIMER.increment();
+    irq::end_of_interrupt(pic::InterruptIndex::Timer.as_u8());
+
+    // Switch tasks if the running one has used up its time slice
+    task::yield_now();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
+extern "x86-interrupt" fn reschedule_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
-    use x86_64::instructions::port::Port;
+    irq::RESCHEDULE.increment();
+    apic::end_of_interrupt();
+    crate::task::yield_now();
+}
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    
-    crate::keyboard::add_scancode(scancode);
+extern "x86-interrupt" fn halt_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    x86_64::instructions::interrupts::disable();
+    hlt_loop();
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(pic::InterruptIndex::Keyboard.as_u8());
+/// Halts every other CPU, so a panic message is not scrolled away or followed
+/// by more damage.
+pub fn stop_other_cpus() {
+    if apic_enabled() {
+        apic::broadcast(HALT_VECTOR);
     }
 }
 
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    // Spurious interrupts must not be acknowledged
+    irq::SPURIOUS.increment();
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
@@ -4,6 +4,7 @@ use crossbeam_queue::ArrayQueue;
 use core::{pin::Pin, task::{Context, Poll}};
 use futures_util::stream::Stream;
 use futures_util::task::AtomicWaker;
+use crate::interrupts::IrqReturn;
 use crate::println;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
@@ -24,10 +25,21 @@ pub struct KeyboardStream {
 
 impl KeyboardStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(QUEUE_SIZE))
-            .expect("KeyboardStream::new should only be called once");
+        SCANCODE_QUEUE.get().expect("keyboard::init must be called first");
         KeyboardStream { _private: () }
     }
+
+    /// Feeds queued scancodes through the shared decoder until one of them
+    /// completes a key press. Partial multi-byte sequences stay buffered in the
+    /// decoder for the next call.
+    fn next_key(queue: &ArrayQueue<u8>) -> Option<KeyEvent> {
+        while let Some(scancode) = queue.pop() {
+            if let Some(key) = process_scancode(scancode) {
+                return Some(key);
+            }
+        }
+        None
+    }
 }
 
 impl Stream for KeyboardStream {
@@ -35,24 +47,30 @@ impl Stream for KeyboardStream {
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         let queue = SCANCODE_QUEUE.get().expect("not initialized");
-        
+
+        // Fast path: avoid touching the waker if input is already waiting
+        if let Some(key) = Self::next_key(queue) {
+            return Poll::Ready(Some(key));
+        }
+
         WAKER.register(cx.waker());
-        match queue.pop() {
-            Some(scancode) => {
-                let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
-                if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-                    if let Some(key) = decode_key(key_event) {
-                        return Poll::Ready(Some(key));
-                    }
-                }
-                Poll::Ready(None)
+        // Re-check in case a scancode arrived before the waker was registered
+        match Self::next_key(queue) {
+            Some(key) => {
+                WAKER.take();
+                Poll::Ready(Some(key))
             }
             None => Poll::Pending,
         }
     }
 }
 
-pub(crate) fn add_scancode(scancode: u8) {
+/// A key press already waiting, for readers that cannot wait for one.
+pub fn try_read_key() -> Option<KeyEvent> {
+    KeyboardStream::next_key(SCANCODE_QUEUE.get()?)
+}
+
+fn add_scancode(scancode: u8) {
     if let Some(queue) = SCANCODE_QUEUE.get() {
         if let Err(_) = queue.push(scancode) {
             println!("WARNING: scancode queue full; dropping keyboard input");
@@ -87,24 +105,21 @@ fn process_scancode(scancode: u8) -> Option<KeyEvent> {
     }
 }
 
-fn decode_key(key_event: pc_keyboard::KeyEvent) -> Option<KeyEvent> {
-    lazy_static::lazy_static! {
-        static ref KEYBOARD: spin::Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            spin::Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,
-                HandleControl::Ignore));
-    }
+/// ISA interrupt line of the PS/2 keyboard.
+const KEYBOARD_IRQ: u8 = 1;
 
-    let mut keyboard = KEYBOARD.lock();
-    if let Some(key) = keyboard.process_keyevent(key_event) {
-        match key {
-            DecodedKey::Unicode(character) => Some(KeyEvent::Char(character)),
-            DecodedKey::RawKey(key) => Some(KeyEvent::SpecialKey(key)),
-        }
-    } else {
-        None
-    }
+fn handle_interrupt() -> IrqReturn {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    add_scancode(scancode);
+    IrqReturn::Handled
 }
 
 pub fn init() {
-    KeyboardStream::new();
+    SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(QUEUE_SIZE))
+        .expect("keyboard::init should only be called once");
+    crate::interrupts::register_irq(KEYBOARD_IRQ, "keyboard", handle_interrupt)
+        .expect("keyboard interrupt line already in use");
 } 
\ No newline at end of file
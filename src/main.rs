@@ -5,54 +5,81 @@
 #![feature(asm_const)]
 #![feature(naked_functions)]
 #![feature(default_alloc_error_handler)]
+#![feature(inline_const)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
-use alloc::string::{String, ToString};
 use bootloader::BootInfo;
 use core::panic::PanicInfo;
 use x86_64::VirtAddr;
-use task::sync::Semaphore;
+use task::sync::BlockingMutex;
+use task::executor::{AsyncTask, Executor};
 use core::sync::atomic::{AtomicUsize, Ordering};
-use futures_util::{StreamExt, FutureExt};
 use memory::heap::init_heap;
 use lazy_static::lazy_static;
-use pc_keyboard::KeyCode;
 
 mod vga_buffer;
+mod acpi;
 mod gdt;
 mod interrupts;
 mod memory;
 mod keyboard;
+mod serial;
 mod task;
 mod fs;
 mod process;
 mod shell;
 mod network;
+mod pci;
+mod block;
+mod smp;
+mod rtc;
 
 lazy_static! {
-    pub static ref PRINT_SEMAPHORE: Semaphore = {
-        let sem = Semaphore::new(1);
-        sem
-    };
+    /// Serialises output from the demo tasks. A `BlockingMutex` rather than a
+    /// semaphore so the low priority task inherits priority while printing.
+    pub static ref PRINT_LOCK: BlockingMutex<()> = BlockingMutex::new(());
 }
 
 /// This function is called on panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    interrupts::stop_other_cpus();
     println!("{}", info);
+    #[cfg(test)]
+    {
+        serial::write(alloc::format!("[failed] {}\n", info).as_bytes());
+        unsafe { x86_64::instructions::port::Port::<u32>::new(0xf4).write(0x11) };
+    }
     interrupts::hlt_loop();
 }
 
+/// Runs the `#[test_case]` functions, reporting on the serial port, and
+/// exits QEMU through the `isa-debug-exit` device.
+#[cfg(test)]
+fn test_runner(tests: &[&dyn Fn()]) {
+    serial::write(alloc::format!("Running {} tests\n", tests.len()).as_bytes());
+    for test in tests {
+        test();
+    }
+    serial::write(b"[ok]\n");
+    // The device exits with `(code << 1) | 1`, 33 for success
+    unsafe { x86_64::instructions::port::Port::<u32>::new(0xf4).write(0x10) };
+}
+
 // Shared counter for testing synchronization
 static SHARED_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 fn high_priority_task() {
     let mut local_counter = 0;
     loop {
-        PRINT_SEMAPHORE.acquire();
-        println!("High Priority Task: {}", local_counter);
-        PRINT_SEMAPHORE.release();
+        {
+            let _guard = PRINT_LOCK.lock();
+            println!("High Priority Task: {}", local_counter);
+        }
         
         SHARED_COUNTER.fetch_add(1, Ordering::SeqCst);
         local_counter += 1;
@@ -68,9 +95,10 @@ fn high_priority_task() {
 fn normal_priority_task() {
     let mut local_counter = 0;
     loop {
-        PRINT_SEMAPHORE.acquire();
-        println!("Normal Priority Task: {}", local_counter);
-        PRINT_SEMAPHORE.release();
+        {
+            let _guard = PRINT_LOCK.lock();
+            println!("Normal Priority Task: {}", local_counter);
+        }
         
         SHARED_COUNTER.fetch_add(1, Ordering::SeqCst);
         local_counter += 1;
@@ -86,9 +114,10 @@ fn normal_priority_task() {
 fn low_priority_task() {
     let mut local_counter = 0;
     loop {
-        PRINT_SEMAPHORE.acquire();
-        println!("Low Priority Task: {}", local_counter);
-        PRINT_SEMAPHORE.release();
+        {
+            let _guard = PRINT_LOCK.lock();
+            println!("Low Priority Task: {}", local_counter);
+        }
         
         SHARED_COUNTER.fetch_add(1, Ordering::SeqCst);
         local_counter += 1;
@@ -127,8 +156,19 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     // Initialize heap
     init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    #[cfg(test)]
+    test_main();
 
     println!("Memory management initialized!");
+
+    rtc::init();
+
+    println!("Probing disks...");
+
+    block::init();
+
     println!("Initializing filesystem...");
     
     // Initialize filesystem
@@ -163,100 +203,42 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     
     // Initialize task scheduler
     task::init();
-    
-    // Create some test files and directories
-    let _ = fs::ROOT_FS.read().create_dir("/bin");
-    let _ = fs::ROOT_FS.read().create_dir("/home");
-    let _ = fs::ROOT_FS.read().create_file("/home/welcome.txt", b"Welcome to RustOS!\n".to_vec());
+
+    println!("Initializing APIC...");
+    match acpi::init().and_then(|_| interrupts::init_apic()) {
+        Ok(()) => println!("Interrupts routed through the IOAPIC"),
+        Err(e) => {
+            println!("Using the 8259 PIC: {}", e);
+            interrupts::start_pit_timer();
+        }
+    }
+
+    println!("Starting application processors...");
+    smp::init();
     
     // Spawn test tasks with different priorities
-    task::spawn_with_priority(high_priority_task, task::TaskPriority::High);
-    task::spawn_with_priority(normal_priority_task, task::TaskPriority::Normal);
-    task::spawn_with_priority(low_priority_task, task::TaskPriority::Low);
+    if let Err(e) = task::spawn_with_priority(high_priority_task, task::TaskPriority::High) {
+        println!("Failed to spawn test task: {}", e);
+    }
+    // The normal priority task runs in a group of its own, so `cgroup` has
+    // something to show
+    if let Err(e) = task::create_group("demo").and_then(|group| task::spawn_in_group(normal_priority_task, group)) {
+        println!("Failed to spawn test task: {}", e.as_str());
+    }
+    if let Err(e) = task::spawn_with_priority(low_priority_task, task::TaskPriority::Low) {
+        println!("Failed to spawn test task: {}", e);
+    }
+    if let Err(e) = task::spawn(block::cache::flush_task) {
+        println!("Failed to spawn buffer cache flush: {}", e);
+    }
     
     println!("Test tasks spawned successfully!");
     println!("Starting scheduler...");
 
-    // Create a keyboard event stream
-    let mut keyboard_events = keyboard::KeyboardStream::new();
-    
     println!("Starting shell...");
-    
-    let mut shell = shell::init();
-    let mut current_line = String::new();
-    print!("> ");  // Initial prompt
-    
-    loop {
-        if let Some(event) = keyboard_events.next().now_or_never().flatten() {
-            match event {
-                keyboard::KeyEvent::Char('\n') => {
-                    println!();  // New line after Enter
-                    if !current_line.is_empty() {
-                        shell.execute(&current_line);
-                        current_line.clear();
-                    }
-                    shell.reset_tab_completion();  // Reset tab completion state
-                    print!("> ");  // Shell prompt
-                },
-                keyboard::KeyEvent::Char(c) => {
-                    print!("{}", c);
-                    current_line.push(c);
-                    shell.reset_tab_completion();  // Reset tab completion when typing
-                },
-                keyboard::KeyEvent::SpecialKey(key) => {
-                    match key {
-                        KeyCode::Backspace => {
-                            if !current_line.is_empty() {
-                                current_line.pop();
-                                print!("\x08 \x08");  // Backspace, space, backspace
-                            }
-                            shell.reset_tab_completion();  // Reset tab completion on backspace
-                        },
-                        KeyCode::Tab => {
-                            if let Some(completed) = shell.tab_complete(&current_line) {
-                                // Clear current line
-                                while !current_line.is_empty() {
-                                    print!("\x08 \x08");
-                                    current_line.pop();
-                                }
-                                // Print and set new line
-                                print!("{}", completed);
-                                current_line = completed;
-                            }
-                        },
-                        KeyCode::ArrowUp => {
-                            // Clear current line
-                            while !current_line.is_empty() {
-                                print!("\x08 \x08");
-                                current_line.pop();
-                            }
-                            
-                            // Get previous command
-                            if let Some(cmd) = shell.previous_command() {
-                                current_line = cmd.to_string();
-                                print!("{}", current_line);
-                            }
-                            shell.reset_tab_completion();
-                        },
-                        KeyCode::ArrowDown => {
-                            // Clear current line
-                            while !current_line.is_empty() {
-                                print!("\x08 \x08");
-                                current_line.pop();
-                            }
-                            
-                            // Get next command
-                            if let Some(cmd) = shell.next_command() {
-                                current_line = cmd.to_string();
-                                print!("{}", current_line);
-                            }
-                            shell.reset_tab_completion();
-                        },
-                        _ => {}
-                    }
-                }
-            }
-        }
-        task::yield_now();
-    }
+
+    let mut executor = Executor::new();
+    executor.spawn(AsyncTask::new(shell::run()));
+    executor.spawn(AsyncTask::new(network::poll_task()));
+    executor.run();
 }
\ No newline at end of file
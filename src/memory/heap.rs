@@ -5,12 +5,149 @@ use x86_64::{
     VirtAddr,
 };
 use linked_list_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use crate::smp::{self, MAX_CPUS};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+// Half of it is for the buffer cache (block::cache) once disks are in use
+pub const HEAP_SIZE: usize = 2 * 1024 * 1024; // 2 MiB
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: InterruptSafeHeap = InterruptSafeHeap(LockedHeap::empty());
+
+/// Most control groups whose heap use can be tracked at once.
+pub const MAX_GROUPS: usize = 32;
+
+/// Stored in front of every allocation: the group slot it is charged to.
+const TAG_SIZE: usize = core::mem::size_of::<usize>();
+const NO_GROUP: usize = usize::MAX;
+
+/// Heap use of one control group. Slots are claimed by groups and stay taken
+/// after release until everything charged to them is freed.
+struct GroupHeap {
+    claimed: AtomicBool,
+    used: AtomicUsize,
+    limit: AtomicUsize,
+}
+
+impl GroupHeap {
+    /// Adds `bytes` unless that would take the group past its limit.
+    fn charge(&self, bytes: usize) -> bool {
+        let limit = self.limit.load(Ordering::Relaxed);
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&total| total <= limit)
+            })
+            .is_ok()
+    }
+}
+
+static GROUPS: [GroupHeap; MAX_GROUPS] = [const {
+    GroupHeap {
+        claimed: AtomicBool::new(false),
+        used: AtomicUsize::new(0),
+        limit: AtomicUsize::new(usize::MAX),
+    }
+}; MAX_GROUPS];
+
+/// Slot of the group of the task each CPU is running, set by the scheduler.
+static RUNNING: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(NO_GROUP) }; MAX_CPUS];
+
+/// Masks interrupts while the heap lock is held, so an interrupt handler that
+/// allocates (e.g. the scheduler growing a run queue) cannot spin forever on a
+/// lock its own CPU already holds. Allocations are charged to the running
+/// task's control group, if any, and fail once it is over its limit.
+struct InterruptSafeHeap(LockedHeap);
+
+impl InterruptSafeHeap {
+    /// Layout of the block holding `layout` behind its tag, and the offset
+    /// of the caller's part within it.
+    fn tagged(layout: Layout) -> Option<(Layout, usize)> {
+        let align = layout.align().max(TAG_SIZE);
+        let full = Layout::from_size_align(layout.size().checked_add(align)?, align).ok()?;
+        Some((full, align))
+    }
+}
+
+unsafe impl GlobalAlloc for InterruptSafeHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some((full, offset)) = Self::tagged(layout) else {
+            return ptr::null_mut();
+        };
+        let slot = RUNNING.get(smp::cpu_id()).map_or(NO_GROUP, |slot| slot.load(Ordering::Relaxed));
+        if slot != NO_GROUP && !GROUPS[slot].charge(layout.size()) {
+            return ptr::null_mut();
+        }
+        let block = interrupts::without_interrupts(|| self.0.alloc(full));
+        if block.is_null() {
+            if slot != NO_GROUP {
+                GROUPS[slot].used.fetch_sub(layout.size(), Ordering::Relaxed);
+            }
+            return block;
+        }
+        let ptr = block.add(offset);
+        (ptr as *mut usize).sub(1).write(slot);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // `alloc` succeeded for this layout, so tagging it cannot fail
+        let (full, offset) = Self::tagged(layout).unwrap();
+        let slot = (ptr as *mut usize).sub(1).read();
+        if slot != NO_GROUP {
+            GROUPS[slot].used.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        interrupts::without_interrupts(|| self.0.dealloc(ptr.sub(offset), full))
+    }
+}
+
+/// Claims a slot for a new control group's heap accounting; `None` when all
+/// are taken.
+pub fn claim_group() -> Option<usize> {
+    GROUPS.iter().position(|group| {
+        group.used.load(Ordering::Relaxed) == 0
+            && group.claimed.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }).map(|slot| {
+        GROUPS[slot].limit.store(usize::MAX, Ordering::Relaxed);
+        slot
+    })
+}
+
+/// Gives up a slot when its group is removed. Memory still charged to it
+/// keeps it from being reused until freed.
+pub fn release_group(slot: usize) {
+    GROUPS[slot].claimed.store(false, Ordering::SeqCst);
+}
+
+/// Heap bytes charged to the group in `slot`.
+pub fn group_usage(slot: usize) -> usize {
+    GROUPS[slot].used.load(Ordering::Relaxed)
+}
+
+/// Heap limit of the group in `slot`, if it has one.
+pub fn group_limit(slot: usize) -> Option<usize> {
+    Some(GROUPS[slot].limit.load(Ordering::Relaxed)).filter(|&limit| limit != usize::MAX)
+}
+
+/// Limits the heap use of the group in `slot`; fails if it already uses more.
+pub fn set_group_limit(slot: usize, limit: Option<usize>) -> bool {
+    let limit = limit.unwrap_or(usize::MAX);
+    if group_usage(slot) > limit {
+        return false;
+    }
+    GROUPS[slot].limit.store(limit, Ordering::Relaxed);
+    true
+}
+
+/// Charges what the calling CPU allocates from now on to the group in `slot`.
+pub fn set_running_group(slot: Option<usize>) {
+    if let Some(running) = RUNNING.get(smp::cpu_id()) {
+        running.store(slot.unwrap_or(NO_GROUP), Ordering::Relaxed);
+    }
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -38,12 +175,20 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.0.lock().init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
 }
 
+/// Bytes of the kernel heap in use, and its size.
+pub fn usage() -> (usize, usize) {
+    interrupts::without_interrupts(|| {
+        let heap = ALLOCATOR.0.lock();
+        (heap.used(), heap.size())
+    })
+}
+
 /// A wrapper around spin::Mutex to permit trait implementations.
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
@@ -1,24 +1,35 @@
 pub mod heap;
+pub mod stack;
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use x86_64::{
     structures::paging::{
         PageTable, PageTableFlags, PhysFrame, Size4KiB, FrameAllocator,
-        Mapper, Page, OffsetPageTable, Translate,
+        FrameDeallocator, Mapper, Page, OffsetPageTable, Translate,
     },
     VirtAddr, PhysAddr,
 };
+use x86_64::structures::paging::mapper::MapToError;
 use spin::Mutex;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 
 const PAGE_SIZE: usize = 4096;
 const PROGRAM_BASE: u64 = 0x400000;
 
+/// Virtual window device registers are mapped into by [`map_mmio`].
+const MMIO_START: u64 = 0x_5555_0000_0000;
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
 lazy_static! {
     pub(crate) static ref FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> =
         Mutex::new(None);
     pub(crate) static ref FRAME_ALLOCATOR_INITIALIZED: spin::Once<()> = spin::Once::new();
+    /// Active kernel page tables, handed over by [`install`].
+    static ref MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
 }
 
 #[derive(Debug)]
@@ -28,6 +39,18 @@ pub struct MemorySpace {
     heap_size: usize,
     code_start: VirtAddr,
     code_size: usize,
+    /// Bytes of program loaded at `PROGRAM_BASE`.
+    program_size: usize,
+}
+
+/// A range of a process's address space, as listed in `/proc/<pid>/maps`.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    pub start: VirtAddr,
+    pub size: usize,
+    pub writable: bool,
+    pub executable: bool,
+    pub name: &'static str,
 }
 
 impl MemorySpace {
@@ -48,6 +71,7 @@ impl MemorySpace {
             heap_size: 1024 * 1024, // 1MB heap
             code_start: VirtAddr::new(0x0000_0000_0000),
             code_size: 1024 * 1024, // 1MB code segment
+            program_size: 0,
         })
     }
 
@@ -92,18 +116,43 @@ impl MemorySpace {
                 );
             }
         }
+        self.program_size = num_pages * PAGE_SIZE;
         Ok(())
     }
 
     pub fn entry_point(&self) -> usize {
         PROGRAM_BASE as usize
     }
+
+    /// Loaded program and heap, in address order.
+    pub fn regions(&self) -> Vec<MemoryRegion> {
+        let mut regions = Vec::new();
+        if self.program_size > 0 {
+            regions.push(MemoryRegion {
+                start: VirtAddr::new(PROGRAM_BASE),
+                size: self.program_size,
+                writable: true,
+                executable: true,
+                name: "[program]",
+            });
+        }
+        regions.push(MemoryRegion {
+            start: self.heap_start,
+            size: self.heap_size,
+            writable: true,
+            executable: false,
+            name: "[heap]",
+        });
+        regions
+    }
 }
 
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
     total_frames: Option<usize>,
+    /// Frames given back, handed out again before fresh ones.
+    free: Vec<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
@@ -112,6 +161,7 @@ impl BootInfoFrameAllocator {
             memory_map,
             next: 0,
             total_frames: None,
+            free: Vec::new(),
         }
     }
     
@@ -132,6 +182,9 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free.pop() {
+            return Some(frame);
+        }
         if self.total_frames.is_none() {
             self.total_frames = Some(self.count_total_frames());
         }
@@ -146,6 +199,12 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free.push(frame);
+    }
+}
+
 pub fn init_frame_allocator(memory_map: &'static MemoryMap) {
     FRAME_ALLOCATOR_INITIALIZED.call_once(|| {
         let mut allocator = FRAME_ALLOCATOR.lock();
@@ -156,6 +215,20 @@ pub fn init_frame_allocator(memory_map: &'static MemoryMap) {
     });
 }
 
+/// Usable physical frames and how many have been handed out, once the frame
+/// allocator is initialized.
+pub fn frame_stats() -> Option<(usize, usize)> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut guard = FRAME_ALLOCATOR.lock();
+        let allocator = guard.as_mut()?;
+        if allocator.total_frames.is_none() {
+            allocator.total_frames = Some(allocator.count_total_frames());
+        }
+        let total = allocator.total_frames.unwrap();
+        Some((total, allocator.next.min(total) - allocator.free.len()))
+    })
+}
+
 pub fn ensure_frame_allocator_initialized() -> Result<(), &'static str> {
     if FRAME_ALLOCATOR_INITIALIZED.r#try().is_some() {
         Ok(())
@@ -165,10 +238,131 @@ pub fn ensure_frame_allocator_initialized() -> Result<(), &'static str> {
 }
 
 pub fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
     let level_4_table = unsafe { active_level_4_table(physical_memory_offset) };
     unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) }
 }
 
+/// Keeps the boot page tables and frame allocator around once the heap is up,
+/// so later subsystems can map device memory.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *MAPPER.lock() = Some(mapper);
+    FRAME_ALLOCATOR_INITIALIZED.call_once(|| {
+        *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    });
+}
+
+/// Address through which physical memory can be accessed directly.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst) + addr.as_u64())
+}
+
+fn with_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> Result<R, &'static str> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) => Ok(f(mapper, frame_allocator)),
+            _ => Err("Page tables not installed"),
+        }
+    })
+}
+
+/// Maps `size` bytes of device registers at `phys` uncached and returns the
+/// virtual address of `phys`.
+pub fn map_mmio(phys: PhysAddr, size: usize) -> Result<VirtAddr, &'static str> {
+    let offset = phys.as_u64() % PAGE_SIZE as u64;
+    let pages = (offset as usize + size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let virt = NEXT_MMIO.fetch_add((pages * PAGE_SIZE) as u64, Ordering::SeqCst);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+
+    with_mapper(|mapper, frame_allocator| {
+        for i in 0..pages as u64 {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt + i * PAGE_SIZE as u64));
+            let frame = PhysFrame::containing_address(phys - offset + i * PAGE_SIZE as u64);
+            unsafe {
+                mapper.map_to(page, frame, flags, frame_allocator)
+                    .map_err(|_| "Failed to map device memory")?
+                    .flush();
+            }
+        }
+        Ok(VirtAddr::new(virt + offset))
+    })?
+}
+
+/// Physical address behind `addr` in the kernel page tables.
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper, _| mapper.translate_addr(addr)).ok().flatten()
+}
+
+/// Allocates `pages` physically contiguous, zeroed frames that a device can
+/// reach by DMA and returns the address of the first. They stay allocated for
+/// good, and are accessed through [`phys_to_virt`].
+pub fn alloc_dma(pages: usize) -> Result<PhysAddr, &'static str> {
+    let start = with_mapper(|_, frame_allocator| {
+        // Fresh frames come out in address order, so a run only breaks at the
+        // end of a memory region or on a frame that was given back. Frames
+        // that end up outside the run go back once it is found.
+        let mut skipped = Vec::new();
+        let mut run: Option<(PhysFrame, usize)> = None;
+        let result = loop {
+            let frame = match frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => break Err("out of memory for DMA"),
+            };
+            run = match run {
+                Some((start, count)) if frame == start + count as u64 => Some((start, count + 1)),
+                Some((start, count)) => {
+                    skipped.extend((0..count as u64).map(|i| start + i));
+                    Some((frame, 1))
+                }
+                None => Some((frame, 1)),
+            };
+            if let Some((start, _)) = run.filter(|&(_, count)| count >= pages) {
+                break Ok(start.start_address());
+            }
+        };
+        if let (Err(_), Some((start, count))) = (&result, run) {
+            skipped.extend((0..count as u64).map(|i| start + i));
+        }
+        for frame in skipped {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+        result
+    })??;
+    unsafe {
+        core::ptr::write_bytes(phys_to_virt(start).as_mut_ptr::<u8>(), 0, pages * PAGE_SIZE);
+    }
+    Ok(start)
+}
+
+/// Makes `frame` reachable at the same virtual address, e.g. for code that runs
+/// while paging is being switched on. Already identity-mapped frames are left
+/// alone.
+pub fn identity_map(frame: PhysFrame, flags: PageTableFlags) -> Result<(), &'static str> {
+    with_mapper(|mapper, frame_allocator| {
+        let addr = VirtAddr::new(frame.start_address().as_u64());
+        if mapper.translate_addr(addr) == Some(frame.start_address()) {
+            return Ok(());
+        }
+        let page = Page::<Size4KiB>::containing_address(addr);
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(MapToError::PageAlreadyMapped(_)) => Err("Page already mapped elsewhere"),
+            Err(_) => Err("Failed to identity map frame"),
+        }
+    })?
+}
+
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     -> &'static mut PageTable
 {
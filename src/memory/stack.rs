@@ -0,0 +1,124 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use super::{with_mapper, PAGE_SIZE};
+
+/// Virtual region task stacks are carved from. It is split into fixed slots;
+/// a stack is mapped at the top of its slot and the pages below it stay
+/// unmapped, so running off the bottom of a stack faults instead of
+/// overwriting whatever lies below.
+const STACK_REGION_START: u64 = 0x_6666_0000_0000;
+const SLOT_SIZE: usize = 64 * 1024;
+const MAX_SLOTS: usize = 1024;
+
+/// Largest stack a slot can hold while keeping at least one guard page.
+const MAX_STACK_PAGES: usize = SLOT_SIZE / PAGE_SIZE - 1;
+
+/// No task owns the slot.
+const NO_OWNER: usize = usize::MAX;
+
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+/// Released slots with the number of pages still mapped in them. Slots are
+/// never unmapped, which spares a TLB shootdown on every task exit.
+static FREE_SLOTS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+/// Task owning each slot, read by the page fault handler without locking.
+static OWNERS: [AtomicUsize; MAX_SLOTS] = [const { AtomicUsize::new(NO_OWNER) }; MAX_SLOTS];
+
+/// A kernel stack with an unmapped guard area below it.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+    pages: usize,
+}
+
+impl KernelStack {
+    /// Maps a stack of at least `size` bytes for task `owner`.
+    pub fn allocate(size: usize, owner: usize) -> Result<Self, &'static str> {
+        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        if pages == 0 || pages > MAX_STACK_PAGES {
+            return Err("invalid stack size");
+        }
+
+        let reused = interrupts::without_interrupts(|| {
+            let mut free = FREE_SLOTS.lock();
+            let index = free.iter().position(|&(_, mapped)| mapped >= pages)?;
+            Some(free.swap_remove(index))
+        });
+        let stack = match reused {
+            Some((slot, mapped)) => KernelStack { slot, pages: mapped },
+            None => {
+                let slot = NEXT_SLOT.fetch_add(1, Ordering::SeqCst);
+                if slot >= MAX_SLOTS {
+                    return Err("out of kernel stack slots");
+                }
+                map_pages(slot_top(slot), pages)?;
+                KernelStack { slot, pages }
+            }
+        };
+        // Neither a recycled slot nor a fresh frame may leak what it held
+        unsafe {
+            core::ptr::write_bytes((stack.top() - stack.size() as u64).as_mut_ptr::<u8>(), 0, stack.size());
+        }
+        OWNERS[stack.slot].store(owner, Ordering::SeqCst);
+        Ok(stack)
+    }
+
+    /// Initial stack pointer; the stack grows down from here.
+    pub fn top(&self) -> VirtAddr {
+        slot_top(self.slot)
+    }
+
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        OWNERS[self.slot].store(NO_OWNER, Ordering::SeqCst);
+        interrupts::without_interrupts(|| {
+            FREE_SLOTS.lock().push((self.slot, self.pages));
+        });
+    }
+}
+
+fn slot_top(slot: usize) -> VirtAddr {
+    VirtAddr::new(STACK_REGION_START + ((slot + 1) * SLOT_SIZE) as u64)
+}
+
+/// Maps `pages` fresh pages directly below `top`.
+fn map_pages(top: VirtAddr, pages: usize) -> Result<(), &'static str> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    with_mapper(|mapper, frame_allocator| {
+        for i in 1..=pages as u64 {
+            let page = Page::<Size4KiB>::containing_address(top - i * PAGE_SIZE as u64);
+            let frame = frame_allocator.allocate_frame().ok_or("out of memory for stack")?;
+            unsafe {
+                mapper.map_to(page, frame, flags, frame_allocator)
+                    .map_err(|_| "Failed to map stack page")?
+                    .flush();
+            }
+        }
+        Ok(())
+    })?
+}
+
+/// Task whose guard area contains `addr`. Every page of the region that is not
+/// part of a live stack is unmapped, so a fault anywhere in a slot owned by a
+/// task is that task running off the bottom of its stack.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<usize> {
+    let offset = addr.as_u64().checked_sub(STACK_REGION_START)? as usize;
+    let slot = offset / SLOT_SIZE;
+    if slot >= MAX_SLOTS {
+        return None;
+    }
+    match OWNERS[slot].load(Ordering::SeqCst) {
+        NO_OWNER => None,
+        owner => Some(owner),
+    }
+}
@@ -191,6 +191,11 @@ fn handle_arp_reply(packet: ArpPacket) {
     });
 }
 
+/// Cached address mappings, in address order.
+pub fn entries() -> Vec<(IpAddress, MacAddress)> {
+    ARP_CACHE.lock().iter().map(|(ip, entry)| (*ip, entry.mac_address)).collect()
+}
+
 pub fn get_mac_address(ip: IpAddress) -> Option<MacAddress> {
     // Check cache first
     let mut cache = ARP_CACHE.lock();
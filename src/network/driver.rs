@@ -1,7 +1,14 @@
 use alloc::vec::Vec;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
 use spin::Mutex;
 use x86_64::instructions::port::{Port, PortReadAccess, PortWriteAccess};
+use crate::interrupts::{self, IrqReturn};
 use crate::network::{MacAddress, NETWORK_INTERFACE};
+use crate::pci::{self, Bar};
 use alloc::boxed::Box;
 use lazy_static::lazy_static;
 
@@ -23,6 +30,7 @@ pub struct Rtl8139 {
 
 const RTL8139_CMD: u16 = 0x37;
 const RTL8139_IMR: u16 = 0x3C;
+const RTL8139_ISR: u16 = 0x3E;
 const RTL8139_RCR: u16 = 0x44;
 const RTL8139_CONFIG_1: u16 = 0x52;
 
@@ -142,10 +150,87 @@ lazy_static! {
     pub static ref NETWORK_DRIVER: Mutex<Option<Box<dyn NetworkDriver + Send>>> = Mutex::new(None);
 }
 
+const RTL8139_VENDOR_ID: u16 = 0x10EC;
+const RTL8139_DEVICE_ID: u16 = 0x8139;
+
+/// I/O base of the card, for the interrupt handler, which must not take
+/// `NETWORK_DRIVER`.
+static IRQ_IO_BASE: AtomicU16 = AtomicU16::new(0);
+static IRQ_PENDING: AtomicBool = AtomicBool::new(false);
+static IRQ_WAKER: AtomicWaker = AtomicWaker::new();
+
+fn handle_interrupt() -> IrqReturn {
+    let io_base = IRQ_IO_BASE.load(Ordering::SeqCst);
+    if io_base == 0 {
+        return IrqReturn::None;
+    }
+
+    let mut isr_port: Port<u16> = Port::new(io_base + RTL8139_ISR);
+    let status = unsafe { isr_port.read() };
+    if status == 0 {
+        // Someone else on a shared line
+        return IrqReturn::None;
+    }
+    // Bits are cleared by writing them back
+    unsafe {
+        isr_port.write(status);
+    }
+    IRQ_PENDING.store(true, Ordering::SeqCst);
+    IRQ_WAKER.wake();
+    IrqReturn::Handled
+}
+
+/// Yields once for every batch of interrupts raised by the card since the
+/// last item.
+pub struct InterruptStream {
+    _private: (),
+}
+
+impl InterruptStream {
+    pub fn new() -> Self {
+        InterruptStream { _private: () }
+    }
+}
+
+impl Stream for InterruptStream {
+    type Item = ();
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<()>> {
+        if IRQ_PENDING.swap(false, Ordering::SeqCst) {
+            return Poll::Ready(Some(()));
+        }
+
+        IRQ_WAKER.register(cx.waker());
+        // Re-check in case the interrupt arrived before the waker was registered
+        if IRQ_PENDING.swap(false, Ordering::SeqCst) {
+            IRQ_WAKER.take();
+            Poll::Ready(Some(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
 pub fn init() -> Result<(), &'static str> {
-    let mut driver = Rtl8139::new(0xC000); // Default I/O base for QEMU
+    let device = pci::find_device(RTL8139_VENDOR_ID, RTL8139_DEVICE_ID)
+        .ok_or("no RTL8139 on the PCI bus")?;
+    let io_base = match device.bar(0) {
+        Some(Bar::Io(io_base)) => io_base,
+        _ => return Err("RTL8139 has no I/O BAR"),
+    };
+    device.enable();
+
+    let mut driver = Rtl8139::new(io_base);
     driver.init()?;
 
+    // Without an interrupt the card is still polled on every timer tick
+    if let Some(line) = device.interrupt_line() {
+        IRQ_IO_BASE.store(io_base, Ordering::SeqCst);
+        if let Err(e) = interrupts::register_irq(line, "rtl8139", handle_interrupt) {
+            crate::println!("RTL8139: IRQ {} unavailable: {}", line, e);
+        }
+    }
+
     // Store MAC address in network interface
     if let Some(interface) = &mut *NETWORK_INTERFACE.lock() {
         interface.mac_address = driver.mac_address();
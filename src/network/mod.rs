@@ -4,6 +4,8 @@ use alloc::boxed::Box;
 use core::fmt;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use futures_util::StreamExt;
+use crate::task::timer;
 
 pub mod driver;
 pub mod ip;
@@ -130,42 +132,9 @@ impl NetworkInterface {
     }
 
     fn process_rx_buffer(&mut self) {
-        if let Some(frame) = ethernet::EthernetFrame::parse(&self.rx_buffer) {
-            match frame.ethertype() {
-                ethernet::EtherType::Arp => {
-                    if let Some(arp_packet) = arp::ArpPacket::parse(frame.payload()) {
-                        arp::handle_arp_packet(arp_packet);
-                    }
-                }
-                ethernet::EtherType::Ipv4 => {
-                    if let Some(ip_packet) = ip::IpPacket::parse(frame.payload()) {
-                        match ip_packet.protocol() {
-                            ip::IpProtocol::Icmp => {
-                                if let Some(icmp_packet) = icmp::IcmpPacket::parse(ip_packet.payload()) {
-                                    icmp::handle_icmp_packet(icmp_packet, ip_packet.source());
-                                }
-                            }
-                            ip::IpProtocol::Tcp => {
-                                if let Some(tcp_segment) = tcp::TcpSegment::parse(ip_packet.payload()) {
-                                    tcp::handle_tcp_segment(tcp_segment, ip_packet.source(), ip_packet.destination());
-                                }
-                            }
-                            ip::IpProtocol::Udp => {
-                                if let Some(udp_packet) = udp::UdpPacket::parse(ip_packet.payload()) {
-                                    if udp_packet.destination_port == 68 { // DHCP client port
-                                        let _ = dhcp::handle_dhcp_packet(&udp_packet, self);
-                                    } else {
-                                        udp::handle_udp_packet(udp_packet, ip_packet.source());
-                                    }
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-                _ => {}
-            }
-        }
+        let rx_buffer = core::mem::take(&mut self.rx_buffer);
+        handle_frame(&rx_buffer, Some(self));
+        self.rx_buffer = rx_buffer;
     }
 
     fn process_tx_buffer(&mut self) {
@@ -206,6 +175,86 @@ impl NetworkInterface {
     }
 }
 
+/// Dispatches a received Ethernet frame to the protocol handlers. Handlers
+/// lock `NETWORK_INTERFACE` themselves, so callers that already hold it pass
+/// the interface in for the DHCP path instead.
+fn handle_frame(data: &[u8], interface: Option<&mut NetworkInterface>) {
+    if let Some(frame) = ethernet::EthernetFrame::parse(data) {
+        match frame.ethertype() {
+            ethernet::EtherType::Arp => {
+                if let Some(arp_packet) = arp::ArpPacket::parse(frame.payload()) {
+                    arp::handle_arp_packet(arp_packet);
+                }
+            }
+            ethernet::EtherType::Ipv4 => {
+                if let Some(ip_packet) = ip::IpPacket::parse(frame.payload()) {
+                    match ip_packet.protocol() {
+                        ip::IpProtocol::Icmp => {
+                            if let Some(icmp_packet) = icmp::IcmpPacket::parse(ip_packet.payload()) {
+                                icmp::handle_icmp_packet(icmp_packet, ip_packet.source());
+                            }
+                        }
+                        ip::IpProtocol::Tcp => {
+                            if let Some(tcp_segment) = tcp::TcpSegment::parse(ip_packet.payload()) {
+                                tcp::handle_tcp_segment(tcp_segment, ip_packet.source(), ip_packet.destination());
+                            }
+                        }
+                        ip::IpProtocol::Udp => {
+                            if let Some(udp_packet) = udp::UdpPacket::parse(ip_packet.payload()) {
+                                if udp_packet.destination_port == 68 { // DHCP client port
+                                    match interface {
+                                        Some(interface) => {
+                                            let _ = dhcp::handle_dhcp_packet(&udp_packet, interface);
+                                        }
+                                        None => {
+                                            if let Some(interface) = &mut *NETWORK_INTERFACE.lock() {
+                                                let _ = dhcp::handle_dhcp_packet(&udp_packet, interface);
+                                            }
+                                        }
+                                    }
+                                } else {
+                                    udp::handle_udp_packet(udp_packet, ip_packet.source());
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Upper bound on frames drained per call so a misbehaving card cannot keep
+/// the caller busy forever.
+const MAX_FRAMES_PER_POLL: usize = 16;
+
+/// Moves frames waiting in the driver into the protocol stack.
+pub fn poll() {
+    for _ in 0..MAX_FRAMES_PER_POLL {
+        let frame = match &mut *NETWORK_DRIVER.lock() {
+            Some(driver) => driver.receive(),
+            None => None,
+        };
+        match frame {
+            Some(frame) => handle_frame(&frame, None),
+            None => break,
+        }
+    }
+}
+
+/// Executor task that polls the network driver whenever the card raises an
+/// interrupt, and on every timer tick in case it has none, waking any socket
+/// futures whose data arrived.
+pub async fn poll_task() {
+    let ticks = timer::interval(1).map(|_| ());
+    let mut events = futures_util::stream::select(ticks, driver::InterruptStream::new());
+    while events.next().await.is_some() {
+        poll();
+    }
+}
+
 lazy_static! {
     pub static ref NETWORK_INTERFACE: Mutex<Option<NetworkInterface>> = Mutex::new(None);
 }
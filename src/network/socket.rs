@@ -2,6 +2,9 @@ use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
 use core::time::Duration;
 use spin::Mutex;
 use lazy_static::lazy_static;
@@ -37,6 +40,7 @@ pub struct Socket {
     remote_port: Option<u16>,
     receive_buffer: Vec<u8>,
     tcp_connection: Option<tcp::TcpConnection>,
+    rx_waker: Option<Waker>,
 }
 
 pub type SocketId = u32;
@@ -58,6 +62,7 @@ impl Socket {
             remote_port: None,
             receive_buffer: Vec::new(),
             tcp_connection: None,
+            rx_waker: None,
         })
     }
 
@@ -205,6 +210,9 @@ impl Socket {
     fn handle_udp_data(&mut self, data: &[u8], src_ip: IpAddress, src_port: u16) {
         if self.remote_addr.is_none() || self.remote_addr == Some(src_ip) {
             self.receive_buffer.extend_from_slice(data);
+            if let Some(waker) = self.rx_waker.take() {
+                waker.wake();
+            }
         }
     }
 
@@ -215,6 +223,24 @@ impl Socket {
     pub fn state(&self) -> SocketState {
         self.state
     }
+
+    pub fn socket_type(&self) -> SocketType {
+        self.socket_type
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    /// Address and port of the peer, once connected.
+    pub fn remote(&self) -> Option<(IpAddress, u16)> {
+        self.remote_addr.zip(self.remote_port)
+    }
+
+    /// State of the TCP connection behind a stream socket.
+    pub fn tcp_state(&self) -> Option<tcp::TcpState> {
+        self.tcp_connection.as_ref().map(|connection| connection.state())
+    }
 }
 
 pub fn socket(socket_type: SocketType) -> Result<SocketId, &'static str> {
@@ -225,7 +251,9 @@ pub fn socket(socket_type: SocketType) -> Result<SocketId, &'static str> {
 }
 
 pub fn bind(socket_id: SocketId, addr: IpAddress, port: u16) -> Result<(), &'static str> {
-    if let Some(socket) = SOCKETS.lock().get(&socket_id) {
+    // `Socket::bind` looks through the table itself
+    let socket = SOCKETS.lock().get(&socket_id).cloned();
+    if let Some(socket) = socket {
         socket.lock().bind(addr, port)
     } else {
         Err("Invalid socket")
@@ -289,7 +317,12 @@ pub fn recv_from(socket_id: SocketId, buffer: &mut [u8], timeout: core::time::Du
 }
 
 pub fn close(socket_id: SocketId) -> Result<(), &'static str> {
-    SOCKETS.lock().remove(&socket_id);
+    if let Some(socket) = SOCKETS.lock().remove(&socket_id) {
+        let socket = socket.lock();
+        if socket.socket_type == SocketType::Dgram && socket.local_port != 0 {
+            udp::unbind(socket.local_port);
+        }
+    }
     Ok(())
 }
 
@@ -301,6 +334,59 @@ pub fn receive(socket_id: SocketId, buffer: &mut [u8]) -> Result<(usize, IpAddre
     }
 }
 
+/// Future returned by [`recv_async`].
+pub struct Recv {
+    socket_id: SocketId,
+}
+
+/// Resolves with everything buffered on the socket once data is available.
+pub fn recv_async(socket_id: SocketId) -> Recv {
+    Recv { socket_id }
+}
+
+impl Future for Recv {
+    type Output = Result<Vec<u8>, &'static str>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let socket = match SOCKETS.lock().get(&self.socket_id) {
+            Some(socket) => Arc::clone(socket),
+            None => return Poll::Ready(Err("Invalid socket")),
+        };
+
+        let mut socket = socket.lock();
+        if socket.receive_buffer.is_empty() {
+            socket.rx_waker = Some(cx.waker().clone());
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(core::mem::take(&mut socket.receive_buffer)))
+        }
+    }
+}
+
+/// Hands a TCP segment to the stream socket bound to its destination port,
+/// moving whatever payload the connection accepted into the socket's receive
+/// buffer.
+pub fn handle_tcp_segment(port: u16, segment: tcp::TcpSegment) {
+    let socket = match find_socket_by_port(port) {
+        Some(socket) => socket,
+        None => return,
+    };
+    let mut socket = socket.lock();
+    let received = match socket.tcp_connection.as_mut() {
+        Some(connection) => {
+            connection.handle_segment(segment);
+            connection.take_received()
+        }
+        None => return,
+    };
+    if !received.is_empty() {
+        socket.receive_buffer.extend_from_slice(&received);
+        if let Some(waker) = socket.rx_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
 fn find_socket_by_port(port: u16) -> Option<Arc<Mutex<Socket>>> {
     for socket in SOCKETS.lock().values() {
         let socket_ref = socket.lock();
@@ -2,7 +2,7 @@ use alloc::vec::Vec;
 use alloc::string::String;
 use alloc::boxed::Box;
 use core::fmt;
-use crate::network::{IpAddress, NETWORK_DRIVER};
+use crate::network::{socket, IpAddress, NETWORK_DRIVER};
 use spin::Mutex;
 
 /// Length of TCP header without options
@@ -256,6 +256,11 @@ impl TcpConnection {
         }
     }
 
+    /// Returns the current state of the connection
+    pub fn state(&self) -> TcpState {
+        self.state
+    }
+
     /// Initiates a TCP connection to the specified remote endpoint
     pub fn connect(&mut self, remote_addr: IpAddress, remote_port: u16) -> Result<(), &'static str> {
         if self.state != TcpState::Closed {
@@ -426,6 +431,11 @@ impl TcpConnection {
         Ok(())
     }
 
+    /// Takes the payload received since the last call.
+    pub fn take_received(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.receive_buffer)
+    }
+
     pub fn start_listen(&mut self) -> Result<(), &'static str> {
         if self.state != TcpState::Closed {
             return Err("Connection not in closed state");
@@ -437,5 +447,6 @@ impl TcpConnection {
 
 /// Handles an incoming TCP segment
 pub fn handle_tcp_segment(segment: TcpSegment, source_ip: IpAddress, dest_ip: IpAddress) {
-    // TODO: Implement TCP connection handling logic
+    let port = segment.destination_port;
+    socket::handle_tcp_segment(port, segment);
 }
\ No newline at end of file
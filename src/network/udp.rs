@@ -130,6 +130,11 @@ pub fn unbind(port: PortNumber) {
     }
 }
 
+/// Ports with a bound callback, in port order.
+pub fn bound_ports() -> Vec<PortNumber> {
+    UDP_SOCKETS.lock().keys().copied().collect()
+}
+
 pub fn send(
     source_port: PortNumber,
     destination_ip: IpAddress,
@@ -0,0 +1,166 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const REG_ID: u8 = 0x00;
+const REG_COMMAND: u8 = 0x04;
+const REG_CLASS: u8 = 0x08;
+const REG_HEADER: u8 = 0x0C;
+const REG_BAR0: u8 = 0x10;
+const REG_INTERRUPT: u8 = 0x3C;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+const HEADER_MULTIFUNCTION: u32 = 0x80 << 16;
+const BAR_IO: u32 = 1;
+
+/// Interrupt line value meaning the firmware did not route the device.
+const NO_INTERRUPT_LINE: u8 = 0xFF;
+
+/// The address and data ports form a pair; a second access must not slip in
+/// between them.
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// A function on the PCI bus, addressed through configuration mechanism #1.
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+}
+
+/// A decoded base address register.
+#[derive(Debug, Clone, Copy)]
+pub enum Bar {
+    Io(u16),
+    Memory(u64),
+}
+
+impl PciDevice {
+    fn address(&self, offset: u8) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.slot as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        interrupts::without_interrupts(|| {
+            let _guard = CONFIG_LOCK.lock();
+            unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(self.address(offset));
+                Port::<u32>::new(CONFIG_DATA).read()
+            }
+        })
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        interrupts::without_interrupts(|| {
+            let _guard = CONFIG_LOCK.lock();
+            unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(self.address(offset));
+                Port::<u32>::new(CONFIG_DATA).write(value);
+            }
+        })
+    }
+
+    /// Decodes base address register `index` (0-5). 64-bit memory BARs take
+    /// up `index` and `index + 1`.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if index > 5 {
+            return None;
+        }
+        let offset = REG_BAR0 + index * 4;
+        let raw = self.read_u32(offset);
+        if raw == 0 {
+            return None;
+        }
+        if raw & BAR_IO != 0 {
+            return Some(Bar::Io((raw & !0x3) as u16));
+        }
+        let low = (raw & !0xF) as u64;
+        // Type field 0b10: 64-bit BAR
+        if (raw >> 1) & 0b11 == 0b10 && index < 5 {
+            let high = self.read_u32(offset + 4) as u64;
+            return Some(Bar::Memory(high << 32 | low));
+        }
+        Some(Bar::Memory(low))
+    }
+
+    /// Interrupt line the firmware routed the device's INTx pin to, if any.
+    pub fn interrupt_line(&self) -> Option<u8> {
+        let line = self.read_u32(REG_INTERRUPT) as u8;
+        if line == NO_INTERRUPT_LINE { None } else { Some(line) }
+    }
+
+    /// Turns on I/O and memory decoding and lets the device master the bus,
+    /// which it needs for DMA.
+    pub fn enable(&self) {
+        let value = self.read_u32(REG_COMMAND);
+        let command = value as u16 | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER;
+        self.write_u32(REG_COMMAND, (value & 0xFFFF_0000) | command as u32);
+    }
+}
+
+fn probe(bus: u8, slot: u8, function: u8) -> Option<PciDevice> {
+    let mut device = PciDevice {
+        bus,
+        slot,
+        function,
+        vendor_id: 0,
+        device_id: 0,
+        class: 0,
+        subclass: 0,
+    };
+    let id = device.read_u32(REG_ID);
+    if id & 0xFFFF == 0xFFFF {
+        return None;
+    }
+    device.vendor_id = id as u16;
+    device.device_id = (id >> 16) as u16;
+    let class = device.read_u32(REG_CLASS);
+    device.class = (class >> 24) as u8;
+    device.subclass = (class >> 16) as u8;
+    Some(device)
+}
+
+/// Calls `f` for every function present on the bus.
+pub fn for_each_device(mut f: impl FnMut(&PciDevice)) {
+    for bus in 0..=255u8 {
+        for slot in 0..32 {
+            let first = match probe(bus, slot, 0) {
+                Some(device) => device,
+                None => continue,
+            };
+            f(&first);
+            if first.read_u32(REG_HEADER) & HEADER_MULTIFUNCTION != 0 {
+                for function in 1..8 {
+                    if let Some(device) = probe(bus, slot, function) {
+                        f(&device);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// First function with the given vendor and device ID.
+pub fn find_device(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    let mut found = None;
+    for_each_device(|device| {
+        if found.is_none() && device.vendor_id == vendor_id && device.device_id == device_id {
+            found = Some(*device);
+        }
+    });
+    found
+}
@@ -0,0 +1,237 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::fs::{self, lock, notify::Inotify, File, FileStats, FsError, Result};
+
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+const O_ACCMODE: usize = 3;
+pub const O_CREAT: usize = 0o100;
+pub const O_TRUNC: usize = 0o1000;
+pub const O_APPEND: usize = 0o2000;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// Descriptors 0-2 are the console.
+const FIRST_FD: usize = 3;
+
+/// A file opened by a process, with its own position. I/O goes through a
+/// copy taken from the `FileTable`, so the process need not stay locked
+/// while it runs; the position is stored back afterwards.
+#[derive(Clone)]
+pub struct OpenFile {
+    file: Arc<dyn File>,
+    path: String,
+    offset: usize,
+    flags: usize,
+    /// The instance behind an inotify descriptor, which `file` also reads.
+    inotify: Option<Arc<Inotify>>,
+}
+
+impl OpenFile {
+    /// Opens the absolute `path` with `O_*` flags, for `FileTable::install`.
+    /// An access mode other than the three defined fails, as on Linux.
+    pub fn open(path: &str, flags: usize) -> Result<Self> {
+        if !matches!(flags & O_ACCMODE, O_RDONLY | O_WRONLY | O_RDWR) {
+            return Err(FsError::InvalidArgument);
+        }
+        let root = fs::ROOT_FS.read();
+        let file = match root.get_file(path) {
+            Err(FsError::NotFound) if flags & O_CREAT != 0 => {
+                root.create_file(path, Vec::new())?;
+                root.get_file(path)?
+            }
+            result => result?,
+        };
+        if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY {
+            file.set_len(0)?;
+        }
+
+        Ok(Self {
+            file,
+            path: String::from(path),
+            offset: 0,
+            flags,
+            inotify: None,
+        })
+    }
+
+    /// Absolute path the file was opened by.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn flags(&self) -> usize {
+        self.flags
+    }
+
+    fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+
+    /// Reads at the position and advances it.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.readable() {
+            return Err(FsError::PermissionDenied);
+        }
+        let count = self.file.read_at(self.offset, buf)?;
+        self.offset += count;
+        Ok(count)
+    }
+
+    /// Writes at the position, or at the end of the file with `O_APPEND`,
+    /// and advances it.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize> {
+        if !self.writable() {
+            return Err(FsError::PermissionDenied);
+        }
+        if self.flags & O_APPEND != 0 {
+            self.offset = self.file.stats()?.size;
+        }
+        let count = self.file.write_at(self.offset, data)?;
+        self.offset += count;
+        Ok(count)
+    }
+
+    pub fn stat(&self) -> Result<FileStats> {
+        self.file.stats()
+    }
+
+    /// Moves the position as `lseek` does and returns the new one. Seeking
+    /// past the end is allowed; a later write leaves a hole.
+    pub fn seek(&mut self, offset: isize, whence: usize) -> Result<usize> {
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => self.offset,
+            SEEK_END => self.file.stats()?.size,
+            _ => return Err(FsError::InvalidArgument),
+        };
+        let position = (base as isize).checked_add(offset)
+            .filter(|&position| position >= 0)
+            .ok_or(FsError::InvalidArgument)?;
+        self.offset = position as usize;
+        Ok(self.offset)
+    }
+}
+
+/// Open files of a process, indexed by descriptor.
+pub struct FileTable {
+    /// Process the table belongs to, which owns the locks taken through it.
+    pid: usize,
+    files: Vec<Option<OpenFile>>,
+}
+
+impl fmt::Debug for FileTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.iter().map(|(fd, open_file)| (fd, open_file.path.as_str())))
+            .finish()
+    }
+}
+
+impl FileTable {
+    pub fn new(pid: usize) -> Self {
+        Self { pid, files: Vec::new() }
+    }
+
+    /// Opens a new inotify instance and returns its descriptor.
+    pub fn open_inotify(&mut self) -> usize {
+        let instance = Inotify::new();
+        self.install(OpenFile {
+            file: Arc::clone(&instance) as Arc<dyn File>,
+            path: String::from("anon_inode:inotify"),
+            offset: 0,
+            flags: O_RDONLY,
+            inotify: Some(instance),
+        })
+    }
+
+    /// The inotify instance open as `fd`.
+    pub fn inotify(&self, fd: usize) -> Result<Arc<Inotify>> {
+        self.get(fd)?.inotify.clone().ok_or(FsError::InvalidArgument)
+    }
+
+    /// Puts `open_file` in the lowest free descriptor and returns it.
+    pub fn install(&mut self, open_file: OpenFile) -> usize {
+        let slot = match self.files.iter().position(|f| f.is_none()) {
+            Some(slot) => slot,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+        };
+        self.files[slot] = Some(open_file);
+        slot + FIRST_FD
+    }
+
+    /// Open descriptors and their files, lowest first.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &OpenFile)> {
+        self.files.iter().enumerate().filter_map(|(slot, open_file)| {
+            open_file.as_ref().map(|open_file| (slot + FIRST_FD, open_file))
+        })
+    }
+
+    pub fn get(&self, fd: usize) -> Result<&OpenFile> {
+        fd.checked_sub(FIRST_FD)
+            .and_then(|slot| self.files.get(slot))
+            .and_then(|f| f.as_ref())
+            .ok_or(FsError::BadDescriptor)
+    }
+
+    fn get_mut(&mut self, fd: usize) -> Result<&mut OpenFile> {
+        fd.checked_sub(FIRST_FD)
+            .and_then(|slot| self.files.get_mut(slot))
+            .and_then(|f| f.as_mut())
+            .ok_or(FsError::BadDescriptor)
+    }
+
+    /// Closes `fd`, releasing the advisory locks that go with it.
+    pub fn close(&mut self, fd: usize) -> Result<()> {
+        let key = self.lock_key(fd).ok();
+        self.get(fd)?;
+        self.files[fd - FIRST_FD] = None;
+        lock::release_fd(key, self.pid, fd);
+        Ok(())
+    }
+
+    /// Closes every descriptor, as when the process exits, releasing all its
+    /// advisory locks.
+    pub fn close_all(&mut self) {
+        self.files.clear();
+        lock::release_process(self.pid);
+    }
+
+    /// Identity of the file behind `fd`, which its advisory locks are kept
+    /// under.
+    pub fn lock_key(&self, fd: usize) -> Result<lock::Key> {
+        Ok(lock::key(&self.stat(fd)?))
+    }
+
+    pub fn stat(&self, fd: usize) -> Result<FileStats> {
+        self.get(fd)?.file.stats()
+    }
+
+    /// Stores the position a copy of `fd` was left at by I/O.
+    pub fn set_offset(&mut self, fd: usize, offset: usize) -> Result<()> {
+        self.get_mut(fd)?.offset = offset;
+        Ok(())
+    }
+}
+
+impl Drop for FileTable {
+    fn drop(&mut self) {
+        self.close_all();
+    }
+}
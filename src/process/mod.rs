@@ -1,8 +1,9 @@
 use alloc::{string::String, vec::Vec, sync::Arc};
 use spin::RwLock;
 use lazy_static::lazy_static;
-use crate::{memory, task, println};
+use crate::{fs, memory, task, println};
 
+pub mod fd;
 pub mod syscall;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -20,6 +21,9 @@ pub struct Process {
     name: String,
     memory_space: memory::MemorySpace,
     task: Arc<RwLock<task::Task>>,
+    /// Working directory relative paths in system calls are resolved against.
+    cwd: String,
+    files: fd::FileTable,
 }
 
 impl Process {
@@ -42,7 +46,7 @@ impl Process {
         let entry_point = memory_space.entry_point();
         let task = Arc::new(RwLock::new(task::Task::new(unsafe {
             core::mem::transmute::<usize, fn()>(entry_point)
-        })));
+        })?));
 
         Ok(Self {
             id: pid,
@@ -50,6 +54,8 @@ impl Process {
             name,
             memory_space,
             task,
+            cwd: "/".into(),
+            files: fd::FileTable::new(pid),
         })
     }
 
@@ -64,6 +70,34 @@ impl Process {
     pub fn state(&self) -> ProcessState {
         self.state
     }
+
+    pub fn memory_space(&self) -> &memory::MemorySpace {
+        &self.memory_space
+    }
+
+    pub fn task(&self) -> &Arc<RwLock<task::Task>> {
+        &self.task
+    }
+
+    pub fn files(&self) -> &fd::FileTable {
+        &self.files
+    }
+
+    pub fn files_mut(&mut self) -> &mut fd::FileTable {
+        &mut self.files
+    }
+
+    pub fn cwd(&self) -> &str {
+        &self.cwd
+    }
+
+    /// Changes the working directory to `path`, which must be a directory.
+    pub fn set_cwd(&mut self, path: &str) -> fs::Result<()> {
+        let path = fs::resolve_path(&self.cwd, path)?;
+        fs::ROOT_FS.read().get_dir(&path)?;
+        self.cwd = path;
+        Ok(())
+    }
 }
 
 pub struct ProcessManager {
@@ -93,18 +127,28 @@ impl ProcessManager {
             unsafe {
                 core::mem::transmute::<usize, fn()>(entry)();
             }
-        });
+        })?;
 
         self.processes.push(Arc::clone(&process));
         Ok(pid)
     }
 
+    /// The process `pid`, whether running or waiting to.
     pub fn get_process(&self, pid: usize) -> Option<Arc<RwLock<Process>>> {
-        self.processes.iter()
+        self.current.iter().chain(self.processes.iter())
             .find(|p| p.read().id() == pid)
             .map(Arc::clone)
     }
 
+    /// Every process, the running one included, in PID order.
+    pub fn processes(&self) -> Vec<Arc<RwLock<Process>>> {
+        let mut processes: Vec<_> = self.current.iter().chain(self.processes.iter())
+            .map(Arc::clone)
+            .collect();
+        processes.sort_by_key(|process| process.read().id());
+        processes
+    }
+
     pub fn current_process(&self) -> Option<Arc<RwLock<Process>>> {
         self.current.as_ref().map(Arc::clone)
     }
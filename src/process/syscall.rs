@@ -1,8 +1,11 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use lazy_static::lazy_static;
+use alloc::string::String;
 use alloc::vec::Vec;
 use core::arch::asm;
-use crate::{fs, print, println};
+use crate::fs::{self, lock::{self, LockType}, FsError};
+use crate::{print, println};
+use super::fd::{OpenFile, SEEK_CUR, SEEK_END, SEEK_SET};
 
 #[derive(Debug, Clone, Copy)]
 #[repr(usize)]
@@ -17,10 +20,118 @@ pub enum SyscallNumber {
     Remove = 7,
     Spawn = 8,
     GetPid = 9,
+    Mount = 10,
+    Umount = 11,
+    Chdir = 12,
+    Getcwd = 13,
+    Lseek = 14,
+    Stat = 15,
+    Fstat = 16,
+    Sync = 17,
+    Flock = 18,
+    Fcntl = 19,
+    InotifyInit = 20,
+    InotifyAddWatch = 21,
+    InotifyRmWatch = 22,
 }
 
 const SYSCALL_INTERRUPT: u8 = 0x80;
 
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+
+// `flock` operations
+const LOCK_SH: usize = 1;
+const LOCK_EX: usize = 2;
+const LOCK_NB: usize = 4;
+const LOCK_UN: usize = 8;
+
+// `fcntl` commands and lock types
+const F_GETFL: usize = 3;
+const F_GETLK: usize = 5;
+const F_SETLK: usize = 6;
+const F_SETLKW: usize = 7;
+const F_RDLCK: i16 = 0;
+const F_WRLCK: i16 = 1;
+const F_UNLCK: i16 = 2;
+
+// Errors `flock` and `fcntl` return negated, as Linux does, since callers
+// need to tell a held lock from a deadlock
+const EBADF: isize = 9;
+const EAGAIN: isize = 11;
+const EINVAL: isize = 22;
+const EDEADLK: isize = 35;
+
+/// `struct stat` as laid out on x86_64 Linux, filled by `Stat` and `Fstat`.
+#[repr(C)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    _pad: u32,
+    pub rdev: u64,
+    pub size: i64,
+    pub blksize: i64,
+    pub blocks: i64,
+    pub atime: i64,
+    pub atime_nsec: i64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub ctime: i64,
+    pub ctime_nsec: i64,
+    _reserved: [i64; 3],
+}
+
+impl From<&fs::FileStats> for Stat {
+    fn from(stats: &fs::FileStats) -> Self {
+        let file_type = match stats.file_type {
+            fs::FileType::File => S_IFREG,
+            fs::FileType::Directory => S_IFDIR,
+            fs::FileType::Symlink => S_IFLNK,
+            fs::FileType::CharDevice => S_IFCHR,
+            fs::FileType::BlockDevice => S_IFBLK,
+        };
+        Stat {
+            dev: stats.dev,
+            ino: stats.inode,
+            nlink: stats.links as u64,
+            mode: file_type | stats.permissions as u32,
+            uid: stats.uid,
+            gid: stats.gid,
+            _pad: 0,
+            rdev: stats.rdev,
+            size: stats.size as i64,
+            blksize: 4096,
+            blocks: stats.blocks as i64,
+            atime: stats.atime as i64,
+            atime_nsec: 0,
+            mtime: stats.mtime as i64,
+            mtime_nsec: 0,
+            ctime: stats.ctime as i64,
+            ctime_nsec: 0,
+            _reserved: [0; 3],
+        }
+    }
+}
+
+/// `struct flock` as laid out on x86_64 Linux, for the `Fcntl` lock commands.
+#[repr(C)]
+pub struct Flock {
+    pub l_type: i16,
+    pub l_whence: i16,
+    pub l_start: i64,
+    /// Bytes from `l_start`; 0 for up to the end of the file however it
+    /// grows, negative for the bytes before `l_start`.
+    pub l_len: i64,
+    pub l_pid: i32,
+}
+
 lazy_static! {
     static ref SYSCALL_IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -80,6 +191,19 @@ extern "x86-interrupt" fn syscall_handler(stack_frame: InterruptStackFrame) {
         SyscallNumber::Remove => sys_remove(arg1 as *const u8),
         SyscallNumber::Spawn => sys_spawn(arg1 as *const u8),
         SyscallNumber::GetPid => sys_getpid(),
+        SyscallNumber::Mount => sys_mount(arg1 as *const u8, arg2 as *const u8, arg3 as *const u8),
+        SyscallNumber::Umount => sys_umount(arg1 as *const u8),
+        SyscallNumber::Chdir => sys_chdir(arg1 as *const u8),
+        SyscallNumber::Getcwd => sys_getcwd(arg1 as *mut u8, arg2),
+        SyscallNumber::Lseek => sys_lseek(arg1, arg2 as isize, arg3),
+        SyscallNumber::Stat => sys_stat(arg1 as *const u8, arg2 as *mut Stat),
+        SyscallNumber::Fstat => sys_fstat(arg1, arg2 as *mut Stat),
+        SyscallNumber::Sync => sys_sync(),
+        SyscallNumber::Flock => sys_flock(arg1, arg2),
+        SyscallNumber::Fcntl => sys_fcntl(arg1, arg2, arg3 as *mut Flock),
+        SyscallNumber::InotifyInit => sys_inotify_init(),
+        SyscallNumber::InotifyAddWatch => sys_inotify_add_watch(arg1, arg2 as *const u8, arg3 as u32),
+        SyscallNumber::InotifyRmWatch => sys_inotify_rm_watch(arg1, arg2 as i32),
     };
 
     // Return value goes in rax
@@ -93,6 +217,9 @@ extern "x86-interrupt" fn syscall_handler(stack_frame: InterruptStackFrame) {
 
 fn sys_exit(status: i32) -> usize {
     println!("Process exited with status: {}", status);
+    if let Some(process) = super::PROCESS_MANAGER.read().current_process() {
+        process.write().files_mut().close_all();
+    }
     0
 }
 
@@ -107,59 +234,268 @@ fn sys_write(fd: usize, buf: *const u8, count: usize) -> usize {
             print!("{}", core::str::from_utf8(slice).unwrap_or("Invalid UTF-8"));
             count
         }
-        _ => {
-            // Handle regular file writes
-            0
-        }
+        _ => with_open_file(fd, |open_file| open_file.write(slice)),
     }
 }
 
 fn sys_read(fd: usize, buf: *mut u8, count: usize) -> usize {
-    0 // TODO: Implement actual file reading
+    if fd == 0 {
+        return 0; // No console input for processes yet
+    }
+    let slice = unsafe { core::slice::from_raw_parts_mut(buf, count) };
+    with_open_file(fd, |open_file| open_file.read(slice))
 }
 
 fn sys_open(path: *const u8, flags: usize) -> usize {
-    0 // TODO: Implement file opening
+    let path = path_arg(path);
+    match OpenFile::open(&path, flags) {
+        Ok(open_file) => with_files(|files| Ok(files.install(open_file))),
+        Err(_) => usize::MAX,
+    }
 }
 
 fn sys_close(fd: usize) -> usize {
-    0 // TODO: Implement file closing
+    with_files(|files| files.close(fd).map(|()| 0))
 }
 
-fn sys_create_file(path: *const u8) -> usize {
-    let path_str = unsafe {
-        let path = core::slice::from_raw_parts(path, 1024); // Max path length
-        let len = path.iter().position(|&c| c == 0).unwrap_or(1024);
-        core::str::from_utf8(&path[..len]).unwrap_or("")
+fn sys_lseek(fd: usize, offset: isize, whence: usize) -> usize {
+    with_open_file(fd, |open_file| open_file.seek(offset, whence))
+}
+
+fn sys_stat(path: *const u8, buf: *mut Stat) -> usize {
+    let path = path_arg(path);
+
+    match fs::stat("/", &path) {
+        Ok(stats) => {
+            unsafe { buf.write(Stat::from(&stats)) };
+            0
+        }
+        Err(_) => usize::MAX,
+    }
+}
+
+fn sys_fstat(fd: usize, buf: *mut Stat) -> usize {
+    with_open_file(fd, |open_file| {
+        let stats = open_file.stat()?;
+        unsafe { buf.write(Stat::from(&stats)) };
+        Ok(0)
+    })
+}
+
+fn sys_sync() -> usize {
+    match fs::sync() {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+/// What a lock request on descriptor `fd` of the calling process needs to
+/// know. Taken before locking, so the process is not kept locked while the
+/// request waits.
+struct LockTarget {
+    pid: usize,
+    key: lock::Key,
+    offset: i64,
+    size: i64,
+}
+
+fn lock_target(fd: usize) -> fs::Result<LockTarget> {
+    let process = super::PROCESS_MANAGER.read().current_process().ok_or(FsError::BadDescriptor)?;
+    let process = process.read();
+    let files = process.files();
+    let stats = files.stat(fd)?;
+    Ok(LockTarget {
+        pid: process.id(),
+        key: lock::key(&stats),
+        offset: files.get(fd)?.offset() as i64,
+        size: stats.size as i64,
+    })
+}
+
+/// Whole-file lock on the open file `fd`, as `flock(2)`.
+fn sys_flock(fd: usize, operation: usize) -> usize {
+    let result = lock_target(fd).and_then(|target| {
+        let owner = lock::Owner::File { pid: target.pid, fd };
+        let wait = operation & LOCK_NB == 0;
+        match operation & !LOCK_NB {
+            LOCK_SH => lock::lock(target.key, owner, LockType::Shared, 0, lock::EOF, wait),
+            LOCK_EX => lock::lock(target.key, owner, LockType::Exclusive, 0, lock::EOF, wait),
+            LOCK_UN => {
+                lock::unlock(target.key, owner, 0, lock::EOF);
+                Ok(())
+            }
+            _ => Err(FsError::InvalidArgument),
+        }
+    });
+    fcntl_result(result.map(|()| 0))
+}
+
+/// `fcntl(2)`; only `F_GETFL` and the byte-range lock commands are
+/// supported.
+fn sys_fcntl(fd: usize, cmd: usize, flock: *mut Flock) -> usize {
+    let result = match cmd {
+        F_GETFL => open_flags(fd),
+        F_GETLK | F_SETLK | F_SETLKW => record_lock(fd, cmd, flock).map(|()| 0),
+        _ => Err(FsError::InvalidArgument),
     };
-    
-    match fs::ROOT_FS.read().create_file(path_str, Vec::new()) {
+    fcntl_result(result)
+}
+
+/// The `O_*` flags descriptor `fd` of the calling process was opened with.
+fn open_flags(fd: usize) -> fs::Result<usize> {
+    let process = super::PROCESS_MANAGER.read().current_process().ok_or(FsError::BadDescriptor)?;
+    let process = process.read();
+    Ok(process.files().get(fd)?.flags())
+}
+
+/// The value of a successful `flock` or `fcntl`, or the negative errno of a
+/// failed one.
+fn fcntl_result(result: fs::Result<usize>) -> usize {
+    let errno = match result {
+        Ok(value) => return value,
+        Err(FsError::BadDescriptor) => EBADF,
+        Err(FsError::WouldBlock) => EAGAIN,
+        Err(FsError::Deadlock) => EDEADLK,
+        Err(_) => EINVAL,
+    };
+    -errno as usize
+}
+
+fn record_lock(fd: usize, cmd: usize, flock: *mut Flock) -> fs::Result<()> {
+    let target = lock_target(fd)?;
+    let request = unsafe { flock.read() };
+    let base = match request.l_whence as usize {
+        SEEK_SET => 0,
+        SEEK_CUR => target.offset,
+        SEEK_END => target.size,
+        _ => return Err(FsError::InvalidArgument),
+    };
+    let start = base.checked_add(request.l_start).ok_or(FsError::InvalidArgument)?;
+    let end = start.checked_add(request.l_len).ok_or(FsError::InvalidArgument)?;
+    let (start, end) = match request.l_len {
+        0 => (start, lock::EOF),
+        len if len < 0 => (end, start as u64),
+        _ => (start, end as u64),
+    };
+    if start < 0 {
+        return Err(FsError::InvalidArgument);
+    }
+    let start = start as u64;
+
+    let owner = lock::Owner::Process(target.pid);
+    let lock_type = match request.l_type {
+        F_RDLCK => Some(LockType::Shared),
+        F_WRLCK => Some(LockType::Exclusive),
+        F_UNLCK => None,
+        _ => return Err(FsError::InvalidArgument),
+    };
+    match (cmd, lock_type) {
+        (F_GETLK, Some(lock_type)) => {
+            let reply = match lock::test(target.key, owner, lock_type, start, end) {
+                Some(held) => Flock {
+                    l_type: if held.lock_type == LockType::Exclusive { F_WRLCK } else { F_RDLCK },
+                    l_whence: SEEK_SET as i16,
+                    l_start: held.start as i64,
+                    l_len: if held.end == lock::EOF { 0 } else { (held.end - held.start) as i64 },
+                    l_pid: held.owner.pid() as i32,
+                },
+                None => Flock { l_type: F_UNLCK, ..request },
+            };
+            unsafe { flock.write(reply) };
+            Ok(())
+        }
+        (F_GETLK, None) => Err(FsError::InvalidArgument),
+        (_, Some(lock_type)) => lock::lock(target.key, owner, lock_type, start, end, cmd == F_SETLKW),
+        (_, None) => {
+            lock::unlock(target.key, owner, start, end);
+            Ok(())
+        }
+    }
+}
+
+/// New inotify instance; its events are read from the returned descriptor.
+fn sys_inotify_init() -> usize {
+    with_files(|files| Ok(files.open_inotify()))
+}
+
+/// The inotify instance open as `fd` in the calling process. Watches are
+/// added without the process locked, as resolving the path may need it.
+fn inotify_arg(fd: usize) -> fs::Result<alloc::sync::Arc<fs::notify::Inotify>> {
+    let process = super::PROCESS_MANAGER.read().current_process().ok_or(FsError::BadDescriptor)?;
+    let process = process.read();
+    process.files().inotify(fd)
+}
+
+fn sys_inotify_add_watch(fd: usize, path: *const u8, mask: u32) -> usize {
+    let path = path_arg(path);
+    match inotify_arg(fd).and_then(|instance| instance.add_watch(&path, mask)) {
+        Ok(wd) => wd as usize,
+        Err(_) => usize::MAX,
+    }
+}
+
+fn sys_inotify_rm_watch(fd: usize, wd: i32) -> usize {
+    match inotify_arg(fd).and_then(|instance| instance.rm_watch(wd)) {
         Ok(()) => 0,
         Err(_) => usize::MAX,
     }
 }
 
-fn sys_create_dir(path: *const u8) -> usize {
-    let path_str = unsafe {
-        let path = core::slice::from_raw_parts(path, 1024);
-        let len = path.iter().position(|&c| c == 0).unwrap_or(1024);
-        core::str::from_utf8(&path[..len]).unwrap_or("")
+/// Runs `f` on the calling process's open files; errors become `usize::MAX`.
+/// The process stays locked meanwhile, so `f` must not do file I/O: reading
+/// procfs locks processes too.
+fn with_files(f: impl FnOnce(&mut super::fd::FileTable) -> fs::Result<usize>) -> usize {
+    let process = match super::PROCESS_MANAGER.read().current_process() {
+        Some(process) => process,
+        None => return usize::MAX,
     };
-    
-    match fs::ROOT_FS.read().create_dir(path_str) {
+
+    let result = f(process.write().files_mut());
+    result.unwrap_or(usize::MAX)
+}
+
+/// Runs `f` on a copy of descriptor `fd` of the calling process with the
+/// process unlocked, then stores the position `f` left it at; errors become
+/// `usize::MAX`.
+fn with_open_file(fd: usize, f: impl FnOnce(&mut OpenFile) -> fs::Result<usize>) -> usize {
+    let process = match super::PROCESS_MANAGER.read().current_process() {
+        Some(process) => process,
+        None => return usize::MAX,
+    };
+    let mut open_file = match process.read().files().get(fd) {
+        Ok(open_file) => open_file.clone(),
+        Err(_) => return usize::MAX,
+    };
+
+    let result = f(&mut open_file);
+    if process.write().files_mut().set_offset(fd, open_file.offset()).is_err() {
+        return usize::MAX;
+    }
+    result.unwrap_or(usize::MAX)
+}
+
+fn sys_create_file(path: *const u8) -> usize {
+    let path_str = path_arg(path);
+
+    match fs::ROOT_FS.read().create_file(&path_str, Vec::new()) {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+fn sys_create_dir(path: *const u8) -> usize {
+    let path_str = path_arg(path);
+
+    match fs::ROOT_FS.read().create_dir(&path_str) {
         Ok(()) => 0,
         Err(_) => usize::MAX,
     }
 }
 
 fn sys_remove(path: *const u8) -> usize {
-    let path_str = unsafe {
-        let path = core::slice::from_raw_parts(path, 1024);
-        let len = path.iter().position(|&c| c == 0).unwrap_or(1024);
-        core::str::from_utf8(&path[..len]).unwrap_or("")
-    };
-    
-    match fs::ROOT_FS.read().remove(path_str) {
+    let path_str = path_arg(path);
+
+    match fs::ROOT_FS.read().remove(&path_str) {
         Ok(()) => 0,
         Err(_) => usize::MAX,
     }
@@ -176,6 +512,81 @@ fn sys_spawn(path: *const u8) -> usize {
     0
 }
 
+/// Reads a NUL-terminated string argument of at most 1024 bytes.
+unsafe fn str_arg<'a>(ptr: *const u8) -> &'a str {
+    // Only the bytes up to the terminator are touched, in case the string
+    // ends right before an unmapped page
+    let mut len = 0;
+    while len < 1024 && *ptr.add(len) != 0 {
+        len += 1;
+    }
+    core::str::from_utf8(core::slice::from_raw_parts(ptr, len)).unwrap_or("")
+}
+
+/// Path argument made absolute against the calling process's working
+/// directory.
+fn path_arg(ptr: *const u8) -> String {
+    let path = unsafe { str_arg(ptr) };
+    let process = super::PROCESS_MANAGER.read().current_process();
+    match process {
+        Some(process) => fs::path::absolute(process.read().cwd(), path),
+        None => fs::path::absolute("/", path),
+    }
+}
+
+fn sys_mount(source: *const u8, target: *const u8, fs_type: *const u8) -> usize {
+    let (source, fs_type) = unsafe { (str_arg(source), str_arg(fs_type)) };
+    let target = path_arg(target);
+
+    match fs::mount(source, &target, fs_type) {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+fn sys_umount(target: *const u8) -> usize {
+    let target = path_arg(target);
+
+    match fs::umount(&target) {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+fn sys_chdir(path: *const u8) -> usize {
+    let path = unsafe { str_arg(path) };
+    let process = match super::PROCESS_MANAGER.read().current_process() {
+        Some(process) => process,
+        None => return usize::MAX,
+    };
+
+    let result = process.write().set_cwd(path);
+    match result {
+        Ok(()) => 0,
+        Err(_) => usize::MAX,
+    }
+}
+
+/// Copies the working directory, NUL-terminated, into `buf` and returns its
+/// length.
+fn sys_getcwd(buf: *mut u8, size: usize) -> usize {
+    let process = match super::PROCESS_MANAGER.read().current_process() {
+        Some(process) => process,
+        None => return usize::MAX,
+    };
+
+    let process = process.read();
+    let cwd = process.cwd().as_bytes();
+    if cwd.len() + 1 > size {
+        return usize::MAX;
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(cwd.as_ptr(), buf, cwd.len());
+        *buf.add(cwd.len()) = 0;
+    }
+    cwd.len()
+}
+
 fn sys_getpid() -> usize {
     super::PROCESS_MANAGER.read()
         .current_process()
@@ -196,6 +607,19 @@ impl From<usize> for SyscallNumber {
             7 => SyscallNumber::Remove,
             8 => SyscallNumber::Spawn,
             9 => SyscallNumber::GetPid,
+            10 => SyscallNumber::Mount,
+            11 => SyscallNumber::Umount,
+            12 => SyscallNumber::Chdir,
+            13 => SyscallNumber::Getcwd,
+            14 => SyscallNumber::Lseek,
+            15 => SyscallNumber::Stat,
+            16 => SyscallNumber::Fstat,
+            17 => SyscallNumber::Sync,
+            18 => SyscallNumber::Flock,
+            19 => SyscallNumber::Fcntl,
+            20 => SyscallNumber::InotifyInit,
+            21 => SyscallNumber::InotifyAddWatch,
+            22 => SyscallNumber::InotifyRmWatch,
             _ => SyscallNumber::Exit, // Default to Exit for invalid syscall numbers
         }
     }
@@ -0,0 +1,109 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::interrupts::apic::TIMER_HZ;
+use crate::task::timer;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATING: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOURS_PM: u8 = 1 << 7;
+
+/// Wall-clock time at boot, in seconds since 1970.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+fn read_register(register: u8) -> u8 {
+    let mut address = Port::<u8>::new(CMOS_ADDRESS);
+    let mut data = Port::<u8>::new(CMOS_DATA);
+    unsafe {
+        // Bit 7 of the address keeps NMIs disabled
+        address.write(0x80 | register);
+        data.read()
+    }
+}
+
+/// Days since 1970-01-01 for a date of the proleptic Gregorian calendar.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Counting years from March puts the leap day last
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Year, month and day of the date `days` after 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// The CMOS clock's date and time in seconds since 1970, taken as UTC.
+fn read_clock() -> u64 {
+    interrupts::without_interrupts(|| {
+        // Registers read during an update can be half old, half new, so read
+        // until two passes agree
+        let read_all = || {
+            while read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0 {
+                core::hint::spin_loop();
+            }
+            [REG_SECONDS, REG_MINUTES, REG_HOURS, REG_DAY, REG_MONTH, REG_YEAR].map(read_register)
+        };
+        let mut values = read_all();
+        loop {
+            let again = read_all();
+            if again == values {
+                break;
+            }
+            values = again;
+        }
+
+        let status = read_register(REG_STATUS_B);
+        let [seconds, minutes, hours, day, month, year] = values;
+        let pm = hours & HOURS_PM != 0;
+        let decode = |value: u8| if status & STATUS_B_BINARY != 0 {
+            value as u64
+        } else {
+            (value >> 4) as u64 * 10 + (value & 0x0F) as u64
+        };
+        let mut hours = decode(hours & !HOURS_PM);
+        if status & STATUS_B_24_HOUR == 0 {
+            hours = hours % 12 + if pm { 12 } else { 0 };
+        }
+        // The century register is not at a fixed place; assume this one
+        let days = days_from_civil(2000 + decode(year) as i64, decode(month) as i64, decode(day) as i64);
+        days.max(0) as u64 * 86_400 + hours * 3600 + decode(minutes) * 60 + decode(seconds)
+    })
+}
+
+/// Reads the CMOS clock once; `now` counts timer ticks from there.
+pub fn init() {
+    BOOT_TIME.store(read_clock().saturating_sub(timer::ticks() / TIMER_HZ), Ordering::Relaxed);
+}
+
+/// Wall-clock time in seconds since 1970, for on-disk timestamps.
+pub fn now() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + timer::ticks() / TIMER_HZ
+}
@@ -0,0 +1,44 @@
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+/// I/O base of COM1, QEMU's `-serial` port.
+const COM1: u16 = 0x3F8;
+
+/// Line status register offset and its data-ready bit.
+const LINE_STATUS: u16 = 5;
+const DATA_READY: u8 = 1 << 0;
+
+lazy_static::lazy_static! {
+    static ref SERIAL1: Mutex<SerialPort> = {
+        let mut port = unsafe { SerialPort::new(COM1) };
+        port.init();
+        // Input is polled, so the receive interrupt `init` turns on stays off
+        unsafe { Port::<u8>::new(COM1 + 1).write(0) };
+        Mutex::new(port)
+    };
+}
+
+/// Sends `data` on COM1 as is, without newline translation.
+pub fn write(data: &[u8]) {
+    interrupts::without_interrupts(|| {
+        let mut port = SERIAL1.lock();
+        for &byte in data {
+            port.send_raw(byte);
+        }
+    });
+}
+
+/// A byte received on COM1, if one is waiting.
+pub fn try_read() -> Option<u8> {
+    interrupts::without_interrupts(|| {
+        let _port = SERIAL1.lock();
+        unsafe {
+            if Port::<u8>::new(COM1 + LINE_STATUS).read() & DATA_READY == 0 {
+                return None;
+            }
+            Some(Port::<u8>::new(COM1).read())
+        }
+    })
+}
@@ -4,10 +4,18 @@ use alloc::boxed::Box;
 use alloc::format;
 use alloc::borrow::ToOwned;
 use crate::fs::{self, Filesystem, FsError};
+use crate::task::{self, group::GroupError, policy::SchedPolicyKind};
+use crate::smp;
+use crate::block;
+use crate::interrupts::{self, irq::{self, IrqCounter}};
 use crate::vga_buffer;
 use crate::print;
 use crate::println;
 use core::fmt;
+use futures_util::StreamExt;
+use pc_keyboard::KeyCode;
+use crate::keyboard::{KeyEvent, KeyboardStream};
+use crate::network::{socket::{self, SocketType}, IpAddress};
 
 impl fmt::Display for FsError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -41,11 +49,7 @@ impl<T: ?Sized + Filesystem> FilesystemExt for T {
     }
 
     fn canonicalize_path(&self, base: &str, path: &str) -> Result<String, FsError> {
-        if path.starts_with('/') {
-            Ok(path.to_string())
-        } else {
-            Ok(format!("{}/{}", base.trim_end_matches('/'), path))
-        }
+        fs::path::normalize(base, path)
     }
 
     fn is_dir(&self, path: &str) -> bool {
@@ -269,7 +273,7 @@ impl Shell {
 
         if path_to_complete.is_empty() {
             // Complete commands
-            for cmd in ["ls", "cd", "pwd", "help", "clear", "cat", "mkdir", "touch", "rm", "echo", "cp", "mv"] {
+            for cmd in ["ls", "cd", "pwd", "help", "clear", "cat", "mkdir", "touch", "rm", "echo", "cp", "mv", "sched", "cgroup", "interrupts", "mount", "umount", "ln", "readlink", "stat", "lsblk", "sync", "sleep", "recv"] {
                 if cmd.starts_with(prefix) {
                     self.tab_completions.push(cmd.to_owned());
                 }
@@ -342,23 +346,55 @@ impl Shell {
         }
 
         let src_path = self.resolve_path(&args[0]);
-        let dst_path = self.resolve_path(&args[1]);
+        let mut dst_path = self.resolve_path(&args[1]);
         let fs = fs::ROOT_FS.read();
 
-        // First try to read the source file
-        match fs.read_file(&src_path) {
-            Ok(contents) => {
-                // Create the destination file
-                if let Err(e) = fs.create_file(&dst_path, contents) {
-                    println!("mv: error writing to {}: {}", args[1], e);
-                    return;
-                }
-                // Remove the source file
-                if let Err(e) = fs.remove(&src_path) {
-                    println!("mv: error removing source file {}: {}", args[0], e);
-                }
+        // Moving onto a directory moves into it
+        if fs.is_dir(&dst_path) {
+            let name = fs::path::components(&src_path).last().unwrap_or("");
+            dst_path = format!("{}/{}", dst_path.trim_end_matches('/'), name);
+        }
+        if let Err(e) = fs.rename(&src_path, &dst_path) {
+            println!("mv: cannot move {} to {}: {}", args[0], args[1], e);
+        }
+    }
+
+    fn cmd_ln(&self, args: &[String]) {
+        let (symbolic, args) = match args.split_first() {
+            Some((flag, rest)) if flag == "-s" => (true, rest),
+            _ => (false, args),
+        };
+        if args.len() != 2 {
+            println!("ln: missing file operand");
+            println!("Usage: ln [-s] <target> <link>");
+            return;
+        }
+
+        let link_path = self.resolve_path(&args[1]);
+        let fs = fs::ROOT_FS.read();
+        let result = if symbolic {
+            // The target is stored as typed, relative to the link's directory
+            fs.symlink(&args[0], &link_path)
+        } else {
+            fs.link(&self.resolve_path(&args[0]), &link_path)
+        };
+        if let Err(e) = result {
+            println!("ln: {}: {}", args[1], e);
+        }
+    }
+
+    fn cmd_readlink(&self, args: &[String]) {
+        if args.is_empty() {
+            println!("readlink: missing operand");
+            return;
+        }
+
+        let fs = fs::ROOT_FS.read();
+        for path in args {
+            match fs.readlink(&self.resolve_path(path)) {
+                Ok(target) => println!("{}", target),
+                Err(e) => println!("readlink: {}: {}", path, e),
             }
-            Err(e) => println!("mv: error reading {}: {}", args[0], e),
         }
     }
 
@@ -405,6 +441,16 @@ impl Shell {
             "echo" => self.cmd_echo(&command.args),
             "cp" => self.cmd_cp(&command.args),
             "mv" => self.cmd_mv(&command.args),
+            "ln" => self.cmd_ln(&command.args),
+            "readlink" => self.cmd_readlink(&command.args),
+            "stat" => self.cmd_stat(&command.args),
+            "lsblk" => self.cmd_lsblk(),
+            "sync" => self.cmd_sync(&command.args),
+            "sched" => self.cmd_sched(&command.args),
+            "cgroup" => self.cmd_cgroup(&command.args),
+            "interrupts" => self.cmd_interrupts(&command.args),
+            "mount" => self.cmd_mount(&command.args),
+            "umount" => self.cmd_umount(&command.args),
             _ => println!("Unknown command: {}", command.name),
         }
 
@@ -431,20 +477,26 @@ impl Shell {
 
         // Handle final output redirection
         match command.output_redirect {
+            // An existing file, device files included, is written in place
             Redirection::Output(ref file) => {
                 let path = self.resolve_path(file);
-                if let Err(e) = fs::ROOT_FS.read().create_file(&path, output) {
+                let fs = fs::ROOT_FS.read();
+                let result = match fs.get_file(&path) {
+                    Ok(existing) => existing.write(&output),
+                    Err(_) => fs.create_file(&path, output),
+                };
+                if let Err(e) = result {
                     println!("Error writing to {}: {}", file, e);
                 }
             }
             Redirection::Append(ref file) => {
                 let path = self.resolve_path(file);
-                let mut contents = match fs::ROOT_FS.read().read_file(&path) {
-                    Ok(c) => c,
-                    Err(_) => Vec::new(),
+                let fs = fs::ROOT_FS.read();
+                let result = match fs.get_file(&path) {
+                    Ok(existing) => existing.append(&output),
+                    Err(_) => fs.create_file(&path, output),
                 };
-                contents.extend(output);
-                if let Err(e) = fs::ROOT_FS.read().create_file(&path, contents) {
+                if let Err(e) = result {
                     println!("Error appending to {}: {}", file, e);
                 }
             }
@@ -470,10 +522,53 @@ impl Shell {
         }
     }
 
+    /// Runs the commands that wait on a future, so the executor keeps
+    /// polling its other tasks meanwhile. Returns false for anything else,
+    /// which goes through `execute`.
+    async fn execute_waiting(&mut self, input: &str) -> bool {
+        let args: Vec<&str> = input.split_whitespace().collect();
+        match args.as_slice() {
+            ["sleep", seconds] => match seconds.parse::<u64>() {
+                Ok(seconds) => task::timer::sleep_ticks(seconds * interrupts::apic::TIMER_HZ).await,
+                Err(_) => println!("sleep: invalid time: {}", seconds),
+            },
+            ["recv", port] => self.cmd_recv(port).await,
+            _ => return false,
+        }
+        self.command_history.push(input.to_owned());
+        self.history_position = None;
+        true
+    }
+
+    async fn cmd_recv(&self, port: &str) {
+        let port = match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => {
+                println!("recv: invalid port: {}", port);
+                return;
+            }
+        };
+        let result = match socket::socket(SocketType::Dgram) {
+            Ok(id) => {
+                let result = match socket::bind(id, IpAddress::new([0, 0, 0, 0]), port) {
+                    Ok(()) => socket::recv_async(id).await,
+                    Err(e) => Err(e),
+                };
+                let _ = socket::close(id);
+                result
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(data) => println!("{}", String::from_utf8_lossy(&data)),
+            Err(e) => println!("recv: {}", e),
+        }
+    }
+
     // Update help to include pipe information
     fn cmd_help(&self) {
         println!("Available commands:");
-        println!("  ls [path]     - List directory contents");
+        println!("  ls [-l] [path] - List directory contents");
         println!("  cd [path]     - Change current directory");
         println!("  pwd           - Print current directory");
         println!("  cat <file>    - Display file contents");
@@ -482,7 +577,21 @@ impl Shell {
         println!("  rm <file>     - Remove a file");
         println!("  echo [text]   - Display a line of text");
         println!("  cp <src> <dst> - Copy a file");
-        println!("  mv <src> <dst> - Move a file");
+        println!("  mv <src> <dst> - Move or rename a file or directory");
+        println!("  ln [-s] <target> <link> - Create a hard or symbolic link");
+        println!("  readlink <link> - Print a symbolic link's target");
+        println!("  stat <path>   - Show inode metadata");
+        println!("  sched [policy] - Show or set the scheduling policy");
+        println!("  sched stats   - Show per-task wait times");
+        println!("  sched cpus    - Show per-CPU run queues");
+        println!("  cgroup [cmd]  - Manage task groups (cgroup help for details)");
+        println!("  interrupts    - Show interrupt counts per CPU");
+        println!("  mount [-t type source dir] - List or attach filesystems");
+        println!("  umount <dir>  - Detach a filesystem");
+        println!("  lsblk         - List block devices");
+        println!("  sync [-v]     - Write cached disk blocks back; -v shows cache stats");
+        println!("  sleep <secs>  - Wait without holding up other tasks");
+        println!("  recv <port>   - Wait for a UDP datagram and print it");
         println!("  clear         - Clear the screen");
         println!("  help          - Show this help message");
         println!("\nRedirection and Pipes:");
@@ -527,14 +636,18 @@ impl Shell {
         }
     }
 
+    /// Absolute form of `path`; the filesystem resolves `.`, `..` and links
+    /// when it is used.
     fn resolve_path(&self, path: &str) -> String {
-        let fs = fs::ROOT_FS.read();
-        fs.canonicalize_path(&self.current_dir, path)
-            .unwrap_or_else(|_| path.to_owned())
+        fs::path::absolute(&self.current_dir, path)
     }
 
     // Existing commands...
     fn cmd_ls(&self, args: &[String]) {
+        let (long, args) = match args.split_first() {
+            Some((flag, rest)) if flag == "-l" => (true, rest),
+            _ => (false, args),
+        };
         let path = if args.is_empty() {
             &self.current_dir
         } else {
@@ -542,21 +655,97 @@ impl Shell {
         };
 
         let fs = fs::ROOT_FS.read();
-        match fs.read_dir(path) {
+        let dir_path = self.resolve_path(path);
+        match fs.read_dir(&dir_path) {
             Ok(entries) => {
                 for entry in entries {
-                    println!("{}", entry);
+                    if long {
+                        self.print_long(&fs::path::absolute(&dir_path, &entry), &entry);
+                    } else {
+                        println!("{}", entry);
+                    }
                 }
             }
+            Err(FsError::NotFound) if long && !fs.is_dir(&dir_path) => {
+                self.print_long(&dir_path, path);
+            }
             Err(e) => println!("ls: {}: {}", path, e),
         }
     }
 
+    /// One `ls -l` line for `path`, shown as `name`.
+    fn print_long(&self, path: &str, name: &str) {
+        let stats = match fs::lstat(&self.current_dir, path) {
+            Ok(stats) => stats,
+            Err(e) => {
+                println!("ls: {}: {}", name, e);
+                return;
+            }
+        };
+        let target = match stats.file_type {
+            fs::FileType::Symlink => match fs::ROOT_FS.read().readlink(path) {
+                Ok(target) => format!(" -> {}", target),
+                Err(_) => String::new(),
+            },
+            _ => String::new(),
+        };
+        // Device files show their device number in place of a size
+        let size = match stats.file_type {
+            fs::FileType::CharDevice | fs::FileType::BlockDevice => {
+                format!("{}, {}", fs::major(stats.rdev), fs::minor(stats.rdev))
+            }
+            _ => format!("{}", stats.size),
+        };
+        println!("{} {:>3} {:>4} {:>4} {:>8} {:>6} {}{}",
+            mode_string(&stats), stats.links, stats.uid, stats.gid,
+            size, stats.mtime, name, target);
+    }
+
+    fn cmd_stat(&self, args: &[String]) {
+        if args.is_empty() {
+            println!("stat: missing operand");
+            return;
+        }
+
+        for path in args {
+            let stats = match fs::lstat(&self.current_dir, path) {
+                Ok(stats) => stats,
+                Err(e) => {
+                    println!("stat: {}: {}", path, e);
+                    continue;
+                }
+            };
+            let kind = match stats.file_type {
+                fs::FileType::File => "regular file",
+                fs::FileType::Directory => "directory",
+                fs::FileType::Symlink => "symbolic link",
+                fs::FileType::CharDevice => "character special file",
+                fs::FileType::BlockDevice => "block special file",
+            };
+            match fs::ROOT_FS.read().readlink(&self.resolve_path(path)) {
+                Ok(target) => println!("  File: {} -> {}", path, target),
+                Err(_) => println!("  File: {}", path),
+            }
+            println!("  Size: {:<10} Blocks: {:<10} {}", stats.size, stats.blocks, kind);
+            match stats.file_type {
+                fs::FileType::CharDevice | fs::FileType::BlockDevice => println!(
+                    "Device: {:x}h/{}d  Inode: {:<8} Links: {:<5} Device type: {},{}",
+                    stats.dev, stats.dev, stats.inode, stats.links, fs::major(stats.rdev), fs::minor(stats.rdev)),
+                _ => println!("Device: {:x}h/{}d  Inode: {:<8} Links: {}", stats.dev, stats.dev, stats.inode, stats.links),
+            }
+            println!("Access: ({:04o}/{})  Uid: {}  Gid: {}",
+                stats.permissions, mode_string(&stats), stats.uid, stats.gid);
+            println!("Access: {}s", stats.atime);
+            println!("Modify: {}s", stats.mtime);
+            println!("Change: {}s", stats.ctime);
+        }
+    }
+
     fn cmd_cd(&mut self, args: &[String]) {
         let path = args.get(0).map(|s| s.as_str()).unwrap_or("/");
         let fs = fs::ROOT_FS.read();
-        
-        match fs.canonicalize_path(&self.current_dir, path) {
+
+        match fs::resolve_path(&self.current_dir, path) {
             Ok(new_path) => {
                 if fs.is_dir(&new_path) {
                     self.current_dir = new_path;
@@ -578,14 +767,30 @@ impl Shell {
         let fs = fs::ROOT_FS.read();
         for path in args {
             let full_path = self.resolve_path(path);
-            match fs.read_file(&full_path) {
-                Ok(contents) => {
-                    // Convert bytes to string and print
-                    for byte in contents {
+            // Read a chunk at a time, as files can be larger than the heap.
+            // Devices like /dev/zero never end, so they get a single read.
+            let result = fs.get_file(&full_path).and_then(|file| {
+                if matches!(file.stats()?.file_type, fs::FileType::CharDevice | fs::FileType::BlockDevice) {
+                    for byte in file.read()? {
                         print!("{}", byte as char);
                     }
-                    println!();
+                    return Ok(());
                 }
+                let mut buf = [0; 512];
+                let mut offset = 0;
+                loop {
+                    let count = file.read_at(offset, &mut buf)?;
+                    if count == 0 {
+                        return Ok(());
+                    }
+                    for &byte in &buf[..count] {
+                        print!("{}", byte as char);
+                    }
+                    offset += count;
+                }
+            });
+            match result {
+                Ok(()) => println!(),
                 Err(e) => println!("cat: {}: {}", path, e),
             }
         }
@@ -641,10 +846,233 @@ impl Shell {
         println!("{}", text);
     }
 
+    fn cmd_sched(&self, args: &[String]) {
+        match args.get(0) {
+            None => {
+                println!("Current policy: {}", task::policy_name());
+                print!("Available:");
+                for kind in SchedPolicyKind::ALL {
+                    print!(" {}", kind.name());
+                }
+                println!();
+            }
+            Some(arg) if arg == "cpus" => {
+                println!("{:>4} {:>8} {:>8} {:>6}", "CPU", "APIC ID", "TASK", "READY");
+                for stats in task::cpu_stats() {
+                    let apic_id = smp::cpu_info(stats.cpu).map_or(0, |info| info.apic_id());
+                    let current = stats.current
+                        .map_or("idle".to_owned(), |id| id.to_string());
+                    println!("{:>4} {:>8} {:>8} {:>6}", stats.cpu, apic_id, current, stats.ready);
+                }
+            }
+            Some(arg) if arg == "stats" => {
+                println!("{:>4} {:>8} {:>8} {:>14} {:>14} {:>8}",
+                    "ID", "PRIO", "BASE", "TOTAL WAIT", "MAX WAIT", "SWITCHES");
+                for stats in task::wait_stats() {
+                    println!("{:>4} {:>8} {:>8} {:>14} {:>14} {:>8}",
+                        stats.id, stats.priority, stats.base_priority,
+                        stats.total_wait, stats.max_wait, stats.context_switches);
+                }
+            }
+            Some(name) => match SchedPolicyKind::from_name(name) {
+                Some(kind) => {
+                    task::set_policy(kind);
+                    println!("Scheduling policy set to {}", kind.name());
+                }
+                None => println!("sched: unknown policy: {}", name),
+            },
+        }
+    }
+
+    fn cmd_mount(&self, args: &[String]) {
+        match args {
+            [] => {
+                for mount in fs::mounts() {
+                    println!("{} on {} type {}", mount.source, mount.path, mount.fs_type);
+                }
+            }
+            [flag, fs_type, source, target] if flag == "-t" => {
+                let target = self.resolve_path(target);
+                if let Err(e) = fs::mount(source, &target, fs_type) {
+                    println!("mount: {}: {}", target, e);
+                }
+            }
+            _ => {
+                println!("Usage: mount -t <type> <source> <dir>");
+                println!("Types: {}", fs::VFS.fs_types().join(", "));
+            }
+        }
+    }
+
+    fn cmd_umount(&self, args: &[String]) {
+        if args.is_empty() {
+            println!("umount: missing operand");
+            return;
+        }
+
+        for target in args {
+            let path = self.resolve_path(target);
+            if let Err(e) = fs::umount(&path) {
+                println!("umount: {}: {}", target, e);
+            }
+        }
+    }
+
+    fn cmd_interrupts(&self, _args: &[String]) {
+        let cpus = smp::online_cpus();
+        let counts = |counter: &IrqCounter| {
+            let mut line = String::new();
+            for cpu in 0..cpus {
+                line.push_str(&format!(" {:>10}", counter.get(cpu)));
+            }
+            line
+        };
+
+        print!("    ");
+        for cpu in 0..cpus {
+            print!(" {:>10}", format!("CPU{}", cpu));
+        }
+        println!();
+        let controller = if interrupts::apic_enabled() { "IO-APIC" } else { "XT-PIC" };
+        for stats in irq::irq_stats() {
+            println!("{:>3}:{}  {:<8} {}", stats.line, counts(stats.counts),
+                controller, stats.handlers.join(", "));
+        }
+        println!("LOC:{}  Timer interrupts", counts(&irq::TIMER));
+        println!("RES:{}  Rescheduling interrupts", counts(&irq::RESCHEDULE));
+        println!("SPU:{}  Spurious interrupts", counts(&irq::SPURIOUS));
+        println!("ERR:{}  Unhandled interrupts", counts(&irq::UNHANDLED));
+    }
+
+    fn cmd_cgroup(&self, args: &[String]) {
+        let arg = |i: usize| args.get(i).map(|s| s.as_str());
+        let number = |i: usize| arg(i).and_then(|s| s.parse::<usize>().ok());
+
+        let result = match (arg(0), arg(1)) {
+            (None, _) | (Some("list"), _) => {
+                println!("{:>4} {:<12} {:>7} {:>6} {:>10} {:>10} {:>10} {:>14}",
+                    "ID", "NAME", "TASKS", "SHARES", "CPU LIMIT", "STACKS", "HEAP", "RUNTIME");
+                for stats in task::list_groups() {
+                    let cpu_limit = stats.cpu_limit
+                        .map_or("-".to_owned(), |limit| format!("{}%", limit));
+                    println!("{:>4} {:<12} {:>7} {:>6} {:>10} {:>10} {:>10} {:>14}",
+                        stats.id, stats.name, stats.members.len(), stats.cpu_shares,
+                        cpu_limit, stats.stack_usage, stats.heap_usage, stats.total_runtime);
+                }
+                Ok(())
+            }
+            (Some("create"), Some(name)) => task::create_group(name).map(|id| {
+                println!("Created group {} ({})", id, name);
+            }),
+            (Some("rm"), Some(_)) => match number(1) {
+                Some(id) => task::remove_group(id),
+                None => Err(GroupError::NotFound),
+            },
+            (Some("move"), Some(_)) => match (number(1), number(2)) {
+                (Some(task_id), Some(group_id)) => task::move_task(task_id, group_id),
+                _ => Err(GroupError::TaskNotFound),
+            },
+            (Some("kill"), Some(_)) => match number(1) {
+                Some(id) => task::kill_group(id).map(|count| {
+                    println!("Killed {} task(s)", count);
+                }),
+                None => Err(GroupError::NotFound),
+            },
+            (Some("set"), Some(_)) => match (number(1), arg(2), arg(3)) {
+                (Some(id), Some(key), Some(value)) => {
+                    let limit = match value {
+                        "none" => Ok(None),
+                        value => value.parse::<usize>().map(Some).map_err(|_| GroupError::InvalidLimit),
+                    };
+                    limit.and_then(|limit| {
+                        task::with_group(id, |group| match (key, limit) {
+                            ("shares", Some(shares)) => group.set_cpu_shares(shares as u64),
+                            ("cpu", limit) => group.set_cpu_limit(limit.map(|percent| percent.min(255) as u8)),
+                            ("stack", limit) => group.set_stack_limit(limit),
+                            ("heap", limit) => group.set_heap_limit(limit),
+                            _ => Err(GroupError::InvalidLimit),
+                        })
+                    }).and_then(|result| result)
+                }
+                _ => Err(GroupError::InvalidLimit),
+            },
+            (Some("stats"), Some(_)) => match number(1).and_then(task::group_stats) {
+                Some(stats) => {
+                    println!("Group {} ({})", stats.id, stats.name);
+                    println!("  Members:          {:?}", stats.members);
+                    println!("  CPU shares:       {}", stats.cpu_shares);
+                    match stats.cpu_limit {
+                        Some(limit) => println!("  CPU limit:        {}%", limit),
+                        None => println!("  CPU limit:        none"),
+                    }
+                    match stats.stack_limit {
+                        Some(limit) => println!("  Stacks:           {} / {} bytes", stats.stack_usage, limit),
+                        None => println!("  Stacks:           {} bytes", stats.stack_usage),
+                    }
+                    match stats.heap_limit {
+                        Some(limit) => println!("  Heap:             {} / {} bytes", stats.heap_usage, limit),
+                        None => println!("  Heap:             {} bytes", stats.heap_usage),
+                    }
+                    println!("  Total runtime:    {}", stats.total_runtime);
+                    println!("  Total wait:       {}", stats.total_wait);
+                    println!("  Context switches: {}", stats.context_switches);
+                    Ok(())
+                }
+                None => Err(GroupError::NotFound),
+            },
+            _ => {
+                println!("Usage:");
+                println!("  cgroup [list]                  - List groups and usage");
+                println!("  cgroup create <name>           - Create a group");
+                println!("  cgroup rm <id>                 - Remove an empty group");
+                println!("  cgroup move <task> <id>        - Move a task into a group");
+                println!("  cgroup kill <id>               - Terminate all tasks in a group");
+                println!("  cgroup set <id> shares <n>     - Set CPU shares");
+                println!("  cgroup set <id> cpu <pct|none> - Set CPU limit");
+                println!("  cgroup set <id> stack <bytes|none> - Set stack and TLS limit");
+                println!("  cgroup set <id> heap <bytes|none>  - Set heap limit");
+                println!("  cgroup stats <id>              - Show aggregated usage");
+                Ok(())
+            }
+        };
+
+        if let Err(e) = result {
+            println!("cgroup: {}", e.as_str());
+        }
+    }
+
     fn cmd_pwd(&self) {
         println!("{}", self.current_dir);
     }
 
+    fn cmd_lsblk(&self) {
+        println!("{:<8} {:>7} {:>8} {:>2}  {}", "NAME", "MAJ:MIN", "SIZE", "RO", "MODEL");
+        for device in block::devices() {
+            let major_minor = format!("{}:{}", fs::major(device.dev()), fs::minor(device.dev()));
+            println!("{:<8} {:>7} {:>8} {:>2}  {}",
+                device.name(), major_minor, block::format_size(device.size()),
+                device.read_only() as u8, device.model());
+        }
+    }
+
+    fn cmd_sync(&self, args: &[String]) {
+        if let Err(e) = fs::sync() {
+            println!("sync: {}", e);
+        }
+        if args.first().map(|s| s.as_str()) == Some("-v") {
+            let stats = block::cache::stats();
+            let lookups = stats.hits + stats.misses;
+            println!("Buffer cache: {} of {} blocks ({} dirty), {} bytes each",
+                stats.cached, stats.capacity, stats.dirty, block::cache::BLOCK_SIZE);
+            println!("  Hits:        {} ({}%)", stats.hits,
+                if lookups == 0 { 0 } else { stats.hits * 100 / lookups });
+            println!("  Misses:      {}", stats.misses);
+            println!("  Read-ahead:  {}", stats.read_ahead);
+            println!("  Write-backs: {}", stats.write_backs);
+            println!("  Evictions:   {}", stats.evictions);
+        }
+    }
+
     fn cmd_clear(&self) {
         let mut writer = vga_buffer::WRITER.lock();
         writer.clear();
@@ -653,4 +1081,105 @@ impl Shell {
 
 pub fn init() -> Shell {
     Shell::new()
+}
+
+/// Type and permission bits in `ls -l` form, e.g. `drwxr-xr-x`.
+fn mode_string(stats: &fs::FileStats) -> String {
+    let mut mode = String::with_capacity(10);
+    mode.push(match stats.file_type {
+        fs::FileType::File => '-',
+        fs::FileType::Directory => 'd',
+        fs::FileType::Symlink => 'l',
+        fs::FileType::CharDevice => 'c',
+        fs::FileType::BlockDevice => 'b',
+    });
+    for shift in [6, 3, 0] {
+        let bits = stats.permissions >> shift;
+        mode.push(if bits & 4 != 0 { 'r' } else { '-' });
+        mode.push(if bits & 2 != 0 { 'w' } else { '-' });
+        mode.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    mode
+}
+
+/// Line editor and command loop, run as a task on the async executor so the
+/// CPU idles between key presses.
+pub async fn run() {
+    let mut keyboard_events = KeyboardStream::new();
+    let mut shell = init();
+    let mut current_line = String::new();
+    print!("> ");  // Initial prompt
+
+    while let Some(event) = keyboard_events.next().await {
+        match event {
+            KeyEvent::Char('\n') => {
+                println!();  // New line after Enter
+                if !current_line.is_empty() {
+                    if !shell.execute_waiting(&current_line).await {
+                        shell.execute(&current_line);
+                    }
+                    current_line.clear();
+                }
+                shell.reset_tab_completion();  // Reset tab completion state
+                print!("> ");  // Shell prompt
+            },
+            KeyEvent::Char(c) => {
+                print!("{}", c);
+                current_line.push(c);
+                shell.reset_tab_completion();  // Reset tab completion when typing
+            },
+            KeyEvent::SpecialKey(key) => {
+                match key {
+                    KeyCode::Backspace => {
+                        if !current_line.is_empty() {
+                            current_line.pop();
+                            print!("\x08 \x08");  // Backspace, space, backspace
+                        }
+                        shell.reset_tab_completion();  // Reset tab completion on backspace
+                    },
+                    KeyCode::Tab => {
+                        if let Some(completed) = shell.tab_complete(&current_line) {
+                            // Clear current line
+                            while !current_line.is_empty() {
+                                print!("\x08 \x08");
+                                current_line.pop();
+                            }
+                            // Print and set new line
+                            print!("{}", completed);
+                            current_line = completed;
+                        }
+                    },
+                    KeyCode::ArrowUp => {
+                        // Clear current line
+                        while !current_line.is_empty() {
+                            print!("\x08 \x08");
+                            current_line.pop();
+                        }
+
+                        // Get previous command
+                        if let Some(cmd) = shell.previous_command() {
+                            current_line = cmd.to_string();
+                            print!("{}", current_line);
+                        }
+                        shell.reset_tab_completion();
+                    },
+                    KeyCode::ArrowDown => {
+                        // Clear current line
+                        while !current_line.is_empty() {
+                            print!("\x08 \x08");
+                            current_line.pop();
+                        }
+
+                        // Get next command
+                        if let Some(cmd) = shell.next_command() {
+                            current_line = cmd.to_string();
+                            print!("{}", current_line);
+                        }
+                        shell.reset_tab_completion();
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,184 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+use crate::interrupts::apic;
+use crate::{acpi, gdt, interrupts, println, task};
+
+pub mod trampoline;
+
+use trampoline::BootParams;
+
+/// Most CPUs the kernel will bring up; further processors stay parked.
+pub const MAX_CPUS: usize = 8;
+
+const AP_STACK_SIZE: usize = 4096 * 4; // 16 KiB
+
+/// Timer ticks to wait for an AP to check in after its startup IPIs.
+const AP_STARTUP_TIMEOUT: u64 = 20;
+
+/// Per-CPU data. The GS base of each CPU points at its entry in `CPUS`.
+pub struct CpuInfo {
+    id: usize,
+    apic_id: AtomicU32,
+    online: AtomicBool,
+}
+
+impl CpuInfo {
+    const fn new(id: usize) -> Self {
+        CpuInfo {
+            id,
+            apic_id: AtomicU32::new(0),
+            online: AtomicBool::new(false),
+        }
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::SeqCst)
+    }
+}
+
+static CPUS: [CpuInfo; MAX_CPUS] = {
+    let mut cpus = [const { CpuInfo::new(0) }; MAX_CPUS];
+    let mut i = 0;
+    while i < MAX_CPUS {
+        cpus[i] = CpuInfo::new(i);
+        i += 1;
+    }
+    cpus
+};
+
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+#[repr(align(16))]
+struct ApStack([u8; AP_STACK_SIZE]);
+
+impl ApStack {
+    const EMPTY: ApStack = ApStack([0; AP_STACK_SIZE]);
+}
+
+static mut AP_STACKS: [ApStack; MAX_CPUS - 1] = [ApStack::EMPTY; MAX_CPUS - 1];
+
+/// Index of the calling CPU; 0 is the BSP. Valid before `init` as well, when
+/// only the BSP is running.
+pub fn cpu_id() -> usize {
+    let base = GsBase::read().as_u64();
+    if base == 0 {
+        0
+    } else {
+        unsafe { (*(base as *const CpuInfo)).id }
+    }
+}
+
+/// Number of CPUs that have finished coming up.
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+pub fn cpu_info(cpu: usize) -> Option<&'static CpuInfo> {
+    CPUS.get(cpu).filter(|info| info.online.load(Ordering::SeqCst))
+}
+
+/// Makes `cpu` re-run its scheduler, e.g. because work was queued on it while
+/// it sat halted in its idle task.
+pub fn kick(cpu: usize) {
+    if cpu != cpu_id() {
+        if let Some(info) = cpu_info(cpu) {
+            apic::send_fixed(info.apic_id(), interrupts::RESCHEDULE_VECTOR);
+        }
+    }
+}
+
+fn set_cpu_local(cpu: usize) {
+    GsBase::write(VirtAddr::from_ptr(&CPUS[cpu]));
+}
+
+fn wait_ticks(ticks: u64) {
+    let deadline = task::timer::ticks() + ticks;
+    while task::timer::ticks() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Rust entry point of an application processor, reached from the trampoline
+/// on a private stack with the kernel's page tables loaded.
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    set_cpu_local(cpu);
+    gdt::init_ap(cpu);
+    interrupts::init_ap();
+    CPUS[cpu].apic_id.store(apic::id(), Ordering::SeqCst);
+
+    // The code running now becomes this CPU's idle task
+    task::init_ap(cpu);
+    CPUS[cpu].online.store(true, Ordering::SeqCst);
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+
+    x86_64::instructions::interrupts::enable();
+    task::idle_loop();
+}
+
+fn start_ap(cpu: usize, apic_id: u32) -> bool {
+    let stack_top = unsafe { AP_STACKS[cpu - 1].0.as_ptr() as u64 + AP_STACK_SIZE as u64 };
+    trampoline::set_params(&BootParams {
+        cr3: Cr3::read().0.start_address().as_u64(),
+        stack_top,
+        entry: ap_entry,
+        cpu,
+    });
+
+    // INIT, then up to two startup IPIs as the MP specification asks for
+    let page = (trampoline::TRAMPOLINE_ADDR >> 12) as u8;
+    apic::send_init(apic_id);
+    wait_ticks(1);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, page);
+        for _ in 0..AP_STARTUP_TIMEOUT {
+            if CPUS[cpu].online.load(Ordering::SeqCst) {
+                return true;
+            }
+            wait_ticks(1);
+        }
+    }
+    false
+}
+
+/// Brings up every enabled processor listed in the MADT. Requires the heap,
+/// the scheduler and interrupts routed through the APIC.
+pub fn init() {
+    set_cpu_local(0);
+    CPUS[0].online.store(true, Ordering::SeqCst);
+
+    let madt = match acpi::madt() {
+        Some(madt) if interrupts::apic_enabled() => madt,
+        _ => {
+            println!("SMP: no local APIC in use, running on the BSP only");
+            return;
+        }
+    };
+    if let Err(e) = trampoline::install() {
+        println!("SMP: {}, running on the BSP only", e);
+        return;
+    }
+
+    let bsp_apic_id = apic::id();
+    CPUS[0].apic_id.store(bsp_apic_id, Ordering::SeqCst);
+
+    let mut next_cpu = 1;
+    for processor in madt.processors.iter().filter(|p| p.enabled) {
+        let apic_id = processor.apic_id as u32;
+        if apic_id == bsp_apic_id {
+            continue;
+        }
+        if next_cpu == MAX_CPUS {
+            println!("SMP: ignoring CPUs beyond {}", MAX_CPUS);
+            break;
+        }
+        if start_ap(next_cpu, apic_id) {
+            next_cpu += 1;
+        } else {
+            println!("SMP: CPU with APIC ID {} did not start", apic_id);
+        }
+    }
+
+    println!("SMP: {} CPU(s) online", online_cpus());
+}
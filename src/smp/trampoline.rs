@@ -0,0 +1,153 @@
+use core::arch::global_asm;
+use core::ptr;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::PhysAddr;
+use crate::memory;
+
+/// Physical page the application processors start executing at. It sits in
+/// the bootloader's region below 1 MiB, which is no longer needed once the
+/// kernel runs and is never handed out by the frame allocator.
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+// Real mode -> protected mode -> long mode, using a throwaway GDT. The code is
+// copied to TRAMPOLINE_ADDR, so every absolute address is computed relative to
+// that. The parameter block at the end is filled in by the BSP before each
+// startup IPI.
+global_asm!(
+    r#"
+    .pushsection .text.ap_trampoline, "ax"
+    .global ap_trampoline_start
+    .global ap_trampoline_end
+    .global ap_tramp_cr3
+    .global ap_tramp_stack
+    .global ap_tramp_entry
+    .global ap_tramp_cpu
+
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    xorw %ax, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    lgdtl (ap_tramp_gdt_ptr - ap_trampoline_start + 0x8000)
+    movl %cr0, %eax
+    orl $1, %eax
+    movl %eax, %cr0
+    ljmpl $0x08, $(ap_tramp_32 - ap_trampoline_start + 0x8000)
+
+    .code32
+ap_tramp_32:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    // PAE
+    movl %cr4, %eax
+    orl $(1 << 5), %eax
+    movl %eax, %cr4
+    movl (ap_tramp_cr3 - ap_trampoline_start + 0x8000), %eax
+    movl %eax, %cr3
+    // Long mode and no-execute, which the kernel page tables rely on
+    movl $0xC0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+    // Paging and write protect on, caching back on after INIT
+    movl %cr0, %eax
+    andl $0x9FFFFFFF, %eax
+    orl $0x80010000, %eax
+    movl %eax, %cr0
+    ljmpl $0x18, $(ap_tramp_64 - ap_trampoline_start + 0x8000)
+
+    .code64
+ap_tramp_64:
+    xorw %ax, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movw %ax, %fs
+    movw %ax, %gs
+    movq (ap_tramp_stack - ap_trampoline_start + 0x8000), %rsp
+    movq (ap_tramp_cpu - ap_trampoline_start + 0x8000), %rdi
+    movq (ap_tramp_entry - ap_trampoline_start + 0x8000), %rax
+    callq *%rax
+1:
+    hlt
+    jmp 1b
+
+    .balign 8
+ap_tramp_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+ap_tramp_gdt_ptr:
+    .word ap_tramp_gdt_ptr - ap_tramp_gdt - 1
+    .long ap_tramp_gdt - ap_trampoline_start + 0x8000
+
+    .balign 8
+ap_tramp_cr3:
+    .quad 0
+ap_tramp_stack:
+    .quad 0
+ap_tramp_entry:
+    .quad 0
+ap_tramp_cpu:
+    .quad 0
+ap_trampoline_end:
+    .popsection
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_tramp_cr3: u8;
+    static ap_tramp_stack: u8;
+    static ap_tramp_entry: u8;
+    static ap_tramp_cpu: u8;
+}
+
+/// Start-up parameters for one application processor.
+pub struct BootParams {
+    pub cr3: u64,
+    pub stack_top: u64,
+    pub entry: extern "C" fn(usize) -> !,
+    pub cpu: usize,
+}
+
+fn start() -> *const u8 {
+    unsafe { &ap_trampoline_start as *const u8 }
+}
+
+/// Copies the trampoline into low memory and identity maps it, so execution
+/// continues there once the AP turns paging on.
+pub fn install() -> Result<(), &'static str> {
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+    memory::identity_map(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)?;
+
+    unsafe {
+        let len = &ap_trampoline_end as *const u8 as usize - start() as usize;
+        let dest = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR)).as_mut_ptr::<u8>();
+        ptr::copy_nonoverlapping(start(), dest, len);
+    }
+    Ok(())
+}
+
+/// Fills in the parameter block of the installed trampoline.
+pub fn set_params(params: &BootParams) {
+    let slot = |symbol: &u8| {
+        let offset = symbol as *const u8 as u64 - start() as u64;
+        memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR + offset)).as_mut_ptr::<u64>()
+    };
+
+    unsafe {
+        ptr::write_volatile(slot(&ap_tramp_cr3), params.cr3);
+        ptr::write_volatile(slot(&ap_tramp_stack), params.stack_top);
+        ptr::write_volatile(slot(&ap_tramp_entry), params.entry as usize as u64);
+        ptr::write_volatile(slot(&ap_tramp_cpu), params.cpu as u64);
+    }
+}
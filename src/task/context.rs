@@ -1,50 +1,65 @@
+use core::sync::atomic::AtomicBool;
+use x86_64::instructions::interrupts;
 use crate::task::SCHEDULER;
 
+/// Saved state of a task that is not running. The callee-saved registers are
+/// pushed onto the task's own stack, so only the stack pointer lives here.
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct TaskContext {
-    // Callee-saved registers
-    rsp: usize,    // Stack pointer
-    r15: usize,
-    r14: usize,
-    r13: usize,
-    r12: usize,
-    rbx: usize,
-    rbp: usize,    // Base pointer
-    rip: usize,    // Instruction pointer
+    rsp: usize,
 }
 
 impl TaskContext {
+    /// Context of a task that has never run: the first switch to it pops a
+    /// frame that "returns" into `task_entry` with `entry_point` in r12.
     pub fn new(entry_point: usize, stack_top: usize) -> Self {
-        Self {
-            rsp: stack_top,
-            r15: 0,
-            r14: 0,
-            r13: 0,
-            r12: 0,
-            rbx: 0,
-            rbp: 0,
-            rip: entry_point,
-        }
-    }
-
-    pub fn switch(&mut self, next: &mut TaskContext) {
+        let top = stack_top & !0xf;
+        // r15, r14, r13, r12, rbx, rbp, return address
+        let frame = [0, 0, 0, entry_point, 0, 0, task_entry as usize];
+        let rsp = top - frame.len() * core::mem::size_of::<usize>();
         unsafe {
-            switch_context_inner(self, next);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut usize, frame.len());
         }
+        Self { rsp }
     }
 
-    pub fn switch_to(&mut self, next: &mut TaskContext) {
-        let mut guard = SCHEDULER.lock();
-        if let Some(current) = guard.current.as_ref() {
-            let mut current = current.write();
-            self.switch(next);
-        }
+    /// Context of code that is already running, e.g. a CPU's boot thread. It is
+    /// filled in the first time the task is switched away from.
+    pub const fn running() -> Self {
+        Self { rsp: 0 }
     }
 }
 
 #[naked]
-unsafe extern "C" fn switch_context_inner(_current: *mut TaskContext, _next: *mut TaskContext) {
+unsafe extern "C" fn task_entry() -> ! {
+    use core::arch::asm;
+    asm!(
+        "mov rdi, r12",
+        "call {start}",
+        start = sym task_start,
+        options(noreturn)
+    );
+}
+
+extern "C" fn task_start(entry_point: usize) -> ! {
+    // A new task starts inside the switch that picked it, with interrupts off
+    interrupts::enable();
+    let entry: fn() = unsafe { core::mem::transmute(entry_point) };
+    entry();
+    super::exit();
+}
+
+/// Saves the callee-saved registers and stack pointer into `current`, marks it
+/// as no longer on a CPU and resumes `next`. Clearing `current_on_cpu` only
+/// after the stack pointer is saved keeps other CPUs from resuming the task
+/// before its context is complete.
+#[naked]
+unsafe extern "C" fn switch_context_inner(
+    _current: *mut TaskContext,
+    _next: *const TaskContext,
+    _current_on_cpu: *const AtomicBool,
+) {
     use core::arch::asm;
     asm!(
         // Save current context
@@ -55,6 +70,7 @@ unsafe extern "C" fn switch_context_inner(_current: *mut TaskContext, _next: *mu
         "push r14",
         "push r15",
         "mov [rdi + 0], rsp",  // Save RSP
+        "mov byte ptr [rdx], 0",
 
         // Load next context
         "mov rsp, [rsi + 0]",  // Restore RSP
@@ -70,16 +86,12 @@ unsafe extern "C" fn switch_context_inner(_current: *mut TaskContext, _next: *mu
 }
 
 pub unsafe fn switch_context() {
-    use x86_64::instructions::interrupts;
-
     interrupts::without_interrupts(|| {
-        let mut guard = SCHEDULER.lock();
-        if let Some(next_task) = guard.schedule() {
-            let mut next = next_task.write();
-            if let Some(current_task) = guard.current.as_ref() {
-                let mut current = current_task.write();
-                current.context.switch(&mut next.context);
-            }
+        // The scheduler lock must not be held across the switch; the task we
+        // switch to may be the one that releases it
+        let switch = SCHEDULER.lock().switch_next();
+        if let Some(switch) = switch {
+            switch_context_inner(switch.current, switch.next, switch.current_on_cpu);
         }
     });
-} 
\ No newline at end of file
+}
@@ -0,0 +1,176 @@
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+/// Number of wakeups queued between two executor passes before the executor
+/// falls back to polling every future.
+const TASK_QUEUE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AsyncTaskId(u64);
+
+impl AsyncTaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        AsyncTaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A top-level future driven by the [`Executor`]. Unlike [`super::Task`] it has
+/// no stack of its own and only runs when its waker fires.
+pub struct AsyncTask {
+    id: AsyncTaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl AsyncTask {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+        Self {
+            id: AsyncTaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/// Ids of woken futures. Wakers can fire in interrupt handlers, so pushing
+/// neither allocates nor locks; a wakeup that does not fit marks the queue as
+/// overflowed rather than being lost.
+struct TaskQueue {
+    ready: ArrayQueue<AsyncTaskId>,
+    overflowed: AtomicBool,
+}
+
+impl TaskQueue {
+    fn push(&self, id: AsyncTaskId) {
+        if self.ready.push(id).is_err() {
+            self.overflowed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ready.is_empty() && !self.overflowed.load(Ordering::SeqCst)
+    }
+}
+
+/// Cooperative executor for kernel futures. When nothing is runnable the CPU
+/// is halted until the next interrupt, which is what wakes futures up.
+pub struct Executor {
+    tasks: BTreeMap<AsyncTaskId, AsyncTask>,
+    task_queue: Arc<TaskQueue>,
+    waker_cache: BTreeMap<AsyncTaskId, Waker>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(TaskQueue {
+                ready: ArrayQueue::new(TASK_QUEUE_SIZE),
+                overflowed: AtomicBool::new(false),
+            }),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: AsyncTask) {
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("async task with same ID already in tasks");
+        }
+        self.task_queue.push(id);
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        let Self {
+            tasks,
+            task_queue,
+            waker_cache,
+        } = self;
+
+        loop {
+            while let Some(id) = task_queue.ready.pop() {
+                Self::poll_task(tasks, waker_cache, task_queue, id);
+            }
+            if !task_queue.overflowed.swap(false, Ordering::SeqCst) {
+                break;
+            }
+            // Which wakeups were dropped is unknown; futures put up with
+            // being polled when not ready, so poll them all
+            let ids: Vec<AsyncTaskId> = tasks.keys().copied().collect();
+            for id in ids {
+                Self::poll_task(tasks, waker_cache, task_queue, id);
+            }
+        }
+    }
+
+    fn poll_task(
+        tasks: &mut BTreeMap<AsyncTaskId, AsyncTask>,
+        waker_cache: &mut BTreeMap<AsyncTaskId, Waker>,
+        task_queue: &Arc<TaskQueue>,
+        id: AsyncTaskId,
+    ) {
+        let task = match tasks.get_mut(&id) {
+            Some(task) => task,
+            None => return, // Woken after it already completed
+        };
+        let waker = waker_cache
+            .entry(id)
+            .or_insert_with(|| TaskWaker::new(id, Arc::clone(task_queue)));
+        let mut context = Context::from_waker(waker);
+        if let Poll::Ready(()) = task.poll(&mut context) {
+            tasks.remove(&id);
+            waker_cache.remove(&id);
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // Interrupts are disabled while checking so a wake-up that arrives
+        // between the check and `hlt` is not lost.
+        interrupts::disable();
+        if self.task_queue.is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+struct TaskWaker {
+    id: AsyncTaskId,
+    task_queue: Arc<TaskQueue>,
+}
+
+impl TaskWaker {
+    fn new(id: AsyncTaskId, task_queue: Arc<TaskQueue>) -> Waker {
+        Waker::from(Arc::new(TaskWaker { id, task_queue }))
+    }
+
+    fn wake_task(&self) {
+        self.task_queue.push(self.id);
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
@@ -0,0 +1,245 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+use spin::RwLock;
+use super::{Task, TaskState};
+use crate::memory::heap;
+
+type TaskRef = Arc<RwLock<Task>>;
+
+/// Share value of a group that was never tuned; groups with twice the shares
+/// accumulate virtual runtime half as fast under the fair-share policy.
+pub const DEFAULT_CPU_SHARES: u64 = 1024;
+
+/// Length of the accounting window used to enforce `cpu_limit`, in cycles.
+pub const CPU_WINDOW: u64 = 100_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupError {
+    NotFound,
+    AlreadyExists,
+    TaskNotFound,
+    Busy,
+    StackLimitExceeded,
+    HeapLimitExceeded,
+    TooManyGroups,
+    InvalidLimit,
+    SpawnFailed,
+}
+
+impl GroupError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GroupError::NotFound => "no such group",
+            GroupError::AlreadyExists => "group already exists",
+            GroupError::TaskNotFound => "no such task",
+            GroupError::Busy => "group still has members",
+            GroupError::StackLimitExceeded => "stack limit exceeded",
+            GroupError::HeapLimitExceeded => "heap limit exceeded",
+            GroupError::TooManyGroups => "too many groups",
+            GroupError::InvalidLimit => "invalid limit",
+            GroupError::SpawnFailed => "could not allocate the task",
+        }
+    }
+}
+
+/// A control group: a set of tasks sharing CPU limits, a limit on the kernel
+/// memory reserved for their stacks and TLS, and one on what they allocate
+/// from the heap.
+#[derive(Debug)]
+pub struct TaskGroup {
+    id: usize,
+    name: String,
+    cpu_shares: u64,
+    /// Maximum percentage of each `CPU_WINDOW` the group may run for.
+    cpu_limit: Option<u8>,
+    /// Bytes of stack and TLS the members may reserve between them.
+    stack_limit: Option<usize>,
+    stack_usage: usize,
+    /// Slot in the heap's per-group accounting, which enforces the heap
+    /// limit on every allocation.
+    heap_slot: usize,
+    window_start: u64,
+    window_runtime: u64,
+    members: Vec<TaskRef>,
+}
+
+/// Aggregated usage of a group, as reported by [`super::Scheduler::group_stats`].
+#[derive(Debug, Clone)]
+pub struct GroupStats {
+    pub id: usize,
+    pub name: String,
+    pub members: Vec<usize>,
+    pub cpu_shares: u64,
+    pub cpu_limit: Option<u8>,
+    pub stack_limit: Option<usize>,
+    pub stack_usage: usize,
+    pub heap_limit: Option<usize>,
+    pub heap_usage: usize,
+    pub total_runtime: u64,
+    pub total_wait: u64,
+    pub context_switches: usize,
+}
+
+impl TaskGroup {
+    pub fn new(id: usize, name: String) -> Result<Self, GroupError> {
+        Ok(Self {
+            id,
+            name,
+            cpu_shares: DEFAULT_CPU_SHARES,
+            cpu_limit: None,
+            stack_limit: None,
+            stack_usage: 0,
+            heap_slot: heap::claim_group().ok_or(GroupError::TooManyGroups)?,
+            window_start: 0,
+            window_runtime: 0,
+            members: Vec::new(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn cpu_shares(&self) -> u64 {
+        self.cpu_shares
+    }
+
+    pub fn set_cpu_shares(&mut self, shares: u64) -> Result<(), GroupError> {
+        if shares == 0 {
+            return Err(GroupError::InvalidLimit);
+        }
+        self.cpu_shares = shares;
+        Ok(())
+    }
+
+    pub fn set_cpu_limit(&mut self, percent: Option<u8>) -> Result<(), GroupError> {
+        if matches!(percent, Some(0) | Some(101..)) {
+            return Err(GroupError::InvalidLimit);
+        }
+        self.cpu_limit = percent;
+        Ok(())
+    }
+
+    pub fn set_stack_limit(&mut self, limit: Option<usize>) -> Result<(), GroupError> {
+        if limit.map_or(false, |limit| limit < self.stack_usage) {
+            return Err(GroupError::StackLimitExceeded);
+        }
+        self.stack_limit = limit;
+        Ok(())
+    }
+
+    /// Limits the heap use of the members. Allocations that would take them
+    /// past it fail.
+    pub fn set_heap_limit(&mut self, limit: Option<usize>) -> Result<(), GroupError> {
+        if !heap::set_group_limit(self.heap_slot, limit) {
+            return Err(GroupError::HeapLimitExceeded);
+        }
+        Ok(())
+    }
+
+    /// Slot the members' heap allocations are charged to.
+    pub(super) fn heap_slot(&self) -> usize {
+        self.heap_slot
+    }
+
+    pub fn members(&self) -> &[TaskRef] {
+        &self.members
+    }
+
+    /// Charges `bytes` against the stack limit.
+    pub fn charge(&mut self, bytes: usize) -> Result<(), GroupError> {
+        let usage = self.stack_usage + bytes;
+        if self.stack_limit.map_or(false, |limit| usage > limit) {
+            return Err(GroupError::StackLimitExceeded);
+        }
+        self.stack_usage = usage;
+        Ok(())
+    }
+
+    pub fn uncharge(&mut self, bytes: usize) {
+        self.stack_usage = self.stack_usage.saturating_sub(bytes);
+    }
+
+    pub(super) fn add_member(&mut self, task: TaskRef) -> Result<(), GroupError> {
+        let footprint = task.read().stack_footprint();
+        self.charge(footprint)?;
+        task.write().group_id = Some(self.id);
+        self.members.push(task);
+        Ok(())
+    }
+
+    pub(super) fn remove_member(&mut self, task: &TaskRef) {
+        let before = self.members.len();
+        self.members.retain(|member| !Arc::ptr_eq(member, task));
+        if self.members.len() != before {
+            self.uncharge(task.read().stack_footprint());
+            task.write().group_id = None;
+        }
+    }
+
+    /// Drops members that have terminated, releasing their stack charge.
+    pub(super) fn reap(&mut self) {
+        let mut freed = 0;
+        self.members.retain(|member| {
+            let member = member.read();
+            if member.state == TaskState::Terminated {
+                freed += member.stack_footprint();
+                false
+            } else {
+                true
+            }
+        });
+        self.uncharge(freed);
+    }
+
+    /// Records `delta` cycles of CPU time used by a member at `now`.
+    pub(super) fn account(&mut self, now: u64, delta: u64) {
+        if now.saturating_sub(self.window_start) >= CPU_WINDOW {
+            self.window_start = now;
+            self.window_runtime = 0;
+        }
+        self.window_runtime += delta;
+    }
+
+    /// Whether the group has used up its CPU budget for the current window.
+    pub(super) fn is_throttled(&self, now: u64) -> bool {
+        match self.cpu_limit {
+            Some(percent) if now.saturating_sub(self.window_start) < CPU_WINDOW => {
+                self.window_runtime >= CPU_WINDOW * percent as u64 / 100
+            }
+            _ => false,
+        }
+    }
+
+    pub fn stats(&self) -> GroupStats {
+        let mut stats = GroupStats {
+            id: self.id,
+            name: self.name.clone(),
+            members: Vec::with_capacity(self.members.len()),
+            cpu_shares: self.cpu_shares,
+            cpu_limit: self.cpu_limit,
+            stack_limit: self.stack_limit,
+            stack_usage: self.stack_usage,
+            heap_limit: heap::group_limit(self.heap_slot),
+            heap_usage: heap::group_usage(self.heap_slot),
+            total_runtime: 0,
+            total_wait: 0,
+            context_switches: 0,
+        };
+
+        for member in &self.members {
+            let member = member.read();
+            stats.members.push(member.id);
+            stats.total_runtime += member.stats.total_runtime;
+            stats.total_wait += member.stats.total_wait;
+            stats.context_switches += member.stats.context_switches;
+        }
+
+        stats
+    }
+}
+
+impl Drop for TaskGroup {
+    fn drop(&mut self) {
+        heap::release_group(self.heap_slot);
+    }
+}
@@ -1,15 +1,23 @@
 use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec, vec::Vec, collections::BTreeMap};
+use alloc::{format, string::{String, ToString}};
 use spin::{Mutex, RwLock};
 use lazy_static::lazy_static;
 use x86_64::instructions::interrupts;
 use x86_64::instructions::random::RdRand;
-use core::sync::atomic::{AtomicUsize, Ordering};
-use crate::println;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::{println, smp};
+use crate::memory::{heap, stack::KernelStack};
 
 pub mod context;
+pub mod executor;
+pub mod group;
+pub mod policy;
 pub mod sync;
+pub mod timer;
 
 use context::TaskContext;
+use group::{GroupError, GroupStats, TaskGroup};
+use policy::{SchedPolicy, SchedPolicyKind};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TaskState {
@@ -20,19 +28,63 @@ pub enum TaskState {
     Suspended,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum TaskPriority {
     Low = 0,
     Normal = 1,
     High = 2,
 }
 
+impl TaskPriority {
+    /// Scheduler ticks a task at this level may run before being preempted.
+    /// Interactive, high priority work gets short slices; batch work at low
+    /// priority gets long ones so it makes progress when it does run.
+    pub const fn quantum(self) -> usize {
+        match self {
+            TaskPriority::Low => 200,
+            TaskPriority::Normal => 100,
+            TaskPriority::High => 50,
+        }
+    }
+
+    fn raised(self) -> Self {
+        match self {
+            TaskPriority::Low => TaskPriority::Normal,
+            TaskPriority::Normal | TaskPriority::High => TaskPriority::High,
+        }
+    }
+
+    fn lowered(self) -> Self {
+        match self {
+            TaskPriority::Low | TaskPriority::Normal => TaskPriority::Low,
+            TaskPriority::High => TaskPriority::Normal,
+        }
+    }
+}
+
+/// The variant name, honouring width and alignment so tables line up.
+impl core::fmt::Display for TaskPriority {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.pad(match self {
+            TaskPriority::Low => "Low",
+            TaskPriority::Normal => "Normal",
+            TaskPriority::High => "High",
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct TaskStatistics {
     created_at: u64,
     total_runtime: u64,
     context_switches: usize,
     last_scheduled: Option<u64>,
+    vruntime: u64,
+    enqueued_at: Option<u64>,
+    total_wait: u64,
+    max_wait: u64,
+    /// Levels the task has been raised by aging since it last ran.
+    aged_levels: usize,
 }
 
 impl TaskStatistics {
@@ -42,8 +94,51 @@ impl TaskStatistics {
             total_runtime: 0,
             context_switches: 0,
             last_scheduled: None,
+            vruntime: 0,
+            enqueued_at: None,
+            total_wait: 0,
+            max_wait: 0,
+            aged_levels: 0,
         }
     }
+
+    /// Total cycles spent ready but not running.
+    pub fn total_wait(&self) -> u64 {
+        self.total_wait
+    }
+
+    /// Longest single stretch spent on a ready queue.
+    pub fn max_wait(&self) -> u64 {
+        self.max_wait
+    }
+
+    pub fn context_switches(&self) -> usize {
+        self.context_switches
+    }
+
+    pub fn total_runtime(&self) -> u64 {
+        self.total_runtime
+    }
+
+    pub fn vruntime(&self) -> u64 {
+        self.vruntime
+    }
+}
+
+/// Per-task scheduling latency, as reported by [`Scheduler::wait_stats`].
+#[derive(Debug, Clone)]
+pub struct WaitStats {
+    pub id: usize,
+    pub priority: TaskPriority,
+    pub base_priority: TaskPriority,
+    pub total_wait: u64,
+    pub max_wait: u64,
+    pub context_switches: usize,
+}
+
+fn next_task_id() -> usize {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    NEXT_ID.fetch_add(1, Ordering::SeqCst)
 }
 
 fn get_current_time() -> u64 {
@@ -58,49 +153,104 @@ pub struct Task {
     state: TaskState,
     priority: TaskPriority,
     context: TaskContext,
-    stack: Box<[u8]>,
+    /// Absent for tasks wrapping code that already had a stack, like a CPU's
+    /// boot thread.
+    stack: Option<KernelStack>,
     tls: Option<Box<[u8]>>,
-    quantum: usize,
     time_slice: AtomicUsize,
     deadline: Option<u64>,
     group_id: Option<usize>,
     stats: TaskStatistics,
     base_priority: TaskPriority,
+    blocked_on: Option<Arc<sync::InheritanceState>>,
+    held_locks: Vec<Arc<sync::InheritanceState>>,
+    /// Levels lent by waiters on locks the task holds, taken back on unlock.
+    inherited_levels: usize,
+    /// Set while a CPU runs on this task's stack, until its context is saved.
+    on_cpu: AtomicBool,
+    /// Idle tasks only run when a CPU has nothing else to do.
+    idle: bool,
 }
 
 impl Task {
     const STACK_SIZE: usize = 4096 * 5; // 20KB stack
     const TLS_SIZE: usize = 4096;       // 4KB TLS
-    const DEFAULT_QUANTUM: usize = 100;  // Default time quantum
 
-    pub fn new(entry_point: fn()) -> Self {
-        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
-        
-        let stack = Box::new([0; Self::STACK_SIZE]);
-        let stack_top = stack.as_ptr() as usize + Self::STACK_SIZE;
-        
-        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    /// Sets up a task to run `entry_point` on a fresh stack. Fails when no
+    /// stack can be mapped for it.
+    pub fn new(entry_point: fn()) -> Result<Self, &'static str> {
+        let id = next_task_id();
+        let stack = KernelStack::allocate(Self::STACK_SIZE, id)?;
+        let stack_top = stack.top().as_u64() as usize;
 
-        Self {
+        Ok(Self {
             id,
             state: TaskState::Ready,
             priority: TaskPriority::Normal,
             context: TaskContext::new(entry_point as usize, stack_top),
-            stack,
+            stack: Some(stack),
             tls: Some(Box::new([0; Self::TLS_SIZE])),
-            quantum: Self::DEFAULT_QUANTUM,
-            time_slice: AtomicUsize::new(Self::DEFAULT_QUANTUM),
+            time_slice: AtomicUsize::new(TaskPriority::Normal.quantum()),
             deadline: None,
             group_id: None,
             stats: TaskStatistics::new(),
             base_priority: TaskPriority::Normal,
+            blocked_on: None,
+            held_locks: Vec::new(),
+            inherited_levels: 0,
+            on_cpu: AtomicBool::new(false),
+            idle: false,
+        })
+    }
+
+    /// Wraps code that is already running on the current CPU, such as the
+    /// kernel's boot thread, so it can be switched away from like any task.
+    fn bootstrap(priority: TaskPriority) -> Self {
+        Self {
+            id: next_task_id(),
+            state: TaskState::Running,
+            priority,
+            context: TaskContext::running(),
+            stack: None,
+            tls: None,
+            time_slice: AtomicUsize::new(priority.quantum()),
+            deadline: None,
+            group_id: None,
+            stats: TaskStatistics::new(),
+            base_priority: priority,
+            blocked_on: None,
+            held_locks: Vec::new(),
+            inherited_levels: 0,
+            on_cpu: AtomicBool::new(true),
+            idle: false,
         }
     }
 
-    pub fn with_priority(entry_point: fn(), priority: TaskPriority) -> Self {
-        let mut task = Self::new(entry_point);
+    fn into_idle(mut self) -> Self {
+        self.idle = true;
+        self.priority = TaskPriority::Low;
+        self.base_priority = TaskPriority::Low;
+        self
+    }
+
+    pub fn with_priority(entry_point: fn(), priority: TaskPriority) -> Result<Self, &'static str> {
+        let mut task = Self::new(entry_point)?;
         task.priority = priority;
-        task
+        task.base_priority = priority;
+        task.reset_time_slice();
+        Ok(task)
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn priority(&self) -> TaskPriority {
+        self.priority
+    }
+
+    pub fn base_priority(&self) -> TaskPriority {
+        self.base_priority
     }
 
     pub fn get_tls(&self) -> Option<&[u8]> {
@@ -111,8 +261,12 @@ impl Task {
         self.tls.as_mut().map(|tls| tls.as_mut())
     }
 
+    pub fn quantum(&self) -> usize {
+        self.priority.quantum()
+    }
+
     pub fn reset_time_slice(&self) {
-        self.time_slice.store(self.quantum, Ordering::SeqCst);
+        self.time_slice.store(self.quantum(), Ordering::SeqCst);
     }
 
     pub fn decrement_time_slice(&self) -> bool {
@@ -127,18 +281,39 @@ impl Task {
         self.group_id = Some(group_id);
     }
 
+    pub fn group_id(&self) -> Option<usize> {
+        self.group_id
+    }
+
+    /// Bytes charged to the task's control group for its stack and TLS.
+    pub fn stack_footprint(&self) -> usize {
+        self.stack.as_ref().map_or(0, |stack| stack.size()) + self.tls.as_ref().map_or(0, |tls| tls.len())
+    }
+
     pub fn boost_priority(&mut self) {
-        if self.priority != TaskPriority::High {
-            self.priority = match self.priority {
-                TaskPriority::Low => TaskPriority::Normal,
-                TaskPriority::Normal => TaskPriority::High,
-                TaskPriority::High => TaskPriority::High,
-            };
+        self.priority = self.priority.raised();
+    }
+
+    /// Raises the task one level if it has waited `now - enqueued_at` for
+    /// longer than the levels it was already aged by allow.
+    fn age(&mut self, now: u64) -> bool {
+        let waited = self.stats.enqueued_at.map_or(0, |enqueued_at| now.saturating_sub(enqueued_at));
+        let raised = self.priority.raised();
+        if raised == self.priority || waited <= AGING_INTERVAL * (self.stats.aged_levels as u64 + 1) {
+            return false;
         }
+        self.priority = raised;
+        self.stats.aged_levels += 1;
+        true
     }
 
-    pub fn reset_priority(&mut self) {
-        self.priority = self.base_priority;
+    /// Takes back the levels gained through aging, keeping deadline boosts
+    /// and never dropping below the base or an inherited priority.
+    fn end_aging(&mut self) {
+        let floor = self.base_priority.max(self.inherited_priority().unwrap_or(self.base_priority));
+        for _ in 0..core::mem::take(&mut self.stats.aged_levels) {
+            self.priority = self.priority.lowered().max(floor);
+        }
     }
 
     pub fn suspend(&mut self) {
@@ -158,124 +333,580 @@ impl Task {
     }
 }
 
+/// Cycles a ready task may wait before it is raised one priority level
+/// (roughly 100ms at 2 GHz). A task waiting `n` intervals is raised `n` levels.
+const AGING_INTERVAL: u64 = 200_000_000;
+
+type TaskRef = Arc<RwLock<Task>>;
+
+/// Run queues and bookkeeping of a single CPU.
+struct CpuQueue {
+    tasks: Vec<VecDeque<TaskRef>>,
+    current: Option<TaskRef>,
+    idle: Option<TaskRef>,
+    /// Task switched away from last. It is kept alive until the next pick on
+    /// this CPU, by which time its context has been saved.
+    previous: Option<TaskRef>,
+}
+
+impl CpuQueue {
+    fn new(current: Option<TaskRef>, idle: Option<TaskRef>) -> Self {
+        Self {
+            tasks: vec![VecDeque::new(); 3], // One queue per priority level
+            current,
+            idle,
+            previous: None,
+        }
+    }
+
+    /// Ready tasks plus the running one, unless the CPU is idle.
+    fn load(&self) -> usize {
+        let running = self.current.as_ref().map_or(0, |task| !task.read().idle as usize);
+        self.tasks.iter().map(|queue| queue.len()).sum::<usize>() + running
+    }
+}
+
+/// Per-CPU load, as reported by [`Scheduler::cpu_stats`].
+#[derive(Debug, Clone)]
+pub struct CpuStats {
+    pub cpu: usize,
+    pub current: Option<usize>,
+    pub ready: usize,
+}
+
+/// Pointers needed to switch from the running task to the next one; the tasks
+/// are kept alive by the scheduler until the switch has happened.
+pub(crate) struct Switch {
+    pub current: *mut TaskContext,
+    pub next: *const TaskContext,
+    pub current_on_cpu: *const AtomicBool,
+}
+
 pub struct Scheduler {
-    tasks: Vec<VecDeque<Arc<RwLock<Task>>>>,
-    current: Option<Arc<RwLock<Task>>>,
-    task_groups: BTreeMap<usize, Vec<Arc<RwLock<Task>>>>,
+    cpus: Vec<CpuQueue>,
+    task_groups: BTreeMap<usize, TaskGroup>,
+    next_group_id: usize,
+    policy: Box<dyn SchedPolicy>,
 }
 
 impl Scheduler {
     pub fn new() -> Self {
+        // Whatever is running when the scheduler is first touched, i.e. the
+        // kernel's boot thread, becomes an ordinary task on CPU 0
+        let boot = Arc::new(RwLock::new(Task::bootstrap(TaskPriority::Normal)));
         Self {
-            tasks: vec![VecDeque::new(); 3], // One queue per priority level
-            current: None,
+            cpus: vec![CpuQueue::new(Some(boot), None)],
             task_groups: BTreeMap::new(),
+            next_group_id: 1,
+            policy: SchedPolicyKind::boot_default().build(),
         }
     }
 
-    pub fn spawn(&mut self, entry_point: fn()) {
-        self.spawn_with_priority(entry_point, TaskPriority::Normal);
+    /// Adds the run queue of CPU `cpu`, whose running code becomes its idle
+    /// task.
+    pub fn register_cpu(&mut self, cpu: usize) {
+        let idle = Arc::new(RwLock::new(Task::bootstrap(TaskPriority::Low).into_idle()));
+        while self.cpus.len() <= cpu {
+            self.cpus.push(CpuQueue::new(None, None));
+        }
+        self.cpus[cpu] = CpuQueue::new(Some(Arc::clone(&idle)), Some(idle));
+    }
+
+    pub fn set_idle_task(&mut self, cpu: usize, entry_point: fn()) -> Result<(), &'static str> {
+        let idle = Task::new(entry_point)?.into_idle();
+        self.cpus[cpu].idle = Some(Arc::new(RwLock::new(idle)));
+        Ok(())
     }
 
-    pub fn spawn_with_priority(&mut self, entry_point: fn(), priority: TaskPriority) {
-        let task = Arc::new(RwLock::new(Task::with_priority(entry_point, priority)));
-        self.tasks[priority as usize].push_back(task);
+    pub fn policy_name(&self) -> &'static str {
+        self.policy.name()
+    }
+
+    /// Swaps the scheduling policy. Ready tasks are re-announced to the new
+    /// policy so it can initialise any per-task state it relies on.
+    pub fn set_policy(&mut self, kind: SchedPolicyKind) {
+        self.policy = kind.build();
+        for task in self.cpus.iter().flat_map(|cpu| cpu.tasks.iter().flatten()) {
+            self.policy.enqueue(&mut task.write());
+        }
+    }
+
+    /// Queues a task on the least loaded CPU.
+    fn enqueue(&mut self, task: TaskRef) {
+        let cpu = self.least_loaded_cpu();
+        self.enqueue_on(cpu, task);
+    }
+
+    fn enqueue_on(&mut self, cpu: usize, task: TaskRef) {
+        let priority = {
+            let mut task_write = task.write();
+            task_write.stats.enqueued_at = Some(get_current_time());
+            self.policy.enqueue(&mut task_write);
+            task_write.priority as usize
+        };
+        self.cpus[cpu].tasks[priority].push_back(task);
+
+        let idle = self.cpus[cpu].current.as_ref().map_or(false, |current| current.read().idle);
+        if idle {
+            smp::kick(cpu);
+        }
     }
 
-    pub fn spawn_with_deadline(&mut self, entry_point: fn(), deadline: u64) {
-        let mut task = Task::new(entry_point);
+    fn least_loaded_cpu(&self) -> usize {
+        (0..self.cpus.len())
+            .filter(|&cpu| self.cpus[cpu].current.is_some())
+            .min_by_key(|&cpu| self.cpus[cpu].load())
+            .unwrap_or(0)
+    }
+
+    pub fn spawn(&mut self, entry_point: fn()) -> Result<(), &'static str> {
+        self.spawn_with_priority(entry_point, TaskPriority::Normal)
+    }
+
+    pub fn spawn_with_priority(&mut self, entry_point: fn(), priority: TaskPriority) -> Result<(), &'static str> {
+        let task = Arc::new(RwLock::new(Task::with_priority(entry_point, priority)?));
+        self.enqueue(task);
+        Ok(())
+    }
+
+    pub fn spawn_with_deadline(&mut self, entry_point: fn(), deadline: u64) -> Result<(), &'static str> {
+        let mut task = Task::new(entry_point)?;
         task.set_deadline(deadline);
         let task = Arc::new(RwLock::new(task));
-        self.tasks[TaskPriority::Normal as usize].push_back(task);
+        self.enqueue(task);
+        Ok(())
     }
 
-    pub fn spawn_in_group(&mut self, entry_point: fn(), group_id: usize) {
-        let mut task = Task::new(entry_point);
-        task.set_group(group_id);
+    pub fn spawn_in_group(&mut self, entry_point: fn(), group_id: usize) -> Result<(), GroupError> {
+        let task = Task::new(entry_point).map_err(|_| GroupError::SpawnFailed)?;
         let task = Arc::new(RwLock::new(task));
-        self.task_groups.entry(group_id)
-            .or_insert_with(Vec::new)
-            .push(Arc::clone(&task));
-        self.tasks[TaskPriority::Normal as usize].push_back(task);
+        if !self.task_groups.contains_key(&group_id) {
+            self.insert_group(group_id, format!("group{}", group_id))?;
+        }
+        self.task_groups.get_mut(&group_id)
+            .ok_or(GroupError::NotFound)?
+            .add_member(Arc::clone(&task))?;
+        self.enqueue(task);
+        Ok(())
+    }
+
+    fn insert_group(&mut self, group_id: usize, name: String) -> Result<(), GroupError> {
+        if self.task_groups.values().any(|group| group.name() == name) {
+            return Err(GroupError::AlreadyExists);
+        }
+        self.task_groups.insert(group_id, TaskGroup::new(group_id, name)?);
+        self.next_group_id = self.next_group_id.max(group_id + 1);
+        Ok(())
+    }
+
+    pub fn create_group(&mut self, name: &str) -> Result<usize, GroupError> {
+        let group_id = self.next_group_id;
+        self.insert_group(group_id, name.to_string())?;
+        Ok(group_id)
+    }
+
+    /// Removes an empty group. Groups with live members must be killed or
+    /// emptied first.
+    pub fn remove_group(&mut self, group_id: usize) -> Result<(), GroupError> {
+        let group = self.task_groups.get_mut(&group_id).ok_or(GroupError::NotFound)?;
+        group.reap();
+        if !group.members().is_empty() {
+            return Err(GroupError::Busy);
+        }
+        self.task_groups.remove(&group_id);
+        Ok(())
+    }
+
+    pub fn group_mut(&mut self, group_id: usize) -> Option<&mut TaskGroup> {
+        self.task_groups.get_mut(&group_id)
     }
 
     pub fn suspend_group(&mut self, group_id: usize) {
-        if let Some(tasks) = self.task_groups.get(&group_id) {
-            for task in tasks {
+        if let Some(group) = self.task_groups.get(&group_id) {
+            for task in group.members() {
                 task.write().suspend();
             }
         }
     }
 
     pub fn resume_group(&mut self, group_id: usize) {
-        if let Some(tasks) = self.task_groups.get(&group_id) {
-            for task in tasks {
-                task.write().resume();
+        let members = match self.task_groups.get(&group_id) {
+            Some(group) => group.members().to_vec(),
+            None => return,
+        };
+        for task in members {
+            let was_suspended = task.read().state == TaskState::Suspended;
+            task.write().resume();
+            // A task suspended while running was never put back on a queue
+            if was_suspended && !self.is_queued(&task) && !self.is_current(&task) {
+                self.enqueue(task);
             }
         }
     }
 
-    pub fn schedule(&mut self) -> Option<Arc<RwLock<Task>>> {
-        if let Some(ref current) = self.current {
+    /// Terminates every member of the group and drops them from the run
+    /// queues. Members running on a CPU stop at its next reschedule.
+    pub fn kill_group(&mut self, group_id: usize) -> Result<usize, GroupError> {
+        let group = self.task_groups.get_mut(&group_id).ok_or(GroupError::NotFound)?;
+        let members = group.members().to_vec();
+        for task in &members {
+            task.write().state = TaskState::Terminated;
+        }
+        for queue in self.cpus.iter_mut().flat_map(|cpu| cpu.tasks.iter_mut()) {
+            queue.retain(|task| !members.iter().any(|member| Arc::ptr_eq(member, task)));
+        }
+        group.reap();
+        Ok(members.len())
+    }
+
+    /// Moves a task into another group, charging its memory to the new group.
+    pub fn move_task(&mut self, task_id: usize, group_id: usize) -> Result<(), GroupError> {
+        let task = self.find_task(task_id).ok_or(GroupError::TaskNotFound)?;
+        if !self.task_groups.contains_key(&group_id) {
+            return Err(GroupError::NotFound);
+        }
+
+        let old_group = task.read().group_id;
+        if old_group == Some(group_id) {
+            return Ok(());
+        }
+        if let Some(group) = old_group.and_then(|id| self.task_groups.get_mut(&id)) {
+            group.remove_member(&task);
+        }
+
+        let result = self.task_groups.get_mut(&group_id)
+            .ok_or(GroupError::NotFound)?
+            .add_member(Arc::clone(&task));
+        if result.is_err() {
+            // Put it back where it came from; it fit there a moment ago
+            if let Some(group) = old_group.and_then(|id| self.task_groups.get_mut(&id)) {
+                let _ = group.add_member(task);
+            }
+        }
+        result
+    }
+
+    pub fn group_stats(&mut self, group_id: usize) -> Option<GroupStats> {
+        let group = self.task_groups.get_mut(&group_id)?;
+        group.reap();
+        Some(group.stats())
+    }
+
+    pub fn list_groups(&mut self) -> Vec<GroupStats> {
+        self.task_groups.values_mut()
+            .map(|group| {
+                group.reap();
+                group.stats()
+            })
+            .collect()
+    }
+
+    fn find_task(&self, task_id: usize) -> Option<TaskRef> {
+        self.cpus.iter()
+            .flat_map(|cpu| cpu.current.iter().chain(cpu.tasks.iter().flatten()))
+            .chain(self.task_groups.values().flat_map(|group| group.members()))
+            .find(|task| task.read().id == task_id)
+            .cloned()
+    }
+
+    fn is_queued(&self, task: &TaskRef) -> bool {
+        self.cpus.iter()
+            .flat_map(|cpu| cpu.tasks.iter().flatten())
+            .any(|queued| Arc::ptr_eq(queued, task))
+    }
+
+    fn is_current(&self, task: &TaskRef) -> bool {
+        self.cpus.iter()
+            .filter_map(|cpu| cpu.current.as_ref())
+            .any(|current| Arc::ptr_eq(current, task))
+    }
+
+    /// Whether a ready task may be picked: it is not suspended and its group
+    /// still has CPU budget left in the current window.
+    fn is_runnable(&self, task: &TaskRef, now: u64) -> bool {
+        let task = task.read();
+        if task.state == TaskState::Suspended {
+            return false;
+        }
+        task.group_id
+            .and_then(|id| self.task_groups.get(&id))
+            .map_or(true, |group| !group.is_throttled(now))
+    }
+
+    /// Like `is_runnable`, but also rules out tasks another CPU has not
+    /// finished switching away from. `switching_out` is the task the calling
+    /// CPU is leaving, which it may pick again.
+    fn is_pickable(&self, task: &TaskRef, now: u64, switching_out: Option<&TaskRef>) -> bool {
+        let leaving = switching_out.map_or(false, |prev| Arc::ptr_eq(prev, task));
+        self.is_runnable(task, now) && (leaving || !task.read().on_cpu.load(Ordering::SeqCst))
+    }
+
+    /// Lets the policy choose among pickable tasks on `cpu` only. Suspended
+    /// tasks and throttled groups are set aside for this pick and keep their
+    /// queue.
+    fn pick_next(&mut self, cpu: usize, switching_out: Option<&TaskRef>) -> Option<TaskRef> {
+        let now = get_current_time();
+        let mut parked = Vec::new();
+
+        for level in 0..self.cpus[cpu].tasks.len() {
+            let queue = core::mem::take(&mut self.cpus[cpu].tasks[level]);
+            for task in queue {
+                if self.is_pickable(&task, now, switching_out) {
+                    self.cpus[cpu].tasks[level].push_back(task);
+                } else {
+                    parked.push((level, task));
+                }
+            }
+        }
+
+        let next = self.policy.pick_next(&mut self.cpus[cpu].tasks);
+        for (level, task) in parked {
+            self.cpus[cpu].tasks[level].push_back(task);
+        }
+        next
+    }
+
+    /// Takes a ready task from the busiest other CPU so no CPU idles while
+    /// another one has a backlog. The highest priority task is taken, from
+    /// the back of its queue where it is least likely to run soon anyway.
+    fn steal(&mut self, cpu: usize) -> Option<TaskRef> {
+        let now = get_current_time();
+        let victim = (0..self.cpus.len())
+            .filter(|&other| other != cpu)
+            .max_by_key(|&other| self.cpus[other].tasks.iter().map(|queue| queue.len()).sum::<usize>())?;
+
+        for level in (0..self.cpus[victim].tasks.len()).rev() {
+            let pos = self.cpus[victim].tasks[level].iter()
+                .rposition(|task| self.is_pickable(task, now, None));
+            if let Some(pos) = pos {
+                return self.cpus[victim].tasks[level].remove(pos);
+            }
+        }
+        None
+    }
+
+    /// Picks the task that should run next on the calling CPU and makes it
+    /// current. Returns the task to run, which may be the one already running.
+    pub fn schedule(&mut self) -> Option<TaskRef> {
+        let cpu = smp::cpu_id();
+        if cpu >= self.cpus.len() {
+            return None;
+        }
+        // Whatever ran before the current task has saved its context by now
+        self.cpus[cpu].previous = None;
+
+        if let Some(ref current) = self.cpus[cpu].current {
             let mut task = current.write();
+            let now = get_current_time();
             if let Some(last_scheduled) = task.stats.last_scheduled {
-                task.stats.total_runtime += get_current_time() - last_scheduled;
+                let delta = now.saturating_sub(last_scheduled);
+                let group = task.group_id.and_then(|id| self.task_groups.get_mut(&id));
+                let shares = group.as_ref().map_or(group::DEFAULT_CPU_SHARES, |group| group.cpu_shares());
+                if let Some(group) = group {
+                    group.account(now, delta);
+                }
+                task.stats.total_runtime += delta;
+                task.stats.vruntime += delta * policy::NICE_0_WEIGHT / policy::weight(task.priority)
+                    * group::DEFAULT_CPU_SHARES / shares;
             }
+            task.stats.last_scheduled = Some(now);
             task.stats.context_switches += 1;
         }
 
-        for priority_queue in &mut self.tasks {
-            for task in priority_queue.iter() {
-                let mut task = task.write();
-                if let Some(deadline) = task.deadline {
-                    if get_current_time() > deadline {
-                        task.boost_priority();
-                    }
-                }
+        if let Some(ref current) = self.cpus[cpu].current {
+            let throttled = !self.is_runnable(current, get_current_time());
+            let task = current.read();
+            let expired = task.decrement_time_slice();
+            if task.state == TaskState::Running && !task.idle && !expired && !throttled {
+                return self.cpus[cpu].current.clone();
             }
         }
 
-        if let Some(ref current) = self.current {
-            let task = current.read();
-            if !task.decrement_time_slice() {
-                return self.current.clone();
+        let previous = self.cpus[cpu].current.take();
+        if let Some(ref current) = previous {
+            // Blocked, suspended and terminated tasks leave the run queues
+            let requeue = {
+                let mut task = current.write();
+                if task.state == TaskState::Running && !task.idle {
+                    task.state = TaskState::Ready;
+                    task.reset_time_slice();
+                    true
+                } else {
+                    false
+                }
+            };
+            if requeue {
+                self.enqueue_on(cpu, Arc::clone(current));
             }
         }
 
-        if let Some(current) = self.current.take() {
-            let mut task = current.write();
-            if task.state != TaskState::Terminated && task.state != TaskState::Suspended {
-                task.state = TaskState::Ready;
-                task.reset_time_slice();
-                self.tasks[task.priority as usize].push_back(Arc::clone(&current));
+        if self.policy.uses_aging() {
+            self.age_ready_tasks(cpu);
+        }
+
+        let next = self.pick_next(cpu, previous.as_ref())
+            .or_else(|| self.steal(cpu))
+            .or_else(|| self.cpus[cpu].idle.clone())
+            .or_else(|| previous.clone());
+
+        if let Some(ref task) = next {
+            let mut task_write = task.write();
+            let now = get_current_time();
+            if let Some(enqueued_at) = task_write.stats.enqueued_at.take() {
+                let wait = now.saturating_sub(enqueued_at);
+                task_write.stats.total_wait += wait;
+                task_write.stats.max_wait = task_write.stats.max_wait.max(wait);
             }
+            // An aging boost only lasts until the task gets the CPU
+            task_write.end_aging();
+            task_write.state = TaskState::Running;
+            task_write.stats.last_scheduled = Some(now);
+            task_write.reset_time_slice();
+            task_write.on_cpu.store(true, Ordering::SeqCst);
+            let group = task_write.group_id.and_then(|id| self.task_groups.get(&id));
+            heap::set_running_group(group.map(TaskGroup::heap_slot));
+        }
+
+        let switched = match (&previous, &next) {
+            (Some(previous), Some(next)) => !Arc::ptr_eq(previous, next),
+            _ => true,
+        };
+        if switched {
+            self.cpus[cpu].previous = previous;
+        }
+        self.cpus[cpu].current = next;
+        self.cpus[cpu].current.clone()
+    }
+
+    /// Runs `schedule` and, if a different task was picked, returns what the
+    /// caller needs to switch to it once the scheduler lock is released.
+    pub(crate) fn switch_next(&mut self) -> Option<Switch> {
+        let cpu = smp::cpu_id();
+        let current = self.cpus.get(cpu)?.current.clone()?;
+        let next = self.schedule()?;
+        if Arc::ptr_eq(&current, &next) {
+            return None;
         }
 
-        for priority in (0..self.tasks.len()).rev() {
-            if let Some(task) = self.tasks[priority].pop_front() {
-                let mut task_write = task.write();
-                task_write.state = TaskState::Running;
-                task_write.stats.last_scheduled = Some(get_current_time());
-                drop(task_write);
-                self.current = Some(task);
-                return self.current.clone();
+        // Both tasks stay referenced by the scheduler (as `previous` and
+        // `current`) until the next pick on this CPU, so the pointers remain
+        // valid across the switch.
+        let mut current = current.write();
+        let next = next.read();
+        Some(Switch {
+            current: &mut current.context as *mut TaskContext,
+            next: &next.context as *const TaskContext,
+            current_on_cpu: &current.on_cpu as *const AtomicBool,
+        })
+    }
+
+    /// Raises tasks that have been waiting for too long one level at a time so
+    /// lower levels cannot be starved by a steady stream of higher work.
+    fn age_ready_tasks(&mut self, cpu: usize) {
+        let now = get_current_time();
+        let tasks = &mut self.cpus[cpu].tasks;
+        let top = tasks.len() - 1;
+
+        for level in 0..top {
+            let queue = core::mem::take(&mut tasks[level]);
+            for task in queue {
+                let raised = {
+                    let mut task_write = task.write();
+                    if task_write.age(now) {
+                        Some(task_write.priority as usize)
+                    } else {
+                        None
+                    }
+                };
+
+                match raised {
+                    Some(priority) => tasks[priority].push_back(task),
+                    None => tasks[level].push_back(task),
+                }
             }
         }
+    }
 
-        self.current.clone()
+    /// Snapshot of wait-time metrics for the running and all ready tasks.
+    pub fn wait_stats(&self) -> Vec<WaitStats> {
+        self.cpus.iter()
+            .flat_map(|cpu| cpu.current.iter().chain(cpu.tasks.iter().flatten()))
+            .filter(|task| !task.read().idle)
+            .map(|task| {
+                let task = task.read();
+                WaitStats {
+                    id: task.id,
+                    priority: task.priority,
+                    base_priority: task.base_priority,
+                    total_wait: task.stats.total_wait,
+                    max_wait: task.stats.max_wait,
+                    context_switches: task.stats.context_switches,
+                }
+            })
+            .collect()
     }
 
+    pub fn cpu_stats(&self) -> Vec<CpuStats> {
+        self.cpus.iter()
+            .enumerate()
+            .filter(|(_, queue)| queue.current.is_some())
+            .map(|(cpu, queue)| CpuStats {
+                cpu,
+                current: queue.current.as_ref()
+                    .map(|task| task.read())
+                    .filter(|task| !task.idle)
+                    .map(|task| task.id),
+                ready: queue.tasks.iter().map(|queue| queue.len()).sum(),
+            })
+            .collect()
+    }
+
+    pub fn current(&self) -> Option<TaskRef> {
+        self.cpus.get(smp::cpu_id())?.current.clone()
+    }
+
+    /// Marks the running task as blocked. It stays off the run queues once the
+    /// caller switches away, until `unblock_task` is called.
     pub fn block_current(&mut self) {
-        if let Some(ref current) = self.current {
+        if let Some(current) = self.current() {
             current.write().state = TaskState::Blocked;
         }
-        self.schedule();
     }
 
-    pub fn unblock_task(&mut self, task: Arc<RwLock<Task>>) {
+    pub fn unblock_task(&mut self, task: TaskRef) {
+        if self.is_current(&task) {
+            // Woken before it got to switch away: it simply keeps running
+            let mut task = task.write();
+            if task.state == TaskState::Blocked {
+                task.state = TaskState::Running;
+            }
+            return;
+        }
+        if !self.is_queued(&task) {
+            task.write().state = TaskState::Ready;
+            self.enqueue(task);
+        }
+    }
+
+    /// Moves a ready task into the queue matching its current priority, e.g.
+    /// after it inherited or gave back a priority boost.
+    pub fn requeue(&mut self, task: &TaskRef) {
         let priority = task.read().priority as usize;
-        task.write().state = TaskState::Ready;
-        self.tasks[priority].push_back(task);
+        let found = self.cpus.iter().enumerate().find_map(|(cpu, queue)| {
+            queue.tasks.iter().enumerate().find_map(|(level, queue)| {
+                queue.iter()
+                    .position(|queued| Arc::ptr_eq(queued, task))
+                    .map(|pos| (cpu, level, pos))
+            })
+        });
+
+        if let Some((cpu, level, pos)) = found {
+            if level != priority {
+                if let Some(task) = self.cpus[cpu].tasks[level].remove(pos) {
+                    self.cpus[cpu].tasks[priority].push_back(task);
+                }
+            }
+        }
     }
 }
 
@@ -283,28 +914,69 @@ lazy_static! {
     pub static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
 }
 
-pub fn spawn(entry_point: fn()) {
+/// Set by `init` once the heap exists and the scheduler may be entered from
+/// the timer interrupt.
+static READY: AtomicBool = AtomicBool::new(false);
+
+pub fn spawn(entry_point: fn()) -> Result<(), &'static str> {
     interrupts::without_interrupts(|| {
-        SCHEDULER.lock().spawn(entry_point);
-    });
+        SCHEDULER.lock().spawn(entry_point)
+    })
 }
 
-pub fn spawn_with_priority(entry_point: fn(), priority: TaskPriority) {
+pub fn spawn_with_priority(entry_point: fn(), priority: TaskPriority) -> Result<(), &'static str> {
     interrupts::without_interrupts(|| {
-        SCHEDULER.lock().spawn_with_priority(entry_point, priority);
-    });
+        SCHEDULER.lock().spawn_with_priority(entry_point, priority)
+    })
 }
 
 pub fn yield_now() {
+    if !READY.load(Ordering::SeqCst) {
+        return;
+    }
     unsafe {
         context::switch_context();
     }
 }
 
+/// Blocks the running task until another task calls `unblock_task` on it.
 pub fn block_current() {
     interrupts::without_interrupts(|| {
         SCHEDULER.lock().block_current();
     });
+    yield_now();
+}
+
+/// Blocks the running task until `woken` is set. Whoever sets it must call
+/// `unblock_task` afterwards; the flag is checked with the scheduler locked,
+/// so a wakeup between checking and blocking is not lost.
+pub fn park(woken: &AtomicBool) {
+    loop {
+        let blocked = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            if woken.load(Ordering::SeqCst) {
+                return false;
+            }
+            scheduler.block_current();
+            true
+        });
+        if !blocked {
+            return;
+        }
+        yield_now();
+    }
+}
+
+/// Terminates the running task. Called when a task's entry function returns.
+pub fn exit() -> ! {
+    interrupts::without_interrupts(|| {
+        if let Some(task) = current() {
+            task.write().state = TaskState::Terminated;
+        }
+    });
+    loop {
+        yield_now();
+    }
 }
 
 pub fn unblock_task(task: Arc<RwLock<Task>>) {
@@ -313,22 +985,85 @@ pub fn unblock_task(task: Arc<RwLock<Task>>) {
     });
 }
 
-pub fn init() {
-    println!("Task scheduler initialized");
+pub fn requeue(task: &Arc<RwLock<Task>>) {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().requeue(task);
+    });
 }
 
-pub fn spawn_with_deadline(entry_point: fn(), deadline: u64) {
+pub fn set_policy(kind: SchedPolicyKind) {
     interrupts::without_interrupts(|| {
-        SCHEDULER.lock().spawn_with_deadline(entry_point, deadline);
+        SCHEDULER.lock().set_policy(kind);
     });
 }
 
-pub fn spawn_in_group(entry_point: fn(), group_id: usize) {
+pub fn policy_name() -> &'static str {
     interrupts::without_interrupts(|| {
-        SCHEDULER.lock().spawn_in_group(entry_point, group_id);
+        SCHEDULER.lock().policy_name()
+    })
+}
+
+pub fn wait_stats() -> Vec<WaitStats> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().wait_stats()
+    })
+}
+
+/// Returns the task currently running on this CPU, if any.
+pub fn current() -> Option<Arc<RwLock<Task>>> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().current()
+    })
+}
+
+pub fn cpu_stats() -> Vec<CpuStats> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().cpu_stats()
+    })
+}
+
+/// Body of every CPU's idle task: hand the CPU to anything that became
+/// runnable, otherwise wait for the next interrupt. Work queued from another
+/// CPU arrives with a reschedule IPI.
+pub fn idle_loop() -> ! {
+    loop {
+        yield_now();
+        x86_64::instructions::hlt();
+    }
+}
+
+fn bsp_idle() {
+    idle_loop();
+}
+
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().set_idle_task(0, bsp_idle)
+    }).expect("failed to allocate the idle task");
+    READY.store(true, Ordering::SeqCst);
+    println!("Task scheduler initialized ({} policy)", policy_name());
+}
+
+/// Gives application processor `cpu` a run queue. The caller's code becomes
+/// the CPU's idle task.
+pub fn init_ap(cpu: usize) {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().register_cpu(cpu);
     });
 }
 
+pub fn spawn_with_deadline(entry_point: fn(), deadline: u64) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().spawn_with_deadline(entry_point, deadline)
+    })
+}
+
+pub fn spawn_in_group(entry_point: fn(), group_id: usize) -> Result<(), GroupError> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().spawn_in_group(entry_point, group_id)
+    })
+}
+
 pub fn suspend_group(group_id: usize) {
     interrupts::without_interrupts(|| {
         SCHEDULER.lock().suspend_group(group_id);
@@ -339,4 +1074,116 @@ pub fn resume_group(group_id: usize) {
     interrupts::without_interrupts(|| {
         SCHEDULER.lock().resume_group(group_id);
     });
-} 
\ No newline at end of file
+}
+
+pub fn create_group(name: &str) -> Result<usize, GroupError> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().create_group(name)
+    })
+}
+
+pub fn remove_group(group_id: usize) -> Result<(), GroupError> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().remove_group(group_id)
+    })
+}
+
+pub fn kill_group(group_id: usize) -> Result<usize, GroupError> {
+    let killed = interrupts::without_interrupts(|| {
+        SCHEDULER.lock().kill_group(group_id)
+    })?;
+    // The caller may have been one of the members
+    if current().map_or(false, |task| task.read().state == TaskState::Terminated) {
+        exit();
+    }
+    Ok(killed)
+}
+
+pub fn move_task(task_id: usize, group_id: usize) -> Result<(), GroupError> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().move_task(task_id, group_id)
+    })
+}
+
+/// Runs `f` on a group while holding the scheduler lock, e.g. to adjust limits.
+pub fn with_group<R>(group_id: usize, f: impl FnOnce(&mut TaskGroup) -> R) -> Result<R, GroupError> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().group_mut(group_id).map(f).ok_or(GroupError::NotFound)
+    })
+}
+
+pub fn group_stats(group_id: usize) -> Option<GroupStats> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().group_stats(group_id)
+    })
+}
+
+pub fn list_groups() -> Vec<GroupStats> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().list_groups()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = AGING_INTERVAL * 10;
+
+    /// A ready task at `priority` that was queued `waited` cycles before `NOW`.
+    fn waiting(priority: TaskPriority, waited: u64) -> Task {
+        let mut task = Task::bootstrap(priority);
+        task.state = TaskState::Ready;
+        task.stats.enqueued_at = Some(NOW - waited);
+        task
+    }
+
+    #[test_case]
+    fn aging_raises_one_level_per_interval() {
+        let mut task = waiting(TaskPriority::Low, AGING_INTERVAL);
+        assert!(!task.age(NOW));
+
+        task.stats.enqueued_at = Some(NOW - AGING_INTERVAL - 1);
+        assert!(task.age(NOW));
+        assert_eq!(task.priority, TaskPriority::Normal);
+        // The second level takes a second interval
+        assert!(!task.age(NOW));
+
+        task.stats.enqueued_at = Some(NOW - 2 * AGING_INTERVAL - 1);
+        assert!(task.age(NOW));
+        assert_eq!(task.priority, TaskPriority::High);
+        assert!(!task.age(NOW));
+    }
+
+    #[test_case]
+    fn aging_ends_when_the_task_runs() {
+        let mut task = waiting(TaskPriority::Low, 3 * AGING_INTERVAL);
+        assert!(task.age(NOW));
+        assert!(task.age(NOW));
+        task.end_aging();
+        assert_eq!(task.priority, TaskPriority::Low);
+        assert_eq!(task.stats.aged_levels, 0);
+    }
+
+    #[test_case]
+    fn aging_keeps_deadline_boost() {
+        let mut task = waiting(TaskPriority::Low, AGING_INTERVAL + 1);
+        assert!(task.age(NOW));
+        // A missed deadline, as `StrictPriority` raises it
+        task.boost_priority();
+        assert_eq!(task.priority, TaskPriority::High);
+        task.end_aging();
+        assert_eq!(task.priority, TaskPriority::Normal);
+    }
+
+    #[test_case]
+    fn inherited_priority_is_not_an_aging_step() {
+        let mut task = waiting(TaskPriority::Low, AGING_INTERVAL + 1);
+        // Lent by a waiter, as `sync::propagate_priority` does
+        task.priority = TaskPriority::Normal;
+        assert!(task.age(NOW));
+        assert_eq!(task.priority, TaskPriority::High);
+        task.end_aging();
+        assert_eq!(task.priority, TaskPriority::Normal);
+    }
+}
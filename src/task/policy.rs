@@ -0,0 +1,182 @@
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc};
+use spin::RwLock;
+use super::{get_current_time, Task, TaskPriority};
+
+type TaskRef = Arc<RwLock<Task>>;
+
+/// Load weight of a `Normal` task; other levels are scaled relative to it.
+pub const NICE_0_WEIGHT: u64 = 1024;
+
+/// Decides which ready task runs next. The scheduler keeps one ready queue per
+/// priority level and hands all of them to the policy on every pick.
+pub trait SchedPolicy: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Called whenever a task is put on a ready queue.
+    fn enqueue(&mut self, _task: &mut Task) {}
+
+    /// Removes and returns the next task to run from the ready queues.
+    fn pick_next(&mut self, queues: &mut [VecDeque<TaskRef>]) -> Option<TaskRef>;
+
+    /// Whether the scheduler should raise long-waiting tasks before picking.
+    /// Policies that cannot starve a task can opt out.
+    fn uses_aging(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicyKind {
+    Priority,
+    FairShare,
+    Deadline,
+}
+
+impl SchedPolicyKind {
+    pub const ALL: [SchedPolicyKind; 3] = [
+        SchedPolicyKind::Priority,
+        SchedPolicyKind::FairShare,
+        SchedPolicyKind::Deadline,
+    ];
+
+    /// Policy the kernel boots with, chosen through the `sched-fair` and
+    /// `sched-edf` cargo features.
+    pub const fn boot_default() -> Self {
+        if cfg!(feature = "sched-edf") {
+            SchedPolicyKind::Deadline
+        } else if cfg!(feature = "sched-fair") {
+            SchedPolicyKind::FairShare
+        } else {
+            SchedPolicyKind::Priority
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SchedPolicyKind::Priority => "priority",
+            SchedPolicyKind::FairShare => "fair",
+            SchedPolicyKind::Deadline => "edf",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+
+    pub fn build(self) -> Box<dyn SchedPolicy> {
+        match self {
+            SchedPolicyKind::Priority => Box::new(StrictPriority),
+            SchedPolicyKind::FairShare => Box::new(FairShare::new()),
+            SchedPolicyKind::Deadline => Box::new(EarliestDeadlineFirst),
+        }
+    }
+}
+
+/// Load weight used to scale runtime into virtual runtime.
+pub fn weight(priority: TaskPriority) -> u64 {
+    match priority {
+        TaskPriority::Low => NICE_0_WEIGHT / 2,
+        TaskPriority::Normal => NICE_0_WEIGHT,
+        TaskPriority::High => NICE_0_WEIGHT * 2,
+    }
+}
+
+/// Round robin within a level, always draining higher levels first. Tasks that
+/// have missed their deadline are boosted one level.
+pub struct StrictPriority;
+
+impl StrictPriority {
+    fn pop_highest(queues: &mut [VecDeque<TaskRef>]) -> Option<TaskRef> {
+        queues.iter_mut().rev().find_map(|queue| queue.pop_front())
+    }
+}
+
+impl SchedPolicy for StrictPriority {
+    fn name(&self) -> &'static str {
+        SchedPolicyKind::Priority.name()
+    }
+
+    fn pick_next(&mut self, queues: &mut [VecDeque<TaskRef>]) -> Option<TaskRef> {
+        let now = get_current_time();
+        for queue in queues.iter() {
+            for task in queue.iter() {
+                let mut task = task.write();
+                if task.deadline.map_or(false, |deadline| now > deadline) {
+                    task.boost_priority();
+                }
+            }
+        }
+
+        Self::pop_highest(queues)
+    }
+}
+
+/// CFS-like fair share: runs the task with the smallest virtual runtime, where
+/// virtual runtime is `total_runtime` scaled down by the task's weight.
+pub struct FairShare {
+    min_vruntime: u64,
+}
+
+impl FairShare {
+    pub fn new() -> Self {
+        Self { min_vruntime: 0 }
+    }
+}
+
+impl SchedPolicy for FairShare {
+    fn name(&self) -> &'static str {
+        SchedPolicyKind::FairShare.name()
+    }
+
+    fn uses_aging(&self) -> bool {
+        false
+    }
+
+    fn enqueue(&mut self, task: &mut Task) {
+        // Newly spawned or long-sleeping tasks start at the current minimum
+        // instead of monopolising the CPU until they catch up.
+        if task.stats.vruntime < self.min_vruntime {
+            task.stats.vruntime = self.min_vruntime;
+        }
+    }
+
+    fn pick_next(&mut self, queues: &mut [VecDeque<TaskRef>]) -> Option<TaskRef> {
+        let (level, pos, vruntime) = queues.iter()
+            .enumerate()
+            .flat_map(|(level, queue)| {
+                queue.iter().enumerate().map(move |(pos, task)| {
+                    (level, pos, task.read().stats.vruntime)
+                })
+            })
+            .min_by_key(|&(_, _, vruntime)| vruntime)?;
+
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        queues[level].remove(pos)
+    }
+}
+
+/// Earliest deadline first for tasks spawned with a deadline; tasks without
+/// one only run when no deadline task is ready, in strict priority order.
+pub struct EarliestDeadlineFirst;
+
+impl SchedPolicy for EarliestDeadlineFirst {
+    fn name(&self) -> &'static str {
+        SchedPolicyKind::Deadline.name()
+    }
+
+    fn pick_next(&mut self, queues: &mut [VecDeque<TaskRef>]) -> Option<TaskRef> {
+        let earliest = queues.iter()
+            .enumerate()
+            .flat_map(|(level, queue)| {
+                queue.iter().enumerate().filter_map(move |(pos, task)| {
+                    task.read().deadline.map(|deadline| (level, pos, deadline))
+                })
+            })
+            .min_by_key(|&(_, _, deadline)| deadline);
+
+        match earliest {
+            Some((level, pos, _)) => queues[level].remove(pos),
+            None => StrictPriority::pop_highest(queues),
+        }
+    }
+}
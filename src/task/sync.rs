@@ -2,6 +2,10 @@ use alloc::collections::VecDeque;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use spin::{Mutex as SpinMutex, MutexGuard};
 use alloc::sync::Arc;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use x86_64::instructions::interrupts;
+use super::{Task, TaskPriority};
 
 pub struct Semaphore {
     count: AtomicUsize,
@@ -47,44 +51,257 @@ impl Semaphore {
     }
 }
 
+/// Upper bound on how many owners a single boost is forwarded through, so a
+/// cycle of tasks blocked on each other cannot hang the kernel.
+const MAX_INHERITANCE_DEPTH: usize = 8;
+
+type TaskRef = Arc<spin::RwLock<Task>>;
+
+/// A task queued on a priority-inheriting lock. `task` is `None` when the lock
+/// is taken outside of any scheduled task (e.g. during early boot).
+struct Waiter {
+    flag: Arc<AtomicBool>,
+    task: Option<TaskRef>,
+}
+
+/// Owner and waiter bookkeeping shared between a lock and the tasks that hold
+/// or wait on it.
+pub struct InheritanceState {
+    owner: SpinMutex<Option<TaskRef>>,
+    waiters: SpinMutex<VecDeque<Waiter>>,
+}
+
+impl InheritanceState {
+    fn new() -> Self {
+        Self {
+            owner: SpinMutex::new(None),
+            waiters: SpinMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Highest effective priority among the tasks waiting on this lock.
+    fn highest_waiter_priority(&self) -> Option<TaskPriority> {
+        self.waiters.lock()
+            .iter()
+            .filter_map(|waiter| waiter.task.as_ref())
+            .map(|task| task.read().priority)
+            .max()
+    }
+
+    fn remove_waiter(&self, flag: &Arc<AtomicBool>) {
+        self.waiters.lock().retain(|waiter| !Arc::ptr_eq(&waiter.flag, flag));
+    }
+
+    /// Wakes the waiter with the highest priority, the longest waiting of
+    /// them on a tie, so the boost it lent goes to the task that needs it.
+    fn wake_next(&self) {
+        let mut waiters = self.waiters.lock();
+        let next = waiters.iter()
+            .enumerate()
+            .max_by_key(|&(index, waiter)| {
+                (waiter.task.as_ref().map(|task| task.read().priority), core::cmp::Reverse(index))
+            })
+            .map(|(index, _)| index);
+        if let Some(waiter) = next.and_then(|index| waiters.remove(index)) {
+            waiter.flag.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl fmt::Debug for InheritanceState {
+    // Only the owner id is printed: the owner's `Task` refers back to this
+    // state through `held_locks`, so a derived impl would recurse forever.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InheritanceState")
+            .field("owner", &self.owner.lock().as_ref().map(|task| task.read().id))
+            .field("waiters", &self.waiters.lock().len())
+            .finish()
+    }
+}
+
+/// Raises the owner of `state` to at least `priority`, following the chain of
+/// locks that owner is itself blocked on.
+fn propagate_priority(state: &Arc<InheritanceState>, priority: TaskPriority) {
+    let mut next = Some(Arc::clone(state));
+    let mut depth = 0;
+
+    while let Some(state) = next.take() {
+        if depth == MAX_INHERITANCE_DEPTH {
+            break;
+        }
+        depth += 1;
+
+        let owner = match state.owner.lock().clone() {
+            Some(owner) => owner,
+            None => break,
+        };
+
+        let mut task = owner.write();
+        if task.priority >= priority {
+            break;
+        }
+        task.inherit(priority);
+        next = task.blocked_on.clone();
+        drop(task);
+
+        super::requeue(&owner);
+    }
+}
+
+/// Drops any inherited boost on `task`, keeping the highest priority still
+/// demanded by waiters on locks it continues to hold.
+fn restore_priority(task: &TaskRef) {
+    task.write().restore_priority();
+    super::requeue(task);
+}
+
+impl Task {
+    /// Highest priority lent to this task by waiters on locks it holds.
+    pub(super) fn inherited_priority(&self) -> Option<TaskPriority> {
+        self.held_locks.iter()
+            .filter_map(|state| state.highest_waiter_priority())
+            .max()
+    }
+
+    /// Raises the task to `priority`, counting the levels as lent.
+    fn inherit(&mut self, priority: TaskPriority) {
+        while self.priority < priority {
+            self.priority = self.priority.raised();
+            self.inherited_levels += 1;
+        }
+    }
+
+    /// Takes back the lent levels only, so what aging or a missed deadline
+    /// added stays, then lends again what waiters on locks still held ask.
+    fn restore_priority(&mut self) {
+        for _ in 0..core::mem::take(&mut self.inherited_levels) {
+            self.priority = self.priority.lowered().max(self.base_priority);
+        }
+        if let Some(priority) = self.inherited_priority() {
+            self.inherit(priority);
+        }
+    }
+}
+
+/// A sleeping mutex that lends the priority of its highest waiter to the
+/// current owner until the lock is released.
 pub struct BlockingMutex<T> {
     inner: SpinMutex<T>,
-    waiters: SpinMutex<VecDeque<Arc<AtomicBool>>>,
+    state: Arc<InheritanceState>,
 }
 
 impl<T> BlockingMutex<T> {
     pub fn new(value: T) -> Self {
         Self {
             inner: SpinMutex::new(value),
-            waiters: SpinMutex::new(VecDeque::new()),
+            state: Arc::new(InheritanceState::new()),
         }
     }
 
-    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
-        self.inner.try_lock()
+    pub fn try_lock(&self) -> Option<BlockingMutexGuard<T>> {
+        let guard = self.inner.try_lock()?;
+
+        // The scheduler inspects task and waiter state from the timer
+        // interrupt, so none of those locks may be held with interrupts on
+        interrupts::without_interrupts(|| {
+            let current = super::current();
+            if let Some(ref task) = current {
+                task.write().held_locks.push(Arc::clone(&self.state));
+            }
+            *self.state.owner.lock() = current;
+
+            // Tasks that queued up while the lock was free still need the boost
+            if let Some(priority) = self.state.highest_waiter_priority() {
+                propagate_priority(&self.state, priority);
+            }
+        });
+
+        Some(BlockingMutexGuard {
+            mutex: self,
+            guard: Some(guard),
+        })
     }
 
-    pub fn lock(&self) -> MutexGuard<T> {
+    pub fn lock(&self) -> BlockingMutexGuard<T> {
+        let current = super::current();
+        let mut waiter: Option<Arc<AtomicBool>> = None;
+
         loop {
             if let Some(guard) = self.try_lock() {
+                interrupts::without_interrupts(|| {
+                    if let Some(flag) = waiter {
+                        self.state.remove_waiter(&flag);
+                    }
+                    if let Some(ref task) = current {
+                        task.write().blocked_on = None;
+                    }
+                });
                 return guard;
             }
 
-            // Create a waiter flag
-            let waiter = Arc::new(AtomicBool::new(false));
-            self.waiters.lock().push_back(Arc::clone(&waiter));
-
-            // Wait until we're woken up
-            while !waiter.load(Ordering::SeqCst) {
-                super::yield_now();
+            match waiter {
+                // Still queued: let the owner run so it can release the lock
+                Some(ref flag) if !flag.load(Ordering::SeqCst) => super::yield_now(),
+                _ => waiter = Some(interrupts::without_interrupts(|| self.enqueue(current.as_ref()))),
             }
         }
     }
 
-    pub fn unlock_next(&self) {
-        if let Some(waiter) = self.waiters.lock().pop_front() {
-            waiter.store(true, Ordering::SeqCst);
+    /// Queues the caller as a waiter and lends its priority to the owner.
+    fn enqueue(&self, current: Option<&TaskRef>) -> Arc<AtomicBool> {
+        let flag = Arc::new(AtomicBool::new(false));
+        self.state.waiters.lock().push_back(Waiter {
+            flag: Arc::clone(&flag),
+            task: current.cloned(),
+        });
+
+        if let Some(task) = current {
+            let priority = {
+                let mut task = task.write();
+                task.blocked_on = Some(Arc::clone(&self.state));
+                task.priority
+            };
+            propagate_priority(&self.state, priority);
         }
+
+        flag
+    }
+}
+
+/// RAII guard for [`BlockingMutex`]. Dropping it restores the owner's priority
+/// and wakes the next waiter.
+pub struct BlockingMutexGuard<'a, T> {
+    mutex: &'a BlockingMutex<T>,
+    guard: Option<MutexGuard<'a, T>>,
+}
+
+impl<'a, T> Deref for BlockingMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for BlockingMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for BlockingMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let state = &self.mutex.state;
+        interrupts::without_interrupts(|| {
+            let owner = state.owner.lock().take();
+            if let Some(owner) = owner {
+                owner.write().held_locks.retain(|held| !Arc::ptr_eq(held, state));
+                restore_priority(&owner);
+            }
+        });
+
+        drop(self.guard.take());
+        state.wake_next();
     }
 }
 
@@ -99,21 +316,20 @@ impl Condvar {
         }
     }
 
-    pub fn wait<T>(&self, mutex: &BlockingMutex<T>) {
+    pub fn wait<'a, T>(&self, guard: BlockingMutexGuard<'a, T>) -> BlockingMutexGuard<'a, T> {
+        let mutex = guard.mutex;
         let waiter = Arc::new(AtomicBool::new(false));
         self.waiters.lock().push_back(Arc::clone(&waiter));
 
         // Release the mutex and wait
-        unsafe {
-            mutex.inner.force_unlock();
-        }
+        drop(guard);
 
         while !waiter.load(Ordering::SeqCst) {
             super::yield_now();
         }
 
         // Reacquire the mutex
-        let _ = mutex.lock();
+        mutex.lock()
     }
 
     pub fn notify_one(&self) {
@@ -174,4 +390,95 @@ impl<T> RwLock<T> {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::TaskState;
+
+    fn task(priority: TaskPriority) -> TaskRef {
+        let mut task = Task::bootstrap(priority);
+        task.state = TaskState::Ready;
+        Arc::new(spin::RwLock::new(task))
+    }
+
+    /// A lock held by `owner`, as `BlockingMutex::try_lock` records it.
+    fn held_by(owner: &TaskRef) -> Arc<InheritanceState> {
+        let state = Arc::new(InheritanceState::new());
+        *state.owner.lock() = Some(Arc::clone(owner));
+        owner.write().held_locks.push(Arc::clone(&state));
+        state
+    }
+
+    /// Queues `waiter` on `state` and lends its priority, as `enqueue` does.
+    fn wait_on(state: &Arc<InheritanceState>, waiter: &TaskRef) -> Arc<AtomicBool> {
+        let flag = Arc::new(AtomicBool::new(false));
+        state.waiters.lock().push_back(Waiter { flag: Arc::clone(&flag), task: Some(Arc::clone(waiter)) });
+        let priority = {
+            let mut waiter = waiter.write();
+            waiter.blocked_on = Some(Arc::clone(state));
+            waiter.priority
+        };
+        propagate_priority(state, priority);
+        flag
+    }
+
+    /// Drops `state` from `owner`, as the guard's `Drop` does.
+    fn release(state: &Arc<InheritanceState>, owner: &TaskRef) {
+        state.owner.lock().take();
+        owner.write().held_locks.retain(|held| !Arc::ptr_eq(held, state));
+        restore_priority(owner);
+    }
+
+    #[test_case]
+    fn inheritance_follows_a_chain_of_owners() {
+        let (low, middle, high) = (task(TaskPriority::Low), task(TaskPriority::Low), task(TaskPriority::High));
+        let first = held_by(&low);
+        let second = held_by(&middle);
+        wait_on(&first, &middle);
+        wait_on(&second, &high);
+        assert_eq!(middle.read().priority, TaskPriority::High);
+        assert_eq!(low.read().priority, TaskPriority::High);
+    }
+
+    #[test_case]
+    fn unlock_gives_back_only_inherited_levels() {
+        let owner = task(TaskPriority::Low);
+        // A missed deadline, as `StrictPriority` raises it
+        owner.write().boost_priority();
+        let state = held_by(&owner);
+        wait_on(&state, &task(TaskPriority::High));
+        assert_eq!(owner.read().priority, TaskPriority::High);
+        release(&state, &owner);
+        assert_eq!(owner.read().priority, TaskPriority::Normal);
+        assert_eq!(owner.read().inherited_levels, 0);
+    }
+
+    #[test_case]
+    fn unlock_keeps_boost_from_locks_still_held() {
+        let owner = task(TaskPriority::Low);
+        let first = held_by(&owner);
+        let second = held_by(&owner);
+        wait_on(&first, &task(TaskPriority::High));
+        wait_on(&second, &task(TaskPriority::Normal));
+        release(&first, &owner);
+        assert_eq!(owner.read().priority, TaskPriority::Normal);
+        release(&second, &owner);
+        assert_eq!(owner.read().priority, TaskPriority::Low);
+    }
+
+    #[test_case]
+    fn highest_priority_waiter_is_woken_first() {
+        let state = held_by(&task(TaskPriority::Low));
+        let normal = wait_on(&state, &task(TaskPriority::Normal));
+        let high = wait_on(&state, &task(TaskPriority::High));
+        let second_high = wait_on(&state, &task(TaskPriority::High));
+        state.wake_next();
+        assert!(high.load(Ordering::SeqCst));
+        assert!(!second_high.load(Ordering::SeqCst) && !normal.load(Ordering::SeqCst));
+        state.wake_next();
+        assert!(second_high.load(Ordering::SeqCst));
+        assert!(!normal.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,137 @@
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Timer interrupts since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// A future waiting for the tick it wants to be woken at.
+struct Sleeper {
+    id: usize,
+    deadline: u64,
+    waker: Waker,
+}
+
+/// Futures waiting for a tick, one entry per `Sleep` or `Interval`.
+static SLEEPERS: Mutex<Vec<Sleeper>> = Mutex::new(Vec::new());
+
+fn next_sleeper_id() -> usize {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Called from the timer interrupt handler.
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    // If the lock is held the interrupted code is registering a sleeper; it
+    // will be picked up on the next tick.
+    if let Some(mut sleepers) = SLEEPERS.try_lock() {
+        sleepers.retain(|sleeper| {
+            if sleeper.deadline <= now {
+                sleeper.waker.wake_by_ref();
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Asks for `waker` to be woken at `deadline`, replacing what future `id`
+/// registered before so repeated polls don't pile up entries.
+fn register(id: usize, deadline: u64, waker: &Waker) {
+    interrupts::without_interrupts(|| {
+        let mut sleepers = SLEEPERS.lock();
+        match sleepers.iter_mut().find(|sleeper| sleeper.id == id) {
+            Some(sleeper) => {
+                sleeper.deadline = deadline;
+                if !sleeper.waker.will_wake(waker) {
+                    sleeper.waker = waker.clone();
+                }
+            }
+            None => sleepers.push(Sleeper { id, deadline, waker: waker.clone() }),
+        }
+    });
+}
+
+fn unregister(id: usize) {
+    interrupts::without_interrupts(|| {
+        SLEEPERS.lock().retain(|sleeper| sleeper.id != id);
+    });
+}
+
+/// Completes once `ticks` timer interrupts have fired.
+pub fn sleep_ticks(ticks: u64) -> Sleep {
+    Sleep {
+        id: next_sleeper_id(),
+        deadline: self::ticks() + ticks,
+    }
+}
+
+pub struct Sleep {
+    id: usize,
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+        register(self.id, self.deadline, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        unregister(self.id);
+    }
+}
+
+/// Yields the current tick count every `period` ticks.
+pub fn interval(period: u64) -> Interval {
+    Interval {
+        id: next_sleeper_id(),
+        period: period.max(1),
+        next: ticks() + period.max(1),
+    }
+}
+
+pub struct Interval {
+    id: usize,
+    period: u64,
+    next: u64,
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        let now = ticks();
+        if now >= self.next {
+            self.next = now + self.period;
+            return Poll::Ready(Some(now));
+        }
+        register(self.id, self.next, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Interval {
+    fn drop(&mut self) {
+        unregister(self.id);
+    }
+}